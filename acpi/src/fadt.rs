@@ -0,0 +1,174 @@
+//! Module that parses the Fixed ACPI Description Table (FADT), which describes the fixed hardware
+//! features of the platform.
+use parseme::ReadMe;
+use read_me::{Reader, ReaderError};
+use crate::sdt::{GenericAddress, SdtHeader, SDT_HEADER_SIZE};
+use crate::AcpiError;
+
+pub const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+
+// Size of the ACPI 1.0 FADT
+const FADT_V1_SIZE: usize = 116;
+// Size of the FADT up to and including `x_dsdt`
+const FADT_X_DSDT_END: usize = 148;
+
+// IA-PC boot architecture flags
+// The motherboard contains support for a port 60 and 64 based keyboard controller
+const BOOT_ARCH_8042: u16 = 1 << 1;
+// The system has LPC or ISA devices that cannot be enumerated
+const BOOT_ARCH_LEGACY_DEVICES: u16 = 1 << 0;
+// The FADT `flags` bit saying the reset register is supported
+const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+// Fields present in all the FADT revisions
+#[derive(Debug)]
+#[derive(ReadMe)]
+struct FadtV1 {
+    firmware_ctrl: u32,
+    dsdt: u32,
+    reserved0: u8,
+    preferred_pm_profile: u8,
+    sci_int: u16,
+    smi_cmd: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_req: u8,
+    pstate_cnt: u8,
+    pm1a_evt_blk: u32,
+    pm1b_evt_blk: u32,
+    pm1a_cnt_blk: u32,
+    pm1b_cnt_blk: u32,
+    pm2_cnt_blk: u32,
+    pm_tmr_blk: u32,
+    gpe0_blk: u32,
+    gpe1_blk: u32,
+    pm1_evt_len: u8,
+    pm1_cnt_len: u8,
+    pm2_cnt_len: u8,
+    pm_tmr_len: u8,
+    gpe0_blk_len: u8,
+    gpe1_blk_len: u8,
+    gpe1_base: u8,
+    cst_cnt: u8,
+    p_lvl2_lat: u16,
+    p_lvl3_lat: u16,
+    flush_size: u16,
+    flush_stride: u16,
+    duty_offset: u8,
+    duty_width: u8,
+    day_alrm: u8,
+    mon_alrm: u8,
+    century: u8,
+    iapc_boot_arch: u16,
+    reserved1: u8,
+    flags: u32,
+}
+
+// Fields added by ACPI 2.0, up to the 64-bit DSDT address
+#[derive(Debug)]
+#[derive(ReadMe)]
+struct FadtV2 {
+    reset_reg: GenericAddress,
+    reset_value: u8,
+    arm_boot_arch: u16,
+    fadt_minor_version: u8,
+    x_firmware_ctrl: u64,
+    x_dsdt: u64,
+}
+
+/// Fixed ACPI Description Table
+#[derive(Debug)]
+pub struct Fadt {
+    header: SdtHeader,
+    v1: FadtV1,
+    // Only present if the table is long enough
+    v2: Option<FadtV2>,
+}
+
+impl Fadt {
+    /// Parse the FADT from `bytes`, which contain the whole table, including the SDT header
+    pub fn parse(header: SdtHeader, bytes: &[u8]) -> Result<Self, AcpiError> {
+        let length = usize::try_from(header.length())?;
+        if length < FADT_V1_SIZE {
+            return Err(AcpiError::Length(header.signature(), header.length()));
+        }
+
+        let mut reader = Reader::from(bytes);
+        reader.seek(SDT_HEADER_SIZE)?;
+        let v1 = reader.read::<FadtV1>()?;
+
+        let v2 = if length >= FADT_X_DSDT_END {
+            Some(reader.read::<FadtV2>()?)
+        } else {
+            None
+        };
+
+        Ok(Self { header, v1, v2 })
+    }
+
+    pub fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+
+    /// Returns the physical address of the DSDT, preferring the 64-bit one if present
+    pub fn dsdt(&self) -> u64 {
+        self.v2.as_ref()
+            .map(|v2| v2.x_dsdt)
+            .filter(|x_dsdt| *x_dsdt != 0)
+            .unwrap_or(u64::from(self.v1.dsdt))
+    }
+
+    /// Returns the legacy 8259 IRQ the System Control Interrupt is wired to
+    pub fn sci_interrupt(&self) -> u16 {
+        self.v1.sci_int
+    }
+
+    /// Returns the I/O port used to transfer the control of the ACPI hardware from the firmware
+    pub fn smi_command_port(&self) -> u32 {
+        self.v1.smi_cmd
+    }
+
+    /// Returns the values written to the SMI command port to enable and disable ACPI mode
+    pub fn acpi_enable_disable(&self) -> (u8, u8) {
+        (self.v1.acpi_enable, self.v1.acpi_disable)
+    }
+
+    /// Returns the I/O port of the power management 1a control block
+    pub fn pm1a_control_block(&self) -> u32 {
+        self.v1.pm1a_cnt_blk
+    }
+
+    /// Returns the I/O port of the ACPI power management timer, if the platform has one
+    pub fn pm_timer_block(&self) -> Option<u32> {
+        Some(self.v1.pm_tmr_blk).filter(|_| self.v1.pm_tmr_len == 4)
+    }
+
+    /// Returns the index of the century in the RTC CMOS RAM, if supported
+    pub fn century(&self) -> Option<u8> {
+        Some(self.v1.century).filter(|century| *century != 0)
+    }
+
+    /// Returns `true` if the system has a PS/2 keyboard controller. ACPI 1.0 tables do not have
+    /// the flags, so the controller is assumed to be there.
+    pub fn has_8042(&self) -> bool {
+        self.header.revision() < 2 || self.v1.iapc_boot_arch & BOOT_ARCH_8042 != 0
+    }
+
+    /// Returns `true` if the system has legacy devices which cannot be enumerated
+    pub fn has_legacy_devices(&self) -> bool {
+        self.header.revision() < 2 || self.v1.iapc_boot_arch & BOOT_ARCH_LEGACY_DEVICES != 0
+    }
+
+    /// Returns the fixed feature flags
+    pub fn flags(&self) -> u32 {
+        self.v1.flags
+    }
+
+    /// Returns the register and the value to write into it in order to reset the system
+    pub fn reset_register(&self) -> Option<(GenericAddress, u8)> {
+        if self.v1.flags & FLAG_RESET_REG_SUP == 0 {
+            return None;
+        }
+        self.v2.as_ref().map(|v2| (v2.reset_reg, v2.reset_value))
+    }
+}
@@ -0,0 +1,75 @@
+//! Module that parses the High Precision Event Timer description table
+use parseme::ReadMe;
+use read_me::{Reader, ReaderError};
+use crate::sdt::{GenericAddress, SdtHeader, SDT_HEADER_SIZE};
+use crate::AcpiError;
+
+pub const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+
+// The main counter is 64 bits wide
+const COUNTER_SIZE_64: u32 = 1 << 13;
+
+#[derive(Debug)]
+#[derive(ReadMe)]
+struct HpetFields {
+    // Copy of the low 32 bits of the General Capabilities and ID register
+    event_timer_block_id: u32,
+    // Address of the HPET registers
+    base_address: GenericAddress,
+    // Sequence number of this HPET
+    hpet_number: u8,
+    // Minimum clock tick in periodic mode, without lost interrupts
+    minimum_tick: u16,
+    // Page protection and OEM attributes
+    page_protection: u8,
+}
+
+/// High Precision Event Timer description table
+#[derive(Debug)]
+pub struct Hpet {
+    header: SdtHeader,
+    fields: HpetFields,
+}
+
+impl Hpet {
+    /// Parse the HPET table from `bytes`, which contain the whole table, including the SDT header
+    pub fn parse(header: SdtHeader, bytes: &[u8]) -> Result<Self, AcpiError> {
+        let mut reader = Reader::from(bytes);
+        reader.seek(SDT_HEADER_SIZE)?;
+        let fields = reader.read::<HpetFields>()?;
+
+        Ok(Self { header, fields })
+    }
+
+    pub fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+
+    /// Returns the location of the HPET registers
+    pub fn base_address(&self) -> GenericAddress {
+        self.fields.base_address
+    }
+
+    pub fn hpet_number(&self) -> u8 {
+        self.fields.hpet_number
+    }
+
+    pub fn minimum_tick(&self) -> u16 {
+        self.fields.minimum_tick
+    }
+
+    /// Returns the PCI vendor ID of the HPET
+    pub fn vendor_id(&self) -> u16 {
+        (self.fields.event_timer_block_id >> 16) as u16
+    }
+
+    /// Returns the number of comparators of the first timer block
+    pub fn comparators(&self) -> u8 {
+        ((self.fields.event_timer_block_id >> 8) & 0x1f) as u8 + 1
+    }
+
+    /// Returns `true` if the main counter is 64 bits wide
+    pub fn counter_64bit(&self) -> bool {
+        self.fields.event_timer_block_id & COUNTER_SIZE_64 != 0
+    }
+}
@@ -0,0 +1,613 @@
+//! Parsing of the ACPI tables the kernel needs to discover the platform: the RSDP, the RSDT/XSDT
+//! and the MADT, HPET, FADT and MCFG tables they point to.
+#![no_std]
+
+mod fadt;
+mod hpet;
+mod madt;
+mod mcfg;
+mod rsdp;
+mod sdt;
+
+pub use fadt::{Fadt, FADT_SIGNATURE};
+pub use hpet::{Hpet, HPET_SIGNATURE};
+pub use madt::{
+    InterruptSourceOverride, IoApic, LocalApic, LocalX2Apic, Madt, MadtEntriesIterator, MadtEntry,
+    MADT_SIGNATURE,
+};
+pub use mcfg::{Mcfg, McfgAllocation, MCFG_SIGNATURE};
+pub use rsdp::{Rsdp, RSDP_SIGNATURE};
+pub use sdt::{GenericAddress, SdtHeader, SDT_HEADER_SIZE};
+
+use read_me::{Reader, ReaderError};
+
+pub const RSDT_SIGNATURE: &[u8; 4] = b"RSDT";
+pub const XSDT_SIGNATURE: &[u8; 4] = b"XSDT";
+
+/// Access to the physical memory the ACPI tables live in
+pub trait PhysicalMemory {
+    /// Returns `len` bytes of physical memory starting at `address` or `None` if they are not
+    /// accessible
+    fn read(&self, address: u64, len: usize) -> Option<&[u8]>;
+}
+
+/// ACPI tables of the platform, found by following the RSDP
+pub struct Acpi<'mem, M: PhysicalMemory> {
+    memory: &'mem M,
+    rsdp: Rsdp,
+    // Physical address of the RSDT or XSDT used to find the other tables
+    root_address: u64,
+    // Size of the pointers in the root table: 4 for the RSDT and 8 for the XSDT
+    entry_size: usize,
+}
+
+impl<'mem, M: PhysicalMemory> Acpi<'mem, M> {
+    /// Find the RSDP in `memory` and validate the root table it points to. The XSDT is used when
+    /// present, falling back to the RSDT otherwise.
+    pub fn parse(memory: &'mem M) -> Result<Self, AcpiError> {
+        let rsdp = Rsdp::find(memory)?;
+
+        let (root_address, entry_size, signature) = match rsdp.xsdt_address() {
+            Some(xsdt) => (xsdt, 8, XSDT_SIGNATURE),
+            None => (u64::from(rsdp.rsdt_address()), 4, RSDT_SIGNATURE),
+        };
+
+        // Make sure the root table is sane before handing it out
+        sdt::read_table(memory, root_address, signature)?;
+
+        Ok(Self { memory, rsdp, root_address, entry_size })
+    }
+
+    pub fn rsdp(&self) -> &Rsdp {
+        &self.rsdp
+    }
+
+    /// Returns an iterator over the physical addresses of all the tables in the root table
+    pub fn table_addresses(&self) -> impl Iterator<Item = u64> + 'mem {
+        let signature = if self.entry_size == 8 { XSDT_SIGNATURE } else { RSDT_SIGNATURE };
+        let entries = sdt::read_table(self.memory, self.root_address, signature)
+            .ok()
+            .and_then(|(_, bytes)| bytes.get(SDT_HEADER_SIZE..))
+            .unwrap_or(&[]);
+        let entry_size = self.entry_size;
+
+        entries.chunks_exact(entry_size).filter_map(move |entry| {
+            let mut reader = Reader::from(entry);
+            if entry_size == 8 {
+                reader.read::<u64>().ok()
+            } else {
+                reader.read::<u32>().ok().map(u64::from)
+            }
+        })
+    }
+
+    /// Find the table with `signature` and return its validated header and bytes
+    pub fn find_table(&self, signature: &[u8; 4]) -> Result<(SdtHeader, &'mem [u8]), AcpiError> {
+        self.table_addresses()
+            .find(|address| {
+                self.memory.read(*address, signature.len())
+                    .is_some_and(|bytes| bytes == signature)
+            })
+            .ok_or(AcpiError::TableNotFound(*signature))
+            .and_then(|address| sdt::read_table(self.memory, address, signature))
+    }
+
+    /// Returns the Multiple APIC Description Table
+    pub fn madt(&self) -> Result<Madt<'mem>, AcpiError> {
+        let (header, bytes) = self.find_table(MADT_SIGNATURE)?;
+        Madt::parse(header, bytes)
+    }
+
+    /// Returns the High Precision Event Timer table
+    pub fn hpet(&self) -> Result<Hpet, AcpiError> {
+        let (header, bytes) = self.find_table(HPET_SIGNATURE)?;
+        Hpet::parse(header, bytes)
+    }
+
+    /// Returns the Fixed ACPI Description Table
+    pub fn fadt(&self) -> Result<Fadt, AcpiError> {
+        let (header, bytes) = self.find_table(FADT_SIGNATURE)?;
+        Fadt::parse(header, bytes)
+    }
+
+    /// Returns the PCI Express memory mapped configuration space table
+    pub fn mcfg(&self) -> Result<Mcfg<'mem>, AcpiError> {
+        let (header, bytes) = self.find_table(MCFG_SIGNATURE)?;
+        Mcfg::parse(header, bytes)
+    }
+}
+
+#[derive(Debug)]
+pub enum AcpiError {
+    ReaderError(ReaderError),
+    TryFromIntError(core::num::TryFromIntError),
+    // No valid RSDP in the EBDA or the BIOS area
+    RsdpNotFound,
+    RsdpSignature,
+    RsdpChecksum,
+    // The requested bytes of physical memory, address and length, are not accessible
+    UnmappedMemory(u64, usize),
+    // A table has a different signature from the one we expected
+    Signature([u8; 4]),
+    // The table with this signature does not sum to zero
+    Checksum([u8; 4]),
+    // The table with this signature is shorter than its fixed fields
+    Length([u8; 4], u32),
+    TableNotFound([u8; 4]),
+}
+
+impl From<ReaderError> for AcpiError {
+    fn from(err: ReaderError) -> Self {
+        Self::ReaderError(err)
+    }
+}
+
+impl From<core::num::TryFromIntError> for AcpiError {
+    fn from(err: core::num::TryFromIntError) -> Self {
+        Self::TryFromIntError(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    // Like SeaBIOS, the RSDP goes in the BIOS area and the tables at the top of the RAM of a `pc`
+    // machine with 128MiB
+    const RSDP_ADDRESS: u64 = 0xf5a40;
+    const DSDT_ADDRESS: u64 = 0x7fe0040;
+    const FADT_ADDRESS: u64 = 0x7fe2000;
+    const MADT_ADDRESS: u64 = 0x7fe2100;
+    const HPET_ADDRESS: u64 = 0x7fe2200;
+    const RSDT_ADDRESS: u64 = 0x7fe2300;
+    const MCFG_ADDRESS: u64 = 0x7fe2400;
+
+    // Physical memory made out of a few disjoint regions
+    struct FakeMemory {
+        regions: Vec<(u64, Vec<u8>)>,
+    }
+
+    impl FakeMemory {
+        fn new() -> Self {
+            // Low memory, covering the BDA, the EBDA and the BIOS area
+            Self { regions: std::vec![(0, std::vec![0; 0x10_0000])] }
+        }
+
+        fn write(&mut self, address: u64, bytes: &[u8]) {
+            for (base, region) in self.regions.iter_mut() {
+                let start = address.wrapping_sub(*base) as usize;
+                if address >= *base && start + bytes.len() <= region.len() {
+                    region[start..start + bytes.len()].copy_from_slice(bytes);
+                    return;
+                }
+            }
+            self.regions.push((address, bytes.to_vec()));
+        }
+    }
+
+    impl PhysicalMemory for FakeMemory {
+        fn read(&self, address: u64, len: usize) -> Option<&[u8]> {
+            self.regions.iter().find_map(|(base, region)| {
+                let start = usize::try_from(address.checked_sub(*base)?).ok()?;
+                region.get(start..start.checked_add(len)?)
+            })
+        }
+    }
+
+    // Fix the byte at `offset` such that `bytes` sum to zero
+    fn fix_checksum(bytes: &mut [u8], offset: usize) {
+        bytes[offset] = 0;
+        let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        bytes[offset] = 0u8.wrapping_sub(sum);
+    }
+
+    // Build a table with the header QEMU uses and a valid checksum
+    fn table(signature: &[u8; 4], revision: u8, body: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(signature);
+        bytes.extend_from_slice(&((SDT_HEADER_SIZE + body.len()) as u32).to_le_bytes());
+        bytes.push(revision);
+        bytes.push(0);
+        bytes.extend_from_slice(b"BOCHS ");
+        bytes.extend_from_slice(b"BXPC");
+        bytes.extend_from_slice(&signature[..]);
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(b"BXPC");
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(body);
+        fix_checksum(&mut bytes, 9);
+        bytes
+    }
+
+    fn rsdp_v1(rsdt: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(RSDP_SIGNATURE);
+        bytes.push(0);
+        bytes.extend_from_slice(b"BOCHS ");
+        bytes.push(0);
+        bytes.extend_from_slice(&rsdt.to_le_bytes());
+        fix_checksum(&mut bytes, 8);
+        bytes
+    }
+
+    fn rsdp_v2(rsdt: u32, xsdt: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(RSDP_SIGNATURE);
+        bytes.push(0);
+        bytes.extend_from_slice(b"BOCHS ");
+        bytes.push(2);
+        bytes.extend_from_slice(&rsdt.to_le_bytes());
+        fix_checksum(&mut bytes, 8);
+        bytes.extend_from_slice(&36u32.to_le_bytes());
+        bytes.extend_from_slice(&xsdt.to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        fix_checksum(&mut bytes, 32);
+        bytes
+    }
+
+    // MADT of a `pc` machine with 2 CPUs
+    fn madt() -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+        body.extend_from_slice(&1u32.to_le_bytes());
+        // Local APICs
+        for id in 0..2u8 {
+            body.extend_from_slice(&[0, 8, id, id, 1, 0, 0, 0]);
+        }
+        // I/O APIC
+        body.extend_from_slice(&[1, 12, 0, 0]);
+        body.extend_from_slice(&0xfec0_0000u32.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        // Interrupt source overrides, IRQ0 to GSI2 and the level triggered PCI interrupts
+        body.extend_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+        for irq in [5u8, 9, 10, 11] {
+            body.extend_from_slice(&[2, 10, 0, irq, irq, 0, 0, 0, 0xd, 0]);
+        }
+        // Local APIC NMI
+        body.extend_from_slice(&[4, 6, 0xff, 0, 0, 1]);
+        table(MADT_SIGNATURE, 1, &body)
+    }
+
+    fn hpet() -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&0x8086_a201u32.to_le_bytes());
+        body.extend_from_slice(&[0, 0, 0, 0]);
+        body.extend_from_slice(&0xfed0_0000u64.to_le_bytes());
+        body.push(0);
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.push(0);
+        table(HPET_SIGNATURE, 1, &body)
+    }
+
+    // ACPI 1.0 FADT as generated by QEMU for the `pc` machine
+    fn fadt_v1() -> Vec<u8> {
+        // The ACPI 1.0 FADT is 116 bytes long
+        let mut body = std::vec![0u8; 116 - SDT_HEADER_SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            let offset = offset - SDT_HEADER_SIZE;
+            body[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(40, &(DSDT_ADDRESS as u32).to_le_bytes());
+        put(46, &9u16.to_le_bytes());
+        put(48, &0xb2u32.to_le_bytes());
+        put(52, &[0xf1, 0xf0]);
+        put(56, &0x600u32.to_le_bytes());
+        put(64, &0x604u32.to_le_bytes());
+        put(76, &0x608u32.to_le_bytes());
+        put(80, &0xafe0u32.to_le_bytes());
+        put(88, &[4, 2, 0, 4, 4]);
+        put(96, &0xfffu16.to_le_bytes());
+        put(98, &0xfffu16.to_le_bytes());
+        put(108, &[0x32]);
+        put(112, &0x80a5u32.to_le_bytes());
+        table(FADT_SIGNATURE, 1, &body)
+    }
+
+    // ACPI 1.0 layout: RSDP in the BIOS area pointing to an RSDT
+    fn pc_machine() -> FakeMemory {
+        let mut memory = FakeMemory::new();
+        memory.write(RSDP_ADDRESS, &rsdp_v1(RSDT_ADDRESS as u32));
+
+        let mut rsdt = Vec::new();
+        for address in [FADT_ADDRESS, MADT_ADDRESS, HPET_ADDRESS, MCFG_ADDRESS] {
+            rsdt.extend_from_slice(&(address as u32).to_le_bytes());
+        }
+        memory.write(RSDT_ADDRESS, &table(RSDT_SIGNATURE, 1, &rsdt));
+        memory.write(FADT_ADDRESS, &fadt_v1());
+        memory.write(MADT_ADDRESS, &madt());
+        memory.write(HPET_ADDRESS, &hpet());
+        memory.write(MCFG_ADDRESS, &mcfg());
+        memory
+    }
+
+    // MCFG of a `q35` machine, with the ECAM region of all the buses of segment 0
+    fn mcfg() -> Vec<u8> {
+        let mut body = std::vec![0u8; 8];
+        body.extend_from_slice(&0xb000_0000u64.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&[0, 0xff]);
+        body.extend_from_slice(&0u32.to_le_bytes());
+        table(MCFG_SIGNATURE, 1, &body)
+    }
+
+    #[test]
+    fn rsdp_in_bios_area() {
+        let memory = pc_machine();
+        let acpi = Acpi::parse(&memory).expect("Failed to parse ACPI");
+
+        assert_eq!(acpi.rsdp().revision(), 0);
+        assert_eq!(&acpi.rsdp().oem_id(), b"BOCHS ");
+        assert_eq!(acpi.rsdp().rsdt_address(), RSDT_ADDRESS as u32);
+        assert_eq!(acpi.rsdp().xsdt_address(), None);
+        assert_eq!(
+            acpi.table_addresses().collect::<Vec<_>>(),
+            [FADT_ADDRESS, MADT_ADDRESS, HPET_ADDRESS, MCFG_ADDRESS],
+        );
+    }
+
+    #[test]
+    fn rsdp_in_ebda_with_xsdt() {
+        let mut memory = FakeMemory::new();
+        let ebda = 0x9fc00u64;
+        let xsdt_address = 0x7fe3000u64;
+        // EBDA segment in the BIOS Data Area
+        memory.write(0x40e, &((ebda >> 4) as u16).to_le_bytes());
+        memory.write(ebda + 0x40, &rsdp_v2(0, xsdt_address));
+
+        let mut xsdt = Vec::new();
+        xsdt.extend_from_slice(&MADT_ADDRESS.to_le_bytes());
+        memory.write(xsdt_address, &table(XSDT_SIGNATURE, 1, &xsdt));
+        memory.write(MADT_ADDRESS, &madt());
+
+        let acpi = Acpi::parse(&memory).expect("Failed to parse ACPI");
+        assert_eq!(acpi.rsdp().revision(), 2);
+        assert_eq!(acpi.rsdp().xsdt_address(), Some(xsdt_address));
+        assert_eq!(acpi.table_addresses().collect::<Vec<_>>(), [MADT_ADDRESS]);
+        assert!(acpi.madt().is_ok());
+        assert!(matches!(acpi.hpet(), Err(AcpiError::TableNotFound(sig)) if &sig == b"HPET"));
+    }
+
+    #[test]
+    fn rsdp_bad_checksum_is_skipped() {
+        let mut memory = pc_machine();
+        // A corrupted RSDP before the good one must not be picked
+        let mut bad = rsdp_v1(0xdead_beef);
+        bad[8] = bad[8].wrapping_add(1);
+        memory.write(0xe0000, &bad);
+
+        let acpi = Acpi::parse(&memory).expect("Failed to parse ACPI");
+        assert_eq!(acpi.rsdp().rsdt_address(), RSDT_ADDRESS as u32);
+
+        // Without the good one, there is nothing to be found
+        memory.write(RSDP_ADDRESS, &[0; 20]);
+        assert!(matches!(Acpi::parse(&memory), Err(AcpiError::RsdpNotFound)));
+    }
+
+    #[test]
+    fn table_bad_checksum() {
+        let mut memory = pc_machine();
+        let mut madt = madt();
+        madt[40] ^= 0xff;
+        memory.write(MADT_ADDRESS, &madt);
+
+        let acpi = Acpi::parse(&memory).expect("Failed to parse ACPI");
+        assert!(matches!(acpi.madt(), Err(AcpiError::Checksum(sig)) if &sig == b"APIC"));
+    }
+
+    #[test]
+    fn madt_entries() {
+        let memory = pc_machine();
+        let acpi = Acpi::parse(&memory).expect("Failed to parse ACPI");
+        let madt = acpi.madt().expect("Failed to parse MADT");
+
+        assert_eq!(madt.local_apic_address(), 0xfee0_0000);
+        assert!(madt.pcat_compat());
+        assert_eq!(madt.processors().collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(madt.enabled_processors().collect::<Vec<_>>(), [0, 1]);
+
+        let io_apics = madt.entries()
+            .filter_map(|entry| match entry {
+                MadtEntry::IoApic(io_apic) => Some(io_apic),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(io_apics, [IoApic { io_apic_id: 0, reserved: 0, address: 0xfec0_0000, gsi_base: 0 }]);
+
+        let overrides = madt.entries()
+            .filter_map(|entry| match entry {
+                MadtEntry::InterruptSourceOverride(iso) => Some((iso.source, iso.gsi, iso.flags)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(overrides, [(0, 2, 0), (5, 5, 0xd), (9, 9, 0xd), (10, 10, 0xd), (11, 11, 0xd)]);
+
+        assert_eq!(madt.entries().last(), Some(MadtEntry::Unknown(4)));
+    }
+
+    #[test]
+    fn madt_x2apic_and_address_override() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        // Disabled Local APIC, not online capable
+        body.extend_from_slice(&[0, 8, 0, 0, 0, 0, 0, 0]);
+        // Local APIC which can be hot-added later
+        body.extend_from_slice(&[0, 8, 1, 1, 2, 0, 0, 0]);
+        // Local x2APIC with an ID that does not fit in 8 bits
+        body.extend_from_slice(&[9, 16, 0, 0]);
+        body.extend_from_slice(&0x100u32.to_le_bytes());
+        body.extend_from_slice(&1u32.to_le_bytes());
+        body.extend_from_slice(&7u32.to_le_bytes());
+        // Local APIC address override
+        body.extend_from_slice(&[5, 12, 0, 0]);
+        body.extend_from_slice(&0x1_fee0_0000u64.to_le_bytes());
+        // Truncated entry, must stop the iteration
+        body.extend_from_slice(&[0, 0]);
+        let bytes = table(MADT_SIGNATURE, 4, &body);
+
+        let header = Reader::from(&bytes[..]).read::<SdtHeader>().unwrap();
+        let madt = Madt::parse(header, &bytes).expect("Failed to parse MADT");
+
+        assert!(!madt.pcat_compat());
+        assert_eq!(madt.local_apic_address(), 0x1_fee0_0000);
+        assert_eq!(madt.processors().collect::<Vec<_>>(), [1, 0x100]);
+        assert_eq!(madt.enabled_processors().collect::<Vec<_>>(), [0x100]);
+        assert_eq!(madt.entries().count(), 4);
+    }
+
+    #[test]
+    fn hpet_table() {
+        let memory = pc_machine();
+        let acpi = Acpi::parse(&memory).expect("Failed to parse ACPI");
+        let hpet = acpi.hpet().expect("Failed to parse HPET");
+
+        assert_eq!(hpet.base_address().address_space_id, 0);
+        assert_eq!(hpet.base_address().address, 0xfed0_0000);
+        assert_eq!(hpet.vendor_id(), 0x8086);
+        assert_eq!(hpet.comparators(), 3);
+        assert!(hpet.counter_64bit());
+        assert_eq!(hpet.hpet_number(), 0);
+        assert_eq!(hpet.minimum_tick(), 0);
+    }
+
+    #[test]
+    fn fadt_v1_table() {
+        let memory = pc_machine();
+        let acpi = Acpi::parse(&memory).expect("Failed to parse ACPI");
+        let fadt = acpi.fadt().expect("Failed to parse FADT");
+
+        assert_eq!(fadt.header().revision(), 1);
+        assert_eq!(fadt.dsdt(), DSDT_ADDRESS);
+        assert_eq!(fadt.sci_interrupt(), 9);
+        assert_eq!(fadt.smi_command_port(), 0xb2);
+        assert_eq!(fadt.acpi_enable_disable(), (0xf1, 0xf0));
+        assert_eq!(fadt.pm1a_control_block(), 0x604);
+        assert_eq!(fadt.pm_timer_block(), Some(0x608));
+        assert_eq!(fadt.century(), Some(0x32));
+        assert!(fadt.has_8042());
+        assert_eq!(fadt.reset_register(), None);
+    }
+
+    #[test]
+    fn mcfg_table() {
+        let memory = pc_machine();
+        let acpi = Acpi::parse(&memory).expect("Failed to parse ACPI");
+        let mcfg = acpi.mcfg().expect("Failed to parse MCFG");
+
+        let allocations = mcfg.allocations().collect::<Vec<_>>();
+        assert_eq!(allocations, [McfgAllocation {
+            base_address: 0xb000_0000,
+            segment: 0,
+            start_bus: 0,
+            end_bus: 0xff,
+            reserved: 0,
+        }]);
+        assert_eq!(allocations[0].start_address(), 0xb000_0000);
+        assert_eq!(allocations[0].size(), 256 << 20);
+
+        let bus_8 = McfgAllocation { start_bus: 8, end_bus: 9, ..allocations[0] };
+        assert_eq!(bus_8.start_address(), 0xb080_0000);
+        assert_eq!(bus_8.size(), 2 << 20);
+    }
+
+    #[test]
+    fn fadt_with_x_dsdt() {
+        let mut body = std::vec![0u8; 244 - SDT_HEADER_SIZE];
+        // DSDT, 32 and 64-bit
+        body[40 - SDT_HEADER_SIZE..44 - SDT_HEADER_SIZE].copy_from_slice(&0x1234u32.to_le_bytes());
+        body[140 - SDT_HEADER_SIZE..148 - SDT_HEADER_SIZE]
+            .copy_from_slice(&0x1_0000_0000u64.to_le_bytes());
+        // Boot architecture flags, only the 8042
+        body[109 - SDT_HEADER_SIZE] = 0b10;
+        // Reset register supported
+        body[112 - SDT_HEADER_SIZE..116 - SDT_HEADER_SIZE]
+            .copy_from_slice(&(1u32 << 10).to_le_bytes());
+        // Reset register in the I/O space at 0xcf9, writing 0xf
+        body[116 - SDT_HEADER_SIZE..128 - SDT_HEADER_SIZE]
+            .copy_from_slice(&[1, 8, 0, 0, 0xf9, 0xc, 0, 0, 0, 0, 0, 0]);
+        body[128 - SDT_HEADER_SIZE] = 0xf;
+        let bytes = table(FADT_SIGNATURE, 3, &body);
+
+        let header = Reader::from(&bytes[..]).read::<SdtHeader>().unwrap();
+        let fadt = Fadt::parse(header, &bytes).expect("Failed to parse FADT");
+
+        assert_eq!(fadt.dsdt(), 0x1_0000_0000);
+        assert!(fadt.has_8042());
+        assert!(!fadt.has_legacy_devices());
+        assert_eq!(fadt.pm_timer_block(), None);
+        let (reset, value) = fadt.reset_register().expect("No reset register");
+        assert_eq!(reset.address_space_id, 1);
+        assert_eq!(reset.address, 0xcf9);
+        assert_eq!(value, 0xf);
+    }
+
+    // Tables captured from the firmware of a Firecracker microVM with a single vCPU, through
+    // `/sys/firmware/acpi/tables`. There are no QEMU captures yet, see `testdata/README.md`.
+    const FIRECRACKER_MADT: &[u8] = include_bytes!("../testdata/firecracker/APIC.bin");
+    const FIRECRACKER_FADT: &[u8] = include_bytes!("../testdata/firecracker/FACP.bin");
+    const FIRECRACKER_MCFG: &[u8] = include_bytes!("../testdata/firecracker/MCFG.bin");
+
+    // Read and check the header of a captured table
+    fn captured_header(bytes: &[u8], signature: &[u8; 4]) -> SdtHeader {
+        let header = Reader::from(bytes).read::<SdtHeader>().expect("Failed to read the header");
+        assert_eq!(&header.signature(), signature);
+        assert_eq!(header.length() as usize, bytes.len());
+        assert_eq!(&header.oem_id(), b"FIRECK");
+        assert!(sdt::checksum(bytes));
+        header
+    }
+
+    #[test]
+    fn firecracker_madt() {
+        let header = captured_header(FIRECRACKER_MADT, MADT_SIGNATURE);
+        let madt = Madt::parse(header, FIRECRACKER_MADT).expect("Failed to parse MADT");
+
+        assert_eq!(madt.local_apic_address(), 0xfee0_0000);
+        // There is no 8259 PIC in a microVM
+        assert!(!madt.pcat_compat());
+        let io_apic = IoApic { io_apic_id: 0, reserved: 0, address: 0xfec0_0000, gsi_base: 0 };
+        assert_eq!(madt.entries().collect::<Vec<_>>(), [
+            MadtEntry::IoApic(io_apic),
+            MadtEntry::LocalApic(LocalApic { processor_uid: 0, apic_id: 0, flags: 1 }),
+        ]);
+        assert_eq!(madt.enabled_processors().collect::<Vec<_>>(), [0]);
+    }
+
+    #[test]
+    fn firecracker_fadt() {
+        let header = captured_header(FIRECRACKER_FADT, FADT_SIGNATURE);
+        let fadt = Fadt::parse(header, FIRECRACKER_FADT).expect("Failed to parse FADT");
+
+        // An ACPI 6 table with the hardware-reduced flag, which only has the 64-bit DSDT address
+        assert_eq!(fadt.header().revision(), 6);
+        assert_eq!(fadt.dsdt(), 0x9_fd30);
+        assert_eq!(fadt.flags(), 0x10_0030);
+        assert!(!fadt.has_8042());
+        assert!(!fadt.has_legacy_devices());
+        assert_eq!(fadt.sci_interrupt(), 0);
+        assert_eq!(fadt.pm_timer_block(), None);
+        assert_eq!(fadt.century(), None);
+        assert_eq!(fadt.reset_register(), None);
+    }
+
+    #[test]
+    fn firecracker_mcfg() {
+        let header = captured_header(FIRECRACKER_MCFG, MCFG_SIGNATURE);
+        let mcfg = Mcfg::parse(header, FIRECRACKER_MCFG).expect("Failed to parse MCFG");
+
+        let allocations = mcfg.allocations().collect::<Vec<_>>();
+        assert_eq!(allocations, [McfgAllocation {
+            base_address: 0xeec0_0000,
+            segment: 0,
+            start_bus: 0,
+            end_bus: 0,
+            reserved: 0,
+        }]);
+        assert_eq!(allocations[0].start_address(), 0xeec0_0000);
+        assert_eq!(allocations[0].size(), 1 << 20);
+    }
+
+}
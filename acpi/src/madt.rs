@@ -0,0 +1,204 @@
+//! Module that parses the Multiple APIC Description Table (MADT), which describes the interrupt
+//! controllers of the system and, through them, the processors.
+use parseme::ReadMe;
+use read_me::{Reader, ReaderError};
+use crate::sdt::{SdtHeader, SDT_HEADER_SIZE};
+use crate::AcpiError;
+
+pub const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+// Types of the interrupt controller structures
+const TYPE_LOCAL_APIC: u8 = 0;
+const TYPE_IO_APIC: u8 = 1;
+const TYPE_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const TYPE_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const TYPE_LOCAL_X2APIC: u8 = 9;
+
+// The processor is ready to use
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+// The processor is disabled, but the system supports enabling it at runtime
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+// The system also has a PC-AT-compatible dual-8259 setup
+const PCAT_COMPAT: u32 = 1 << 0;
+
+#[derive(Debug)]
+#[derive(ReadMe)]
+struct MadtFields {
+    local_apic_address: u32,
+    flags: u32,
+}
+
+/// Processor Local APIC structure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(ReadMe)]
+pub struct LocalApic {
+    pub processor_uid: u8,
+    pub apic_id: u8,
+    pub flags: u32,
+}
+
+/// I/O APIC structure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(ReadMe)]
+pub struct IoApic {
+    pub io_apic_id: u8,
+    pub reserved: u8,
+    // Physical address of the I/O APIC registers
+    pub address: u32,
+    // First Global System Interrupt handled by this I/O APIC
+    pub gsi_base: u32,
+}
+
+/// Interrupt Source Override structure, describing how an ISA interrupt is wired to the I/O APIC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(ReadMe)]
+pub struct InterruptSourceOverride {
+    // Always 0, meaning ISA
+    pub bus: u8,
+    // ISA IRQ
+    pub source: u8,
+    // Global System Interrupt the IRQ signals
+    pub gsi: u32,
+    // Polarity in bits 0:1 and trigger mode in bits 2:3
+    pub flags: u16,
+}
+
+/// Processor Local x2APIC structure, used for the APIC IDs which do not fit in 8 bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(ReadMe)]
+pub struct LocalX2Apic {
+    pub reserved: [u8; 2],
+    pub x2apic_id: u32,
+    pub flags: u32,
+    pub processor_uid: u32,
+}
+
+/// Interrupt controller structure following the MADT fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    LocalApic(LocalApic),
+    IoApic(IoApic),
+    InterruptSourceOverride(InterruptSourceOverride),
+    // 64-bit physical address of the Local APIC, overriding the MADT one
+    LocalApicAddressOverride(u64),
+    LocalX2Apic(LocalX2Apic),
+    // Structure we do not parse, with its type
+    Unknown(u8),
+}
+
+/// Multiple APIC Description Table
+#[derive(Debug)]
+pub struct Madt<'data> {
+    header: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+    // Bytes of the interrupt controller structures
+    entries: &'data [u8],
+}
+
+impl<'data> Madt<'data> {
+    /// Parse the MADT from `bytes`, which contain the whole table, including the SDT header
+    pub fn parse(header: SdtHeader, bytes: &'data [u8]) -> Result<Self, AcpiError> {
+        let mut reader = Reader::from(bytes);
+        reader.seek(SDT_HEADER_SIZE)?;
+        let fields = reader.read::<MadtFields>()?;
+
+        let length = usize::try_from(header.length())?;
+        let entries = bytes.get(reader.offset()..length)
+            .ok_or(AcpiError::Length(header.signature(), header.length()))?;
+
+        Ok(Self {
+            header,
+            local_apic_address: fields.local_apic_address,
+            flags: fields.flags,
+            entries,
+        })
+    }
+
+    pub fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+
+    /// Returns the physical address of the Local APIC, taking the address override into account
+    pub fn local_apic_address(&self) -> u64 {
+        self.entries()
+            .find_map(|entry| match entry {
+                MadtEntry::LocalApicAddressOverride(address) => Some(address),
+                _ => None,
+            })
+            .unwrap_or(u64::from(self.local_apic_address))
+    }
+
+    /// Returns `true` if the system also has legacy 8259 PICs, which have to be masked
+    pub fn pcat_compat(&self) -> bool {
+        self.flags & PCAT_COMPAT != 0
+    }
+
+    /// Returns an iterator over the interrupt controller structures
+    pub fn entries(&self) -> MadtEntriesIterator<'data> {
+        MadtEntriesIterator { bytes: self.entries, offset: 0 }
+    }
+
+    /// Returns an iterator over the APIC IDs of the processors that are enabled or can be enabled
+    pub fn processors(&self) -> impl Iterator<Item = u32> + 'data {
+        let usable = |flags: u32| flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0;
+        self.entries().filter_map(move |entry| match entry {
+            MadtEntry::LocalApic(lapic) if usable(lapic.flags) => Some(u32::from(lapic.apic_id)),
+            MadtEntry::LocalX2Apic(x2apic) if usable(x2apic.flags) => Some(x2apic.x2apic_id),
+            _ => None,
+        })
+    }
+
+    /// Returns an iterator over the APIC IDs of the processors that are enabled, which are the
+    /// ones present at boot. Online capable processors are only there once hot-added.
+    pub fn enabled_processors(&self) -> impl Iterator<Item = u32> + 'data {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::LocalApic(lapic) if lapic.flags & LOCAL_APIC_ENABLED != 0 => {
+                Some(u32::from(lapic.apic_id))
+            }
+            MadtEntry::LocalX2Apic(x2apic) if x2apic.flags & LOCAL_APIC_ENABLED != 0 => {
+                Some(x2apic.x2apic_id)
+            }
+            _ => None,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct MadtEntriesIterator<'data> {
+    bytes: &'data [u8],
+    offset: usize,
+}
+
+impl<'data> Iterator for MadtEntriesIterator<'data> {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Each structure starts with its type and its length, including these 2 bytes
+        let typ = *self.bytes.get(self.offset)?;
+        let length = usize::from(*self.bytes.get(self.offset.saturating_add(1))?);
+        // A malformed length would have us loop forever or read outside the table
+        if length < 2 {
+            return None;
+        }
+        let end = self.offset.saturating_add(length);
+        let body = self.bytes.get(self.offset.saturating_add(2)..end)?;
+        self.offset = end;
+
+        let mut reader = Reader::from(body);
+        let entry = match typ {
+            TYPE_LOCAL_APIC => MadtEntry::LocalApic(reader.read::<LocalApic>().ok()?),
+            TYPE_IO_APIC => MadtEntry::IoApic(reader.read::<IoApic>().ok()?),
+            TYPE_INTERRUPT_SOURCE_OVERRIDE => MadtEntry::InterruptSourceOverride(
+                reader.read::<InterruptSourceOverride>().ok()?),
+            TYPE_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                reader.skip(2);
+                MadtEntry::LocalApicAddressOverride(reader.read::<u64>().ok()?)
+            }
+            TYPE_LOCAL_X2APIC => MadtEntry::LocalX2Apic(reader.read::<LocalX2Apic>().ok()?),
+            _ => MadtEntry::Unknown(typ),
+        };
+
+        Some(entry)
+    }
+}
@@ -0,0 +1,67 @@
+//! Module that parses the PCI Express memory mapped configuration space table (MCFG), which gives
+//! the location of the ECAM region of each PCI segment
+use parseme::ReadMe;
+use read_me::{Reader, ReaderError};
+use crate::sdt::{SdtHeader, SDT_HEADER_SIZE};
+use crate::AcpiError;
+
+pub const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
+
+// Reserved bytes between the SDT header and the allocation structures
+const MCFG_RESERVED_SIZE: usize = 8;
+// Size of a configuration space base address allocation structure
+const ALLOCATION_SIZE: usize = 16;
+
+/// Configuration space base address allocation structure, describing the ECAM region of the
+/// `start_bus..=end_bus` buses of a PCI segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(ReadMe)]
+pub struct McfgAllocation {
+    // Physical address of the configuration space of bus 0, even if `start_bus` is not 0
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+    pub reserved: u32,
+}
+
+impl McfgAllocation {
+    /// Returns the physical address of the configuration space of `start_bus`
+    pub fn start_address(&self) -> u64 {
+        self.base_address + (u64::from(self.start_bus) << 20)
+    }
+
+    /// Returns the size of the configuration space of all the buses, 1MiB for each
+    pub fn size(&self) -> u64 {
+        (u64::from(self.end_bus.saturating_sub(self.start_bus)) + 1) << 20
+    }
+}
+
+/// PCI Express memory mapped configuration space table
+#[derive(Debug)]
+pub struct Mcfg<'data> {
+    header: SdtHeader,
+    // Bytes of the allocation structures
+    allocations: &'data [u8],
+}
+
+impl<'data> Mcfg<'data> {
+    /// Parse the MCFG from `bytes`, which contain the whole table, including the SDT header
+    pub fn parse(header: SdtHeader, bytes: &'data [u8]) -> Result<Self, AcpiError> {
+        let length = usize::try_from(header.length())?;
+        let allocations = bytes.get(SDT_HEADER_SIZE + MCFG_RESERVED_SIZE..length)
+            .ok_or(AcpiError::Length(header.signature(), header.length()))?;
+
+        Ok(Self { header, allocations })
+    }
+
+    pub fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+
+    /// Returns an iterator over the ECAM regions
+    pub fn allocations(&self) -> impl Iterator<Item = McfgAllocation> + 'data {
+        self.allocations.chunks_exact(ALLOCATION_SIZE)
+            .filter_map(|bytes| Reader::from(bytes).read::<McfgAllocation>().ok())
+    }
+}
@@ -0,0 +1,139 @@
+//! Module that locates and parses the Root System Description Pointer (RSDP)
+use parseme::ReadMe;
+use read_me::{Reader, ReaderError};
+use crate::{sdt, AcpiError, PhysicalMemory};
+
+pub const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+// Size of the ACPI 1.0 RSDP, covered by the first checksum
+const RSDP_V1_SIZE: usize = 20;
+// Size of the ACPI 2.0+ RSDP, covered by the extended checksum
+const RSDP_V2_SIZE: usize = 36;
+// The RSDP is always on a 16-byte boundary
+const RSDP_ALIGNMENT: usize = 16;
+// Physical address of the word holding the real mode segment of the Extended BIOS Data Area
+const EBDA_SEGMENT_POINTER: u64 = 0x40e;
+// Only the first KiB of the EBDA is searched
+const EBDA_SEARCH_SIZE: usize = 1024;
+// Read-only BIOS area between 0xE0000 and 0xFFFFF
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_SIZE: usize = 0x20000;
+
+#[derive(Debug)]
+#[derive(ReadMe)]
+struct RsdpV1 {
+    signature: [u8; 8],
+    // Makes the first 20 bytes sum to zero
+    checksum: u8,
+    oem_id: [u8; 6],
+    // 0 for ACPI 1.0, 2 for ACPI 2.0 and later
+    revision: u8,
+    // 32-bit physical address of the RSDT
+    rsdt_address: u32,
+}
+
+#[derive(Debug)]
+#[derive(ReadMe)]
+struct RsdpV2 {
+    // Length of the entire table
+    length: u32,
+    // 64-bit physical address of the XSDT
+    xsdt_address: u64,
+    // Makes the entire table sum to zero
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Root System Description Pointer, which gives the address of the RSDT and, starting with ACPI
+/// 2.0, the address of the XSDT.
+#[derive(Debug)]
+pub struct Rsdp {
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    xsdt_address: Option<u64>,
+}
+
+impl Rsdp {
+    /// Parse and validate the RSDP at the start of `bytes`
+    pub fn parse(bytes: &[u8]) -> Result<Self, AcpiError> {
+        let mut reader = Reader::from(bytes);
+        let v1 = reader.read::<RsdpV1>()?;
+
+        if &v1.signature != RSDP_SIGNATURE {
+            return Err(AcpiError::RsdpSignature);
+        }
+
+        if !sdt::checksum(bytes.get(..RSDP_V1_SIZE).ok_or(AcpiError::RsdpChecksum)?) {
+            return Err(AcpiError::RsdpChecksum);
+        }
+
+        // ACPI 1.0 stops here
+        if v1.revision == 0 {
+            return Ok(Self {
+                oem_id: v1.oem_id,
+                revision: v1.revision,
+                rsdt_address: v1.rsdt_address,
+                xsdt_address: None,
+            });
+        }
+
+        let v2 = reader.read::<RsdpV2>()?;
+        let length = core::cmp::max(usize::try_from(v2.length)?, RSDP_V2_SIZE);
+        if !sdt::checksum(bytes.get(..length).ok_or(AcpiError::RsdpChecksum)?) {
+            return Err(AcpiError::RsdpChecksum);
+        }
+
+        Ok(Self {
+            oem_id: v1.oem_id,
+            revision: v1.revision,
+            rsdt_address: v1.rsdt_address,
+            xsdt_address: Some(v2.xsdt_address).filter(|address| *address != 0),
+        })
+    }
+
+    /// Search for the RSDP in the first KiB of the EBDA and then in the BIOS read-only area, as
+    /// described by the ACPI specification for legacy systems.
+    pub fn find<M: PhysicalMemory>(memory: &M) -> Result<Self, AcpiError> {
+        // The EBDA segment pointer might not be set
+        let ebda = memory.read(EBDA_SEGMENT_POINTER, 2)
+            .and_then(|bytes| Reader::from(bytes).read::<u16>().ok())
+            .map(|segment| u64::from(segment) << 4)
+            .filter(|ebda| *ebda != 0);
+
+        if let Some(ebda) = ebda {
+            if let Some(rsdp) = Self::scan(memory, ebda, EBDA_SEARCH_SIZE) {
+                return Ok(rsdp);
+            }
+        }
+
+        Self::scan(memory, BIOS_AREA_START, BIOS_AREA_SIZE).ok_or(AcpiError::RsdpNotFound)
+    }
+
+    // Scan `size` bytes starting at `address` for a valid RSDP
+    fn scan<M: PhysicalMemory>(memory: &M, address: u64, size: usize) -> Option<Self> {
+        let area = memory.read(address, size)?;
+
+        (0..area.len()).step_by(RSDP_ALIGNMENT)
+            .filter_map(|offset| area.get(offset..))
+            .filter(|candidate| candidate.starts_with(RSDP_SIGNATURE))
+            // Skip over signatures which do not belong to a valid RSDP
+            .find_map(|candidate| Self::parse(candidate).ok())
+    }
+
+    pub fn oem_id(&self) -> [u8; 6] {
+        self.oem_id
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    pub fn rsdt_address(&self) -> u32 {
+        self.rsdt_address
+    }
+
+    pub fn xsdt_address(&self) -> Option<u64> {
+        self.xsdt_address
+    }
+}
@@ -0,0 +1,103 @@
+//! Module that defines and parses the header shared by all the System Description Tables
+use parseme::ReadMe;
+use read_me::{Reader, ReaderError};
+use crate::{AcpiError, PhysicalMemory};
+
+/// Size of the `SdtHeader`, in bytes
+pub const SDT_HEADER_SIZE: usize = 36;
+
+/// Header at the start of every System Description Table
+#[derive(Debug, Clone, Copy)]
+#[derive(ReadMe)]
+pub struct SdtHeader {
+    // ASCII identifier of the table
+    signature: [u8; 4],
+    // Length of the entire table in bytes, including this header
+    length: u32,
+    // Revision of the structure corresponding to the signature
+    revision: u8,
+    // All the bytes of the table must sum to zero
+    checksum: u8,
+    // OEM supplied string that identifies the OEM
+    oem_id: [u8; 6],
+    // OEM supplied string that identifies the particular data table
+    oem_table_id: [u8; 8],
+    // OEM supplied revision number
+    oem_revision: u32,
+    // Vendor ID of the utility that created the table
+    creator_id: [u8; 4],
+    // Revision of the utility that created the table
+    creator_revision: u32,
+}
+
+impl SdtHeader {
+    pub fn signature(&self) -> [u8; 4] {
+        self.signature
+    }
+
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    pub fn oem_id(&self) -> [u8; 6] {
+        self.oem_id
+    }
+
+    pub fn oem_table_id(&self) -> [u8; 8] {
+        self.oem_table_id
+    }
+}
+
+/// ACPI Generic Address Structure, describing the position of registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(ReadMe)]
+pub struct GenericAddress {
+    // 0 for system memory, 1 for system I/O
+    pub address_space_id: u8,
+    // Size in bits of the register
+    pub register_bit_width: u8,
+    // Bit offset of the register at the given address
+    pub register_bit_offset: u8,
+    // Access size, 1 for byte up to 4 for quad word, 0 for undefined
+    pub access_size: u8,
+    // Address of the register in the given address space
+    pub address: u64,
+}
+
+/// Returns `true` if all the bytes in `bytes` add up to zero
+pub fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Read the System Description Table at the physical `address` and make sure it has the expected
+/// `signature` and a valid checksum. Returns the header and the whole table, header included.
+pub fn read_table<'mem, M: PhysicalMemory>(
+    memory: &'mem M,
+    address: u64,
+    signature: &[u8; 4],
+) -> Result<(SdtHeader, &'mem [u8]), AcpiError> {
+    let header_bytes = memory.read(address, SDT_HEADER_SIZE)
+        .ok_or(AcpiError::UnmappedMemory(address, SDT_HEADER_SIZE))?;
+    let header = Reader::from(header_bytes).read::<SdtHeader>()?;
+
+    if &header.signature != signature {
+        return Err(AcpiError::Signature(header.signature));
+    }
+
+    let length = usize::try_from(header.length)?;
+    if length < SDT_HEADER_SIZE {
+        return Err(AcpiError::Length(header.signature, header.length));
+    }
+
+    let bytes = memory.read(address, length).ok_or(AcpiError::UnmappedMemory(address, length))?;
+
+    if !checksum(bytes) {
+        return Err(AcpiError::Checksum(header.signature));
+    }
+
+    Ok((header, bytes))
+}
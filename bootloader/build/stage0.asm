@@ -3,21 +3,144 @@
 ; Execution starts in 16-bit Real Mode
 [bits 16]
 
+; The PXE ROM only loads this sector, which downloads the rest of the bootloader over TFTP right
+; after itself. That keeps the network boot program far below the 32 KiB every ROM can load.
+; Only this sector is named by the DHCP server, so pizza.flat has to be in the same directory of
+; the same TFTP server.
+loader_base: equ 0x7e00
+
+; Parameter blocks of the PXE calls, in the free conventional memory below this sector, as they do
+; not fit in it along with the code
+file_size: equ 0x0600
+read_file: equ 0x0700
+
+; Number of times the download is attempted before giving up
+DOWNLOAD_TRIES: equ 3
+
+; PXE API functions and the status they return on success
+PXENV_TFTP_READ_FILE:       equ 0x0023
+PXENV_TFTP_GET_FSIZE:       equ 0x0025
+PXENV_GET_CACHED_INFO:      equ 0x0071
+PXENV_PACKET_TYPE_DHCP_ACK: equ 2
+PXENV_EXIT_SUCCESS:         equ 0
+
+; Parameters of PXENV_TFTP_GET_FSIZE
+struc tftp_get_fsize
+    .status:       resw 1
+    .server_ip:    resd 1
+    .gateway_ip:   resd 1
+    .file_name:    resb 128
+    .file_size:    resd 1
+endstruc
+
+; Parameters of PXENV_TFTP_READ_FILE
+struc tftp_read_file
+    .status:       resw 1
+    .file_name:    resb 128
+    ; Size of the buffer on input, size of the file on output
+    .buffer_size:  resd 1
+    ; Physical address of the buffer
+    .buffer:       resd 1
+    .server_ip:    resd 1
+    .gateway_ip:   resd 1
+    ; Left to 0 as this is a plain TFTP download, as are the ports and timeouts
+    .mcast_ip:     resd 1
+    .client_port:  resw 1
+    .server_port:  resw 1
+    .open_timeout: resw 1
+    .reopen_delay: resw 1
+endstruc
+
 entry:
     ; Stop serving IRQs (interrupt requests)
     cli
     ; Make sure we go from lowest to highest address incrementing
     cld
 
+    ; Make sure ds and es are set to 0 such that we can use the selector with a known value
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+
+    ; Check that PXE is installed, in which case es:bx points to the PXENV+ structure
+    mov ax, 0x5650
+    int 0x1a
+    jc error
+    cmp ax, 0x564e
+    jne error
+
+    ; Up to version 2.1, the API is called through the real mode entry point of PXENV+. Newer
+    ; versions have a !PXE structure, whose entry point takes its arguments on the stack.
+    mov eax, [es:bx + 0x0a]
+    cmp word [es:bx + 0x06], 0x0201
+    jb .pxe_found
+    les bx, [es:bx + 0x28]
+    mov eax, [es:bx + 0x10]
+.pxe_found:
+    mov [pxe_entry], eax
+
+    ; The cached DHCP acknowledgement holds the address of the TFTP server we booted from
+    mov bx, PXENV_GET_CACHED_INFO
+    mov di, cached_info
+    call pxe_api
+    jne error
+    les si, [cached_info.buffer]
+    ; The `siaddr` field of the BOOTP packet
+    mov edx, [es:si + 20]
+
+    ; Clear both parameter blocks, then fill in the server and the file name
+    push ds
+    pop es
+    mov di, file_size
+    mov cx, 0x200
+    xor ax, ax
+    rep stosb
+    mov [file_size + tftp_get_fsize.server_ip], edx
+    mov [read_file + tftp_read_file.server_ip], edx
+    mov si, file_name
+    mov di, file_size + tftp_get_fsize.file_name
+    mov cx, file_name.len
+    rep movsb
+    mov si, file_name
+    mov di, read_file + tftp_read_file.file_name
+    mov cx, file_name.len
+    rep movsb
+    mov dword [read_file + tftp_read_file.buffer], loader_base
+
+.download:
+    ; Ask for the size first, as the download itself cannot tell a full buffer from a complete file
+    mov bx, PXENV_TFTP_GET_FSIZE
+    mov di, file_size
+    call pxe_api
+    jne .retry
+    ; The bootloader is downloaded right after this sector, and has to fit in the conventional
+    ; memory the BIOS data area reports as free
+    movzx eax, word [ds:0x413]
+    shl eax, 10
+    sub eax, loader_base
+    mov edx, [file_size + tftp_get_fsize.file_size]
+    cmp edx, eax
+    ja error
+    mov [read_file + tftp_read_file.buffer_size], edx
+    mov bx, PXENV_TFTP_READ_FILE
+    mov di, read_file
+    call pxe_api
+    jne .retry
+    ; A transfer cut short would leave us running a truncated bootloader
+    mov eax, [file_size + tftp_get_fsize.file_size]
+    cmp eax, [read_file + tftp_read_file.buffer_size]
+    je .downloaded
+.retry:
+    dec byte [download_tries]
+    jnz .download
+    jmp error
+.downloaded:
+
     ; Enable the A20 to avoid wraparound to 0 of addresses bigger than 1 MiB
     in al, 0x92
     or al, 2
     out 0x92, al
 
-    ; Make sure ds is set to 0 such that we can use the selector with a known value
-    xor ax, ax
-    mov ds, ax
-
     ; Load a 32-bit GDT
     lgdt [ds:pm_gdtr]
 
@@ -31,6 +154,39 @@ entry:
     ; that segment base
     jmp 0x0008:pm_entry
 
+; Call the PXE API function in bx with the parameter structure at ds:di, and set the zero flag if
+; it succeeded. The !PXE entry point takes its arguments on the stack, while the PXENV+ one takes
+; them in bx and es:di, so we pass them both ways.
+pxe_api:
+    push ds
+    pop es
+    push ds
+    push di
+    push bx
+    call far [pxe_entry]
+    add sp, 3 * 2
+    ; Restore the segments, as the API only preserves ds, ss and sp
+    xor bx, bx
+    mov es, bx
+    cmp ax, PXENV_EXIT_SUCCESS
+    ret
+
+; Tell the user the bootloader could not be downloaded and stop there
+error:
+    mov si, error_message
+.print:
+    lodsb
+    test al, al
+    jz .halt
+    ; Teletype output of the character in al
+    mov ah, 0x0e
+    xor bx, bx
+    int 0x10
+    jmp .print
+.halt:
+    hlt
+    jmp .halt
+
 [bits 32]
 pm_entry:
     ; At this points CS segment register is loaded, because of the the far jump we did previously.
@@ -48,8 +204,10 @@ pm_entry:
 
     ; Push the stack end as an argument
     push esp
-    ; Push the bootloader end as an argument
-    push dword bootloader_end
+    ; Push the bootloader end as an argument, which is the end of the file we downloaded
+    mov eax, [read_file + tftp_read_file.buffer_size]
+    add eax, loader_base
+    push eax
     ; Push the bootloader start as an argument
     push dword bootloader_start
     ; Jump to our Rust entry point
@@ -87,13 +245,31 @@ pm_gdtr:
     ; 4-bytes for the offset in 32-bit mode
     dd pm_gdt
 
+; Far pointer to the PXE API entry point
+pxe_entry: dd 0
+
+; Parameters of PXENV_GET_CACHED_INFO
+cached_info:
+    .status:      dw 0
+    .packet_type: dw PXENV_PACKET_TYPE_DHCP_ACK
+    .buffer_size: dw 0
+    ; A null buffer gets us a pointer to the packet the PXE stack keeps
+    .buffer:      dd 0
+    .buffer_limit: dw 0
+
+; Attempts left to download the bootloader
+download_tries: db DOWNLOAD_TRIES
+
+; The file name is shared by the error message and both parameter blocks
+error_message: db "Failed to download "
+file_name:     db "pizza.flat"
+    .len:      equ $ - file_name
+               db 13, 10, 0
+
 ; Fill the rest of the bootloader with 0
 times 510-($-$$) db 0
 ; Tell the BIOS that this is a valid sector to be used as a bootloader by setting the last 2 bytes
 ; of the 512 bytes to 0x55 and 0xAA
 db 0x55,0xAA
 
-incbin "pizza.flat"
-
 bootloader_start: equ $$
-bootloader_end: equ $
@@ -359,14 +359,12 @@ _enter_ia32e:
     mov cr4, eax
     ; Load the CR3 with the physical base address of the Level 4 page map table (PML4)
     mov cr3, esi
-    ; Reads MSR from the adress in ECX into registers EDX:EAX
-    rdmsr
-    xor eax, eax
-    xor edx, edx
-    ; Enable IA-32e mode operation and not execute (bit 8 and 11)
-    mov eax, 0b1001 << 8
     ; Enable IA-32e mode, by setting the IA32_EFER.LME = 1, which is the 8th bit in MSR C000_0080H
     mov ecx, 0xc0000080
+    ; Reads MSR from the adress in ECX into registers EDX:EAX, such that we keep the bits which
+    ; were already set, like the no execute enable (bit 11) set by the Rust side
+    rdmsr
+    or eax, 1 << 8
     ; Writes the contents of registers EDX:EAX into a 64-bit MSR address specified in the ECX
     ; register
     wrmsr
@@ -0,0 +1,105 @@
+//! Boot configuration, taken from the DHCP options and the configuration file on the TFTP server
+use crate::{error::PxeError, pxe::{self, DhcpBoot}, println, Text, BOOT_STATE};
+use alloc::vec::Vec;
+use parse_config::{config_paths, Config, Digest, FileEntry, MtftpConfig};
+use state::{BootConfig, DhcpInfo};
+
+// Kernel file we download when neither the DHCP server nor the configuration name one
+const DEFAULT_KERNEL_FILE: &[u8] = b"pizza.kernel";
+
+/// A file to download from the TFTP server
+pub struct BootFile {
+    pub name: Vec<u8>,
+    // Checked once the file is downloaded, if the configuration lists it
+    pub digest: Option<Digest>,
+    // Multicast TFTP session to download the file through, instead of a plain TFTP transfer
+    pub mtftp: Option<MtftpConfig>,
+}
+
+impl BootFile {
+    fn new(name: Vec<u8>) -> Self {
+        Self { name, digest: None, mtftp: None }
+    }
+
+    /// Download the file and check it against its digest
+    pub fn download(&self) -> Result<Vec<u8>, PxeError> {
+        let bytes = pxe::download(&self.name, self.mtftp.as_ref())?;
+        if self.digest.is_some_and(|digest| !digest.matches(&bytes)) {
+            return Err(PxeError::DigestMismatch);
+        }
+        Ok(bytes)
+    }
+}
+
+impl From<FileEntry<'_>> for BootFile {
+    fn from(entry: FileEntry) -> Self {
+        Self { name: entry.name.to_vec(), digest: entry.digest, mtftp: None }
+    }
+}
+
+/// Files the bootloader has to download
+pub struct BootFiles {
+    pub kernel: BootFile,
+    // Loaded in physical memory next to the kernel, in this order
+    pub modules: Vec<BootFile>,
+}
+
+/// Download the most specific configuration file for this machine and apply it on top of what the
+/// DHCP server sent us. The command line and serial settings are saved in the `BootState`.
+pub fn load(dhcp: &DhcpInfo, dhcp_boot: DhcpBoot) -> BootFiles {
+    let mut kernel =
+        BootFile::new(dhcp_boot.kernel_file.unwrap_or_else(|| DEFAULT_KERNEL_FILE.to_vec()));
+    let mut cmdline = dhcp_boot.cmdline;
+    let mut modules = Vec::new();
+
+    let mut boot_config = BootConfig::empty();
+    // Servers are free to not have any configuration, in which case we go with the defaults
+    let found = config_paths(dhcp.mac, dhcp.your_ip)
+        .find_map(|path| pxe::download(path.as_bytes(), None).ok().map(|bytes| (path, bytes)));
+    // An invalid configuration is reported and ignored, just like a missing one
+    let config = found.as_ref().and_then(|(path, bytes)| match Config::parse(bytes) {
+        Ok(config) => Some((path, config)),
+        Err(err) => {
+            println!("Ignoring configuration {}: {:?}", Text(path.as_bytes()), err);
+            None
+        }
+    });
+    if let Some((path, config)) = config {
+        println!("Using configuration {}", Text(path.as_bytes()));
+        if let Some(entry) = config.kernel() {
+            kernel = BootFile::from(entry);
+        }
+        modules.extend(config.modules().map(BootFile::from));
+        if let Some(line) = config.cmdline() {
+            cmdline = Some(line.to_vec());
+        }
+        if let Some(serial) = config.serial() {
+            let port = serial::Serial::init_port(serial.port, serial.divisor());
+            *BOOT_STATE.serial.lock() = Some(port);
+            boot_config.serial_port = serial.port;
+            boot_config.serial_baud = serial.baud;
+        }
+        if let Some(mtftp) = config.mtftp() {
+            kernel.mtftp = Some(mtftp);
+            modules.iter_mut().for_each(|module| module.mtftp = Some(mtftp));
+        }
+        copy_null_terminated(&mut boot_config.config_file, path.as_bytes());
+    }
+
+    if let Some(cmdline) = cmdline {
+        if BOOT_STATE.cmdline.lock().set(&cmdline).is_none() {
+            println!("Ignoring a command line of {} bytes", cmdline.len());
+        }
+    }
+    copy_null_terminated(&mut boot_config.kernel_file, &kernel.name);
+    *BOOT_STATE.config.lock() = boot_config;
+
+    BootFiles { kernel, modules }
+}
+
+/// Copy `src` into `dest` as a null terminated string, truncating it if it does not fit
+pub fn copy_null_terminated(dest: &mut [u8], src: &[u8]) {
+    dest.fill(0);
+    let len = core::cmp::min(src.len(), dest.len().saturating_sub(1));
+    dest[..len].copy_from_slice(&src[..len]);
+}
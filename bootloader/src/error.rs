@@ -10,4 +10,26 @@ pub enum PxeError {
     FilenameTooLarge,
     InvalidRange(Range<usize>),
     InvalidBufferAddr(u32),
+    OutOfLowMemory,
+    // The server sent a different number of bytes than the file size it advertised
+    SizeMismatch { expected: u32, received: usize },
+    // The downloaded file does not have the digest listed in the configuration
+    DigestMismatch,
+    // The frame does not fit in a single transmit or it is empty
+    InvalidFrameSize(usize),
+}
+
+impl PxeError {
+    /// Returns `true` if the operation could succeed when tried again, for example after a
+    /// timeout or a lost packet
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::ApiStatus(status) => !matches!(
+                *status,
+                crate::pxe::status::TFTP_FILE_NOT_FOUND | crate::pxe::status::TFTP_ACCESS_VIOLATION,
+            ),
+            Self::SizeMismatch { .. } => true,
+            _ => false,
+        }
+    }
 }
@@ -6,36 +6,170 @@ mod asm_ffi;
 mod memory;
 mod pxe;
 mod error;
+mod config;
 
 use core::panic::PanicInfo;
 use cpu::x86;
 use parse_pe::Pe;
-use mmu::{PML4, VirtualAddress, PageSize, RWX};
-use state::BootState;
-use sync::LockCell;
+use error::PxeError;
+use mmu::{PML4, PhysicalAddress, VirtualAddress, PageSize, RWX, PHYSICAL_WINDOW_BASE,
+    PHYSICAL_WINDOW_SIZE};
+use state::{BootState, MemoryType, Region, IDENTITY_MAP_SIZE, STACK_REGION_BASE};
 
-pub static BOOT_STATE: BootState = BootState {
-    mmu: LockCell::new(None),
-    serial: LockCell::new(None),
-};
+pub static BOOT_STATE: BootState = BootState::new();
 
 extern crate alloc;
 
+// The kernel is loaded in the top 2GiB of the address space, at a base picked at boot time
+const KERNEL_BASE_MIN: u64 = 0xffff_ffff_8000_0000;
+// Alignment of the kernel base, such that it could be mapped with 2MiB pages
+const KERNEL_BASE_ALIGN: u64 = 2 * 1024 * 1024;
+// Number of possible bases, spread over the first GiB of the kernel area
+const KERNEL_BASE_SLOTS: u64 = 512;
+
+// The kernel area is above every other region of the higher half: the physical window, then the
+// heap and MMIO regions of the kernel, then the stacks
+const _: () = assert!(PHYSICAL_WINDOW_BASE + PHYSICAL_WINDOW_SIZE <= STACK_REGION_BASE);
+const _: () = assert!(STACK_REGION_BASE < KERNEL_BASE_MIN);
+
+// Kernel base fixed at build time with the hexadecimal `PIZZA_KERNEL_BASE` environment variable. It
+// has to be one of the bases picked at random, an invalid value fails the build.
+const FIXED_KERNEL_BASE: Option<u64> = match option_env!("PIZZA_KERNEL_BASE") {
+    Some(base) => {
+        let digits = match base.as_bytes() {
+            [b'0', b'x', ..] => base.split_at(2).1,
+            _ => base,
+        };
+        let Ok(base) = u64::from_str_radix(digits, 16) else {
+            panic!("PIZZA_KERNEL_BASE is not a hexadecimal address");
+        };
+        assert!(base % KERNEL_BASE_ALIGN == 0, "PIZZA_KERNEL_BASE is not 2MiB aligned");
+        let slot = base.wrapping_sub(KERNEL_BASE_MIN) / KERNEL_BASE_ALIGN;
+        assert!(
+            base >= KERNEL_BASE_MIN && slot < KERNEL_BASE_SLOTS,
+            "PIZZA_KERNEL_BASE is not in the first GiB of the kernel area",
+        );
+        Some(base)
+    }
+    None => None,
+};
+
+// Returns the address the kernel image base is relocated to, `FIXED_KERNEL_BASE` if it is set,
+// otherwise one picked at random.
+fn kernel_base() -> u64 {
+    // The time-stamp counter is the only source of entropy we have this early
+    FIXED_KERNEL_BASE
+        .unwrap_or(KERNEL_BASE_MIN + (x86::rdtsc() % KERNEL_BASE_SLOTS) * KERNEL_BASE_ALIGN)
+}
+
+// Command line flag asking to check that raw frames go both ways through UNDI
+const UNDI_PROBE_FLAG: &[u8] = b"undi-probe";
+// Times the adapter is polled for a frame after the probe was sent
+const UNDI_PROBE_POLLS: u32 = 100_000;
+
+// Broadcast an ARP request for the boot server through `undi`, and wait for any frame to come
+// back. Nothing else sends or receives raw frames in the bootloader, this is how they get tested.
+fn undi_probe(undi: &pxe::Undi, mac: [u8; 6], dhcp: &state::DhcpInfo) -> Result<(), PxeError> {
+    // Ethernet header to the broadcast address, followed by the ARP request, padded to the
+    // smallest Ethernet frame
+    let mut frame = [0u8; 60];
+    frame[..6].fill(0xff);
+    frame[6..12].copy_from_slice(&mac);
+    frame[12..14].copy_from_slice(&0x0806u16.to_be_bytes());
+    // IPv4 over Ethernet, request
+    frame[14..22].copy_from_slice(&[0, 1, 8, 0, 6, 4, 0, 1]);
+    frame[22..28].copy_from_slice(&mac);
+    frame[28..32].copy_from_slice(&dhcp.your_ip);
+    frame[38..42].copy_from_slice(&dhcp.server_ip);
+    undi.transmit(&frame)?;
+
+    for _ in 0..UNDI_PROBE_POLLS {
+        if let Some(frame) = undi.receive()? {
+            let ethertype = frame.get(12..14).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+            println!("UNDI probe received {} bytes, EtherType {:x?}", frame.len(), ethertype);
+            return Ok(());
+        }
+    }
+    println!("UNDI probe received nothing");
+    Ok(())
+}
+
+// Download each of the `modules` in its own page aligned physical memory and record it in the
+// boot state. The memory is allocated from the `Mmu`, so the kernel will not reuse it.
+fn load_modules(modules: &[config::BootFile]) {
+    for module in modules {
+        let name = &module.name;
+        let bytes = module.download().expect("Module download");
+        let size = (bytes.len() as u64).max(1).saturating_add(0xfff) & !0xfff;
+        let base = BOOT_STATE.mmu.lock().as_mut().expect("Physical memory not initialised")
+            .allocate(size, 4096)
+            .expect("Module allocation");
+        unsafe {
+            let memory = core::slice::from_raw_parts_mut(base as *mut u8, size as usize);
+            memory[..bytes.len()].copy_from_slice(&bytes);
+            memory[bytes.len()..].fill(0);
+        }
+
+        // `Config::parse` rejects the names and the number of modules the boot state cannot hold
+        let module = state::Module::new(name, base as u64, bytes.len() as u64)
+            .expect("Module name too long");
+        BOOT_STATE.modules.lock().push(module).expect("Too many modules");
+        println!("Module {} at {:#x} ({} bytes)", Text(name), base, bytes.len());
+    }
+}
+
 #[no_mangle]
-extern "C" fn entry(_bootloader_start: u32, _bootloader_end: u32, _stack_addr: u32) {
+extern "C" fn entry(bootloader_start: u32, bootloader_end: u32, _stack_addr: u32) {
     {
         let mut serial_lock = BOOT_STATE.serial.lock();
         if serial_lock.is_none() {
             *serial_lock = Some(serial::Serial::init());
         }
     }
+    // Let the kernel know which memory we occupy
+    *BOOT_STATE.bootloader.lock() = Region {
+        base: u64::from(bootloader_start),
+        size: u64::from(bootloader_end.saturating_sub(bootloader_start)),
+    };
     // Initialize memory
     memory::init();
 
+    // Save the network configuration for the kernel. The DHCP server can also pick the kernel we
+    // download and its command line.
+    let (dhcp, dhcp_boot) = pxe::dhcp_info().unwrap_or_else(|err| {
+        println!("Failed to get the DHCP information {:?}", err);
+        (state::DhcpInfo::empty(), pxe::DhcpBoot::default())
+    });
+    *BOOT_STATE.dhcp.lock() = dhcp;
+    // The configuration file overrides what the DHCP server sent us
+    let files = config::load(&dhcp, dhcp_boot);
+
     // Download the kernel
-    let kernel = pxe::download(b"pizza.kernel").expect("Kernel download");
+    let kernel = files.kernel.download().expect("Kernel download");
     // Parse the kernel's PE
     let kernel = Pe::parse(&kernel).expect("Kernel parsing");
+    load_modules(&files.modules);
+
+    // The adapter can also be driven directly, once we are done with TFTP
+    match pxe::Undi::open().and_then(|undi| undi.information().map(|info| (undi, info))) {
+        Ok((undi, info)) => {
+            println!("NIC {:02x?} MTU {} IRQ {} I/O {:#x}",
+                info.mac, info.mtu, info.irq, info.io_base);
+            if BOOT_STATE.cmdline.lock().has_flag(UNDI_PROBE_FLAG) {
+                if let Err(err) = undi_probe(&undi, info.mac, &dhcp) {
+                    println!("UNDI probe failed {:?}", err);
+                }
+            }
+        }
+        Err(err) => { println!("Failed to open UNDI {:?}", err); }
+    }
+
+    // The kernel's data and stacks are mapped as not executable, which only takes effect once
+    // EFER.NXE is set. Without it, the NX bit in the page tables is reserved and faults.
+    if !x86::nx_supported() {
+        panic!("The CPU does not support no-execute pages");
+    }
+    unsafe { x86::wrmsr(x86::rdmsr(x86::IA32_EFER) | x86::EFER_NXE, x86::IA32_EFER); }
 
     // Create a page table and jump in IA-32e mode
     let (cr3, stack, entry_point): (u32, u64, u64) =  unsafe {
@@ -44,38 +178,96 @@ extern "C" fn entry(_bootloader_start: u32, _bootloader_end: u32, _stack_addr: u
         let (cr3, stack, entry_point) = {
         let phys_mem = phys_mem_lock.as_mut().expect("Physical memory not initialised");
 
+        // Load the kernel image in a single block of physical memory, such that it can be
+        // described by one range
+        let (linked_start, image_end) = kernel.image_bounds().expect("Kernel image bounds");
+        let image_size = image_end.saturating_sub(linked_start).saturating_add(0xfff) & !0xfff;
+        let image_phys = phys_mem.allocate(image_size, 4096).expect("Kernel image allocation");
+        let image = core::slice::from_raw_parts_mut(image_phys as *mut u8, image_size as usize);
+        image.fill(0);
+        kernel.load_into(image).expect("Failed to load the kernel image");
+
+        // Move the kernel away from the base the linker picked
+        let base = kernel_base();
+        kernel.apply_relocations(base, image).expect("Failed to relocate the kernel");
+        let image_start = base.wrapping_add(linked_start.wrapping_sub(kernel.image_base()));
+        let entry_point = base.wrapping_add(kernel.entry_point().wrapping_sub(kernel.image_base()));
+        println!("Kernel relocated at {:#x}", base);
+
+        *BOOT_STATE.kernel_physical.lock() = Region { base: image_phys as u64, size: image_size };
+        *BOOT_STATE.kernel_virtual.lock() = Region { base: image_start, size: image_size };
+
         // Create a new PML4 table
         let mut pml4 = PML4::new(phys_mem).expect("Cannot create PML4 table");
 
-        // Create an identity map of the current memory
-        for p in (0..(4 * 1024 * 1024 * 1024)).step_by(4096) {
-            pml4.map_page(
-                VirtualAddress(p),
-                p | 3,
-                PageSize::Page4Kb,
-            ).expect("Failed to map PE");
+        // Create an identity map of the first 4GiB, using large pages. We need it to keep running
+        // once paging is enabled, the kernel removes it.
+        pml4.map_range_identity(
+            PhysicalAddress(0),
+            IDENTITY_MAP_SIZE,
+            RWX { read: true, write: true, execute: true },
+        ).expect("Failed to identity map memory");
+
+        // Give the kernel access to all the physical memory through a window in the higher half.
+        // The first 4GiB are always part of it, because they hold the legacy regions and the ACPI
+        // tables, followed by every RAM range above them.
+        let data = RWX { read: true, write: true, execute: false };
+        pml4.map_range(
+            VirtualAddress(PHYSICAL_WINDOW_BASE),
+            PhysicalAddress(0),
+            IDENTITY_MAP_SIZE,
+            data,
+        ).expect("Failed to map the physical window");
+        let mut window_end = IDENTITY_MAP_SIZE;
+        for entry in BOOT_STATE.memory_map.lock().entries() {
+            let ram = matches!(
+                entry.memory_type(),
+                MemoryType::Usable | MemoryType::AcpiReclaimable | MemoryType::AcpiNvs,
+            );
+            let start = core::cmp::max(entry.base & !0xfff, IDENTITY_MAP_SIZE);
+            let end = entry.base.saturating_add(entry.length).saturating_add(0xfff) & !0xfff;
+            let end = core::cmp::min(end, PHYSICAL_WINDOW_SIZE);
+            if !ram || start >= end {
+                continue;
+            }
+            pml4.map_range(
+                VirtualAddress(PHYSICAL_WINDOW_BASE + start),
+                PhysicalAddress(start),
+                end - start,
+                data,
+            ).expect("Failed to map the physical window");
+            window_end = core::cmp::max(window_end, end);
         }
+        *BOOT_STATE.physical_window.lock() = Region { base: PHYSICAL_WINDOW_BASE, size: window_end };
 
-        // Map the section of the kernel in memory
-        kernel.access_sections(|base, _size, bytes| {
-            println!("Base1 {:x?}, bytes len {:x?} size {:x?}", base, bytes.len(), _size);
-            pml4.map_slice(
-                VirtualAddress(base),
-                bytes,
-                PageSize::Page4Kb,
-                RWX { read: true, write: true, execute: true },
+        // Map each section of the kernel image with the permissions it asks for, such that code
+        // is not writable and data is not executable
+        for section in kernel.section_headers() {
+            let offset = kernel.image_base()
+                .wrapping_add(u64::from(section.virtual_address()))
+                .wrapping_sub(linked_start);
+            pml4.map_physical(
+                VirtualAddress(image_start.wrapping_add(offset)),
+                PhysicalAddress((image_phys as u64).wrapping_add(offset)),
+                u64::from(section.virtual_size()).saturating_add(0xfff) & !0xfff,
+                RWX {
+                    read: section.is_readable(),
+                    write: section.is_writable(),
+                    execute: section.is_executable(),
+                },
             ).expect("Failed to map PE");
-            Some(())
-        });
+        }
 
-        // Allocate and map a stack
+        // Allocate and map the stack of the first core. The guard page below it stays unmapped.
+        let stack = BOOT_STATE.stack(0);
         pml4.map_zero(
-            VirtualAddress(0xb00_0000_0000),
-            core::alloc::Layout::from_size_align(8192, 4096).expect("Failed to create layout"),
+            VirtualAddress(stack.base),
+            core::alloc::Layout::from_size_align(stack.size as usize, 4096)
+                .expect("Failed to create layout"),
             PageSize::Page4Kb,
-            RWX { read: true, write: true, execute: false },
+            data,
         ).expect("Failed to map a stack");
-            (pml4.cr3().0 as u32, 0xb00_0000_0000 + 8192, kernel.entry_point())
+            (pml4.cr3().0 as u32, stack.end(), entry_point)
         };
 
         (cr3, stack, entry_point)
@@ -85,8 +277,10 @@ extern "C" fn entry(_bootloader_start: u32, _bootloader_end: u32, _stack_addr: u
         extern {
             fn enter_ia32e(entry_point: u64, stack: u64, param: u64, cr3: u32) -> !;
         }
-        println!("Boot state {:#x?}", &BOOT_STATE as *const BootState as u64);
-        enter_ia32e(entry_point, stack, &BOOT_STATE as *const BootState as u64, cr3);
+        // The kernel only knows about the boot state through the physical window
+        let boot_state = PHYSICAL_WINDOW_BASE + &BOOT_STATE as *const BootState as u64;
+        println!("Boot state {:#x?}", boot_state);
+        enter_ia32e(entry_point, stack, boot_state, cr3);
     }
 }
 
@@ -116,6 +310,20 @@ impl core::fmt::Write for SerialWriter {
     }
 }
 
+/// Byte string printed as ASCII, with `?` in place of anything else. Names from the network are
+/// printed through it, which is cheaper than checking and escaping them as UTF-8.
+pub struct Text<'a>(pub &'a [u8]);
+
+impl core::fmt::Display for Text<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        for &byte in self.0 {
+            let printable = byte.is_ascii_graphic() || byte == b' ';
+            core::fmt::Write::write_char(f, if printable { char::from(byte) } else { '?' })?;
+        }
+        Ok(())
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {
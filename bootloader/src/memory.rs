@@ -1,13 +1,70 @@
 //! Module defining and implementing the memory manager
-use ops::RangeSet;
+use rangeset::{Range, RangeSet};
 use core::{
     alloc::{GlobalAlloc, Layout},
     ops::RangeInclusive,
 };
-use crate::asm_ffi::{RegSelState, real_mode_int};
+use crate::asm_ffi::{RegSelState, RealModeAddr, real_mode_int};
 use mmu::Mmu;
+use state::E820Entry;
+use sync::LockCell;
 use crate::BOOT_STATE;
 
+// Memory below 1MiB, which real mode code can reach. The first 64KiB hold the interrupt vector
+// table, the BIOS data area and our stack, so we stay clear of them.
+const LOW_MEMORY: Range = Range::new(0x1_0000, 1024 * 1024 - 1);
+
+// Address of the BIOS data area word holding the size of the conventional memory in KiB. The
+// memory above it belongs to the EBDA, and to the PXE stack once it is loaded.
+const BDA_BASE_MEMORY: usize = 0x413;
+
+// Pool of low memory for the buffers we share with real mode code, like the BIOS and PXE
+static LOW_POOL: LockCell<RangeSet> = LockCell::new(RangeSet::new());
+
+/// Buffer from the low memory pool, which can be handed to real mode code. It goes back in the
+/// pool when dropped.
+pub struct LowBuffer {
+    // Linear address of the buffer, below 1MiB
+    address: usize,
+    // Size of the buffer in bytes
+    size: usize,
+}
+
+impl LowBuffer {
+    /// Allocate a zeroed buffer of `size` bytes, aligned to `align`, from the low memory pool. The
+    /// buffer has to be addressable from a single real mode segment, so it cannot be bigger than
+    /// 64KiB minus the 16 bytes the segment can be off by.
+    pub fn new(size: usize, align: usize) -> Option<Self> {
+        if size == 0 || size > 0xfff0 {
+            return None;
+        }
+        let address = LOW_POOL.lock().allocate(size as u64, align as u64)?;
+        unsafe { core::ptr::write_bytes(address as *mut u8, 0, size) };
+        Some(Self { address, size })
+    }
+
+    /// Returns the real mode address of the buffer. The segment points right below the buffer,
+    /// such that all of it is reachable through the offset.
+    pub fn real_mode_addr(&self) -> RealModeAddr {
+        RealModeAddr::new((self.address >> 4) as u16, (self.address & 0xf) as u16)
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.address as *const u8, self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.address as *mut u8, self.size) }
+    }
+}
+
+impl Drop for LowBuffer {
+    fn drop(&mut self) {
+        let range = Range::new(self.address as u64, (self.address + self.size - 1) as u64);
+        LOW_POOL.lock().insert(range).expect("Cannot free low memory");
+    }
+}
+
 // Structure used by the memory manager to allocate memory. This implements `GlobalAlloc` crate in
 // order to be used by Rust.
 struct GlobalAllocator;
@@ -76,6 +133,8 @@ pub fn init() -> Option<()> {
 
         // Create a new set of memory ranges
         let mut set = RangeSet::new();
+        // The raw map is handed over to the kernel, with all the range types
+        let mut memory_map = BOOT_STATE.memory_map.lock();
 
         loop {
             // EAX and EDX register values differ between input and output.
@@ -83,22 +142,32 @@ pub fn init() -> Option<()> {
             reg_sel_state.edx = u32::from_be_bytes(*b"SMAP");
             real_mode_int(0x15, &mut reg_sel_state);
 
+            // Compute the start and the length of the range
+            let start = ((addr_range.base_high as u64) << 32) | addr_range.base_low as u64;
+            let length = ((addr_range.length_high as u64) << 32) | addr_range.length_low as u64;
+
+            // If we ran out of space, the kernel only gets the first entries, which is not a
+            // reason to stop booting
+            let _ = memory_map.push(E820Entry {
+                base: start,
+                length,
+                typ: addr_range.addr_type,
+                // We do not ask for the ACPI 3.0 extended attributes, which default to 1
+                attributes: 1,
+            });
+
             // If the range is memory we can use
             if addr_range.addr_type == RANGE_MEMORY {
-                // Compute the start and end for the set entry
-                let start = ((addr_range.base_high as u64) << 32) | addr_range.base_low as u64;
-                let length = ((addr_range.length_high as u64) << 32) | addr_range.length_low as u64;
                 // We are substracting 1 here because we use `RangeInclusive`
                 let end = start.saturating_add(length.saturating_sub(1));
                 // This is a special type of memory allocated by qemu to comply with some AMD
                 // graphics mapping
-                if start == 0x1_0000_0000 && end == 0x1_3fff_ffff {
-                    continue;
-                }
-                // Create a new range
-                let entry = RangeInclusive::new(start, end);
+                if !(start == 0x1_0000_0000 && end == 0x1_3fff_ffff) {
+                    // Create a new range
+                    let entry = Range::new(start, end);
 
-                set.insert(entry);
+                    set.insert(entry);
+                }
             }
 
             // If either carry flag is set (error), or the continuation value (ebx) is zero after
@@ -106,12 +175,24 @@ pub fn init() -> Option<()> {
             // Last address range in AMD systems can be explained in qemu/hw/i386/pc.c:782
             if reg_sel_state.eflags & 1 == 1 || reg_sel_state.ebx == 0 { break; }
         }
+        // The usable memory below 1MiB goes in the pool for real mode buffers, except for our
+        // own image and anything the BIOS reserved at the top of the conventional memory
+        let base_memory = core::ptr::read_volatile(BDA_BASE_MEMORY as *const u16) as u64 * 1024;
+        let low_end = base_memory.saturating_sub(1).min(LOW_MEMORY.end);
+        let mut low = if low_end >= LOW_MEMORY.start { set } else { RangeSet::new() };
+        low.intersect(Range::new(LOW_MEMORY.start, low_end));
+        let image = *BOOT_STATE.bootloader.lock();
+        if image.size > 0 {
+            low.remove(Range::new(image.base, image.base + image.size - 1))?;
+        }
+        *LOW_POOL.lock() = low;
+
         // Remove everything up to the 64 KB boundary (0xff_ffff)
-        let bios_needs = RangeInclusive::new(
+        let bios_needs = Range::new(
             0,
             1024 * 1024 - 1,
         );
-        set.discard(&bios_needs)?;
+        set.remove(bios_needs)?;
 
         set
     };
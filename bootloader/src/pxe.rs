@@ -2,18 +2,40 @@
 mod preboot;
 mod tftp;
 mod api;
+mod undi;
+
+pub use api::status;
 
 use crate::{
     asm_ffi::{real_mode_int, pxe_call, RegSelState, RealModeAddr},
     error::PxeError,
+    memory::LowBuffer,
+    config::copy_null_terminated,
 };
-use sync::LockCell;
+use sync::{LockCell, lockcell::LockCellGuard};
+use state::DhcpInfo;
+use parse_dhcp::{Options, OPTION_PIZZA_KERNEL, OPTION_PIZZA_CMDLINE};
+use parse_config::MtftpConfig;
+use crate::{println, Text};
 use alloc::vec::Vec;
 use preboot::*;
 use api::*;
+use undi::*;
 
 static PXE_LOCK: LockCell<()> = LockCell::new(());
 
+// Number of times we try to download a file before giving up
+const DOWNLOAD_ATTEMPTS: usize = 3;
+// Progress is reported every time this many bytes are downloaded
+const PROGRESS_INTERVAL: usize = 256 * 1024;
+// Packet size every TFTP server supports, used when the block size option is not negotiated
+const TFTP_DEFAULT_PACKET_SIZE: u16 = 512;
+// Bytes of a frame's payload taken by the IP, UDP and TFTP headers
+const TFTP_HEADERS_SIZE: u16 = 20 + 8 + 4;
+// Seconds MTFTP waits for the server, and before joining the session again
+const MTFTP_OPEN_TIMEOUT: u16 = 2;
+const MTFTP_REOPEN_DELAY: u16 = 2;
+
 #[derive(Debug)]
 #[repr(packed)]
 pub struct PxeNvPlus {
@@ -174,6 +196,20 @@ pub struct Pxe {
 }
 
 impl Pxe {
+    // Call the PXE API service `opcode` with its parameter structure `params`, which has to live in
+    // the first 64KiB of memory, such as on our stack
+    fn call<T>(&self, params: &mut T, opcode: u16) {
+        unsafe {
+            pxe_call(
+                self.entry_point_sp.seg,
+                self.entry_point_sp.off,
+                0,
+                params as *mut T as u16,
+                opcode,
+            )
+        };
+    }
+
     pub fn from_real_mode(real_mode_addr: RealModeAddr) -> Option<Self> {
         let pxe_ptr = real_mode_addr.linear() as *const Pxe;
 
@@ -303,19 +339,20 @@ impl Pxe {
         Ok(tftp_open.packet_size)
     }
 
-    /// Reads the `file_name` from the open TFTP connection, where `file_size` is the size of the
-    /// entire file and `buffer_size` is the size of the packet.
-    pub fn tftp_read(&self, size: u32) -> Result<Vec<u8>, PxeError> {
+    /// Reads the file from the open TFTP connection, where `size` is the size of the entire file
+    /// the server advertised and `packet_size` is the one negotiated by `tftp_open`. Progress is
+    /// reported over serial every `PROGRESS_INTERVAL` bytes.
+    pub fn tftp_read(&self, size: u32, packet_size: u16) -> Result<Vec<u8>, PxeError> {
         // Allocate a new buffer to store the data. We allocate directly, such that we avoid
         // multiple allocations
         let mut buffer = Vec::with_capacity(size as usize);
 
-        const BUFFER_SIZE: u16 = 512;
-
         // Create a new read structure
         let mut tftp_read = tftp::TftpRead::default();
-        let mut temp_buffer = [0u8; BUFFER_SIZE as usize];
-        tftp_read.buffer = RealModeAddr { off: &mut temp_buffer as *mut _ as u16, seg: 0u16 };
+        // PXE writes each packet in a bounce buffer it can reach from real mode
+        let temp_buffer = LowBuffer::new(usize::from(packet_size), 16)
+            .ok_or(PxeError::OutOfLowMemory)?;
+        tftp_read.buffer = temp_buffer.real_mode_addr();
 
         loop {
             unsafe {
@@ -329,22 +366,97 @@ impl Pxe {
             };
 
             // If status is unsuccessful or if the buffer size is corrupted, return error
-            if tftp_read.status != 0 || BUFFER_SIZE < tftp_read.buffer_size {
+            if tftp_read.status != 0 || packet_size < tftp_read.buffer_size {
                 return Err(PxeError::ApiStatus(tftp_read.status));
             }
 
             // Extend our allocation by the contents of the buffer
-            buffer.extend(&temp_buffer[..tftp_read.buffer_size as usize]);
+            let before = buffer.len();
+            buffer.extend(&temp_buffer.as_slice()[..tftp_read.buffer_size as usize]);
+            if before / PROGRESS_INTERVAL != buffer.len() / PROGRESS_INTERVAL {
+                println!("Downloaded {} of {} KiB", buffer.len() / 1024, size / 1024);
+            }
 
             // If we read less than the packer size, we know this is the last packet
-            if tftp_read.buffer_size < BUFFER_SIZE {
+            if tftp_read.buffer_size < packet_size {
                 break;
             }
         }
 
+        if buffer.len() != size as usize {
+            return Err(PxeError::SizeMismatch { expected: size, received: buffer.len() });
+        }
+
         Ok(buffer)
     }
 
+    /// Download the whole `filename` of `size` bytes in a single call, through the MTFTP session
+    /// described by `mtftp`. Every client downloading the same file at the same time shares the
+    /// packets the server sends. The PXE stack does not return before the end of the transfer, so
+    /// progress is only reported before and after it.
+    pub fn mtftp_read_file(
+        &self,
+        server_ip: &Ip4,
+        filename: &[u8],
+        size: u32,
+        mtftp: &MtftpConfig,
+    ) -> Result<Vec<u8>, PxeError> {
+        let mut read_file = tftp::TftpReadFile::default();
+
+        if filename.len() + 1 > read_file.file_name.0.len() {
+            return Err(PxeError::FilenameTooLarge);
+        }
+        read_file.file_name.0[..filename.len()].copy_from_slice(filename);
+
+        // PXE writes the file straight into our buffer, through its physical address
+        let mut buffer: Vec<u8> = Vec::with_capacity(size as usize);
+        read_file.buffer_size = size;
+        read_file.buffer = buffer.as_mut_ptr() as u32;
+        read_file.server_ip = *server_ip;
+        // IPs and Ports in this structure have to be network order (big endian)
+        read_file.mcast_ip = Ip4(mtftp.ip);
+        read_file.client_port = mtftp.client_port.to_be();
+        read_file.server_port = mtftp.server_port.to_be();
+        read_file.open_timeout = MTFTP_OPEN_TIMEOUT;
+        read_file.reopen_delay = MTFTP_REOPEN_DELAY;
+
+        println!("Downloading {} KiB through MTFTP", size / 1024);
+        self.call(&mut read_file, opcode::TFTP_READ_FILE);
+
+        if read_file.status != 0 {
+            return Err(PxeError::ApiStatus(read_file.status));
+        }
+        if read_file.buffer_size != size {
+            return Err(PxeError::SizeMismatch {
+                expected: size,
+                received: read_file.buffer_size as usize,
+            });
+        }
+        unsafe { buffer.set_len(size as usize) };
+        println!("Downloaded {} KiB", size / 1024);
+
+        Ok(buffer)
+    }
+
+    /// Returns the information UNDI has about the network adapter
+    pub fn undi_get_information(&self) -> Result<UndiGetInformation, PxeError> {
+        let mut info = UndiGetInformation::default();
+        self.call(&mut info, opcode::UNDI_GET_INFORMATION);
+        if info.status != 0 {
+            return Err(PxeError::ApiStatus(info.status));
+        }
+        Ok(info)
+    }
+
+    /// Returns the largest TFTP packet size that fits in a frame of the adapter, which is the
+    /// block size we ask the server for
+    pub fn tftp_packet_size(&self) -> u16 {
+        self.undi_get_information()
+            .map(|info| info.max_tran_unit.saturating_sub(TFTP_HEADERS_SIZE))
+            .unwrap_or(0)
+            .max(TFTP_DEFAULT_PACKET_SIZE)
+    }
+
     /// Close a previously opened TFTP connection.
     /// Warning: Service cannot be used if there is not an active MTFTP connection.
     /// Service cannot be used in protected mode if the StatusCallout field in the !PXE structure
@@ -387,11 +499,8 @@ pub fn install_check() -> Option<RealModeAddr> {
     }
 }
 
-/// Guarded call(in multithreaded contexts) to download a new `file_name` using the PXE API.
-pub fn download(file_name: &[u8]) -> Result<Vec<u8>, PxeError> {
-    // Make sure this is multithread safe. The lock gets dropped at the end of this function
-    let _pxe_lock = PXE_LOCK.lock();
-
+// Find the `!PXE` structure, which gives us the entry point of the PXE API
+fn find_pxe() -> Result<Pxe, PxeError> {
     let pxenv_addr = install_check()
         .ok_or(PxeError::InstallCheck)?;
 
@@ -399,22 +508,250 @@ pub fn download(file_name: &[u8]) -> Result<Vec<u8>, PxeError> {
         .ok_or(PxeError::PxeNvPlus)?;
 
     // If the API version is 0x201 or higher, we have a `!PXE` structure
-    let pxe = if pxenv.version() >= 0x201 {
+    if pxenv.version() >= 0x201 {
         Pxe::from_real_mode(pxenv.pxe_ptr()).ok_or(PxeError::Pxe)
     } else {
         Err(PxeError::Pxe)
-    }?;
+    }
+}
+
+/// Guarded call(in multithreaded contexts) to download a new `file_name` using the PXE API,
+/// through MTFTP if `mtftp` is set. Transient errors restart the download from the beginning, up
+/// to `DOWNLOAD_ATTEMPTS` times.
+pub fn download(file_name: &[u8], mtftp: Option<&MtftpConfig>) -> Result<Vec<u8>, PxeError> {
+    // Make sure this is multithread safe. The lock gets dropped at the end of this function
+    let _pxe_lock = PXE_LOCK.lock();
+
+    let pxe = find_pxe()?;
 
     let (_cached_info, bootp_packet)= pxe.get_cached_info()?;
+    let server_ip = bootp_packet.next_server_ip;
+
+    let mut attempt = 1;
+    loop {
+        let result = match mtftp {
+            Some(mtftp) => pxe.tftp_get_file_size(&server_ip, file_name)
+                .and_then(|size| pxe.mtftp_read_file(&server_ip, file_name, size, mtftp)),
+            None => download_once(&pxe, &server_ip, file_name),
+        };
+        match result {
+            Err(err) if err.is_transient() && attempt < DOWNLOAD_ATTEMPTS => {
+                println!("Download of {} failed with {:?}, retrying", Text(file_name), err);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
 
+// Download `file_name` from `server_ip` in a single TFTP transfer, with the largest block size
+// the server agrees to
+fn download_once(pxe: &Pxe, server_ip: &Ip4, file_name: &[u8]) -> Result<Vec<u8>, PxeError> {
     // Get the file size of the desired file
-    let file_size = pxe.tftp_get_file_size(&bootp_packet.next_server_ip, file_name)?;
+    let file_size = pxe.tftp_get_file_size(server_ip, file_name)?;
     // Open a TFTP connection and negotiate for a packet size
-    let _negotiated_packet_size = pxe.tftp_open(&bootp_packet.next_server_ip, file_name, 512)?;
-    // Read the file
-    let downloaded = pxe.tftp_read(file_size)?;
+    let packet_size = pxe.tftp_open(server_ip, file_name, pxe.tftp_packet_size())?;
+    // Read the file. The connection has to be closed even if the read fails, otherwise we cannot
+    // open another one.
+    let downloaded = pxe.tftp_read(file_size, packet_size);
     // Close the TFTP connection
-    let _ = pxe.tftp_close()?;
+    let closed = pxe.tftp_close();
 
+    let downloaded = downloaded?;
+    closed?;
     Ok(downloaded)
 }
+
+/// Network adapter information reported by UNDI
+#[derive(Debug, Clone, Copy)]
+pub struct UndiInformation {
+    pub mac: [u8; 6],
+    // Largest payload of a frame
+    pub mtu: u16,
+    pub irq: u16,
+    pub io_base: u16,
+}
+
+/// The adapter opened through the UNDI API, to send and receive raw Ethernet frames. It holds the
+/// PXE lock, so TFTP cannot be used while it is open, and closes the adapter when dropped.
+pub struct Undi {
+    pxe: Pxe,
+    _pxe_lock: LockCellGuard<'static, ()>,
+}
+
+impl Undi {
+    /// Open the adapter, receiving frames sent to us and broadcast frames
+    pub fn open() -> Result<Self, PxeError> {
+        let pxe_lock = PXE_LOCK.lock();
+        let pxe = find_pxe()?;
+
+        let mut undi_open = UndiOpen {
+            packet_filter: FLTR_DIRECTED | FLTR_BRDCST,
+            ..Default::default()
+        };
+        pxe.call(&mut undi_open, opcode::UNDI_OPEN);
+        if undi_open.status != 0 {
+            return Err(PxeError::ApiStatus(undi_open.status));
+        }
+
+        Ok(Self { pxe, _pxe_lock: pxe_lock })
+    }
+
+    pub fn information(&self) -> Result<UndiInformation, PxeError> {
+        let info = self.pxe.undi_get_information()?;
+
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(&info.current_node_address[..6]);
+        Ok(UndiInformation {
+            mac,
+            mtu: info.max_tran_unit,
+            irq: info.int_number,
+            io_base: info.base_io,
+        })
+    }
+
+    /// Transmit `frame`, which starts with its Ethernet header. UNDI copies the frame before
+    /// returning, so the bounce buffer is only kept for the duration of the call.
+    pub fn transmit(&self, frame: &[u8]) -> Result<(), PxeError> {
+        if frame.is_empty() || frame.len() > usize::from(u16::MAX) {
+            return Err(PxeError::InvalidFrameSize(frame.len()));
+        }
+        let mut buffer = LowBuffer::new(frame.len(), 16).ok_or(PxeError::OutOfLowMemory)?;
+        buffer.as_mut_slice().copy_from_slice(frame);
+
+        let mut tbd = UndiTbd {
+            immediate_len: frame.len() as u16,
+            immediate: buffer.real_mode_addr(),
+            ..Default::default()
+        };
+        let mut transmit = UndiTransmit {
+            protocol: P_UNKNOWN,
+            xmit_flag: XMT_DESTADDR,
+            tbd: RealModeAddr::new(0, &mut tbd as *mut _ as u16),
+            ..Default::default()
+        };
+        self.pxe.call(&mut transmit, opcode::UNDI_TRANSMIT);
+        if transmit.status != 0 {
+            return Err(PxeError::ApiStatus(transmit.status));
+        }
+
+        Ok(())
+    }
+
+    /// Poll the adapter for a received frame, including its Ethernet header. Returns `None` if
+    /// there is none. We run with interrupts disabled, so the ISR is serviced right away instead of
+    /// from the adapter's interrupt handler.
+    pub fn receive(&self) -> Result<Option<Vec<u8>>, PxeError> {
+        let mut isr = UndiIsr { func_flag: ISR_FUNC_START, ..Default::default() };
+        self.pxe.call(&mut isr, opcode::UNDI_ISR);
+        if isr.status != 0 {
+            return Err(PxeError::ApiStatus(isr.status));
+        }
+        if isr.func_flag != ISR_OUT_OURS {
+            return Ok(None);
+        }
+
+        let mut frame: Option<Vec<u8>> = None;
+        let mut func = ISR_FUNC_PROCESS;
+        loop {
+            isr = UndiIsr { func_flag: func, ..Default::default() };
+            self.pxe.call(&mut isr, opcode::UNDI_ISR);
+            if isr.status != 0 {
+                return Err(PxeError::ApiStatus(isr.status));
+            }
+            func = ISR_FUNC_GET_NEXT;
+
+            match isr.func_flag {
+                ISR_OUT_RECEIVE => {
+                    let address = { isr.frame }.linear();
+                    let fragment = unsafe {
+                        core::slice::from_raw_parts(
+                            address as *const u8,
+                            usize::from(isr.buffer_length),
+                        )
+                    };
+                    let frame = frame.get_or_insert_with(|| {
+                        Vec::with_capacity(usize::from(isr.frame_length))
+                    });
+                    frame.extend_from_slice(fragment);
+                }
+                // Completed transmits need no action, we keep going until we get a frame
+                ISR_OUT_TRANSMIT | ISR_OUT_BUSY => {}
+                _ => break,
+            }
+            // Stop once we have a whole frame, the next one is fetched by the next call
+            if frame.as_ref().is_some_and(|frame| frame.len() >= usize::from(isr.frame_length)) {
+                break;
+            }
+        }
+
+        Ok(frame)
+    }
+}
+
+impl Drop for Undi {
+    fn drop(&mut self) {
+        let mut status: u16 = 0;
+        self.pxe.call(&mut status, opcode::UNDI_CLOSE);
+    }
+}
+
+/// Boot parameters the DHCP server hands us through site specific options
+#[derive(Debug, Default)]
+pub struct DhcpBoot {
+    // Name of the kernel file to download from the TFTP server
+    pub kernel_file: Option<Vec<u8>>,
+    // Command line to pass to the kernel
+    pub cmdline: Option<Vec<u8>>,
+}
+
+/// Returns the network configuration from the DHCP acknowledgement the PXE firmware received,
+/// together with the boot parameters found in its options
+pub fn dhcp_info() -> Result<(DhcpInfo, DhcpBoot), PxeError> {
+    let _pxe_lock = PXE_LOCK.lock();
+
+    let pxe = find_pxe()?;
+    let (_cached_info, bootp_packet) = pxe.get_cached_info()?;
+
+    let mut info = DhcpInfo::empty();
+    info.client_ip = bootp_packet.client_ip.0;
+    info.your_ip = bootp_packet.your_ip.0;
+    info.server_ip = bootp_packet.next_server_ip.0;
+    info.gateway_ip = bootp_packet.relay_ip.0;
+    // Ethernet addresses are 6 bytes long, the rest of the field is padding
+    let mac_len = core::cmp::min(usize::from(bootp_packet.hardware_len), info.mac.len());
+    if let (Some(mac), Some(addr)) =
+        (info.mac.get_mut(..mac_len), bootp_packet.client_mac_addr.0.get(..mac_len)) {
+        mac.copy_from_slice(addr);
+    }
+    info.server_name = bootp_packet.server_name.0;
+    info.boot_file = bootp_packet.bootfile.0;
+
+    // A plain BOOTP server does not send any options, so there is nothing more to get
+    let Ok(options) = Options::parse(&bootp_packet.vendor.0) else {
+        return Ok((info, DhcpBoot::default()));
+    };
+
+    info.subnet_mask = options.subnet_mask().unwrap_or_default();
+    info.router = options.router().unwrap_or_default();
+    for (dns, server) in info.dns.iter_mut().zip(options.dns_servers()) {
+        *dns = server;
+    }
+    if let Some(hostname) = options.hostname() {
+        copy_null_terminated(&mut info.hostname, hostname);
+    }
+    // Options 66 and 67 replace the fixed size fields, when the server has names too long for them
+    if let Some(server_name) = options.tftp_server_name() {
+        copy_null_terminated(&mut info.server_name, server_name);
+    }
+    if let Some(boot_file) = options.bootfile_name() {
+        copy_null_terminated(&mut info.boot_file, boot_file);
+    }
+
+    let boot = DhcpBoot {
+        kernel_file: options.site_specific(OPTION_PIZZA_KERNEL).map(<[u8]>::to_vec),
+        cmdline: options.site_specific(OPTION_PIZZA_CMDLINE).map(<[u8]>::to_vec),
+    };
+
+    Ok((info, boot))
+}
@@ -1,12 +1,12 @@
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
-pub struct Ip4([u8; 4]);
+pub struct Ip4(pub [u8; 4]);
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
-pub struct MacAddr([u8; 16]);
+pub struct MacAddr(pub [u8; 16]);
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
-pub struct ServerName([u8; 64]);
+pub struct ServerName(pub [u8; 64]);
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
 pub struct BootFile(pub [u8; 128]);
@@ -36,9 +36,21 @@ impl Default for BootFile {
 }
 
 pub mod opcode {
+    pub const UNDI_OPEN: u16 = 0x0006;
+    pub const UNDI_CLOSE: u16 = 0x0007;
+    pub const UNDI_TRANSMIT: u16 = 0x0008;
+    pub const UNDI_GET_INFORMATION: u16 = 0x000c;
+    pub const UNDI_ISR: u16 = 0x0014;
     pub const TFTP_OPEN: u16 = 0x0020;
     pub const TFTP_CLOSE: u16 = 0x0021;
     pub const TFTP_READ: u16 = 0x0022;
+    pub const TFTP_READ_FILE: u16 = 0x0023;
     pub const TFTP_GET_FILE_SIZE: u16 = 0x0025;
     pub const PREBOOT_GET_CACHED_INFO: u16 = 0x0071;
 }
+
+/// PXE API status codes we act on
+pub mod status {
+    pub const TFTP_FILE_NOT_FOUND: u16 = 0x003b;
+    pub const TFTP_ACCESS_VIOLATION: u16 = 0x003c;
+}
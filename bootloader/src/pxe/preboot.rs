@@ -5,7 +5,7 @@ use crate::{
 
 pub const PXENV_PACKET_TYPE_DHCP_ACK: u16 = 2;
 // Maximum length of DHCP options: https://dox.ipxe.org/pxe__api_8h_source.html
-const _BOOTP_DHCPVEND: u16 = 1024;
+pub const BOOTP_DHCPVEND: usize = 1024;
 
 #[derive(Debug, Default)]
 #[repr(C)]
@@ -26,7 +26,7 @@ pub struct BootCachedPacket {
     // Hardware type.
     _hardware: u8,
     // Hardware address length
-    _hardware_len: u8,
+    pub hardware_len: u8,
     // Client sets to zero. Optionally used by relay agent when booting via a relay agent
     _gate_ops: u8,
     // Transaction ID. Random number used by the client.
@@ -36,19 +36,30 @@ pub struct BootCachedPacket {
     // BOOTP/DHCP broadcat flags.
     _flags: u16,
     // Client IPv4 address
-    _client_ip: Ip4,
+    pub client_ip: Ip4,
     // IP address of the current machine
-    _your_ip: Ip4,
+    pub your_ip: Ip4,
     // IP address of next server in boot process
     pub next_server_ip: Ip4,
     // Relay agent IP address
-    _relay_ip: Ip4,
+    pub relay_ip: Ip4,
     // Client hardware address
-    _client_mac_addr: MacAddr,
+    pub client_mac_addr: MacAddr,
     // Optional server host name. Null terminated string.
-    _server_name: ServerName,
+    pub server_name: ServerName,
     // Boot file name. Null terminated string
-    _bootfile: BootFile,
-    // Following, we could have a field containin DHCP options. However, that should be 1024 and we
-    // will not need it.
+    pub bootfile: BootFile,
+    // DHCP options, starting with the magic cookie. Called the vendor area in BOOTP.
+    pub vendor: DhcpVendor,
+}
+
+/// Options area at the end of a BOOTP/DHCP packet
+#[derive(Debug)]
+#[repr(transparent)]
+pub struct DhcpVendor(pub [u8; BOOTP_DHCPVEND]);
+
+impl Default for DhcpVendor {
+    fn default() -> Self {
+        Self([0; BOOTP_DHCPVEND])
+    }
 }
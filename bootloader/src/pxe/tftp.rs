@@ -47,3 +47,27 @@ pub struct TftpRead {
     // Address to the buffer that will store the bytes we read
     pub buffer: RealModeAddr,
 }
+
+/// Structure used to download a whole file in a single call, straight into a buffer anywhere in
+/// memory. With a multicast address, the file is downloaded through MTFTP.
+#[derive(Default)]
+#[repr(C, packed)]
+pub struct TftpReadFile {
+    pub status: u16,
+    pub file_name: BootFile,
+    // Size of the buffer on input, size of the file on output
+    pub buffer_size: u32,
+    // Physical address of the buffer
+    pub buffer: u32,
+    pub server_ip: Ip4,
+    _gateway_ip: Ip4,
+    // Multicast address of the MTFTP session, zero for a unicast TFTP download
+    pub mcast_ip: Ip4,
+    // UDP ports of the MTFTP session, in network order
+    pub client_port: u16,
+    pub server_port: u16,
+    // Seconds to wait for the server to answer
+    pub open_timeout: u16,
+    // Seconds to wait before trying to join the session again
+    pub reopen_delay: u16,
+}
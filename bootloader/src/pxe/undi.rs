@@ -0,0 +1,140 @@
+//! Parameter structures of the UNDI API, which sends and receives raw frames through the adapter
+#![allow(dead_code)]
+use crate::asm_ffi::RealModeAddr;
+
+/// Maximum number of multicast addresses the adapter can be opened with
+pub const MAXNUM_MCADDR: usize = 8;
+/// Maximum number of data blocks a transmit buffer descriptor can point to
+pub const MAX_DATA_BLKS: usize = 8;
+
+/// Receive frames sent to our MAC address
+pub const FLTR_DIRECTED: u16 = 0x0001;
+/// Receive broadcast frames
+pub const FLTR_BRDCST: u16 = 0x0002;
+/// Receive all the frames on the network
+pub const FLTR_PRMSCS: u16 = 0x0004;
+
+/// The frame already contains its media header
+pub const P_UNKNOWN: u8 = 0;
+/// Send the frame to the address in `DestAddr`
+pub const XMT_DESTADDR: u8 = 0;
+
+/// `UNDI_ISR` function asking the adapter if the interrupt is ours
+pub const ISR_FUNC_START: u16 = 1;
+/// `UNDI_ISR` function fetching the first event the adapter has for us
+pub const ISR_FUNC_PROCESS: u16 = 2;
+/// `UNDI_ISR` function fetching the next event, or the next fragment of a received frame
+pub const ISR_FUNC_GET_NEXT: u16 = 3;
+/// Returned by `ISR_FUNC_START` when the adapter has events for us
+pub const ISR_OUT_OURS: u16 = 0;
+/// There are no more events
+pub const ISR_OUT_DONE: u16 = 0;
+/// A transmit completed
+pub const ISR_OUT_TRANSMIT: u16 = 2;
+/// A fragment of a received frame is in `Frame`
+pub const ISR_OUT_RECEIVE: u16 = 3;
+/// The adapter is busy servicing a previous interrupt
+pub const ISR_OUT_BUSY: u16 = 4;
+
+/// Multicast addresses to receive frames for, each in a 16 byte slot
+#[derive(Default)]
+#[repr(C, packed)]
+pub struct McastAddress {
+    pub count: u16,
+    pub addresses: [[u8; 16]; MAXNUM_MCADDR],
+}
+
+/// Structure used to activate the network connection of the adapter
+#[derive(Default)]
+#[repr(C, packed)]
+pub struct UndiOpen {
+    pub status: u16,
+    // Not used in PXE 2.1
+    pub _open_flag: u16,
+    // `FLTR_*` flags selecting the frames we receive
+    pub packet_filter: u16,
+    pub mcast: McastAddress,
+}
+
+/// Structure returned by `UNDI_GET_INFORMATION`
+#[derive(Default)]
+#[repr(C, packed)]
+pub struct UndiGetInformation {
+    pub status: u16,
+    // I/O base address of the adapter
+    pub base_io: u16,
+    // IRQ number of the adapter
+    pub int_number: u16,
+    // Maximum transmission unit, the largest payload of a frame
+    pub max_tran_unit: u16,
+    // ARP hardware type, 1 for Ethernet
+    pub hw_type: u16,
+    pub hw_addr_len: u16,
+    // MAC address the adapter currently uses
+    pub current_node_address: [u8; 16],
+    // MAC address burnt in the adapter
+    pub perm_node_address: [u8; 16],
+    // Real mode segment of the UNDI ROM
+    pub rom_address: u16,
+    // Number of receive and transmit buffers of the adapter
+    pub rx_buf_ct: u16,
+    pub tx_buf_ct: u16,
+}
+
+/// A piece of the frame to transmit
+#[derive(Default, Clone, Copy)]
+#[repr(C, packed)]
+pub struct DataBlock {
+    // 0 if `data` is a segment:offset address, 1 if it is a linear address
+    pub ptr_type: u8,
+    pub _reserved: u8,
+    pub len: u16,
+    pub data: RealModeAddr,
+}
+
+/// Transmit buffer descriptor, listing the pieces of a frame
+#[derive(Default)]
+#[repr(C, packed)]
+pub struct UndiTbd {
+    // Length of the first piece of the frame, at `immediate`
+    pub immediate_len: u16,
+    pub immediate: RealModeAddr,
+    // Number of valid entries in `blocks`
+    pub block_count: u16,
+    pub blocks: [DataBlock; MAX_DATA_BLKS],
+}
+
+/// Structure used to transmit a frame
+#[derive(Default)]
+#[repr(C, packed)]
+pub struct UndiTransmit {
+    pub status: u16,
+    // Protocol of the frame, `P_UNKNOWN` if it already has a media header
+    pub protocol: u8,
+    pub xmit_flag: u8,
+    // MAC address to send the frame to, when the adapter builds the media header
+    pub dest_addr: RealModeAddr,
+    // Address of the `UndiTbd` describing the frame
+    pub tbd: RealModeAddr,
+    pub _reserved: [u32; 2],
+}
+
+/// Structure used to service the adapter's events, through `UNDI_ISR`
+#[derive(Default)]
+#[repr(C, packed)]
+pub struct UndiIsr {
+    pub status: u16,
+    // `ISR_FUNC_*` on input, `ISR_OUT_*` on output
+    pub func_flag: u16,
+    // Length of the fragment of the frame at `frame`
+    pub buffer_length: u16,
+    // Length of the whole frame
+    pub frame_length: u16,
+    // Length of the media header of the frame
+    pub frame_header_length: u16,
+    // Fragment of the received frame, in the adapter's memory
+    pub frame: RealModeAddr,
+    pub protocol: u8,
+    // Whether the frame was directed, broadcast, multicast or promiscuous
+    pub packet_type: u8,
+}
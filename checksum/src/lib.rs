@@ -0,0 +1,130 @@
+//! Checksums used to verify the files the bootloader downloads
+#![no_std]
+
+mod sha256;
+
+pub use sha256::{sha256, Sha256};
+
+/// Returns the CRC-32 (IEEE 802.3, the one used by zlib and Ethernet) of `bytes`
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32_update(0, bytes)
+}
+
+/// Continue the CRC-32 `crc` of the previous bytes over `bytes`
+pub fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in bytes {
+        crc = CRC32_TABLE[usize::from(crc as u8 ^ byte)] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// Reversed polynomial of CRC-32
+const CRC32_POLYNOMIAL: u32 = 0xedb8_8320;
+
+// CRC-32 of each byte value, such that we process a byte at a time instead of a bit
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < table.len() {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ CRC32_POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+/// Expected checksum of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Digest {
+    Crc32(u32),
+    Sha256([u8; 32]),
+}
+
+impl Digest {
+    /// Parse a digest written as `crc32=<8 hex digits>` or `sha256=<64 hex digits>`
+    pub fn parse(text: &[u8]) -> Option<Self> {
+        let split = text.iter().position(|&byte| byte == b'=')?;
+        let (algorithm, hex) = (&text[..split], &text[split + 1..]);
+        if algorithm.eq_ignore_ascii_case(b"crc32") {
+            let mut bytes = [0u8; 4];
+            parse_hex(hex, &mut bytes)?;
+            Some(Self::Crc32(u32::from_be_bytes(bytes)))
+        } else if algorithm.eq_ignore_ascii_case(b"sha256") {
+            let mut bytes = [0u8; 32];
+            parse_hex(hex, &mut bytes)?;
+            Some(Self::Sha256(bytes))
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if `bytes` have this digest
+    pub fn matches(&self, bytes: &[u8]) -> bool {
+        match self {
+            Self::Crc32(crc) => crc32(bytes) == *crc,
+            Self::Sha256(hash) => sha256(bytes) == *hash,
+        }
+    }
+}
+
+// Fill `bytes` with the value of the hexadecimal string `hex`, which has to be twice as long
+fn parse_hex(hex: &[u8], bytes: &mut [u8]) -> Option<()> {
+    if hex.len() != bytes.len() * 2 {
+        return None;
+    }
+    for (byte, digits) in bytes.iter_mut().zip(hex.chunks_exact(2)) {
+        *byte = hex_digit(digits[0])? << 4 | hex_digit(digits[1])?;
+    }
+    Some(())
+}
+
+// Returns the value of the hexadecimal digit `digit`, in either case
+fn hex_digit(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_vectors() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414f_a339);
+        // Computing it in pieces gives the same result
+        let (head, tail) = b"123456789".split_at(4);
+        assert_eq!(crc32_update(crc32(head), tail), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn digests() {
+        let crc = Digest::parse(b"CRC32=CBF43926").expect("Valid digest");
+        assert_eq!(crc, Digest::Crc32(0xcbf4_3926));
+        assert!(crc.matches(b"123456789"));
+        assert!(!crc.matches(b"12345678"));
+
+        let sha = Digest::parse(
+            b"sha256=ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        ).expect("Valid digest");
+        assert!(sha.matches(b"abc"));
+        assert!(!sha.matches(b"abd"));
+
+        assert!(Digest::parse(b"crc32=cbf4392").is_none());
+        assert!(Digest::parse(b"crc32=cbf4392g").is_none());
+        assert!(Digest::parse(b"crc32=+bf43926").is_none());
+        assert!(Digest::parse(b"md5=cbf43926").is_none());
+        assert!(Digest::parse(b"cbf43926").is_none());
+    }
+}
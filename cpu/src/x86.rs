@@ -3,6 +3,10 @@ use core::arch::asm;
 
 const IA32_GS_BASE: u32 = 0xC000_0101;
 pub const IA32_APIC_BASE: u32 = 0x0000_001B;
+/// Extended Feature Enable Register
+pub const IA32_EFER: u32 = 0xC000_0080;
+/// Execute Disable Bit Enable, makes the processor honour the NX bit in the page tables
+pub const EFER_NXE: u64 = 1 << 11;
 
 /// Write or output a `u8` value to the `I/O` port at `address`
 #[inline]
@@ -20,11 +24,43 @@ pub fn in_u8(address: u16) -> u8 {
     value
 }
 
+/// Write or output a `u16` value to the `I/O` port at `address`
+#[inline]
+pub fn out_u16(address: u16, value: u16) {
+    unsafe { asm!("out dx, ax", in("dx") address, in("ax") value); }
+}
+
+/// Read and return a `u16` value from the `I/O` port at `address`
+#[inline]
+pub fn in_u16(address: u16) -> u16 {
+    let value: u16;
+    unsafe {
+        asm!("in ax, dx", in("dx") address, out("ax") value);
+    }
+    value
+}
+
+/// Write or output a `u32` value to the `I/O` port at `address`
+#[inline]
+pub fn out_u32(address: u16, value: u32) {
+    unsafe { asm!("out dx, eax", in("dx") address, in("eax") value); }
+}
+
+/// Read and return a `u32` value from the `I/O` port at `address`
+#[inline]
+pub fn in_u32(address: u16) -> u32 {
+    let value: u32;
+    unsafe {
+        asm!("in eax, dx", in("dx") address, out("eax") value);
+    }
+    value
+}
+
 /// Invalidate TBL entries for page containing m.
 #[inline]
 #[cfg(target_arch = "x86_64")]
 pub unsafe fn invlpg(address: u64) {
-    asm!("invlpg {0}", in(reg) address);
+    asm!("invlpg [{0}]", in(reg) address);
 }
 
 #[inline]
@@ -69,6 +105,141 @@ pub unsafe fn cpuid(eax: u32) -> u32 {
     tmp_eax
 }
 
+/// Read the CR2 register, which holds the linear address that caused the last page fault
+#[inline]
+pub fn read_cr2() -> u64 {
+    let cr2: usize;
+    unsafe { asm!("mov {0}, cr2", out(reg) cr2); }
+    cr2 as u64
+}
+
+/// Pointer structure loaded by `lidt` and `lgdt`, containing the size of the table minus 1 and
+/// the linear address of the table.
+#[cfg(target_arch = "x86_64")]
+#[repr(C, packed)]
+pub struct DescriptorTablePointer {
+    pub limit: u16,
+    pub base: u64,
+}
+
+/// Load the Interrupt Descriptor Table register with the table described by `pointer`
+#[inline]
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn lidt(pointer: &DescriptorTablePointer) {
+    asm!("lidt [{0}]", in(reg) pointer);
+}
+
+/// Load the Global Descriptor Table register with the table described by `pointer`
+#[inline]
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn lgdt(pointer: &DescriptorTablePointer) {
+    asm!("lgdt [{0}]", in(reg) pointer);
+}
+
+/// Load the Task Register with the TSS descriptor found at `selector` in the GDT
+#[inline]
+pub unsafe fn ltr(selector: u16) {
+    asm!("ltr {0:x}", in(reg) selector);
+}
+
+/// Registers returned by the `cpuid` instruction
+#[derive(Debug, Clone, Copy)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Execute the CPUID instruction for `leaf` and `subleaf` (the `EAX` and `ECX` inputs) and return
+/// all the 4 output registers. `rbx` is reserved by LLVM, so we have to save it manually.
+#[inline]
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn cpuid_ext(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u64, u32, u32);
+    asm!(
+        "mov {0}, rbx",
+        "cpuid",
+        "xchg {0}, rbx",
+        out(reg) ebx,
+        inout("eax") leaf => eax,
+        inout("ecx") subleaf => ecx,
+        out("edx") edx,
+    );
+    CpuidResult { eax, ebx: ebx as u32, ecx, edx }
+}
+
+#[inline]
+#[cfg(target_arch = "x86")]
+pub unsafe fn cpuid_ext(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    asm!(
+        "mov {0}, ebx",
+        "cpuid",
+        "xchg {0}, ebx",
+        out(reg) ebx,
+        inout("eax") leaf => eax,
+        inout("ecx") subleaf => ecx,
+        out("edx") edx,
+    );
+    CpuidResult { eax, ebx, ecx, edx }
+}
+
+// Returns the `EDX` feature flags of the extended CPUID leaf 0x8000_0001, if the processor has it
+fn extended_features() -> Option<u32> {
+    unsafe {
+        // The extended leaf has to exist before we can query it
+        if cpuid_ext(0x8000_0000, 0).eax < 0x8000_0001 {
+            return None;
+        }
+        Some(cpuid_ext(0x8000_0001, 0).edx)
+    }
+}
+
+/// Returns `true` if the processor supports the execute disable (NX) bit in the page tables
+pub fn nx_supported() -> bool {
+    extended_features().is_some_and(|edx| edx & (1 << 20) != 0)
+}
+
+/// Returns `true` if the processor supports 1GiB pages
+pub fn page_1gb_supported() -> bool {
+    extended_features().is_some_and(|edx| edx & (1 << 26) != 0)
+}
+
+/// Read the CR3 register, which holds the physical address of the top level page table
+#[inline]
+pub fn read_cr3() -> u64 {
+    let cr3: usize;
+    unsafe { asm!("mov {0}, cr3", out(reg) cr3); }
+    cr3 as u64
+}
+
+/// Read the time-stamp counter, which counts the cycles since the processor was reset
+#[inline]
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdtsc", out("eax") low, out("edx") high); }
+    (u64::from(high) << 32) | u64::from(low)
+}
+
+/// Enable maskable interrupts
+#[inline]
+pub unsafe fn sti() {
+    asm!("sti");
+}
+
+/// Enable interrupts and halt forever, waking up only to service interrupts
+pub fn idle() -> ! {
+    loop {
+        unsafe {
+            asm!(
+                "sti",
+                "hlt",
+            );
+        }
+    }
+}
+
 /// Disable interrupts and halt forever
 pub fn halt() -> ! {
     loop {
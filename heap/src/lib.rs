@@ -0,0 +1,343 @@
+#![no_std]
+//! A kernel heap made out of slab caches for small objects and a page-granular backend for
+//! everything else. Each size class carves the pages it gets from the backend into objects of its
+//! size and keeps the freed objects in an intrusive free list, such that the backend only sees
+//! page sized requests.
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+/// Size of the pages the backend hands out
+pub const PAGE_SIZE: usize = 4096;
+/// Sizes of the objects handed out by the slab caches. Allocations bigger than the last class go
+/// straight to the backend.
+pub const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Provider of the memory used by the heap
+pub trait PageBackend {
+    /// Allocate `pages` contiguous and page aligned pages of usable memory
+    fn alloc_pages(&mut self, pages: usize) -> Option<NonNull<u8>>;
+    /// Give back the `pages` pages at `ptr`, previously returned by `alloc_pages`
+    ///
+    /// # Safety
+    /// The memory must no longer be used.
+    unsafe fn free_pages(&mut self, ptr: NonNull<u8>, pages: usize);
+}
+
+/// Statistics about the use of the heap
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    // Bytes requested by the allocations which are currently live
+    pub bytes_in_use: usize,
+    // Pages currently taken from the backend, by both the slab caches and the large allocations
+    pub pages_in_use: usize,
+    // Number of live allocations in each of the `SIZE_CLASSES`
+    pub class_allocations: [usize; SIZE_CLASSES.len()],
+    // Number of live allocations served directly by the backend
+    pub large_allocations: usize,
+}
+
+// A freed object, which stores the next free object of its class in itself
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// Heap allocator over the pages of `B`
+pub struct Heap<B: PageBackend> {
+    backend: B,
+    // Head of the free list of each size class
+    free_lists: [Option<NonNull<FreeObject>>; SIZE_CLASSES.len()],
+    stats: HeapStats,
+}
+
+// The free lists only point in memory owned by the heap
+unsafe impl<B: PageBackend + Send> Send for Heap<B> {}
+
+impl<B: PageBackend> Heap<B> {
+    pub const fn new(backend: B) -> Self {
+        Self {
+            backend,
+            free_lists: [None; SIZE_CLASSES.len()],
+            stats: HeapStats {
+                bytes_in_use: 0,
+                pages_in_use: 0,
+                class_allocations: [0; SIZE_CLASSES.len()],
+                large_allocations: 0,
+            },
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.stats
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Allocate memory for `layout`. Returns `None` if the backend ran out of pages or if the
+    /// alignment is bigger than a page.
+    pub fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        if layout.align() > PAGE_SIZE {
+            return None;
+        }
+
+        let ptr = match size_class(layout) {
+            Some(class) => {
+                let object = match self.free_lists[class] {
+                    Some(object) => object,
+                    None => self.refill(class)?,
+                };
+                // Take the object out of the free list
+                self.free_lists[class] = unsafe { object.as_ref().next };
+                self.stats.class_allocations[class] += 1;
+                object.cast()
+            }
+            None => {
+                let pages = pages(layout.size());
+                let ptr = self.backend.alloc_pages(pages)?;
+                self.stats.pages_in_use += pages;
+                self.stats.large_allocations += 1;
+                ptr
+            }
+        };
+
+        self.stats.bytes_in_use += layout.size();
+        Some(ptr)
+    }
+
+    /// Free the memory at `ptr`, which was returned by `allocate` for the same `layout`
+    ///
+    /// # Safety
+    /// The memory must no longer be used and must have been allocated by this heap.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        match size_class(layout) {
+            Some(class) => {
+                // Put the object back at the head of the free list of its class
+                let mut object = ptr.cast::<FreeObject>();
+                object.as_mut().next = self.free_lists[class];
+                self.free_lists[class] = Some(object);
+                self.stats.class_allocations[class] -= 1;
+            }
+            None => {
+                let pages = pages(layout.size());
+                self.backend.free_pages(ptr, pages);
+                self.stats.pages_in_use -= pages;
+                self.stats.large_allocations -= 1;
+            }
+        }
+
+        self.stats.bytes_in_use -= layout.size();
+    }
+
+    // Get a new page for `class` from the backend, carve it into objects and put all of them in
+    // the free list. Returns the new head of the list.
+    fn refill(&mut self, class: usize) -> Option<NonNull<FreeObject>> {
+        let page = self.backend.alloc_pages(1)?;
+        self.stats.pages_in_use += 1;
+
+        let size = SIZE_CLASSES[class];
+        // Link the objects from the end of the page, such that the list starts with the first one
+        let mut head = self.free_lists[class];
+        for offset in (0..PAGE_SIZE).step_by(size).rev() {
+            unsafe {
+                let mut object = NonNull::new_unchecked(page.as_ptr().add(offset))
+                    .cast::<FreeObject>();
+                object.as_mut().next = head;
+                head = Some(object);
+            }
+        }
+        self.free_lists[class] = head;
+        head
+    }
+}
+
+// Returns the index of the smallest size class which fits `layout`, if any. The classes are
+// powers of two and the pages are aligned, so each object is aligned to its size.
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = core::cmp::max(layout.size(), layout.align());
+    SIZE_CLASSES.iter().position(|class| *class >= size)
+}
+
+// Number of pages needed for `size` bytes. Zero sized allocations still get a page, such that
+// they have a unique address.
+fn pages(size: usize) -> usize {
+    core::cmp::max(size.div_ceil(PAGE_SIZE), 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    // Backend handing out memory from the host allocator, which checks that the pages it gets
+    // back are the ones it handed out
+    #[derive(Default)]
+    struct HostPages {
+        live: Vec<(usize, usize)>,
+    }
+
+    impl PageBackend for HostPages {
+        fn alloc_pages(&mut self, pages: usize) -> Option<NonNull<u8>> {
+            let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).ok()?;
+            let ptr = NonNull::new(unsafe { std::alloc::alloc(layout) })?;
+            self.live.push((ptr.as_ptr() as usize, pages));
+            Some(ptr)
+        }
+        unsafe fn free_pages(&mut self, ptr: NonNull<u8>, pages: usize) {
+            let idx = self.live.iter().position(|live| *live == (ptr.as_ptr() as usize, pages))
+                .expect("Freeing pages which were not allocated");
+            self.live.swap_remove(idx);
+            let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap();
+            std::alloc::dealloc(ptr.as_ptr(), layout);
+        }
+    }
+
+    // Backend with a fixed number of pages
+    struct LimitedPages {
+        host: HostPages,
+        left: usize,
+    }
+
+    impl PageBackend for LimitedPages {
+        fn alloc_pages(&mut self, pages: usize) -> Option<NonNull<u8>> {
+            self.left = self.left.checked_sub(pages)?;
+            self.host.alloc_pages(pages)
+        }
+        unsafe fn free_pages(&mut self, ptr: NonNull<u8>, pages: usize) {
+            self.left += pages;
+            self.host.free_pages(ptr, pages);
+        }
+    }
+
+    // Value below `max` for the `draw`th choice made in `round`, spread out with a multiplicative
+    // hash such that the stress test is reproducible
+    fn pick(round: usize, draw: usize, max: usize) -> usize {
+        let hash = ((round * 8 + draw) as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        (hash >> 32) as usize % max
+    }
+
+    #[test]
+    fn size_classes() {
+        let class = |size, align| size_class(Layout::from_size_align(size, align).unwrap());
+        assert_eq!(class(0, 1), Some(0));
+        assert_eq!(class(16, 8), Some(0));
+        assert_eq!(class(17, 8), Some(1));
+        assert_eq!(class(8, 64), Some(2));
+        assert_eq!(class(2048, 8), Some(7));
+        assert_eq!(class(2049, 8), None);
+        assert_eq!(class(8, 4096), None);
+    }
+
+    #[test]
+    fn reuse_freed_objects() {
+        let mut heap = Heap::new(HostPages::default());
+        let layout = Layout::from_size_align(24, 8).unwrap();
+
+        let first = heap.allocate(layout).unwrap();
+        let second = heap.allocate(layout).unwrap();
+        assert_ne!(first, second);
+        // Both come from the same page
+        assert_eq!(heap.stats().pages_in_use, 1);
+        assert_eq!(heap.stats().class_allocations[1], 2);
+        assert_eq!(heap.stats().bytes_in_use, 48);
+
+        unsafe { heap.deallocate(first, layout) };
+        assert_eq!(heap.allocate(layout), Some(first));
+
+        unsafe {
+            heap.deallocate(first, layout);
+            heap.deallocate(second, layout);
+        }
+        assert_eq!(heap.stats().bytes_in_use, 0);
+        assert_eq!(heap.stats().class_allocations, [0; SIZE_CLASSES.len()]);
+        // The slab keeps its page
+        assert_eq!(heap.stats().pages_in_use, 1);
+    }
+
+    #[test]
+    fn large_allocations() {
+        let mut heap = Heap::new(HostPages::default());
+        let layout = Layout::from_size_align(3 * PAGE_SIZE + 1, 16).unwrap();
+
+        let ptr = heap.allocate(layout).unwrap();
+        assert_eq!(ptr.as_ptr() as usize % PAGE_SIZE, 0);
+        assert_eq!(heap.stats().pages_in_use, 4);
+        assert_eq!(heap.stats().large_allocations, 1);
+
+        unsafe { heap.deallocate(ptr, layout) };
+        assert_eq!(heap.stats(), HeapStats::default());
+        assert!(heap.backend().live.is_empty());
+
+        // Alignments bigger than a page cannot be honoured
+        assert!(heap.allocate(Layout::from_size_align(16, 2 * PAGE_SIZE).unwrap()).is_none());
+    }
+
+    #[test]
+    fn out_of_pages() {
+        let mut heap = Heap::new(LimitedPages { host: HostPages::default(), left: 2 });
+        let small = Layout::from_size_align(2048, 8).unwrap();
+        let large = Layout::from_size_align(2 * PAGE_SIZE, 8).unwrap();
+
+        let objects = (0..4).map(|_| heap.allocate(small).unwrap()).collect::<Vec<_>>();
+        assert!(heap.allocate(small).is_none());
+        assert!(heap.allocate(large).is_none());
+        assert_eq!(heap.stats().class_allocations[7], 4);
+
+        // Freed objects can be used again
+        unsafe { heap.deallocate(objects[0], small) };
+        assert_eq!(heap.allocate(small), Some(objects[0]));
+    }
+
+    #[test]
+    fn stress() {
+        let mut heap = Heap::new(HostPages::default());
+        // Live allocations, with the byte pattern they are filled with
+        let mut live: Vec<(NonNull<u8>, Layout, u8)> = Vec::new();
+
+        for round in 0..20_000 {
+            if live.is_empty() || pick(round, 0, 100) < 55 {
+                // Mostly small objects, with a few allocations spanning pages
+                let size = if pick(round, 1, 20) == 0 {
+                    pick(round, 2, 4 * PAGE_SIZE)
+                } else {
+                    pick(round, 2, 2048)
+                };
+                let align = 1 << pick(round, 3, 8);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = heap.allocate(layout).expect("Out of memory");
+                assert_eq!(ptr.as_ptr() as usize % align, 0);
+
+                let pattern = round as u8;
+                unsafe { core::ptr::write_bytes(ptr.as_ptr(), pattern, size) };
+                live.push((ptr, layout, pattern));
+            } else {
+                let (ptr, layout, pattern) = live.swap_remove(pick(round, 4, live.len()));
+                // Nobody else wrote over this allocation
+                let bytes = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), layout.size()) };
+                assert!(bytes.iter().all(|byte| *byte == pattern));
+                unsafe { heap.deallocate(ptr, layout) };
+            }
+
+            let stats = heap.stats();
+            assert_eq!(stats.bytes_in_use, live.iter().map(|(_, layout, _)| layout.size()).sum());
+            assert_eq!(
+                stats.class_allocations.iter().sum::<usize>() + stats.large_allocations,
+                live.len(),
+            );
+        }
+
+        for (ptr, layout, pattern) in live.drain(..) {
+            let bytes = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), layout.size()) };
+            assert!(bytes.iter().all(|byte| *byte == pattern));
+            unsafe { heap.deallocate(ptr, layout) };
+        }
+        let stats = heap.stats();
+        assert_eq!(stats.bytes_in_use, 0);
+        assert_eq!(stats.large_allocations, 0);
+        // Only the slab pages are left
+        assert_eq!(stats.pages_in_use, heap.backend().live.len());
+        assert!(heap.backend().live.iter().all(|(_, pages)| *pages == 1));
+    }
+}
@@ -0,0 +1,207 @@
+//! Module driving the Local APIC of each core, in either xAPIC (memory-mapped) or x2APIC (MSR)
+//! mode. The Local APIC timer is used to deliver periodic interrupts, which are counted per core.
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use cpu::x86;
+use mmu::{PhysicalAddress, VirtualAddress, PML4};
+use crate::interrupts::{self, InterruptFrame};
+
+/// Vector used by the Local APIC timer interrupt
+pub const TIMER_VECTOR: u8 = 0x20;
+/// Vector used by the spurious interrupts. The low 4 bits must be set for older processors.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+/// Number of timer interrupts each core receives every second
+pub const TIMER_HZ: u64 = 100;
+
+// Virtual address where the xAPIC registers page is mapped
+const APIC_VIRTUAL_ADDRESS: u64 = 0xffff_e000_0000_0000;
+// Size of the xAPIC registers region
+const APIC_MMIO_SIZE: u64 = 4096;
+
+// Bits of the `IA32_APIC_BASE` MSR
+// Marks the current processor as the bootstrap processor
+const APIC_BASE_BSP: u64 = 1 << 8;
+// Enables x2APIC mode. Only valid if the global enable bit is also set.
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+// Enables or disables the Local APIC globally
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+
+// CPUID.01H:EDX bit that reports the presence of an on-chip APIC
+const CPUID_EDX_APIC: u32 = 1 << 9;
+// CPUID.01H:ECX bit that reports x2APIC support
+const CPUID_ECX_X2APIC: u32 = 1 << 21;
+
+// Register offsets in the xAPIC page. The x2APIC MSR of each register is `0x800 + (offset >> 4)`
+const REG_EOI: u32 = 0xb0;
+const REG_SPURIOUS: u32 = 0xf0;
+const REG_ICR_LOW: u32 = 0x300;
+const REG_ICR_HIGH: u32 = 0x310;
+const REG_LVT_TIMER: u32 = 0x320;
+const REG_TIMER_INITIAL_COUNT: u32 = 0x380;
+const REG_TIMER_CURRENT_COUNT: u32 = 0x390;
+const REG_TIMER_DIVIDE: u32 = 0x3e0;
+// Base of the x2APIC MSR range
+const X2APIC_MSR_BASE: u32 = 0x800;
+// In x2APIC mode the ICR is a single 64-bit MSR, with the destination in the high 32 bits
+const X2APIC_MSR_ICR: u32 = 0x830;
+// Set in the low ICR register while the xAPIC has not sent the previous IPI yet
+const ICR_SEND_PENDING: u32 = 1 << 12;
+
+// Software enable bit of the spurious interrupt vector register
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+// Masks the interrupt of a LVT entry
+const LVT_MASKED: u32 = 1 << 16;
+// Periodic mode of the timer LVT entry
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+// Divide the bus clock by 16 for the timer
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+// How long we let the timer run in order to calibrate it against the PIT, in microseconds
+const CALIBRATION_MICROS: u64 = 10_000;
+
+// Set when the Local APICs are used in x2APIC mode
+static X2APIC: AtomicBool = AtomicBool::new(false);
+// Virtual address of the xAPIC registers. Only used when not in x2APIC mode.
+static XAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+// Initial count of the timer for one period. Computed once, on the bootstrap processor.
+static TIMER_INITIAL_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Initialize the Local APIC of the current core and start its periodic timer. The bootstrap
+/// processor also selects the APIC mode, maps the xAPIC registers if needed and calibrates the
+/// timer, so it has to call this before any other core.
+pub fn init() -> Option<()> {
+    let cpuid = unsafe { x86::cpuid_ext(1, 0) };
+    if cpuid.edx & CPUID_EDX_APIC == 0 {
+        panic!("Local APIC not present!");
+    }
+
+    let apic_msr = unsafe { x86::rdmsr(x86::IA32_APIC_BASE) };
+    let bsp = is_bsp();
+
+    if bsp {
+        if cpuid.ecx & CPUID_ECX_X2APIC != 0 {
+            X2APIC.store(true, Ordering::SeqCst);
+        } else {
+            map_xapic(apic_msr)?;
+        }
+        interrupts::register(TIMER_VECTOR, timer_handler);
+        interrupts::register(SPURIOUS_VECTOR, spurious_handler);
+    }
+
+    // Make sure the Local APIC is enabled and in the desired mode
+    let mut enable = apic_msr | APIC_BASE_GLOBAL_ENABLE;
+    if X2APIC.load(Ordering::SeqCst) {
+        enable |= APIC_BASE_X2APIC_ENABLE;
+    }
+    unsafe { x86::wrmsr(enable, x86::IA32_APIC_BASE) };
+
+    // Software-enable the Local APIC
+    write(REG_SPURIOUS, SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR));
+
+    if bsp {
+        calibrate_timer();
+    }
+
+    // Start the periodic timer
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | u32::from(TIMER_VECTOR));
+    write(REG_TIMER_INITIAL_COUNT, TIMER_INITIAL_COUNT.load(Ordering::SeqCst));
+
+    Some(())
+}
+
+/// Signal the end of the interrupt currently being serviced
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
+
+/// Returns `true` if the current core is the bootstrap processor
+pub fn is_bsp() -> bool {
+    unsafe { x86::rdmsr(x86::IA32_APIC_BASE) & APIC_BASE_BSP != 0 }
+}
+
+/// Send an inter-processor interrupt described by the low 32 bits of the ICR in `command` to the
+/// Local APIC with the `destination` ID. The destination is ignored when `command` uses a
+/// destination shorthand.
+pub fn send_ipi(destination: u32, command: u32) {
+    if X2APIC.load(Ordering::SeqCst) {
+        let icr = (u64::from(destination) << 32) | u64::from(command);
+        unsafe { x86::wrmsr(icr, X2APIC_MSR_ICR) };
+    } else {
+        // Only 8 bits of destination are available in xAPIC mode, in bits 24:31 of the high ICR
+        write(REG_ICR_HIGH, destination << 24);
+        // Writing the low half is what sends the IPI
+        write(REG_ICR_LOW, command);
+        while read(REG_ICR_LOW) & ICR_SEND_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Returns the number of timer interrupts the current core received since its timer started
+pub fn ticks() -> u64 {
+    unsafe { crate::core!().ticks() }
+}
+
+// Map the xAPIC registers page described by `apic_msr` as uncacheable memory
+fn map_xapic(apic_msr: u64) -> Option<()> {
+    // Get the memory-mapped physical address of the Local APIC
+    let apic_base = ((apic_msr >> 12) & 0xff_ffff) << 12;
+
+    let mut mmu_lock = crate::mm::MMU.lock();
+    let mmu = mmu_lock.as_mut()?;
+    let mut pml4 = unsafe { PML4::from_addr(mmu, PhysicalAddress(x86::read_cr3() & !0xfff))? };
+    pml4.map_mmio(
+        VirtualAddress(APIC_VIRTUAL_ADDRESS),
+        PhysicalAddress(apic_base),
+        APIC_MMIO_SIZE,
+    ).ok()?;
+
+    XAPIC_BASE.store(APIC_VIRTUAL_ADDRESS, Ordering::SeqCst);
+    Some(())
+}
+
+// Measure how much the timer counts down in `CALIBRATION_MICROS` using the PIT as a reference and
+// derive the initial count that gives us `TIMER_HZ` interrupts per second.
+fn calibrate_timer() {
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    // One-shot and masked while we calibrate
+    write(REG_LVT_TIMER, LVT_MASKED | u32::from(TIMER_VECTOR));
+    write(REG_TIMER_INITIAL_COUNT, u32::MAX);
+
+    crate::pit::sleep(CALIBRATION_MICROS);
+
+    let elapsed = u64::from(u32::MAX - read(REG_TIMER_CURRENT_COUNT));
+    // Stop the timer
+    write(REG_TIMER_INITIAL_COUNT, 0);
+
+    let per_second = elapsed * 1_000_000 / CALIBRATION_MICROS;
+    let count = u32::try_from(per_second / TIMER_HZ).unwrap_or(u32::MAX);
+    TIMER_INITIAL_COUNT.store(core::cmp::max(count, 1), Ordering::SeqCst);
+}
+
+// Read the Local APIC register at `offset`
+fn read(offset: u32) -> u32 {
+    if X2APIC.load(Ordering::SeqCst) {
+        unsafe { x86::rdmsr(X2APIC_MSR_BASE + (offset >> 4)) as u32 }
+    } else {
+        let base = XAPIC_BASE.load(Ordering::SeqCst);
+        unsafe { core::ptr::read_volatile((base + u64::from(offset)) as *const u32) }
+    }
+}
+
+// Write `value` to the Local APIC register at `offset`
+fn write(offset: u32, value: u32) {
+    if X2APIC.load(Ordering::SeqCst) {
+        unsafe { x86::wrmsr(u64::from(value), X2APIC_MSR_BASE + (offset >> 4)) };
+    } else {
+        let base = XAPIC_BASE.load(Ordering::SeqCst);
+        unsafe { core::ptr::write_volatile((base + u64::from(offset)) as *mut u32, value) };
+    }
+}
+
+fn timer_handler(_frame: &mut InterruptFrame) {
+    unsafe { crate::core!().tick() };
+    eoi();
+}
+
+// Spurious interrupts must not be acknowledged with an EOI
+fn spurious_handler(_frame: &mut InterruptFrame) {}
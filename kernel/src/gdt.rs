@@ -0,0 +1,105 @@
+//! Module defining the Global Descriptor Table (GDT) and the Task State Segment (TSS) of each core.
+//! The bootloader enters the kernel with a GDT in its own memory, which is only reachable through
+//! the identity map, so each core switches to its own GDT before the identity map goes away. The
+//! TSS is only used for the Interrupt Stack Table, which gives the double fault handler a stack of
+//! its own, such that a kernel stack overflow can be reported.
+use cpu::x86;
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+/// Selector of the 64-bit kernel code segment
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+/// Selector of the kernel data segment
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+/// Selector of the TSS of the current core
+pub const TSS_SELECTOR: u16 = 0x18;
+/// Index in the Interrupt Stack Table of the stack used by the double fault handler
+pub const DOUBLE_FAULT_IST: u8 = 1;
+
+// Same segments as the bootloader's, such that the selectors do not change. In 64-bit mode the
+// base and the limit are ignored.
+// Present, DPL 0, code, long mode
+const KERNEL_CODE_DESCRIPTOR: u64 = 0x0020_9a00_0000_0000;
+// Present, DPL 0, data
+const KERNEL_DATA_DESCRIPTOR: u64 = 0x0000_9200_0000_0000;
+// Present, available 64-bit TSS
+const TSS_DESCRIPTOR_ATTRIBUTES: u64 = 0x89 << 40;
+
+/// 64-bit Task State Segment
+#[derive(Debug, Default)]
+#[repr(C, packed)]
+struct Tss {
+    _reserved0: u32,
+    // Stacks loaded when changing to the privilege level of the index
+    rsp: [u64; 3],
+    _reserved1: u64,
+    // Interrupt Stack Table. Entry `n` is used by the gates with IST index `n + 1`.
+    ist: [u64; 7],
+    _reserved2: u64,
+    _reserved3: u16,
+    // Offset of the I/O permission bitmap from the TSS base
+    iomap_base: u16,
+}
+
+/// GDT of a core. The TSS descriptor is twice the size of the other ones in 64-bit mode.
+#[repr(C, align(16))]
+struct Gdt {
+    entries: [u64; 5],
+}
+
+impl Gdt {
+    // Create a GDT with the kernel segments and a descriptor for `tss`
+    fn new(tss: &'static Tss) -> Self {
+        let base = tss as *const Tss as u64;
+        let limit = (core::mem::size_of::<Tss>() - 1) as u64;
+        let tss_low = (limit & 0xffff)
+            | ((base & 0xff_ffff) << 16)
+            | TSS_DESCRIPTOR_ATTRIBUTES
+            | (((limit >> 16) & 0xf) << 48)
+            | (((base >> 24) & 0xff) << 56);
+
+        Self { entries: [0, KERNEL_CODE_DESCRIPTOR, KERNEL_DATA_DESCRIPTOR, tss_low, base >> 32] }
+    }
+}
+
+/// Build a GDT and a TSS for the current core, load them and reload all the segment registers
+pub fn init() -> Option<()> {
+    // The double fault stack has a guard page as well, such that overflowing it does not go
+    // unnoticed either
+    let double_fault_stack = crate::mm::allocate_stack()?;
+
+    let mut tss = Tss { iomap_base: core::mem::size_of::<Tss>() as u16, ..Default::default() };
+    tss.ist[usize::from(DOUBLE_FAULT_IST) - 1] = double_fault_stack;
+
+    // The tables have to outlive the core, so we leak them
+    let tss: &'static Tss = Box::leak(Box::new(tss));
+    let gdt: &'static Gdt = Box::leak(Box::new(Gdt::new(tss)));
+
+    let pointer = x86::DescriptorTablePointer {
+        limit: (core::mem::size_of::<Gdt>() - 1) as u16,
+        base: gdt as *const Gdt as u64,
+    };
+
+    unsafe {
+        x86::lgdt(&pointer);
+        // `cs` can only be changed with a far control transfer, so we return to the next
+        // instruction with the new selector
+        core::arch::asm!(
+            "push {code}",
+            "lea {tmp}, [rip + 2f]",
+            "push {tmp}",
+            "retfq",
+            "2:",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "mov ss, {data:x}",
+            code = in(reg) u64::from(KERNEL_CODE_SELECTOR),
+            data = in(reg) u64::from(KERNEL_DATA_SELECTOR),
+            tmp = out(reg) _,
+        );
+        x86::ltr(TSS_SELECTOR);
+    }
+
+    Some(())
+}
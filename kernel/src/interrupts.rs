@@ -0,0 +1,351 @@
+//! Module defining the Interrupt Descriptor Table (IDT) and the handling of the CPU exceptions
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cpu::x86;
+use crate::gdt::{DOUBLE_FAULT_IST, KERNEL_CODE_SELECTOR};
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+/// Number of vectors reserved by the architecture for CPU exceptions
+const EXCEPTION_VECTORS: usize = 32;
+/// Number of entries in the IDT, one for each possible vector
+const IDT_ENTRIES: usize = 256;
+// Vectors of the exceptions caused by a stack overflow
+const DOUBLE_FAULT: u64 = 8;
+const PAGE_FAULT: u64 = 14;
+// Vector of the exception raised when accessing an MSR which does not exist
+const GENERAL_PROTECTION: u64 = 13;
+// Each interrupt stub generated below is aligned to 16 bytes, such that the address of the stub
+// for vector `n` is `isr_stubs + n * ISR_STUB_SIZE`
+const ISR_STUB_SIZE: u64 = 16;
+// Present, DPL 0, 64-bit interrupt gate. Interrupt gates clear IF on entry.
+const INTERRUPT_GATE: u8 = 0x8e;
+
+// Names of the exceptions defined by the architecture, indexed by their vector
+const EXCEPTION_NAMES: [&str; EXCEPTION_VECTORS] = [
+    "Divide Error (#DE)",
+    "Debug (#DB)",
+    "Non-maskable Interrupt (NMI)",
+    "Breakpoint (#BP)",
+    "Overflow (#OF)",
+    "BOUND Range Exceeded (#BR)",
+    "Invalid Opcode (#UD)",
+    "Device Not Available (#NM)",
+    "Double Fault (#DF)",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS (#TS)",
+    "Segment Not Present (#NP)",
+    "Stack-Segment Fault (#SS)",
+    "General Protection (#GP)",
+    "Page Fault (#PF)",
+    "Reserved",
+    "x87 FPU Floating-Point Error (#MF)",
+    "Alignment Check (#AC)",
+    "Machine Check (#MC)",
+    "SIMD Floating-Point Exception (#XM)",
+    "Virtualization Exception (#VE)",
+    "Control Protection Exception (#CP)",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception (#HV)",
+    "VMM Communication Exception (#VC)",
+    "Security Exception (#SX)",
+    "Reserved",
+];
+
+// Every stub pushes a dummy error code for the vectors where the CPU does not push one, such that
+// all the handlers end up with the same frame layout. The vector number is pushed afterwards and
+// the execution continues in `isr_common`, which saves all the general purpose registers and calls
+// into `interrupt_handler` with a pointer to the saved `InterruptFrame`.
+global_asm!(r#"
+    .text
+    .global isr_stubs
+    .balign 16
+isr_stubs:
+    .set vector, 0
+    .rept 256
+    .balign 16
+    .if (vector == 8) || (vector == 10) || (vector == 11) || (vector == 12) || (vector == 13) || (vector == 14) || (vector == 17) || (vector == 21) || (vector == 29) || (vector == 30)
+    .else
+    pushq $0
+    .endif
+    pushq $vector
+    jmp isr_common
+    .set vector, vector + 1
+    .endr
+
+isr_common:
+    pushq %rax
+    pushq %rbx
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %rbp
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+
+    // The CPU aligns the stack to 16 bytes before pushing the interrupt frame and we pushed an
+    // even number of quad words since then, so the stack is aligned for the call
+    movq %rsp, %rdi
+    cld
+    call interrupt_handler
+
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rbp
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rbx
+    popq %rax
+
+    // Discard the vector and the error code
+    addq $16, %rsp
+    iretq
+"#, options(att_syntax));
+
+// MSR accesses which report a general protection fault instead of halting. Each instruction which
+// may fault has a label, and the address execution resumes at when it does in `FIXUPS`. They
+// return 1 in `eax` if the access succeeded and 0 if it faulted.
+global_asm!(r#"
+    .text
+    .global msr_read
+msr_read:
+    movl %edi, %ecx
+    .global msr_read_instruction
+msr_read_instruction:
+    rdmsr
+    shlq $32, %rdx
+    orq %rdx, %rax
+    movq %rax, (%rsi)
+    movl $1, %eax
+    ret
+    .global msr_read_fixup
+msr_read_fixup:
+    xorl %eax, %eax
+    ret
+
+    .global msr_write
+msr_write:
+    movl %edi, %ecx
+    movl %esi, %eax
+    movq %rsi, %rdx
+    shrq $32, %rdx
+    .global msr_write_instruction
+msr_write_instruction:
+    wrmsr
+    movl $1, %eax
+    ret
+    .global msr_write_fixup
+msr_write_fixup:
+    xorl %eax, %eax
+    ret
+"#, options(att_syntax));
+
+extern "C" {
+    // Start of the interrupt stubs generated above
+    fn isr_stubs();
+
+    fn msr_read_instruction();
+    fn msr_read_fixup();
+    fn msr_write_instruction();
+    fn msr_write_fixup();
+}
+
+extern "sysv64" {
+    fn msr_read(msr: u32, value: &mut u64) -> bool;
+    fn msr_write(msr: u32, value: u64) -> bool;
+}
+
+// Instructions allowed to raise a general protection fault, with the address execution resumes at
+// when they do
+const FIXUPS: [(unsafe extern "C" fn(), unsafe extern "C" fn()); 2] = [
+    (msr_read_instruction, msr_read_fixup),
+    (msr_write_instruction, msr_write_fixup),
+];
+
+/// Register state saved when an interrupt or exception occurs. The general purpose registers are
+/// pushed by `isr_common`, the vector and error code by the stubs and the rest by the CPU.
+#[derive(Debug)]
+#[repr(C)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    // Vector number of the interrupt
+    pub vector: u64,
+    // Error code pushed by the CPU or 0 for the vectors which do not have one
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Handler called for a vector that is not a CPU exception
+pub type InterruptHandler = fn(&mut InterruptFrame);
+
+// Address of the handler registered for each vector which is not a CPU exception, 0 if there is
+// none. They are read on every interrupt by every core, so they are atomics rather than behind a
+// lock.
+static HANDLERS: [AtomicUsize; IDT_ENTRIES] = [const { AtomicUsize::new(0) }; IDT_ENTRIES];
+
+/// A 64-bit IDT gate descriptor
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+struct IdtEntry {
+    offset_low: u16,
+    selector: u16,
+    // Bits 0:2 are the Interrupt Stack Table index, the rest are reserved
+    ist: u8,
+    // Gate type, DPL and present bit
+    attributes: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    _reserved: u32,
+}
+
+impl IdtEntry {
+    // Create an interrupt gate which transfers control to `handler`
+    fn new(handler: u64) -> Self {
+        Self {
+            offset_low: handler as u16,
+            selector: KERNEL_CODE_SELECTOR,
+            ist: 0,
+            attributes: INTERRUPT_GATE,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            _reserved: 0,
+        }
+    }
+}
+
+/// Interrupt Descriptor Table with a gate for each of the 256 vectors
+#[repr(C, align(16))]
+pub struct Idt {
+    entries: [IdtEntry; IDT_ENTRIES],
+}
+
+impl Idt {
+    fn new() -> Self {
+        let mut idt = Self { entries: [IdtEntry::default(); IDT_ENTRIES] };
+        for (vector, entry) in idt.entries.iter_mut().enumerate() {
+            let stub = (isr_stubs as *const () as u64)
+                .saturating_add(vector as u64 * ISR_STUB_SIZE);
+            *entry = IdtEntry::new(stub);
+        }
+        // A double fault is what we get when the page fault of a stack overflow cannot be
+        // delivered on the same stack, so it runs on a known good one
+        idt.entries[DOUBLE_FAULT as usize].ist = DOUBLE_FAULT_IST;
+        idt
+    }
+}
+
+/// Build an IDT for the current core and load it
+pub fn init() {
+    // The table has to outlive the core, so we leak it
+    let idt: &'static Idt = Box::leak(Box::new(Idt::new()));
+
+    let pointer = x86::DescriptorTablePointer {
+        limit: (core::mem::size_of::<Idt>() - 1) as u16,
+        base: idt as *const Idt as u64,
+    };
+
+    unsafe { x86::lidt(&pointer) };
+}
+
+/// Read `msr`, or return `None` if it does not exist on this CPU
+pub fn try_rdmsr(msr: u32) -> Option<u64> {
+    let mut value = 0;
+    unsafe { msr_read(msr, &mut value) }.then_some(value)
+}
+
+/// Write `value` to `msr`, or return `None` if the CPU refused it, because the MSR does not exist
+/// or some of the bits are reserved
+///
+/// # Safety
+///
+/// Writing an MSR can change the behaviour of the CPU in ways the kernel does not expect.
+pub unsafe fn try_wrmsr(value: u64, msr: u32) -> Option<()> {
+    msr_write(msr, value).then_some(())
+}
+
+/// Register `handler` to be called whenever `vector` is delivered. CPU exceptions cannot be
+/// overridden, they always dump the register state and halt.
+pub fn register(vector: u8, handler: InterruptHandler) {
+    if let Some(entry) = HANDLERS.get(usize::from(vector)) {
+        entry.store(handler as usize, Ordering::SeqCst);
+    }
+}
+
+// Common Rust handler called by `isr_common` for every vector
+#[no_mangle]
+extern "sysv64" fn interrupt_handler(frame: &mut InterruptFrame) {
+    if frame.vector as usize >= EXCEPTION_VECTORS {
+        let handler = HANDLERS.get(frame.vector as usize).map_or(0, |h| h.load(Ordering::SeqCst));
+        if handler != 0 {
+            // Only `register` stores in the table, always the address of an `InterruptHandler`
+            let handler = unsafe { core::mem::transmute::<usize, InterruptHandler>(handler) };
+            handler(frame);
+            return;
+        }
+    }
+
+    // Resume after the instructions which are expected to fault
+    if frame.vector == GENERAL_PROTECTION {
+        let fixup = FIXUPS.iter()
+            .find(|(instruction, _)| *instruction as *const () as u64 == frame.rip);
+        if let Some((_, fixup)) = fixup {
+            frame.rip = *fixup as *const () as u64;
+            return;
+        }
+    }
+
+    let name = EXCEPTION_NAMES.get(frame.vector as usize).unwrap_or(&"Unhandled interrupt");
+
+    let core_id = unsafe { crate::core!().id() };
+
+    if (frame.vector == DOUBLE_FAULT || frame.vector == PAGE_FAULT)
+        && unsafe { crate::core!().state.is_stack_guard(x86::read_cr2()) } {
+        crate::println!("Kernel stack overflow on core {:#x}", core_id);
+    }
+    crate::println!("Exception {} {} on core {:#x}", frame.vector, name, core_id);
+    crate::println!("Error code: {:#x}", frame.error_code);
+    crate::println!("CR2: {:#018x}", x86::read_cr2());
+    crate::println!("RIP: {:#018x}", frame.rip);
+    crate::println!("{:#x?}", frame);
+
+    x86::halt();
+}
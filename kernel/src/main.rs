@@ -1,45 +1,208 @@
 #![no_std]
 #![no_main]
 
+mod apic;
 mod compiler_builtins;
+mod gdt;
+mod interrupts;
 mod mm;
+mod pci;
+mod pit;
+mod shell;
+mod smp;
 mod tls;
+mod virtio;
 
 use cpu::x86;
 use core::panic::PanicInfo;
-use state::BootState;
+use net::{Interface, IpConfig, NetError, MAX_FRAME_SIZE};
+use state::{BootState, DhcpInfo};
+
+// Command line flag asking to greet the boot server once the network is up
+const HELLO_FLAG: &[u8] = b"net-hello";
+// Greeting sent to the boot server
+const HELLO: &[u8] = b"Hello from pizza\n";
+// UDP port of the service on the boot server we greet, and the port we send from
+const HELLO_PORT: u16 = 5555;
+// How long we wait for the boot server to answer, in timer ticks
+const HELLO_TIMEOUT: u64 = 2 * apic::TIMER_HZ;
+// Timer ticks between attempts to send the greeting, while the server address is being resolved
+const HELLO_RETRY: u64 = apic::TIMER_HZ / 10;
 
 #[no_mangle]
 extern "C" fn entry(boot_state: &'static BootState) {
+    // The other fields of a boot state with a different layout cannot be trusted, not even the
+    // serial port we would report the error on
+    if !boot_state.is_compatible() {
+        x86::halt();
+    }
+
+    // Until the core local storage is set up, a panic cannot be reported as the serial port is
+    // reached through it, and the panic handler would fault instead. Halt on these failures.
+    // Take over the physical memory from the bootloader, before anything gets allocated
+    mm::init(boot_state).unwrap_or_else(|| x86::halt());
     // Initialise the current local core storage
-    tls::init(boot_state);
+    tls::init(boot_state).unwrap_or_else(|| x86::halt());
+    // Stop using the GDT from the bootloader, which lives in low memory, and set up the stack
+    // for the double fault handler
+    gdt::init().expect("Failed to initialise the GDT");
+    // Install the exception handlers, such that faults are reported instead of triple faulting
+    interrupts::init();
+
+    // Application processors arrive here on the stack from the trampoline, which has to be
+    // replaced for the next core before it can come in
+    let bsp = apic::is_bsp();
+    if bsp {
+        // We run in the higher half, the low memory is only reachable through the physical window
+        mm::drop_identity_map().expect("Failed to remove the identity map");
+    } else {
+        smp::ap_online().expect("Failed to prepare the stack for the next core");
+    }
+
+    // Bring up the Local APIC and its periodic timer
+    apic::init().expect("Failed to initialise the Local APIC");
+    unsafe { x86::sti() };
 
     unsafe {
         println!("ID: {:x?}", core!().id());
     }
 
-    let screen = unsafe {
-        core::slice::from_raw_parts_mut(0xb8000 as *mut u16, 80 * 25)
-    };
-    screen.iter_mut().for_each(|x| *x = 0x0f75);
+    if bsp {
+        report_boot_state(boot_state);
+
+        let screen = unsafe {
+            core::slice::from_raw_parts_mut(mm::physical_to_virtual(0xb8000) as *mut u16, 80 * 25)
+        };
+        screen.iter_mut().for_each(|x| *x = 0x0f75);
+
+        {
+            extern crate alloc;
+            let v = alloc::vec![b'\xbb'; 5];
+            println!("{:#x?}", v.get(..));
+            println!("{:#x?}", mm::heap_stats());
+        }
+
+        // Wait for a few timer interrupts, to make sure they are delivered
+        while apic::ticks() < apic::TIMER_HZ {
+            core::hint::spin_loop();
+        }
+        println!("Core {:#x} got {} timer ticks", unsafe { core!().id() }, apic::ticks());
+
+        println!("CPUID {:#x?}", unsafe { x86::cpuid(0x1u32) });
+
+        // Get the processors the firmware knows about from the MADT
+        let processors = acpi::Acpi::parse(&mm::PhysicalWindow)
+            .and_then(|acpi| acpi.madt().map(|madt| madt.enabled_processors().count()));
+        match &processors {
+            Ok(processors) => { println!("MADT reports {} processors", processors); }
+            Err(err) => { println!("Failed to read the MADT: {:?}", err); }
+        }
+
+        // Wake up the other cores
+        let cores = smp::start_aps(processors.ok())
+            .expect("Failed to start the application processors");
+        println!("{} cores online", cores);
+
+        // Find the devices and bind the drivers to them
+        pci::init();
+        pci::register(virtio::DRIVER).expect("Failed to register the virtio-net driver");
+        pci::probe_drivers();
+
+        if boot_state.cmdline.lock().has_flag(HELLO_FLAG) {
+            network_hello(&boot_state.dhcp.lock());
+        }
+
+        println!("{:#?}", "TOO MANY BALLS");
+
+        // Keep the bootstrap processor taking debugging commands on the serial console
+        shell::run();
+    }
+
+    x86::idle();
+}
+
+// Print what the bootloader handed over to us
+fn report_boot_state(boot_state: &BootState) {
+    for entry in boot_state.memory_map.lock().entries() {
+        println!("E820 {:#018x} {:#018x} {:?}", entry.base, entry.length, entry.memory_type());
+    }
+
+    let bootloader = *boot_state.bootloader.lock();
+    let kernel_physical = *boot_state.kernel_physical.lock();
+    let kernel_virtual = *boot_state.kernel_virtual.lock();
+    println!("Bootloader {:#x}-{:#x}", bootloader.base, bootloader.end());
+    println!("Kernel {:#x}-{:#x} mapped at {:#x}-{:#x}",
+        kernel_physical.base, kernel_physical.end(), kernel_virtual.base, kernel_virtual.end());
+
+    if let Some(cmdline) = boot_state.cmdline.lock().as_str() {
+        println!("Command line: {:?}", cmdline);
+    }
+
+    let dhcp = *boot_state.dhcp.lock();
+    if dhcp.is_valid() {
+        println!("IP {:?} from server {:?}", dhcp.your_ip, dhcp.server_ip);
+        println!("Subnet mask {:?} router {:?} DNS {:?}", dhcp.subnet_mask, dhcp.router, dhcp.dns);
+        println!("Hostname {:?}", null_terminated(&dhcp.hostname));
+    }
+
+    let config = *boot_state.config.lock();
+    if config.is_valid() {
+        println!("Booted with {:?}", null_terminated(&config.config_file));
+    }
+    println!("Kernel file {:?}", null_terminated(&config.kernel_file));
+    if config.serial_port != 0 {
+        println!("Serial console {:#x} at {} baud", config.serial_port, config.serial_baud);
+    }
 
-    {
-        extern crate alloc;
-        let v = alloc::vec![b'\xbb'; 5];
-        println!("{:#x?}", v.get(..));
+    for module in boot_state.modules.lock().entries() {
+        println!("Module {:?} {:#x}-{:#x}",
+            core::str::from_utf8(module.name()), module.base, module.region().end());
     }
-    // Read the APIC base msr
-    let apic_msr = unsafe { x86::rdmsr(x86::IA32_APIC_BASE) };
-    // Check if the APIC is present
-    let is_lapic_present: bool = ((apic_msr >> 11) & 1) != 0;
-    if !is_lapic_present {
-        panic!("Local APIC not present!");
+}
+
+// Bring up the first virtio network card with the address the DHCP server gave the bootloader,
+// greet the boot server and print the datagrams it sends back
+fn network_hello(dhcp: &DhcpInfo) {
+    if !dhcp.is_valid() {
+        return;
     }
-    // Get the memory-mapped physical address of the Local APIC
-    let apic_base = ((apic_msr >> 12) & 0xff_ffff) << 12;
-    println!("CPUID {:#x?}", unsafe { x86::cpuid(0x1u32) });
-    println!("{:#?}", "TOO MANY BALLS");
-    x86::halt();
+    let Some(card) = virtio::take() else {
+        println!("No virtio network card");
+        return;
+    };
+    let config = IpConfig { ip: dhcp.your_ip, subnet_mask: dhcp.subnet_mask, gateway: dhcp.router };
+    let mut interface = Interface::new(card, config);
+    println!("virtio-net {:x?} with IP {:?}", interface.mac(), config.ip);
+
+    let mut buffer = [0u8; MAX_FRAME_SIZE];
+    let deadline = apic::ticks() + HELLO_TIMEOUT;
+    let mut next_attempt = Some(apic::ticks());
+    while apic::ticks() < deadline {
+        if next_attempt.is_some_and(|tick| apic::ticks() >= tick) {
+            match interface.send_udp(dhcp.server_ip, HELLO_PORT, HELLO_PORT, HELLO) {
+                Ok(()) => next_attempt = None,
+                Err(NetError::ArpPending(_)) => next_attempt = Some(apic::ticks() + HELLO_RETRY),
+                Err(err) => {
+                    println!("Failed to greet {:?}: {:?}", dhcp.server_ip, err);
+                    return;
+                }
+            }
+        }
+        match interface.poll(&mut buffer) {
+            Ok(Some(datagram)) if datagram.destination_port == HELLO_PORT => {
+                println!("UDP from {:?}:{} {:?}", datagram.source, datagram.source_port,
+                    core::str::from_utf8(datagram.payload));
+            }
+            Ok(_) => core::hint::spin_loop(),
+            Err(err) => { println!("Network error {:?}", err); }
+        }
+    }
+}
+
+// Returns the string up to the first null byte in `bytes`, if it is valid UTF-8
+fn null_terminated(bytes: &[u8]) -> Option<&str> {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len]).ok()
 }
 
 #[panic_handler]
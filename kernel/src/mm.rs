@@ -1,6 +1,129 @@
 //! Memory manager module for the kernel
 use core::alloc::{Layout, GlobalAlloc};
-use core::ops::RangeInclusive;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, Ordering};
+use cpu::x86;
+use heap::{Heap, HeapStats, PageBackend};
+use mmu::{Mmu, PhysicalAddress, VirtualAddress, PML4, RWX, PHYSICAL_WINDOW_BASE};
+use rangeset::{GrowableRangeSet, Range, RangeSet};
+use state::BootState;
+use state::IDENTITY_MAP_SIZE;
+use sync::LockCell;
+
+// Index of the next kernel stack to be allocated. The first one is set up by the bootloader.
+static NEXT_STACK: AtomicU64 = AtomicU64::new(1);
+
+// Base of the virtual memory region where device memory is mapped
+const MMIO_REGION_BASE: u64 = 0xffff_d000_0000_0000;
+// Next free virtual address in the device memory region. Mappings are never removed.
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_REGION_BASE);
+
+/// Physical memory manager of the kernel, taken over from the boot state by `init`. Its set of free
+/// ranges grows as needed, such that freeing memory does not fail once the set is fragmented.
+pub static MMU: LockCell<Option<Mmu<GrowableRangeSet>>> = LockCell::new(None);
+
+/// Take over the physical memory the bootloader handed us. Only the first core to get here does
+/// it, before anything is allocated.
+pub fn init(state: &BootState) -> Option<()> {
+    let mut mmu = MMU.lock();
+    if mmu.is_none() {
+        *mmu = Some(state.mmu.lock().take()?.into_growable(physical_to_virtual));
+    }
+    Some(())
+}
+
+/// Returns a pointer to the physical memory at `address`, through the physical window
+pub fn physical_to_virtual(address: u64) -> *mut u8 {
+    PHYSICAL_WINDOW_BASE.wrapping_add(address) as *mut u8
+}
+
+/// Base of the virtual memory region where the heap maps its pages
+pub const HEAP_REGION_BASE: u64 = 0xffff_c000_0000_0000;
+
+// Page backend of the kernel heap, which takes physical pages from the `Mmu` and maps them in the
+// heap region
+struct KernelPages {
+    // Next virtual address in the heap region that was never handed out
+    next: u64,
+    // Virtual ranges of the heap region which were handed out and freed since. If the set runs out
+    // of room, the range is not reused, the region is big enough for that.
+    free: RangeSet,
+}
+
+impl PageBackend for KernelPages {
+    fn alloc_pages(&mut self, pages: usize) -> Option<NonNull<u8>> {
+        let size = (pages as u64).checked_mul(heap::PAGE_SIZE as u64)?;
+
+        let mut mmu_lock = MMU.lock();
+        let mmu = mmu_lock.as_mut()?;
+        let physical = mmu.allocate(size, heap::PAGE_SIZE as u64)? as u64;
+
+        let reused = self.free.allocate(size, heap::PAGE_SIZE as u64).map(|va| va as u64);
+        let virtual_address = reused.unwrap_or(self.next);
+        let cr3 = PhysicalAddress(x86::read_cr3() & !0xfff);
+        let mapped = unsafe { PML4::from_addr(&mut *mmu, cr3) }.and_then(|mut pml4| {
+            pml4.map_physical(
+                VirtualAddress(virtual_address),
+                PhysicalAddress(physical),
+                size,
+                RWX { read: true, write: true, execute: false },
+            ).ok()
+        });
+
+        // Give everything back on failure, starting with the pages which did get mapped, such
+        // that the physical memory is not reachable anymore once it returns to the `Mmu`
+        if mapped.is_none() {
+            for offset in (0..size).step_by(heap::PAGE_SIZE) {
+                let address = VirtualAddress(virtual_address + offset);
+                let Some(mut pml4) = (unsafe { PML4::from_addr(&mut *mmu, cr3) }) else {
+                    break;
+                };
+                let ours = pml4.translate(address)
+                    .is_some_and(|(page, _, _)| page.0 == physical + offset);
+                if ours {
+                    let _ = pml4.unmap_page(address);
+                }
+            }
+            let _ = mmu.deallocate(physical..=physical + size - 1);
+            if let Some(reused) = reused {
+                let _ = self.free.insert(Range::new(reused, reused + size - 1));
+            }
+            return None;
+        }
+
+        if reused.is_none() {
+            self.next += size;
+        }
+        NonNull::new(virtual_address as *mut u8)
+    }
+
+    unsafe fn free_pages(&mut self, ptr: NonNull<u8>, pages: usize) {
+        let mut mmu_lock = MMU.lock();
+        let Some(mmu) = mmu_lock.as_mut() else {
+            return;
+        };
+        let cr3 = PhysicalAddress(x86::read_cr3() & !0xfff);
+
+        // Each page is given back on its own, the set merges them again. A page which cannot be
+        // given back is lost, which is better than bringing the kernel down.
+        let start = ptr.as_ptr() as u64;
+        for page in 0..pages {
+            let address = start + (page * heap::PAGE_SIZE) as u64;
+            let unmapped = PML4::from_addr(&mut *mmu, cr3)
+                .and_then(|mut pml4| pml4.unmap_page(VirtualAddress(address)).ok());
+            if let Some((physical, _)) = unmapped {
+                let _ = mmu.deallocate(physical.0..=physical.0 + heap::PAGE_SIZE as u64 - 1);
+            }
+        }
+
+        let end = start + (pages * heap::PAGE_SIZE) as u64 - 1;
+        let _ = self.free.insert(Range::new(start, end));
+    }
+}
+
+// The kernel heap, which serves all the allocations made through `alloc`
+static HEAP: LockCell<Heap<KernelPages>> =
+    LockCell::new(Heap::new(KernelPages { next: HEAP_REGION_BASE, free: RangeSet::new() }));
 
 // Structure used by the memory manager to allocate memory. This implements `GlobalAlloc` crate in
 // order to be used by Rust.
@@ -11,31 +134,94 @@ static ALLOCATOR: GlobalAllocator = GlobalAllocator;
 
 unsafe impl GlobalAlloc for GlobalAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut phys_mem_lock = crate::core!().state.mmu.lock();
-
-        let ptr = phys_mem_lock.as_mut()
-            .and_then(|mmu| {
-                // This currently works because we have an identity map from the bootloader. If
-                // we would not have an identity map, this allocation had to be mapped into the
-                // page table as well
-                let ptr = mmu.allocate(layout.size() as u64, layout.align() as u64);
-                ptr
-            })
-            .unwrap_or(0) as *mut u8;
-        ptr
+        HEAP.lock().allocate(layout).map_or(core::ptr::null_mut(), |ptr| ptr.as_ptr())
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        // We do not have anything to free for zero sized types.
-        if layout.size() == 0 {
-            return;
+        let ptr = NonNull::new(ptr).expect("Freeing a null pointer");
+        HEAP.lock().deallocate(ptr, layout);
+    }
+}
+
+/// Returns the statistics of the kernel heap
+pub fn heap_stats() -> HeapStats {
+    HEAP.lock().stats()
+}
+
+/// Physical memory accessed through the physical window from the bootloader
+pub struct PhysicalWindow;
+
+impl acpi::PhysicalMemory for PhysicalWindow {
+    fn read(&self, address: u64, len: usize) -> Option<&[u8]> {
+        let window = unsafe { *crate::core!().state.physical_window.lock() };
+        let end = address.checked_add(u64::try_from(len).ok()?)?;
+        if end > window.size {
+            return None;
         }
-        let mut phys_mem_lock = crate::core!().state.mmu.lock();
-        let ptr = ptr as u64;
-        let end = ptr.saturating_add(layout.size() as u64).saturating_sub(1);
-        // Compute the range to be deallocated
-        let range = RangeInclusive::new(ptr, end);
-
-        phys_mem_lock.as_mut()
-            .and_then(|mmu| mmu.deallocate(range)).expect("Cannot free memory");
+        Some(unsafe { core::slice::from_raw_parts(physical_to_virtual(address), len) })
+    }
+}
+
+/// Allocate and map a new kernel stack, with an unmapped guard page below it. Returns the top of
+/// the stack.
+pub fn allocate_stack() -> Option<u64> {
+    let index = NEXT_STACK.fetch_add(1, Ordering::SeqCst);
+    let stack = unsafe { crate::core!().state.stack(index) };
+
+    let mut mmu_lock = MMU.lock();
+    let mmu = mmu_lock.as_mut()?;
+    let physical = mmu.allocate(stack.size, 4096)?;
+    let mut pml4 = unsafe { PML4::from_addr(mmu, PhysicalAddress(x86::read_cr3() & !0xfff))? };
+    pml4.map_physical(
+        VirtualAddress(stack.base),
+        PhysicalAddress(physical as u64),
+        stack.size,
+        RWX { read: true, write: true, execute: false },
+    ).ok()?;
+
+    Some(stack.end())
+}
+
+/// Map `size` bytes of device memory at the `physical` address as uncached, returning the virtual
+/// address they are reachable at. The address does not have to be page aligned.
+pub fn map_mmio(physical: u64, size: u64) -> Option<u64> {
+    let offset = physical & 0xfff;
+    let size = offset.checked_add(size)?.checked_next_multiple_of(4096)?;
+    let virtual_address = NEXT_MMIO.fetch_add(size, Ordering::SeqCst);
+
+    let mut mmu_lock = MMU.lock();
+    let mmu = mmu_lock.as_mut()?;
+    let mut pml4 = unsafe { PML4::from_addr(mmu, PhysicalAddress(x86::read_cr3() & !0xfff))? };
+    pml4.map_mmio(VirtualAddress(virtual_address), PhysicalAddress(physical - offset), size).ok()?;
+
+    Some(virtual_address + offset)
+}
+
+/// Allocate `size` bytes of zeroed, page aligned and physically contiguous memory that devices
+/// can access. Returns its physical address and a pointer to it through the physical window. The
+/// memory is never given back, it is meant for the lifetime of a driver.
+pub fn allocate_dma(size: u64) -> Option<(u64, *mut u8)> {
+    let physical = {
+        let mut mmu_lock = MMU.lock();
+        mmu_lock.as_mut()?.allocate(size, 4096)? as u64
+    };
+    let ptr = physical_to_virtual(physical);
+    unsafe { core::ptr::write_bytes(ptr, 0, usize::try_from(size).ok()?) };
+    Some((physical, ptr))
+}
+
+/// Remove the identity map of the low memory the bootloader left us with. From here on, physical
+/// memory is only accessible through the physical window.
+pub fn drop_identity_map() -> Option<()> {
+    let mut mmu_lock = MMU.lock();
+    let mmu = mmu_lock.as_mut()?;
+    let mut pml4 = unsafe { PML4::from_addr(mmu, PhysicalAddress(x86::read_cr3() & !0xfff))? };
+
+    // The page tables left empty are given back to the physical memory manager
+    let mut address = 0;
+    while address < IDENTITY_MAP_SIZE {
+        let (_, page_size) = pml4.unmap_page(VirtualAddress(address)).ok()?;
+        address += page_size.size();
     }
+
+    Some(())
 }
@@ -0,0 +1,112 @@
+//! Discovery of the PCI devices at boot and binding of the registered drivers to them
+use pci::{Bar, ConfigSpace, Driver, DriverError, Drivers, EcamConfig, Function, LegacyConfig};
+use pci::{capability_name, class_name, PciConfig};
+use sync::LockCell;
+use crate::{mm, println};
+
+// Configuration mechanism picked by `init`. Legacy accesses take two port writes, so every access
+// is made with the lock held.
+static CONFIG: LockCell<Option<PciConfig>> = LockCell::new(None);
+
+// Drivers offered the functions found on the bus
+static DRIVERS: LockCell<Drivers<PciConfig>> = LockCell::new(Drivers::new());
+
+/// Pick the configuration mechanism and print a summary of the functions on the bus. ECAM is used
+/// for the first PCI segment when the ACPI MCFG table describes it, the legacy I/O ports otherwise.
+pub fn init() {
+    let config = match ecam() {
+        Some(ecam) => PciConfig::Ecam(ecam),
+        None => {
+            println!("PCI: using the legacy configuration mechanism");
+            PciConfig::Legacy(LegacyConfig)
+        }
+    };
+    let mut lock = CONFIG.lock();
+    let config = lock.insert(config);
+    for function in pci::functions(config) {
+        report(&function);
+    }
+}
+
+// Map the ECAM region of the first PCI segment from the MCFG
+fn ecam() -> Option<EcamConfig> {
+    let acpi = acpi::Acpi::parse(&mm::PhysicalWindow).ok()?;
+    let allocation = acpi.mcfg().ok()?.allocations().find(|allocation| allocation.segment == 0)?;
+    let base = mm::map_mmio(allocation.start_address(), allocation.size())?;
+    println!("PCI: ECAM at {:#x} for buses {:02x}-{:02x}",
+        allocation.start_address(), allocation.start_bus, allocation.end_bus);
+    Some(unsafe { EcamConfig::new(base, allocation.start_bus, allocation.end_bus) })
+}
+
+/// Register a driver, which is offered the functions it handles by `probe_drivers`
+pub fn register(driver: Driver<PciConfig>) -> Result<(), DriverError> {
+    DRIVERS.lock().register(driver)
+}
+
+/// Offer each function to the drivers handling it
+pub fn probe_drivers() {
+    let lock = CONFIG.lock();
+    let Some(config) = lock.as_ref() else {
+        return;
+    };
+    DRIVERS.lock().probe_all(config, |function, driver| {
+        println!("PCI: {} bound to {}", driver.name, function.address);
+    });
+}
+
+// Print the function like `lspci -v` does, in short
+fn report<C: ConfigSpace>(function: &Function<C>) {
+    let (class, subclass, _) = function.class();
+    println!("{} {} [{:02x}{:02x}]: {:04x}:{:04x} (rev {:02x})", function.address,
+        class_name(class, subclass), class, subclass, function.vendor_id(), function.device_id(),
+        function.revision());
+
+    if let Some((primary, secondary, subordinate)) = function.bridge_buses() {
+        println!("    Bus: primary={:02x}, secondary={:02x}, subordinate={:02x}",
+            primary, secondary, subordinate);
+    }
+
+    for index in 0..function.bar_count() {
+        let size = function.bar_size(index).unwrap_or(0);
+        match function.bar(index) {
+            Some(Bar::Io { port }) => {
+                println!("    BAR{}: I/O ports at {:#x} [size={:#x}]", index, port, size);
+            }
+            Some(Bar::Memory { address, prefetchable, is_64 }) => {
+                println!("    BAR{}: Memory at {:#x} ({}-bit, {}) [size={:#x}]", index, address,
+                    if is_64 { 64 } else { 32 },
+                    if prefetchable { "prefetchable" } else { "non-prefetchable" }, size);
+            }
+            None => {}
+        }
+    }
+
+    for (id, offset) in function.capabilities() {
+        print_capability(function, id, offset);
+    }
+}
+
+fn print_capability<C: ConfigSpace>(function: &Function<C>, id: u8, offset: u16) {
+    match id {
+        pci::CAP_MSI => {
+            let msi = pci::Msi::parse(function, offset);
+            println!("    Capabilities: [{:02x}] MSI: Enable{} Count={} 64bit{}", offset,
+                if msi.enabled() { "+" } else { "-" }, msi.vectors(),
+                if msi.is_64bit() { "+" } else { "-" });
+        }
+        pci::CAP_MSIX => {
+            let msix = pci::MsiX::parse(function, offset);
+            println!("    Capabilities: [{:02x}] MSI-X: Enable{} Count={} Table BAR{}+{:#x}",
+                offset, if msix.enabled { "+" } else { "-" }, msix.table_size, msix.table_bar,
+                msix.table_offset);
+        }
+        pci::CAP_PCIE => {
+            let pcie = pci::Pcie::parse(function, offset);
+            println!("    Capabilities: [{:02x}] PCIe {:?}, link speed {} width x{}", offset,
+                pcie.port_type, pcie.link_speed, pcie.link_width);
+        }
+        _ => {
+            println!("    Capabilities: [{:02x}] {} ({:#04x})", offset, capability_name(id), id);
+        }
+    }
+}
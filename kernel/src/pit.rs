@@ -0,0 +1,50 @@
+//! Module using the legacy Programmable Interval Timer (8253/8254) as a busy-wait reference clock.
+//! We only use channel 2, whose gate is controlled through the NMI status and control port, such
+//! that we can poll its output without needing any interrupt.
+use cpu::x86::{in_u8, out_u8};
+
+// Frequency of the PIT input clock in Hz
+const PIT_FREQUENCY: u64 = 1_193_182;
+// Data port of channel 2
+const CHANNEL2_DATA: u16 = 0x42;
+// Mode/Command register
+const COMMAND: u16 = 0x43;
+// NMI status and control port. Bit 0 is the gate of channel 2, bit 1 enables the speaker and bit 5
+// reflects the output of channel 2.
+const NMI_STATUS_CONTROL: u16 = 0x61;
+// Select channel 2, access mode lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+const CHANNEL2_ONE_SHOT: u8 = 0b1011_0000;
+// The counter is 16-bit wide, so this is the longest we can wait for in one go (~54ms)
+const MAX_MICROS_PER_WAIT: u64 = 50_000;
+
+/// Busy-wait for at least `micros` microseconds
+pub fn sleep(micros: u64) {
+    let mut remaining = micros;
+    while remaining > 0 {
+        let chunk = core::cmp::min(remaining, MAX_MICROS_PER_WAIT);
+        wait(chunk);
+        remaining -= chunk;
+    }
+}
+
+// Program channel 2 to count down for `micros` microseconds and wait until it reaches 0
+fn wait(micros: u64) {
+    // Compute the number of PIT ticks we need. A count of 0 would mean 65536 for the PIT.
+    let count = core::cmp::max(PIT_FREQUENCY * micros / 1_000_000, 1) as u16;
+
+    // Disable the speaker and keep the gate low while we program the counter
+    let control = in_u8(NMI_STATUS_CONTROL) & !0b11;
+    out_u8(NMI_STATUS_CONTROL, control);
+
+    out_u8(COMMAND, CHANNEL2_ONE_SHOT);
+    out_u8(CHANNEL2_DATA, count as u8);
+    out_u8(CHANNEL2_DATA, (count >> 8) as u8);
+
+    // Raise the gate to start counting
+    out_u8(NMI_STATUS_CONTROL, control | 1);
+
+    // The output of channel 2 goes high once the count reaches 0
+    while in_u8(NMI_STATUS_CONTROL) & (1 << 5) == 0 {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,402 @@
+//! Command shell on the serial console, for poking at the machine while it runs, like under QEMU
+//! with `-serial stdio`
+use core::str::SplitWhitespace;
+use cpu::x86;
+use mmu::{Mmu, PhysicalAddress, VirtualAddress, PML4};
+use rangeset::GrowableRangeSet;
+use mmu::{PAGE_CACHE_DISABLE, PAGE_LARGE, PAGE_NXE, PAGE_PRESENT, PAGE_USER, PAGE_WRITE};
+use mmu::PAGE_WRITE_THROUGH;
+use state::MemoryType;
+use serial::LineEditor;
+use crate::{apic, interrupts, mm, print, println, tls};
+
+// Longest command line we accept
+const MAX_LINE: usize = 128;
+const PROMPT: &str = "pizza> ";
+// Bytes dumped when the command does not say how many
+const DEFAULT_DUMP_LEN: u64 = 256;
+// Most bytes dumped by a single command
+const MAX_DUMP_LEN: u64 = 64 * 1024;
+// Bytes shown on each line of a dump
+const DUMP_LINE_LEN: usize = 16;
+// Timer ticks we give each way of resetting the machine before trying the next one
+const RESET_WAIT: u64 = apic::TIMER_HZ / 10;
+
+// Address spaces of an ACPI generic address
+const ACPI_SYSTEM_MEMORY: u8 = 0;
+const ACPI_SYSTEM_IO: u8 = 1;
+
+// Commands with their arguments and what they do, as printed by `help`
+const COMMANDS: &[(&str, &str)] = &[
+    ("help", "list the commands"),
+    ("dump <virtual> [len]", "dump memory at a virtual address"),
+    ("pdump <physical> [len]", "dump RAM at a physical address"),
+    ("walk <virtual>", "show the page table entries translating a virtual address"),
+    ("free", "show the free physical memory"),
+    ("cores", "list the cores which are online"),
+    ("rdmsr <msr>", "read a model specific register"),
+    ("wrmsr <msr> <value>", "write a model specific register"),
+    ("reboot", "reset the machine"),
+];
+
+// Names of the paging levels, starting from the root
+const PAGING_LEVELS: [&str; 4] = ["PML4", "PDPT", "PD", "PT"];
+
+#[derive(Debug)]
+enum ShellError {
+    UnknownCommand,
+    // Name of the argument which was not given
+    MissingArgument(&'static str),
+    InvalidNumber,
+    TooManyArguments,
+    // The range wraps around the address space
+    InvalidRange,
+    // Address of the first page of a range which is not mapped
+    NotMapped(u64),
+    // Address of the first page of a range which is mapped as uncached
+    Uncached(u64),
+    // The range is not inside the physical window
+    NotInPhysicalWindow,
+    // First address of a range the memory map does not report as RAM, which could be MMIO
+    NotRam(u64),
+    // The model specific register does not exist, or does not take the value
+    InvalidMsr,
+    NoMmu,
+}
+
+impl core::fmt::Display for ShellError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::UnknownCommand => write!(f, "unknown command, try `help`"),
+            Self::MissingArgument(name) => write!(f, "missing argument <{}>", name),
+            Self::InvalidNumber => write!(f, "invalid number"),
+            Self::TooManyArguments => write!(f, "too many arguments"),
+            Self::InvalidRange => write!(f, "the range wraps around"),
+            Self::NotMapped(address) => write!(f, "{:#x} is not mapped", address),
+            Self::Uncached(address) => write!(f, "{:#x} is mapped as uncached MMIO", address),
+            Self::NotInPhysicalWindow => write!(f, "outside of the physical window"),
+            Self::NotRam(address) => write!(f, "{:#x} is not RAM in the memory map", address),
+            Self::InvalidMsr => write!(f, "the access raised a general protection fault"),
+            Self::NoMmu => write!(f, "no memory manager"),
+        }
+    }
+}
+
+/// Read commands from the serial console and run them, forever
+pub fn run() -> ! {
+    let mut editor = LineEditor::<MAX_LINE>::new();
+    println!("Type `help` for the list of commands");
+    loop {
+        print!("{}", PROMPT);
+        read_line(&mut editor);
+        execute(editor.line());
+    }
+}
+
+// Wait for a complete line. The serial port is only held while draining what was received, such
+// that the other cores can still print in the meantime.
+fn read_line(editor: &mut LineEditor<MAX_LINE>) {
+    loop {
+        let complete = unsafe { crate::core!().state.serial.lock().as_mut() }
+            .is_some_and(|serial| serial.try_read_line(editor).is_some());
+        if complete {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn execute(line: &str) {
+    let mut args = line.split_whitespace();
+    let Some(command) = args.next() else {
+        return;
+    };
+    let result = match command {
+        "help" => help(args),
+        "dump" => dump(args),
+        "pdump" => physical_dump(args),
+        "walk" => walk(args),
+        "free" => free(args),
+        "cores" => cores(args),
+        "rdmsr" => rdmsr(args),
+        "wrmsr" => wrmsr(args),
+        "reboot" => no_more_arguments(args).map(|()| reboot()),
+        _ => Err(ShellError::UnknownCommand),
+    };
+    if let Err(err) = result {
+        println!("{}: {}", command, err);
+    }
+}
+
+fn help(args: SplitWhitespace) -> Result<(), ShellError> {
+    no_more_arguments(args)?;
+    for (usage, description) in COMMANDS {
+        println!("  {:24} {}", usage, description);
+    }
+    Ok(())
+}
+
+fn dump(mut args: SplitWhitespace) -> Result<(), ShellError> {
+    let address = argument(&mut args, "address")?;
+    let len = optional_argument(&mut args)?.unwrap_or(DEFAULT_DUMP_LEN).min(MAX_DUMP_LEN);
+    no_more_arguments(args)?;
+    if len == 0 {
+        return Ok(());
+    }
+    let last = address.checked_add(len - 1).ok_or(ShellError::InvalidRange)?;
+
+    // Reading a page which is not mapped would fault, check all of them first
+    with_pml4(|pml4| {
+        let mut page = address & !0xfff;
+        while page <= last {
+            let translation = is_canonical(page)
+                .then(|| pml4.translate(VirtualAddress(page)))
+                .flatten();
+            match translation {
+                None => return Err(ShellError::NotMapped(page.max(address))),
+                // Uncached pages are device memory, where reads can have side effects
+                Some((_, _, flags)) if flags & PAGE_CACHE_DISABLE != 0 => {
+                    return Err(ShellError::Uncached(page.max(address)));
+                }
+                Some(_) => {}
+            }
+            match page.checked_add(4096) {
+                Some(next) => page = next,
+                None => break,
+            }
+        }
+        Ok(())
+    })??;
+
+    let bytes = unsafe { core::slice::from_raw_parts(address as *const u8, len as usize) };
+    hexdump(address, bytes);
+    Ok(())
+}
+
+fn physical_dump(mut args: SplitWhitespace) -> Result<(), ShellError> {
+    let address = argument(&mut args, "address")?;
+    let len = optional_argument(&mut args)?.unwrap_or(DEFAULT_DUMP_LEN).min(MAX_DUMP_LEN);
+    no_more_arguments(args)?;
+
+    // The physical window is cacheable, reading device memory through it could have side effects
+    let end = address.checked_add(len).ok_or(ShellError::InvalidRange)?;
+    check_ram(address, end)?;
+
+    let bytes = acpi::PhysicalMemory::read(&mm::PhysicalWindow, address, len as usize)
+        .ok_or(ShellError::NotInPhysicalWindow)?;
+    hexdump(address, bytes);
+    Ok(())
+}
+
+// Check that the memory map reports the physical range from `start` up to `end` as RAM or ACPI
+// memory, possibly over several entries
+fn check_ram(start: u64, end: u64) -> Result<(), ShellError> {
+    let memory_map = unsafe { crate::core!().state.memory_map.lock() };
+    let mut address = start;
+    while address < end {
+        address = memory_map.entries().iter()
+            .filter(|entry| matches!(
+                entry.memory_type(),
+                MemoryType::Usable | MemoryType::AcpiReclaimable | MemoryType::AcpiNvs,
+            ))
+            .filter(|entry| entry.base <= address)
+            .map(|entry| entry.base.saturating_add(entry.length))
+            .find(|&entry_end| entry_end > address)
+            .ok_or(ShellError::NotRam(address))?;
+    }
+    Ok(())
+}
+
+fn walk(mut args: SplitWhitespace) -> Result<(), ShellError> {
+    let address = argument(&mut args, "address")?;
+    no_more_arguments(args)?;
+
+    let (entries, translation) = with_pml4(|pml4| {
+        (pml4.page_walk(VirtualAddress(address)), pml4.translate(VirtualAddress(address)))
+    })?;
+    for (level, entry) in PAGING_LEVELS.iter().zip(entries) {
+        let Some(entry) = entry else {
+            break;
+        };
+        print!("{:4} {:#018x}", level, entry);
+        print_flags(entry);
+        println!();
+    }
+    match translation {
+        Some((physical, page_size, _)) => {
+            println!("{:#x} -> {:#x} ({:?})", address, physical.0, page_size);
+        }
+        None => { println!("{:#x} is not mapped", address); }
+    }
+    Ok(())
+}
+
+fn free(args: SplitWhitespace) -> Result<(), ShellError> {
+    no_more_arguments(args)?;
+    let mmu_lock = crate::mm::MMU.lock();
+    let mmu = mmu_lock.as_ref().ok_or(ShellError::NoMmu)?;
+
+    let mut total = 0;
+    for range in mmu.free_ranges() {
+        // The ranges are inclusive
+        let size = range.end - range.start + 1;
+        println!("{:#018x}-{:#018x} {:#x}", range.start, range.end, size);
+        total += size;
+    }
+    println!("{:#x} bytes free ({} MiB)", total, total / (1024 * 1024));
+    Ok(())
+}
+
+fn cores(args: SplitWhitespace) -> Result<(), ShellError> {
+    no_more_arguments(args)?;
+    let current = unsafe { crate::core!().id() };
+    for core in tls::cores() {
+        println!("{} Core {:#x} APIC ID {:#x}, {} timer ticks",
+            if core.id() == current { "*" } else { " " }, core.id(), core.apic_id(), core.ticks());
+    }
+    Ok(())
+}
+
+fn rdmsr(mut args: SplitWhitespace) -> Result<(), ShellError> {
+    let msr = msr_argument(&mut args)?;
+    no_more_arguments(args)?;
+    let value = interrupts::try_rdmsr(msr).ok_or(ShellError::InvalidMsr)?;
+    println!("MSR {:#x} = {:#018x}", msr, value);
+    Ok(())
+}
+
+fn wrmsr(mut args: SplitWhitespace) -> Result<(), ShellError> {
+    let msr = msr_argument(&mut args)?;
+    let value = argument(&mut args, "value")?;
+    no_more_arguments(args)?;
+    unsafe { interrupts::try_wrmsr(value, msr) }.ok_or(ShellError::InvalidMsr)
+}
+
+// Reset the machine with the ACPI reset register, then the keyboard controller, then by triple
+// faulting
+fn reboot() -> ! {
+    println!("Rebooting");
+
+    let reset = acpi::Acpi::parse(&mm::PhysicalWindow)
+        .and_then(|acpi| acpi.fadt())
+        .ok()
+        .and_then(|fadt| fadt.reset_register());
+    if let Some((register, value)) = reset {
+        match register.address_space_id {
+            ACPI_SYSTEM_IO => x86::out_u8(register.address as u16, value),
+            ACPI_SYSTEM_MEMORY => {
+                if let Some(address) = mm::map_mmio(register.address, 1) {
+                    unsafe { (address as *mut u8).write_volatile(value) };
+                }
+            }
+            _ => {}
+        }
+        wait_ticks(RESET_WAIT);
+    }
+
+    // Pulse the reset line of the processor through the keyboard controller
+    x86::out_u8(0x64, 0xfe);
+    wait_ticks(RESET_WAIT);
+
+    // With an empty IDT, the breakpoint can be delivered neither as itself nor as a double fault
+    unsafe {
+        x86::lidt(&x86::DescriptorTablePointer { limit: 0, base: 0 });
+        core::arch::asm!("int3");
+    }
+    x86::halt()
+}
+
+fn wait_ticks(ticks: u64) {
+    let deadline = apic::ticks() + ticks;
+    while apic::ticks() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+// Run `f` on the page tables currently in use
+fn with_pml4<R>(f: impl FnOnce(&PML4<Mmu<GrowableRangeSet>>) -> R) -> Result<R, ShellError> {
+    let mut mmu_lock = crate::mm::MMU.lock();
+    let mmu = mmu_lock.as_mut().ok_or(ShellError::NoMmu)?;
+    let pml4 = unsafe { PML4::from_addr(mmu, PhysicalAddress(x86::read_cr3() & !0xfff)) }
+        .ok_or(ShellError::NoMmu)?;
+    Ok(f(&pml4))
+}
+
+// Returns `true` if bits 48 to 63 of `address` are copies of bit 47
+fn is_canonical(address: u64) -> bool {
+    ((address << 16) as i64 >> 16) as u64 == address
+}
+
+// Print the flags set in a page table entry
+fn print_flags(entry: u64) {
+    let flags = [
+        (PAGE_PRESENT, "P"),
+        (PAGE_WRITE, "W"),
+        (PAGE_USER, "U"),
+        (PAGE_WRITE_THROUGH, "PWT"),
+        (PAGE_CACHE_DISABLE, "PCD"),
+        (PAGE_LARGE, "PS"),
+        (PAGE_NXE, "NX"),
+    ];
+    for (bit, name) in flags {
+        if entry & bit != 0 {
+            print!(" {}", name);
+        }
+    }
+}
+
+// Print `bytes` as hex and ASCII, 16 of them on each line, labelled with their address
+fn hexdump(address: u64, bytes: &[u8]) {
+    for (index, line) in bytes.chunks(DUMP_LINE_LEN).enumerate() {
+        print!("{:016x}:", address.wrapping_add((index * DUMP_LINE_LEN) as u64));
+        for column in 0..DUMP_LINE_LEN {
+            match line.get(column) {
+                Some(byte) => { print!(" {:02x}", byte); }
+                None => { print!("   "); }
+            }
+        }
+        print!("  ");
+        for &byte in line {
+            let printable = byte == b' ' || byte.is_ascii_graphic();
+            print!("{}", if printable { char::from(byte) } else { '.' });
+        }
+        println!();
+    }
+}
+
+// Returns the next argument as a number, which is hexadecimal with a `0x` prefix and decimal
+// otherwise. Underscores can be used to group the digits.
+fn optional_argument(args: &mut SplitWhitespace) -> Result<Option<u64>, ShellError> {
+    let Some(text) = args.next() else {
+        return Ok(None);
+    };
+    let (digits, radix) = match text.strip_prefix("0x") {
+        Some(digits) => (digits, 16),
+        None => (text, 10),
+    };
+    if !digits.starts_with(|c: char| c.is_digit(radix)) {
+        return Err(ShellError::InvalidNumber);
+    }
+    digits.chars()
+        .filter(|c| *c != '_')
+        .try_fold(0u64, |value, c| {
+            value.checked_mul(u64::from(radix))?.checked_add(u64::from(c.to_digit(radix)?))
+        })
+        .map(Some)
+        .ok_or(ShellError::InvalidNumber)
+}
+
+fn argument(args: &mut SplitWhitespace, name: &'static str) -> Result<u64, ShellError> {
+    optional_argument(args)?.ok_or(ShellError::MissingArgument(name))
+}
+
+fn msr_argument(args: &mut SplitWhitespace) -> Result<u32, ShellError> {
+    u32::try_from(argument(args, "msr")?).map_err(|_| ShellError::InvalidNumber)
+}
+
+fn no_more_arguments(mut args: SplitWhitespace) -> Result<(), ShellError> {
+    match args.next() {
+        Some(_) => Err(ShellError::TooManyArguments),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,244 @@
+//! Module booting the application processors (APs). The bootstrap processor copies a real-mode
+//! trampoline below 1MiB and wakes up all the other cores with the INIT-SIPI-SIPI sequence. Each
+//! AP switches straight from real mode to IA-32e mode with the page tables of the BSP and enters
+//! the kernel `entry` on its own stack.
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cpu::x86;
+use mmu::{PhysicalAddress, VirtualAddress, PML4, RWX};
+use crate::{apic, mm};
+
+/// Physical address where the trampoline is copied. It has to be page aligned and below 1MiB,
+/// because the SIPI vector is the page number the APs start executing from in real mode. The
+/// bootloader never hands out memory below 1MiB.
+const TRAMPOLINE_ADDRESS: u64 = 0x1000;
+/// Size of the trampoline page
+const TRAMPOLINE_SIZE: u64 = 4096;
+
+// ICR fields used for the startup sequence
+// INIT delivery mode
+const ICR_INIT: u32 = 0b101 << 8;
+// Start-up delivery mode. The vector is the page number of the real mode entry point.
+const ICR_STARTUP: u32 = 0b110 << 8;
+// Assert level. Must be set for everything but INIT level de-assert.
+const ICR_ASSERT: u32 = 1 << 14;
+// Send the IPI to all the processors, excluding the one sending it
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+// How long we wait for the APs to come online, in timer ticks. They go through the trampoline one
+// at a time, which takes a while with many cores or under emulation.
+const AP_TIMEOUT: u64 = 2 * apic::TIMER_HZ;
+
+// Number of cores running the kernel, including the BSP
+static CORES_ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+// The trampoline is position dependant, all the absolute addresses are computed relative to
+// `TRAMPOLINE_ADDRESS`. Similar to `stage0.asm`, it loads its own GDT, but this one contains 64-bit
+// segments, because we enable paging and IA-32e mode in one go. Since all the APs are started at
+// once, they take turns through a lock in the data area, such that only one of them uses the
+// `stack` at a time. The lock is released from Rust, after the AP set up the stack for the next one.
+global_asm!(r#"
+    .text
+    .global ap_trampoline_start
+    .global ap_trampoline_data
+    .global ap_trampoline_end
+    .code16
+ap_trampoline_start:
+    cli
+    cld
+    xorw %ax, %ax
+    movw %ax, %ds
+
+    // Load the GDT of the trampoline
+    lgdtl {trampoline} + (ap_gdtr - ap_trampoline_start)
+
+    // Enable PAE, OSFXSR and OSXMMEXCPT
+    movl %cr4, %eax
+    orl $((1 << 5) | (1 << 9) | (1 << 10)), %eax
+    movl %eax, %cr4
+
+    // Use the same page tables as the BSP
+    movl {trampoline} + (ap_cr3 - ap_trampoline_start), %eax
+    movl %eax, %cr3
+
+    // Enable IA-32e mode and not execute in IA32_EFER
+    movl $0xc0000080, %ecx
+    rdmsr
+    orl $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+
+    // Clear emulation and enable protected mode, monitor co-processor, write protect and paging
+    movl %cr0, %eax
+    andl $~(1 << 2), %eax
+    orl $((1 << 0) | (1 << 1) | (1 << 16) | (1 << 31)), %eax
+    movl %eax, %cr0
+
+    // Load the 64-bit code segment
+    ljmpl $0x08, ${trampoline} + (ap_long_mode - ap_trampoline_start)
+
+    .code64
+ap_long_mode:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movw %ax, %fs
+    movw %ax, %gs
+
+    // Wait for our turn to use the stack
+    movq ${trampoline} + (ap_lock - ap_trampoline_start), %rbx
+1:
+    lock btsq $0, (%rbx)
+    jnc 2f
+    pause
+    jmp 1b
+2:
+    movq {trampoline} + (ap_stack - ap_trampoline_start), %rsp
+    movq {trampoline} + (ap_state - ap_trampoline_start), %rcx
+    movq {trampoline} + (ap_entry - ap_trampoline_start), %rax
+    // Same as the bootloader, reserve the shadow space of the parameters passed in registers and
+    // the space for a return address
+    subq $0x28, %rsp
+    jmpq *%rax
+
+    .balign 8
+ap_gdt:
+    // First entry is always Null
+    .quad 0
+    // 64-bit code segment
+    .quad 0x00209a0000000000
+    // Data segment
+    .quad 0x0000920000000000
+ap_gdtr:
+    .word ap_gdtr - ap_gdt - 1
+    .long {trampoline} + (ap_gdt - ap_trampoline_start)
+
+    .balign 8
+ap_trampoline_data:
+ap_lock:
+    .quad 0
+ap_cr3:
+    .quad 0
+ap_stack:
+    .quad 0
+ap_entry:
+    .quad 0
+ap_state:
+    .quad 0
+ap_trampoline_end:
+"#, trampoline = const TRAMPOLINE_ADDRESS, options(att_syntax));
+
+extern "C" {
+    fn ap_trampoline_start();
+    fn ap_trampoline_data();
+    fn ap_trampoline_end();
+}
+
+/// Values the trampoline needs in order to get the AP into the kernel. Has to match the layout of
+/// the data area at the end of the trampoline.
+#[repr(C)]
+struct TrampolineData {
+    // Taken by the AP that currently uses `stack`
+    lock: u64,
+    // Physical address of the PML4
+    cr3: u64,
+    // Top of the stack for the next AP
+    stack: u64,
+    // Address of the kernel entry point
+    entry: u64,
+    // Pointer to the `BootState` passed to the kernel entry point
+    state: u64,
+}
+
+// Returns the data area of the trampoline copied at `TRAMPOLINE_ADDRESS`
+fn trampoline_data() -> &'static mut TrampolineData {
+    let offset = ap_trampoline_data as *const () as u64 - ap_trampoline_start as *const () as u64;
+    unsafe { &mut *(mm::physical_to_virtual(TRAMPOLINE_ADDRESS + offset) as *mut TrampolineData) }
+}
+
+// The APs turn on paging while they run the trampoline, so it has to be identity mapped until all
+// of them made it to the kernel. `map` selects between adding and removing the mapping.
+fn identity_map_trampoline(map: bool) -> Option<()> {
+    let mut mmu_lock = crate::mm::MMU.lock();
+    let mmu = mmu_lock.as_mut()?;
+    let mut pml4 = unsafe { PML4::from_addr(mmu, PhysicalAddress(x86::read_cr3() & !0xfff))? };
+
+    if map {
+        pml4.map_physical(
+            VirtualAddress(TRAMPOLINE_ADDRESS),
+            PhysicalAddress(TRAMPOLINE_ADDRESS),
+            TRAMPOLINE_SIZE,
+            RWX { read: true, write: true, execute: true },
+        ).ok()
+    } else {
+        pml4.unmap_page(VirtualAddress(TRAMPOLINE_ADDRESS)).ok().map(|_| ())
+    }
+}
+
+/// Start all the application processors and wait for them to come online. `expected` is the number
+/// of cores the firmware reports, including the BSP. Returns the number of cores running the
+/// kernel, including the BSP.
+pub fn start_aps(expected: Option<usize>) -> Option<usize> {
+    let start = ap_trampoline_start as *const () as u64;
+    let end = ap_trampoline_end as *const () as u64;
+    if end - start > TRAMPOLINE_SIZE {
+        return None;
+    }
+
+    unsafe {
+        // Copy the trampoline in low memory
+        core::ptr::copy_nonoverlapping(
+            start as *const u8,
+            mm::physical_to_virtual(TRAMPOLINE_ADDRESS),
+            usize::try_from(end - start).ok()?,
+        );
+    }
+    identity_map_trampoline(true)?;
+
+    let data = trampoline_data();
+    data.lock = 0;
+    data.cr3 = x86::read_cr3();
+    data.stack = mm::allocate_stack()?;
+    data.entry = crate::entry as *const () as u64;
+    data.state = unsafe { crate::core!().state as *const _ as u64 };
+
+    let vector = u32::try_from(TRAMPOLINE_ADDRESS >> 12).ok()?;
+
+    // INIT, wait 10ms and send 2 SIPIs 200us apart, as described in the Intel MultiProcessor
+    // Specification
+    apic::send_ipi(0, ICR_INIT | ICR_ASSERT | ICR_ALL_EXCLUDING_SELF);
+    crate::pit::sleep(10_000);
+    for _ in 0..2 {
+        apic::send_ipi(0, ICR_STARTUP | ICR_ASSERT | ICR_ALL_EXCLUDING_SELF | vector);
+        crate::pit::sleep(200);
+    }
+
+    let deadline = apic::ticks() + AP_TIMEOUT;
+    let all_online = || expected.is_some_and(|cores| CORES_ONLINE.load(Ordering::SeqCst) >= cores);
+    while !all_online() && apic::ticks() < deadline {
+        core::hint::spin_loop();
+    }
+
+    // An AP still in the trampoline would fault with no IDT loaded when we remove the mapping,
+    // so unless we know they all made it, it stays
+    if all_online() {
+        identity_map_trampoline(false)?;
+    } else {
+        crate::println!("Not all the cores came online, keeping the trampoline mapped");
+    }
+
+    Some(CORES_ONLINE.load(Ordering::SeqCst))
+}
+
+/// Called by each AP once it entered the kernel on the stack from the trampoline. Prepares the
+/// stack for the next AP and lets it through.
+pub fn ap_online() -> Option<()> {
+    let data = trampoline_data();
+    data.stack = mm::allocate_stack()?;
+    CORES_ONLINE.fetch_add(1, Ordering::SeqCst);
+
+    // Release the trampoline lock
+    unsafe { core::ptr::write_volatile(&mut data.lock, 0) };
+
+    Some(())
+}
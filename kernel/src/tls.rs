@@ -1,11 +1,17 @@
 //! Module containing definitions and manipulation resources for unique per thread and/or core
 //! local stored structures
 use state::BootState;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 // Initialize the first core id. This will be incremented atomically for each of the following
 // cores that come online afterwards
-static CORE_ID: AtomicUsize = AtomicUsize::new(0x1337);
+const FIRST_CORE_ID: usize = 0x1337;
+static CORE_ID: AtomicUsize = AtomicUsize::new(FIRST_CORE_ID);
+
+/// Most cores we keep track of in `cores`
+pub const MAX_CORES: usize = 256;
+// Address of the `Core` structure of each core that came online, in the order of their IDs
+static CORES: [AtomicUsize; MAX_CORES] = [const { AtomicUsize::new(0) }; MAX_CORES];
 
 /// Contains unique per core informations that can only be accessed by it's corresponding core.
 // We need the address pointer as the first field of the structure. In order to make sure Rust does
@@ -18,12 +24,30 @@ pub struct Core {
     id: usize,
     // Represents a systems state passed on from the bootloader to the kernel
     pub state: &'static BootState,
+    // Number of Local APIC timer interrupts this core has received
+    ticks: AtomicU64,
+    // Initial APIC ID reported by CPUID, the low 8 bits of the x2APIC ID
+    apic_id: u8,
 }
 
 impl Core {
     pub fn id(&self) -> usize {
         self.id
     }
+
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id
+    }
+
+    /// Returns the monotonic number of timer ticks of this core
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
+    /// Account for a new timer tick on this core
+    pub fn tick(&self) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 pub unsafe fn get_core_state() -> &'static Core {
@@ -32,6 +56,14 @@ pub unsafe fn get_core_state() -> &'static Core {
     &*(ptr as *const Core)
 }
 
+/// Returns the local storage of every core that came online
+pub fn cores() -> impl Iterator<Item = &'static Core> {
+    CORES.iter()
+        .map(|core| core.load(Ordering::SeqCst))
+        .filter(|ptr| *ptr != 0)
+        .map(|ptr| unsafe { &*(ptr as *const Core) })
+}
+
 // Get the current core structure
 #[macro_export]
 macro_rules! core {
@@ -42,7 +74,7 @@ macro_rules! core {
 
 pub fn init(state: &'static BootState) -> Option<()> {
     // Acquire a lock to the memory
-    let mut mmu_lock = state.mmu.lock();
+    let mut mmu_lock = crate::mm::MMU.lock();
     let mmu = mmu_lock.as_mut()?;
 
     // Allocate memory that will hold the `Core` structure
@@ -55,8 +87,11 @@ pub fn init(state: &'static BootState) -> Option<()> {
     // Get a new core id for the current core
     let id = CORE_ID.fetch_add(1, Ordering::Relaxed);
 
+    // We access the structure through the physical window
+    let core_ptr = crate::mm::physical_to_virtual(core_ptr as u64) as usize;
     // Create the core structure
-    let core = Core { core_ptr, id, state };
+    let apic_id = (unsafe { cpu::x86::cpuid_ext(1, 0) }.ebx >> 24) as u8;
+    let core = Core { core_ptr, id, state, ticks: AtomicU64::new(0), apic_id };
 
     unsafe {
         // Write the structure in the newly allocated address
@@ -64,6 +99,9 @@ pub fn init(state: &'static BootState) -> Option<()> {
         // Set the gs base to reflect the new structure
         cpu::x86::write_gs_base(u64::try_from(core_ptr).ok()?);
     }
+    if let Some(slot) = CORES.get(id - FIRST_CORE_ID) {
+        slot.store(core_ptr, Ordering::SeqCst);
+    }
 
     Some(())
 }
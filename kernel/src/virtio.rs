@@ -0,0 +1,536 @@
+//! Driver for virtio network cards found on the PCI bus, through either the legacy (virtio 0.9.5)
+//! I/O port interface or the modern (virtio 1.0) memory-mapped one. The card is polled, it never
+//! raises interrupts.
+use core::sync::atomic::{fence, Ordering};
+use cpu::x86;
+use net::{Device, MacAddress, NetError, MAX_FRAME_SIZE};
+use pci::{Bar, ConfigSpace, DeviceId, Driver, Function, PciConfig};
+use sync::LockCell;
+use crate::{apic, mm};
+
+/// Vendor ID of the virtio devices
+pub const VIRTIO_VENDOR: u16 = 0x1af4;
+// Device ID of network cards offering both interfaces
+const DEVICE_NET_TRANSITIONAL: u16 = 0x1000;
+// Device ID of network cards with only the modern interface
+const DEVICE_NET_MODERN: u16 = 0x1041;
+
+// Bits of the device status register
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 0x80;
+
+// The device has a MAC address in its configuration
+const FEATURE_NET_MAC: u64 = 1 << 5;
+// The device follows the virtio 1.0 specification
+const FEATURE_VERSION_1: u64 = 1 << 32;
+
+// Registers of the legacy interface, in the I/O space of BAR0
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0c;
+const LEGACY_QUEUE_SELECT: u16 = 0x0e;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+// Device specific configuration, as long as MSI-X is disabled
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+
+// Structures the vendor specific capabilities of the modern interface point to
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+// Registers of the common configuration structure of the modern interface
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0c;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+/// Driver for the virtio network cards, to register with the PCI subsystem. It only takes the
+/// first card.
+pub const DRIVER: Driver<PciConfig> = Driver {
+    name: "virtio-net",
+    ids: &[
+        DeviceId::new(VIRTIO_VENDOR, DEVICE_NET_TRANSITIONAL),
+        DeviceId::new(VIRTIO_VENDOR, DEVICE_NET_MODERN),
+    ],
+    probe,
+};
+
+// Network card bound by the driver, until someone takes it
+static CARD: LockCell<Option<VirtioNet>> = LockCell::new(None);
+
+fn probe(function: &Function<PciConfig>) -> bool {
+    let mut card = CARD.lock();
+    if card.is_some() {
+        return false;
+    }
+    *card = VirtioNet::new(function);
+    card.is_some()
+}
+
+/// Take the network card bound by the driver, if there is one
+pub fn take() -> Option<VirtioNet> {
+    CARD.lock().take()
+}
+
+// Index of the virtqueues of a network card
+const QUEUE_RECEIVE: u16 = 0;
+const QUEUE_TRANSMIT: u16 = 1;
+// Largest queue we set up, when the device lets us choose. Legacy devices impose their size.
+const MAX_QUEUE_SIZE: u16 = 256;
+// Alignment of the used ring in the legacy layout of a virtqueue, which we use for both interfaces
+const QUEUE_ALIGN: u64 = 4096;
+// Size of the buffer behind each descriptor, enough for the header and a full frame
+const BUFFER_SIZE: usize = 2048;
+// The device writes in the buffer of the descriptor
+const DESC_F_WRITE: u16 = 2;
+// Timer ticks we give the device to reset or to send a frame, before giving up on it
+const DEVICE_TIMEOUT: u64 = apic::TIMER_HZ;
+
+// Size of the header in front of each frame. The legacy header lacks the `num_buffers` field.
+const NET_HEADER_SIZE_LEGACY: usize = 10;
+const NET_HEADER_SIZE: usize = 12;
+
+// Entry of the descriptor table of a virtqueue
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+// Split virtqueue, where each descriptor always points to the same buffer
+struct Virtqueue {
+    index: u16,
+    size: u16,
+    // Physical address of the descriptor table, followed by the available and used rings
+    physical: u64,
+    descriptors: *mut Descriptor,
+    available: *mut u16,
+    used: *mut u16,
+    // Buffers of the descriptors, `BUFFER_SIZE` bytes each
+    buffers_physical: u64,
+    buffers: *mut u8,
+    // Index of the next entry we add to the available ring
+    next_available: u16,
+    // Index of the next entry of the used ring we have not seen yet
+    next_used: u16,
+    // Address the notifications for this queue are written to, only used by modern devices
+    notify: *mut u16,
+}
+
+impl Virtqueue {
+    // Returns the offsets of the available ring and used ring and the size of a queue with `size`
+    // entries
+    fn layout(size: u16) -> (u64, u64, u64) {
+        let size = u64::from(size);
+        let available = 16 * size;
+        let used = (available + 6 + 2 * size).next_multiple_of(QUEUE_ALIGN);
+        (available, used, used + (6 + 8 * size).next_multiple_of(QUEUE_ALIGN))
+    }
+
+    fn new(index: u16, size: u16) -> Option<Self> {
+        if size == 0 || !size.is_power_of_two() {
+            return None;
+        }
+        let (available, used, total) = Self::layout(size);
+        let (physical, ptr) = mm::allocate_dma(total)?;
+        let (buffers_physical, buffers) =
+            mm::allocate_dma(u64::from(size) * BUFFER_SIZE as u64)?;
+
+        let queue = Self {
+            index,
+            size,
+            physical,
+            descriptors: ptr as *mut Descriptor,
+            available: ptr.wrapping_add(available as usize) as *mut u16,
+            used: ptr.wrapping_add(used as usize) as *mut u16,
+            buffers_physical,
+            buffers,
+            next_available: 0,
+            next_used: 0,
+            notify: core::ptr::null_mut(),
+        };
+        for id in 0..size {
+            let descriptor = Descriptor {
+                address: queue.buffers_physical + u64::from(id) * BUFFER_SIZE as u64,
+                length: BUFFER_SIZE as u32,
+                flags: 0,
+                next: 0,
+            };
+            unsafe { queue.descriptors.add(usize::from(id)).write_volatile(descriptor) };
+        }
+        Some(queue)
+    }
+
+    // Physical addresses of the descriptor table, the available ring and the used ring
+    fn addresses(&self) -> (u64, u64, u64) {
+        let (available, used, _) = Self::layout(self.size);
+        (self.physical, self.physical + available, self.physical + used)
+    }
+
+    fn buffer(&mut self, id: u16) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.buffers.add(usize::from(id) * BUFFER_SIZE),
+                BUFFER_SIZE,
+            )
+        }
+    }
+
+    // Hand the buffer of descriptor `id` to the device, with `length` bytes for it to read or, if
+    // `device_writes` is set, room for it to write in
+    fn push(&mut self, id: u16, length: u32, device_writes: bool) {
+        unsafe {
+            let descriptor = self.descriptors.add(usize::from(id));
+            (&raw mut (*descriptor).length).write_volatile(length);
+            let flags = if device_writes { DESC_F_WRITE } else { 0 };
+            (&raw mut (*descriptor).flags).write_volatile(flags);
+
+            // The ring starts after the flags and index fields
+            let slot = usize::from(self.next_available % self.size);
+            self.available.add(2 + slot).write_volatile(id);
+            self.next_available = self.next_available.wrapping_add(1);
+            // The device must see the entry before the index which makes it available
+            fence(Ordering::SeqCst);
+            self.available.add(1).write_volatile(self.next_available);
+        }
+    }
+
+    // Returns the descriptor ID and length of the next buffer the device is done with. An entry
+    // with an ID outside of the queue is skipped, there is no buffer to give back for it.
+    fn pop(&mut self) -> Option<(u16, u32)> {
+        // The used ring starts after the flags and index fields, with 8 byte entries
+        let index = unsafe { self.used.add(1).read_volatile() };
+        if index == self.next_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let slot = usize::from(self.next_used % self.size);
+        let entry = unsafe { (self.used.add(2) as *mut u32).add(2 * slot) };
+        let (id, length) = unsafe { (entry.read_volatile(), entry.add(1).read_volatile()) };
+        self.next_used = self.next_used.wrapping_add(1);
+        let id = u16::try_from(id).ok().filter(|id| *id < self.size)?;
+        Some((id, length))
+    }
+}
+
+// Spin until `done` returns `true`, for at most `DEVICE_TIMEOUT` ticks. Returns `false` if it
+// timed out.
+fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+    let start = apic::ticks();
+    while !done() {
+        if apic::ticks().wrapping_sub(start) >= DEVICE_TIMEOUT {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+// How the driver talks to the device
+enum Transport {
+    // Registers in I/O space, starting at `port`
+    Legacy { port: u16 },
+    // Memory-mapped structures found through the vendor specific capabilities
+    Modern { common: *mut u8, notify: *mut u8, notify_multiplier: u32, device: *mut u8 },
+}
+
+// Volatile access to the memory-mapped registers of modern devices
+unsafe fn mmio_read<T>(base: *mut u8, offset: usize) -> T {
+    (base.add(offset) as *mut T).read_volatile()
+}
+
+unsafe fn mmio_write<T>(base: *mut u8, offset: usize, value: T) {
+    (base.add(offset) as *mut T).write_volatile(value)
+}
+
+impl Transport {
+    // Look for the capabilities of the modern interface and map the structures they describe
+    fn modern<C: ConfigSpace>(function: &Function<C>) -> Option<Self> {
+        let (mut common, mut notify, mut device) = (None, None, None);
+        let mut notify_multiplier = 0;
+        for (id, offset) in function.capabilities() {
+            if id != pci::CAP_VENDOR_SPECIFIC {
+                continue;
+            }
+            let cfg_type = function.read_u8(offset + 3);
+            let bar = function.bar(function.read_u8(offset + 4));
+            let Some(Bar::Memory { address, .. }) = bar else {
+                continue;
+            };
+            let physical = address + u64::from(function.read_u32(offset + 8));
+            let length = u64::from(function.read_u32(offset + 12));
+            match cfg_type {
+                CFG_TYPE_COMMON if common.is_none() => common = Some((physical, length)),
+                CFG_TYPE_NOTIFY if notify.is_none() => {
+                    notify = Some((physical, length));
+                    notify_multiplier = function.read_u32(offset + 16);
+                }
+                CFG_TYPE_DEVICE if device.is_none() => device = Some((physical, length)),
+                _ => {}
+            }
+        }
+
+        let map = |(physical, length)| mm::map_mmio(physical, length).map(|va| va as *mut u8);
+        Some(Self::Modern {
+            common: map(common?)?,
+            notify: map(notify?)?,
+            notify_multiplier,
+            device: map(device?)?,
+        })
+    }
+
+    fn status(&self) -> u8 {
+        match *self {
+            Self::Legacy { port } => x86::in_u8(port + LEGACY_DEVICE_STATUS),
+            Self::Modern { common, .. } => unsafe { mmio_read(common, COMMON_DEVICE_STATUS) },
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match *self {
+            Self::Legacy { port } => x86::out_u8(port + LEGACY_DEVICE_STATUS, status),
+            Self::Modern { common, .. } => unsafe {
+                mmio_write(common, COMMON_DEVICE_STATUS, status)
+            },
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        match *self {
+            Self::Legacy { port } => u64::from(x86::in_u32(port + LEGACY_DEVICE_FEATURES)),
+            Self::Modern { common, .. } => unsafe {
+                mmio_write(common, COMMON_DEVICE_FEATURE_SELECT, 0u32);
+                let low: u32 = mmio_read(common, COMMON_DEVICE_FEATURE);
+                mmio_write(common, COMMON_DEVICE_FEATURE_SELECT, 1u32);
+                let high: u32 = mmio_read(common, COMMON_DEVICE_FEATURE);
+                (u64::from(high) << 32) | u64::from(low)
+            },
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match *self {
+            Self::Legacy { port } => {
+                x86::out_u32(port + LEGACY_DRIVER_FEATURES, features as u32);
+            }
+            Self::Modern { common, .. } => unsafe {
+                mmio_write(common, COMMON_DRIVER_FEATURE_SELECT, 0u32);
+                mmio_write(common, COMMON_DRIVER_FEATURE, features as u32);
+                mmio_write(common, COMMON_DRIVER_FEATURE_SELECT, 1u32);
+                mmio_write(common, COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+            },
+        }
+    }
+
+    // Returns the size of the queue with `index`, 0 if it does not exist
+    fn queue_size(&self, index: u16) -> u16 {
+        match *self {
+            Self::Legacy { port } => {
+                x86::out_u16(port + LEGACY_QUEUE_SELECT, index);
+                x86::in_u16(port + LEGACY_QUEUE_SIZE)
+            }
+            Self::Modern { common, .. } => unsafe {
+                mmio_write(common, COMMON_QUEUE_SELECT, index);
+                core::cmp::min(mmio_read(common, COMMON_QUEUE_SIZE), MAX_QUEUE_SIZE)
+            },
+        }
+    }
+
+    // Give the device the location of `queue` and enable it
+    fn setup_queue(&self, queue: &mut Virtqueue) -> Option<()> {
+        let (descriptors, available, used) = queue.addresses();
+        match *self {
+            Self::Legacy { port } => {
+                x86::out_u16(port + LEGACY_QUEUE_SELECT, queue.index);
+                let pfn = u32::try_from(descriptors / QUEUE_ALIGN).ok()?;
+                x86::out_u32(port + LEGACY_QUEUE_ADDRESS, pfn);
+            }
+            Self::Modern { common, notify, notify_multiplier, .. } => unsafe {
+                mmio_write(common, COMMON_QUEUE_SELECT, queue.index);
+                mmio_write(common, COMMON_QUEUE_SIZE, queue.size);
+                mmio_write(common, COMMON_QUEUE_DESC, descriptors);
+                mmio_write(common, COMMON_QUEUE_DRIVER, available);
+                mmio_write(common, COMMON_QUEUE_DEVICE, used);
+                let notify_off: u16 = mmio_read(common, COMMON_QUEUE_NOTIFY_OFF);
+                let offset = usize::from(notify_off) * notify_multiplier as usize;
+                queue.notify = notify.add(offset) as *mut u16;
+                mmio_write(common, COMMON_QUEUE_ENABLE, 1u16);
+            },
+        }
+        Some(())
+    }
+
+    // Tell the device there are new buffers in `queue`
+    fn notify(&self, queue: &Virtqueue) {
+        match *self {
+            Self::Legacy { port } => x86::out_u16(port + LEGACY_QUEUE_NOTIFY, queue.index),
+            Self::Modern { .. } => unsafe { queue.notify.write_volatile(queue.index) },
+        }
+    }
+
+    // Read a byte of the device specific configuration
+    fn device_config(&self, offset: u16) -> u8 {
+        match *self {
+            Self::Legacy { port } => x86::in_u8(port + LEGACY_DEVICE_CONFIG + offset),
+            Self::Modern { device, .. } => unsafe { mmio_read(device, usize::from(offset)) },
+        }
+    }
+}
+
+/// A virtio network card
+pub struct VirtioNet {
+    transport: Transport,
+    receive: Virtqueue,
+    transmit: Virtqueue,
+    mac: MacAddress,
+    // Size of the header in front of each frame
+    header_size: usize,
+    // A frame timed out and the device still owns the transmit descriptor
+    transmit_pending: bool,
+}
+
+impl VirtioNet {
+    /// Initialise the virtio network card `function`. The modern interface is preferred when the
+    /// card offers both.
+    pub fn new<C: ConfigSpace>(function: &Function<C>) -> Option<Self> {
+        let (transport, command) = match Transport::modern(function) {
+            Some(transport) => (transport, pci::COMMAND_MEMORY_SPACE),
+            None => match function.bar(0)? {
+                Bar::Io { port } => (Transport::Legacy { port }, pci::COMMAND_IO_SPACE),
+                Bar::Memory { .. } => return None,
+            },
+        };
+        function.enable(command | pci::COMMAND_BUS_MASTER);
+
+        let driver = Self::init(transport);
+        if driver.is_none() {
+            crate::println!("Failed to initialise the virtio network card at {}", function.address);
+        }
+        driver
+    }
+
+    // Go through the initialisation sequence of section 3.1 of the virtio specification
+    fn init(transport: Transport) -> Option<Self> {
+        // Reset the device
+        transport.set_status(0);
+        if !wait_for(|| transport.status() == 0) {
+            return None;
+        }
+        transport.set_status(STATUS_ACKNOWLEDGE);
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let modern = matches!(transport, Transport::Modern { .. });
+        let device_features = transport.device_features();
+        let mut features = device_features & FEATURE_NET_MAC;
+        if modern {
+            // Modern devices without this feature cannot be driven through the modern interface
+            if device_features & FEATURE_VERSION_1 == 0 {
+                transport.set_status(STATUS_FAILED);
+                return None;
+            }
+            features |= FEATURE_VERSION_1;
+        }
+        transport.set_driver_features(features);
+
+        // Legacy devices do not have the features negotiation step
+        let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+        if modern {
+            status |= STATUS_FEATURES_OK;
+            transport.set_status(status);
+            if transport.status() & STATUS_FEATURES_OK == 0 {
+                transport.set_status(STATUS_FAILED);
+                return None;
+            }
+        }
+
+        let mut receive = Virtqueue::new(QUEUE_RECEIVE, transport.queue_size(QUEUE_RECEIVE))?;
+        let mut transmit = Virtqueue::new(QUEUE_TRANSMIT, transport.queue_size(QUEUE_TRANSMIT))?;
+        transport.setup_queue(&mut receive)?;
+        transport.setup_queue(&mut transmit)?;
+
+        // Without a MAC in the configuration, the device accepts any address. Make one up in the
+        // locally administered range.
+        let mut mac = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+        if features & FEATURE_NET_MAC != 0 {
+            for (offset, byte) in mac.iter_mut().enumerate() {
+                *byte = transport.device_config(offset as u16);
+            }
+        }
+
+        let header_size = if modern { NET_HEADER_SIZE } else { NET_HEADER_SIZE_LEGACY };
+        let mut driver =
+            Self { transport, receive, transmit, mac, header_size, transmit_pending: false };
+
+        // Every receive buffer is available to the device from the start
+        for id in 0..driver.receive.size {
+            driver.receive.push(id, BUFFER_SIZE as u32, true);
+        }
+        driver.transport.set_status(status | STATUS_DRIVER_OK);
+        driver.transport.notify(&driver.receive);
+
+        Some(driver)
+    }
+}
+
+impl Device for VirtioNet {
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        let length = self.header_size + frame.len();
+        if frame.len() > MAX_FRAME_SIZE || length > BUFFER_SIZE {
+            return Err(NetError::Device);
+        }
+
+        // We wait for each frame to be sent, so the first descriptor is free unless the last frame
+        // timed out, and the device is still not done with it
+        if self.transmit_pending {
+            if self.transmit.pop().is_none() {
+                return Err(NetError::Device);
+            }
+            self.transmit_pending = false;
+        }
+        let header_size = self.header_size;
+        let buffer = self.transmit.buffer(0);
+        buffer[..header_size].fill(0);
+        buffer[header_size..length].copy_from_slice(frame);
+        self.transmit.push(0, length as u32, false);
+        self.transport.notify(&self.transmit);
+
+        if !wait_for(|| self.transmit.pop().is_some()) {
+            self.transmit_pending = true;
+            return Err(NetError::Device);
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        let (id, length) = self.receive.pop()?;
+        let header_size = self.header_size;
+        let received = self.receive.buffer(id);
+        let frame = received.get(header_size..length as usize).unwrap_or(&[]);
+        let length = core::cmp::min(frame.len(), buffer.len());
+        buffer[..length].copy_from_slice(&frame[..length]);
+
+        // Give the buffer back to the device for the next frame
+        self.receive.push(id, BUFFER_SIZE as u32, true);
+        self.transport.notify(&self.receive);
+        Some(length)
+    }
+}
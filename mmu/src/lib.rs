@@ -1,47 +1,77 @@
 #![no_std]
 use core::{alloc::Layout, ops::RangeInclusive};
 use cpu::x86;
-use ops::RangeSet;
+use rangeset::{GrowableRangeSet, Range, RangeSet, RangeSetOps, Translate};
 
 /// Implementors of this trait are capable of taking advantange of Intels x86 4-Level Paging
 /// linear address translation capability
 pub trait AddressTranslate {
     /// Allocates memory with the specified layout and returns a pointer to that memory.
     unsafe fn alloc(&mut self, layout: Layout) -> *mut u8;
+    /// Gives back memory at `ptr`, previously returned by `alloc` with the same `layout`
+    ///
+    /// # Safety
+    /// The memory must no longer be referenced by anything, including page tables.
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout);
     /// Translates a physical memory address into a virtual memory one, checking whether or not
     /// the block is available for `size` bytes
     unsafe fn translate(&self, physical_address: PhysicalAddress, size: usize) -> Option<*mut u8>;
     /// Allocates memory and fills it with 0
     unsafe fn alloc_zeroed(&mut self, layout: Layout) -> *mut u8 {
         let ptr = self.alloc(layout);
-        // Now that we allocated it, we want to zero it out.
-        core::slice::from_raw_parts_mut(ptr, layout.size())
-            .into_iter()
-            .for_each(|entry| *entry = 0);
+        // Now that we allocated it, we want to zero it out, through its virtual address
+        if let Some(bytes) = self.translate(PhysicalAddress(ptr as u64), layout.size()) {
+            core::slice::from_raw_parts_mut(bytes, layout.size()).fill(0);
+        }
         ptr
     }
+    /// Invalidates the TLB entries for `virtual_address`, after its mapping changed. A page table
+    /// can only be live in 64-bit mode, so this does nothing in the 32-bit bootloader.
+    ///
+    /// # Safety
+    /// Has to run at CPL 0.
+    unsafe fn invalidate(&mut self, virtual_address: VirtualAddress) {
+        if core::mem::size_of::<usize>() == core::mem::size_of::<u64>() {
+            x86::invlpg(virtual_address.0);
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct PhysicalAddress(pub u64);
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct VirtualAddress(pub u64);
 
+/// Virtual address at which the kernel accesses all the physical memory. Physical address `p` is
+/// found at `PHYSICAL_WINDOW_BASE + p`.
+pub const PHYSICAL_WINDOW_BASE: u64 = 0xffff_8000_0000_0000;
+/// Maximum amount of physical memory the window can cover
+pub const PHYSICAL_WINDOW_SIZE: u64 = 1 << 46;
+
 // Each table entry is referenced by 9 bits, at different locations in the linear address, which
 // means each table contains 512 entries. Each entry, is a u64 -> 8 bytes, meaning that an entire
 // page table is 512 * 8 = 4096 bytes
 const PAGE_TABLE_SIZE: usize = 4096;
-// Marks that the page is present
-const PAGE_PRESENT: u64 = 1 << 0;
-// Marks that the page is writable
-const PAGE_WRITE: u64 = 1 << 1;
-// Marks that the page is USER accessible (other option is supervisor)
-const PAGE_USER: u64 = 1 << 2;
-// Execute disable. If 1 and the MSR IA32_EFER.NXE bit is 1, instruction fecthes are not allowed
-// from the region controlled by this page
-const PAGE_NXE: u64 = 1 << 63;
+// Bits 51:12 of an entry hold the physical address of the next table or of the page frame
+const PAGE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// Marks that the page is present
+pub const PAGE_PRESENT: u64 = 1 << 0;
+/// Marks that the page is writable
+pub const PAGE_WRITE: u64 = 1 << 1;
+/// Marks that the page is USER accessible (other option is supervisor)
+pub const PAGE_USER: u64 = 1 << 2;
+/// Page-level write-through
+pub const PAGE_WRITE_THROUGH: u64 = 1 << 3;
+/// Page-level cache disable
+pub const PAGE_CACHE_DISABLE: u64 = 1 << 4;
+/// Page size. In a PDPTE or PDE, marks that the entry maps a 1Gb or 2Mb page instead of pointing
+/// to another page table
+pub const PAGE_LARGE: u64 = 1 << 7;
+/// Execute disable. If 1 and the MSR IA32_EFER.NXE bit is 1, instruction fecthes are not allowed
+/// from the region controlled by this page
+pub const PAGE_NXE: u64 = 1 << 63;
 
 // A x86_64 page table
 pub enum PageTable {
@@ -64,7 +94,7 @@ pub struct PML4<'mem, A: AddressTranslate> {
     mem: &'mem mut A,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PageSize {
     Page4Kb,
     Page2Mb,
@@ -72,13 +102,27 @@ pub enum PageSize {
 }
 
 impl PageSize {
-    fn size(&self) -> u64 {
+    pub fn size(&self) -> u64 {
         match self {
             PageSize::Page4Kb => 4096,
             PageSize::Page2Mb => 2 * 1024 * 1024,
             PageSize::Page1Gb => 1 * 1024 * 1024 * 1024,
         }
     }
+
+    // Size of the page mapped by an entry found at `depth` in the walk, where the PML4 is at 0
+    fn from_depth(depth: usize) -> Self {
+        match depth {
+            1 => PageSize::Page1Gb,
+            2 => PageSize::Page2Mb,
+            _ => PageSize::Page4Kb,
+        }
+    }
+}
+
+// Index of the entry used to translate `virtual_address` in the table found at `depth`
+fn table_index(virtual_address: VirtualAddress, depth: usize) -> usize {
+    ((virtual_address.0 >> (39 - 9 * depth)) & 0x1ff) as usize
 }
 
 
@@ -166,7 +210,9 @@ impl<'mem, A: AddressTranslate> PML4<'mem, A> {
                     Layout::from_size_align(page_size.size() as usize, page_size.size() as usize)?
                 );
                 // Copy the contents of the slice into the page
-                page.copy_from(temp_slice.as_ptr(), temp_slice.len());
+                self.mem.translate(PhysicalAddress(page as u64), temp_slice.len())
+                    .ok_or(MapError::TranslationFailed(PhysicalAddress(page as u64)))?
+                    .copy_from(temp_slice.as_ptr(), temp_slice.len());
                 // Mark the page frame as present and with the desired permissions
                 page as *mut u64 as u64 | PAGE_PRESENT
                     | if rwx.write { PAGE_WRITE } else { 0 }
@@ -178,6 +224,53 @@ impl<'mem, A: AddressTranslate> PML4<'mem, A> {
         Ok(())
     }
 
+    /// Map `size` bytes of already allocated memory found at `physical_address` to
+    /// `virtual_address`, with 4Kb pages and the `rwx` permissions.
+    pub fn map_physical(
+        &mut self,
+        virtual_address: VirtualAddress,
+        physical_address: PhysicalAddress,
+        size: u64,
+        rwx: RWX,
+    ) -> Result<(), MapError> {
+        let page_size = PageSize::Page4Kb;
+        if physical_address.0 & (page_size.size() - 1) != 0 {
+            return Err(MapError::AddressUnaligned((virtual_address, page_size.size())));
+        }
+
+        for offset in (0..size).step_by(page_size.size() as usize) {
+            let raw = physical_address.0.saturating_add(offset) | PAGE_PRESENT
+                | if rwx.write { PAGE_WRITE } else { 0 }
+                | if !rwx.execute { PAGE_NXE } else { 0 };
+            self.map_page(VirtualAddress(virtual_address.0.saturating_add(offset)), raw, page_size)?;
+        }
+
+        Ok(())
+    }
+
+    /// Map `size` bytes of device memory found at `physical_address` to `virtual_address`. The
+    /// region is mapped with 4Kb pages as read-write, not executable and with caching disabled.
+    pub fn map_mmio(
+        &mut self,
+        virtual_address: VirtualAddress,
+        physical_address: PhysicalAddress,
+        size: u64,
+    ) -> Result<(), MapError> {
+        let page_size = PageSize::Page4Kb;
+        // The physical frame has to be aligned as well, we do not adjust it for the caller
+        if physical_address.0 & (page_size.size() - 1) != 0 {
+            return Err(MapError::AddressUnaligned((virtual_address, page_size.size())));
+        }
+
+        for offset in (0..size).step_by(page_size.size() as usize) {
+            let raw = physical_address.0.saturating_add(offset) | PAGE_PRESENT | PAGE_WRITE
+                | PAGE_WRITE_THROUGH | PAGE_CACHE_DISABLE | PAGE_NXE;
+            self.map_page(VirtualAddress(virtual_address.0.saturating_add(offset)), raw, page_size)?;
+        }
+
+        Ok(())
+    }
+
     /// Map a virtual address using the 4-level paging translation, with a page frame `raw` of size
     /// `page_size` with the desired `rwx` read, write, execute permissions.
     /// The page frame located at `raw` has to already be allocated and must be of size `page_size`
@@ -204,135 +297,355 @@ impl<'mem, A: AddressTranslate> PML4<'mem, A> {
         // - PDE (page directory entry) -> bits 29:20 of the linear address
         // - PTE (page table entry) -> bits 20:12 of the linear address
         // - PageFrame (page table entry) -> bits 11:0 of the linear address
-        let page_table_ptrs = match page_size {
-            PageSize::Page4Kb => {
-                [
-                    Some((virtual_address.0 >> 39) & 0x1ff),
-                    Some((virtual_address.0 >> 30) & 0x1ff),
-                    Some((virtual_address.0 >> 21) & 0x1ff),
-                    Some((virtual_address.0 >> 12) & 0x1ff),
-                ]
-            }
-            PageSize::Page2Mb => {
-                [
-                    Some((virtual_address.0 >> 39) & 0x1ff),
-                    Some((virtual_address.0 >> 30) & 0x1ff),
-                    Some((virtual_address.0 >> 21) & 0x1ff),
-                    None,
-                ]
-            }
-            PageSize::Page1Gb => {
-                [
-                    Some((virtual_address.0 >> 39) & 0x1ff),
-                    Some((virtual_address.0 >> 30) & 0x1ff),
-                    None,
-                    None,
-                ]
-            }
+        // Large pages are mapped directly by the PDPTE (1Gb) or PDE (2Mb), which then become the
+        // last level of the translation and have the page size bit set
+        let (levels, raw) = match page_size {
+            PageSize::Page4Kb => (4, raw),
+            PageSize::Page2Mb => (3, raw | PAGE_LARGE),
+            PageSize::Page1Gb => (2, raw | PAGE_LARGE),
         };
 
         // We start at the cr3 root
         let mut next_table = self.cr3_root.0;
 
-        for (depth, maybe_page_table_ptr) in page_table_ptrs.iter().enumerate() {
-            // At this point, the table does exist
-            if let Some(page_table_ptr) = maybe_page_table_ptr {
-                // First we go to the table
-                next_table = next_table & 0xffffffffff000;
-                // Cast the address into a pointer, where each entry is of size `u64`
-                let mut table_ptr = next_table as *mut u64;
-                unsafe {
-                    // Move to the desired entry for that level
-                    table_ptr = table_ptr.add((*page_table_ptr) as usize);
-
-                    // If we are at the last table
-                    if depth == page_table_ptrs.len() - 1 {
-                        // If the entry is currently present and we are in 64 bit mode
-                        if (*table_ptr & PAGE_PRESENT) != 0
-                            && core::mem::size_of::<usize>() != core::mem::size_of::<u64>(){
-                            // We caused an update, we need to invalidate the TLB
-                            x86::invlpg(raw);
-                        }
-                        // Update the page with the desired entry
-                        *table_ptr = raw;
-                        // Mapping done, we return success
-                        return Ok(());
-                    } else {
-                        // If the table is not yet preset, we allocate it
-                        if (*table_ptr & PAGE_PRESENT) == 0 {
-                            let temp_table_ptr =
-                                // This should not use the `alloc` crate allocation methods since we want
-                                // to keep the pointer valid beyond this scope.
-                                self.mem.alloc_zeroed(
-                                    Layout::from_size_align(PAGE_TABLE_SIZE, PAGE_TABLE_SIZE)?
-                                );
-                            // We asign the new address to our pointer
-                            *table_ptr = temp_table_ptr as *mut u64 as u64 ;
-                            *table_ptr = *table_ptr | PAGE_PRESENT | PAGE_USER | PAGE_WRITE;
-                        }
+        for depth in 0..levels {
+            unsafe {
+                // Move to the desired entry for that level
+                let table_ptr = self.entry(next_table, table_index(virtual_address, depth))?;
+
+                // If we are at the last table
+                if depth == levels - 1 {
+                    let present = (*table_ptr & PAGE_PRESENT) != 0;
+                    // Update the page with the desired entry
+                    *table_ptr = raw;
+                    // If we replaced a mapping, we need to invalidate the TLB
+                    if present {
+                        self.mem.invalidate(virtual_address);
                     }
-                    next_table= *table_ptr;
+                    // Mapping done, we return success
+                    return Ok(());
                 }
-            } else {
-                // The biggest page frame we can have is 1Gb, which needs 2 indirect pointers to be
-                // addressed. As such, if we are at a lower depth than 3, this means something is
-                // not ok. Technically, this is solely dependant on how we mapped the table
-                // pointers, so it could not fail currently.
-                if depth < 2 {
-                    return Err(MapError::PagePointerZero(depth));
+
+                // If the table is not yet preset, we allocate it
+                if (*table_ptr & PAGE_PRESENT) == 0 {
+                    let temp_table_ptr =
+                        // This should not use the `alloc` crate allocation methods since we want
+                        // to keep the pointer valid beyond this scope.
+                        self.mem.alloc_zeroed(
+                            Layout::from_size_align(PAGE_TABLE_SIZE, PAGE_TABLE_SIZE)?
+                        );
+                    // We asign the new address to our pointer
+                    *table_ptr = temp_table_ptr as u64 | PAGE_PRESENT | PAGE_USER | PAGE_WRITE;
+                } else if (*table_ptr & PAGE_LARGE) != 0 {
+                    // A large page already covers this address, we cannot walk through it
+                    return Err(MapError::LargePageConflict((virtual_address, depth)));
                 }
+                next_table = *table_ptr;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the physical address `virtual_address` translates to, the size of the page which
+    /// maps it and the flags of that page, or `None` if it is not mapped.
+    pub fn translate(&self, virtual_address: VirtualAddress)
+        -> Option<(PhysicalAddress, PageSize, u64)> {
+        let (path, leaf) = self.walk(virtual_address).ok()?;
+        let entry = unsafe { *path[leaf] };
+        let page_size = PageSize::from_depth(leaf);
+        let offset_mask = page_size.size() - 1;
+        let physical_address = (entry & PAGE_ADDRESS_MASK & !offset_mask)
+            | (virtual_address.0 & offset_mask);
+
+        Some((PhysicalAddress(physical_address), page_size, entry & !PAGE_ADDRESS_MASK))
+    }
+
+    /// Returns the raw entries used at each level to translate `virtual_address`, starting with the
+    /// PML4. The walk stops at the entry mapping the page or at the first one which is not
+    /// present, the levels after it are `None`.
+    pub fn page_walk(&self, virtual_address: VirtualAddress) -> [Option<u64>; 4] {
+        let mut entries = [None; 4];
+        let mut next_table = self.cr3_root.0;
+
+        for (depth, slot) in entries.iter_mut().enumerate() {
+            let index = table_index(virtual_address, depth);
+            let Ok(entry_ptr) = (unsafe { self.entry(next_table, index) }) else {
+                break;
+            };
+            let entry = unsafe { *entry_ptr };
+            *slot = Some(entry);
+            let leaf = depth == 3 || (depth > 0 && entry & PAGE_LARGE != 0);
+            if entry & PAGE_PRESENT == 0 || leaf {
+                break;
+            }
+            next_table = entry;
+        }
+
+        entries
+    }
+
+    /// Remove the mapping of the page starting at `virtual_address` and invalidate it from the
+    /// TLB. Page tables which no longer map anything are given back to the allocator. Returns the
+    /// page frame that was mapped, which is left to the caller to free.
+    pub fn unmap_page(&mut self, virtual_address: VirtualAddress)
+        -> Result<(PhysicalAddress, PageSize), MapError> {
+        let (path, leaf) = self.walk(virtual_address)?;
+        let page_size = PageSize::from_depth(leaf);
+        if virtual_address.0 & (page_size.size() - 1) != 0 {
+            return Err(MapError::AddressUnaligned((virtual_address, page_size.size())));
+        }
+
+        let layout = Layout::from_size_align(PAGE_TABLE_SIZE, PAGE_TABLE_SIZE)?;
+        unsafe {
+            let entry = *path[leaf];
+            *path[leaf] = 0;
+
+            // Go up the walk and free the tables left empty. The PML4 is never freed.
+            for depth in (1..=leaf).rev() {
+                let table = *path[depth - 1] & PAGE_ADDRESS_MASK;
+                if !self.is_table_empty(table)? {
+                    break;
+                }
+                *path[depth - 1] = 0;
+                self.mem.dealloc(table as *mut u8, layout);
+            }
+            self.mem.invalidate(virtual_address);
+
+            Ok((PhysicalAddress(entry & PAGE_ADDRESS_MASK & !(page_size.size() - 1)), page_size))
+        }
+    }
+
+    /// Change the permissions of the pages mapping the `size` bytes at `virtual_address` to
+    /// `rwx`. The range has to cover whole pages, as large pages are never split. If an error
+    /// occurs, the pages before the offending one keep their new permissions.
+    pub fn protect(
+        &mut self,
+        virtual_address: VirtualAddress,
+        size: u64,
+        rwx: RWX,
+    ) -> Result<(), MapError> {
+        let end = virtual_address.0.checked_add(size).ok_or(MapError::RangeOverflow)?;
+
+        let mut address = virtual_address.0;
+        while address < end {
+            let (path, leaf) = self.walk(VirtualAddress(address))?;
+            let page_size = PageSize::from_depth(leaf);
+            if address & (page_size.size() - 1) != 0 || end - address < page_size.size() {
+                return Err(MapError::AddressUnaligned((VirtualAddress(address), page_size.size())));
+            }
+
+            unsafe {
+                *path[leaf] = (*path[leaf] & !(PAGE_WRITE | PAGE_NXE))
+                    | if rwx.write { PAGE_WRITE } else { 0 }
+                    | if !rwx.execute { PAGE_NXE } else { 0 };
+                self.mem.invalidate(VirtualAddress(address));
+            }
+            address = address.saturating_add(page_size.size());
+        }
+
+        Ok(())
+    }
+
+    // Returns a pointer to the entry at `index` in the page table found at the physical address
+    // `table`
+    unsafe fn entry(&self, table: u64, index: usize) -> Result<*mut u64, MapError> {
+        let table = PhysicalAddress(table & PAGE_ADDRESS_MASK);
+        let table_ptr = self.mem.translate(table, PAGE_TABLE_SIZE)
+            .ok_or(MapError::TranslationFailed(table))? as *mut u64;
+        Ok(table_ptr.add(index))
+    }
+
+    // Returns `true` if no entry of the page table found at the physical address `table` is used
+    unsafe fn is_table_empty(&self, table: u64) -> Result<bool, MapError> {
+        let table = PhysicalAddress(table);
+        let table_ptr = self.mem.translate(table, PAGE_TABLE_SIZE)
+            .ok_or(MapError::TranslationFailed(table))? as *const u64;
+        Ok(core::slice::from_raw_parts(table_ptr, PAGE_TABLE_SIZE / 8).iter().all(|e| *e == 0))
+    }
+
+    // Walk the page tables for `virtual_address`, down to the entry mapping its page. Returns the
+    // pointers to the entries used at each level, starting with the PML4, and the depth of the
+    // entry mapping the page.
+    fn walk(&self, virtual_address: VirtualAddress) -> Result<([*mut u64; 4], usize), MapError> {
+        let mut path = [core::ptr::null_mut(); 4];
+        let mut leaf = None;
+        let mut next_table = self.cr3_root.0;
+
+        for (depth, entry_ptr) in path.iter_mut().enumerate() {
+            *entry_ptr = unsafe { self.entry(next_table, table_index(virtual_address, depth))? };
+            let entry = unsafe { **entry_ptr };
+            if entry & PAGE_PRESENT == 0 {
+                return Err(MapError::NotMapped(virtual_address));
+            }
+            // The PML4 cannot map pages, the other levels can if the page size bit is set
+            if depth == 3 || (depth > 0 && entry & PAGE_LARGE != 0) {
+                leaf = Some(depth);
+                break;
             }
+            next_table = entry;
+        }
+
+        leaf.map(|leaf| (path, leaf)).ok_or(MapError::NotMapped(virtual_address))
+    }
+
+    /// Map `size` bytes of physical memory found at `physical_address` to `virtual_address`, with
+    /// the `rwx` permissions. Each page is the largest one allowed by the alignment of both
+    /// addresses and the remaining size, which saves a lot of page tables for big regions.
+    pub fn map_range(
+        &mut self,
+        virtual_address: VirtualAddress,
+        physical_address: PhysicalAddress,
+        size: u64,
+        rwx: RWX,
+    ) -> Result<(), MapError> {
+        let page_sizes: &[PageSize] = if x86::page_1gb_supported() {
+            &[PageSize::Page1Gb, PageSize::Page2Mb, PageSize::Page4Kb]
+        } else {
+            &[PageSize::Page2Mb, PageSize::Page4Kb]
+        };
+
+        let mut offset = 0;
+        while offset < size {
+            let vaddr = virtual_address.0.checked_add(offset).ok_or(MapError::RangeOverflow)?;
+            let paddr = physical_address.0.checked_add(offset).ok_or(MapError::RangeOverflow)?;
+            let remaining = size - offset;
+
+            // Pick the largest page that fits, falling back to 4Kb pages which have to be aligned
+            let page_size = page_sizes.iter().copied()
+                .find(|page_size| {
+                    let mask = page_size.size() - 1;
+                    vaddr & mask == 0 && paddr & mask == 0 && remaining >= page_size.size()
+                })
+                .unwrap_or(PageSize::Page4Kb);
+            if paddr & (page_size.size() - 1) != 0 {
+                return Err(MapError::AddressUnaligned((VirtualAddress(vaddr), page_size.size())));
+            }
+
+            let raw = paddr | PAGE_PRESENT
+                | if rwx.write { PAGE_WRITE } else { 0 }
+                | if !rwx.execute { PAGE_NXE } else { 0 };
+            self.map_page(VirtualAddress(vaddr), raw, page_size)?;
+            offset = offset.saturating_add(page_size.size());
         }
 
         Ok(())
     }
+
+    /// Identity map `size` bytes of physical memory starting at `physical_address`, with the `rwx`
+    /// permissions and the largest pages possible.
+    pub fn map_range_identity(
+        &mut self,
+        physical_address: PhysicalAddress,
+        size: u64,
+        rwx: RWX,
+    ) -> Result<(), MapError> {
+        self.map_range(VirtualAddress(physical_address.0), physical_address, size, rwx)
+    }
 }
 
-impl AddressTranslate for Mmu {
+impl<S: RangeSetOps> AddressTranslate for Mmu<S> {
     unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
         self
             .allocate(layout.size() as u64, layout.align() as u64)
             .expect("Failed to allocate memory")
             as *mut u8
     }
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let start = ptr as u64;
+        let last = (layout.size() as u64).checked_sub(1).and_then(|size| start.checked_add(size));
+        if let Some(end) = last {
+            self.deallocate(start..=end);
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
     unsafe fn translate(&self, physical_address: PhysicalAddress, size: usize) -> Option<*mut u8> {
         // We do not alloc 0 sized allocations
         if size == 0 {
             return None;
         }
-        // Convert the physical address into the size of the bootloader target
+        // The bootloader runs without paging, so the physical address is usable as is once
+        // converted into the size of the bootloader target
         let phys_addr = usize::try_from(physical_address.0).ok()?;
         // Check if the allocation `size` fits into our allowed space
         let _ = phys_addr.checked_add(size)?;
         // Return the physical address pointer
         Some(phys_addr as *mut u8)
     }
+    #[cfg(target_arch = "x86_64")]
+    unsafe fn translate(&self, physical_address: PhysicalAddress, size: usize) -> Option<*mut u8> {
+        // We do not alloc 0 sized allocations
+        if size == 0 {
+            return None;
+        }
+        // The kernel reaches physical memory through the window set up by the bootloader
+        let end = physical_address.0.checked_add(u64::try_from(size).ok()?)?;
+        if end > PHYSICAL_WINDOW_SIZE {
+            return None;
+        }
+        Some(PHYSICAL_WINDOW_BASE.wrapping_add(physical_address.0) as *mut u8)
+    }
 }
 
+/// Physical memory manager. The bootloader hands it over to the kernel with a fixed size
+/// `RangeSet`, which the kernel turns into a `GrowableRangeSet` with `into_growable`.
 #[repr(C)]
-pub struct Mmu {
+pub struct Mmu<S = RangeSet> {
     // Describes the current free memory we have left on the device
-    set: RangeSet,
+    set: S,
 }
 
-impl Mmu {
-    pub fn new(set: RangeSet) -> Self {
+impl Mmu<RangeSet> {
+    /// Move the free memory in a set which grows into memory it allocates from itself, accessed
+    /// through `translate`, once it runs out of entries
+    pub fn into_growable(self, translate: Translate) -> Mmu<GrowableRangeSet> {
+        let mut set = GrowableRangeSet::from_fixed(&self.set);
+        set.enable_growth(translate);
+        Mmu { set }
+    }
+}
+
+impl<S: RangeSetOps> Mmu<S> {
+    pub fn new(set: S) -> Self {
         Self { set }
     }
 
     /// Tries to allocate a region from physical memory with `size` bytes and aligned to a multiple
-    /// of `align` bytes. Returns the address of the new allocated address if allocation was
-    /// successful or null otherwise.
+    /// of `align` bytes, from the smallest free range it fits in. Returns the address of the new
+    /// allocated address if allocation was successful or null otherwise.
     /// Allocation could fail for one of the following reasons:
     /// - Memory is too fragmented and there isn't room to fit a continuous new block
     /// - The allocation does not fit into the pointer size of the target memory. For example
-    /// trying to allocat 0xff_ffff_ffff in a 16-bit mode.
+    ///   trying to allocat 0xff_ffff_ffff in a 16-bit mode.
     pub fn allocate(&mut self, size: u64, align: u64) -> Option<usize> {
         self.set.allocate(size, align)
     }
 
+    /// Same as `allocate`, but the whole allocation has to be inside `range`. Used for memory
+    /// with addressing constraints, like buffers for devices that can only reach the low 16MiB or
+    /// 4GiB of memory.
+    pub fn allocate_in(
+        &mut self,
+        size: u64,
+        align: u64,
+        range: RangeInclusive<u64>,
+    ) -> Option<usize> {
+        self.set.allocate_in(size, align, Range::from(range))
+    }
+
+    /// Allocate the `size` bytes of physical memory found at `address`. Fails if any of them is
+    /// not free.
+    pub fn allocate_at(&mut self, address: u64, size: u64) -> Option<usize> {
+        self.set.allocate_at(address, size)
+    }
+
     pub fn deallocate(&mut self, range: RangeInclusive<u64>) -> Option<()> {
-        self.set.insert(range)
+        self.set.insert(Range::from(range))
+    }
+
+    /// Returns the ranges of physical memory that are still free
+    pub fn free_ranges(&self) -> &[Range] {
+        self.set.entries()
     }
 }
 
@@ -345,6 +658,12 @@ pub enum MapError {
     OverflowingIdx(usize),
     RangeOverflow,
     DataOverflow(usize),
+    // The address is already covered by a large page, found at the given depth of the walk
+    LargePageConflict((VirtualAddress, usize)),
+    // The address is not mapped
+    NotMapped(VirtualAddress),
+    // The page table at this physical address is not accessible
+    TranslationFailed(PhysicalAddress),
 }
 
 impl From<core::alloc::LayoutError> for MapError {
@@ -356,104 +675,338 @@ impl From<core::alloc::LayoutError> for MapError {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use core::{
-        alloc::Layout,
-        cell::LazyCell,
-    };
+    use core::{alloc::Layout, cell::UnsafeCell};
+
+    extern crate std;
+    use std::vec::Vec;
+
+    // Physical address of the first page of the fake memory
+    const FAKE_MEMORY_BASE: u64 = 0x10_0000;
+
+    #[repr(C, align(4096))]
+    struct Page([u8; PAGE_TABLE_SIZE]);
+
+    // Physical memory backed by a `Vec` of pages, which hands out pages in order and records what
+    // was freed and invalidated
+    struct FakeMemory {
+        pages: Vec<UnsafeCell<Page>>,
+        freed: Vec<u64>,
+        invalidated: Vec<u64>,
+    }
+
+    impl FakeMemory {
+        fn new(pages: usize) -> Self {
+            // The capacity is never exceeded, such that the pages never move
+            Self { pages: Vec::with_capacity(pages), freed: Vec::new(), invalidated: Vec::new() }
+        }
 
-    extern crate alloc;
+        // Number of pages currently allocated
+        fn allocated(&self) -> usize {
+            self.pages.len() - self.freed.len()
+        }
+    }
 
-    #[derive(Debug)]
-    pub struct Allocator;
+    impl AddressTranslate for FakeMemory {
+        unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+            assert!(layout.align() <= PAGE_TABLE_SIZE);
+            let pages = layout.size().div_ceil(PAGE_TABLE_SIZE).max(1);
+            assert!(self.pages.len() + pages <= self.pages.capacity(), "Fake memory is full");
 
-    impl AddressTranslate for Allocator {
-        fn alloc(&mut self, layout: Layout) -> *mut u8 {
-            alloc::vec![0u8; layout.size()].as_mut_ptr()
+            let address = FAKE_MEMORY_BASE + (self.pages.len() * PAGE_TABLE_SIZE) as u64;
+            // Fill the pages with garbage, such that we catch tables that are not zeroed
+            (0..pages).for_each(|_| self.pages.push(UnsafeCell::new(Page([0xcc; PAGE_TABLE_SIZE]))));
+            address as *mut u8
+        }
+        unsafe fn dealloc(&mut self, ptr: *mut u8, _layout: Layout) {
+            self.freed.push(ptr as u64);
+        }
+        unsafe fn translate(&self, physical_address: PhysicalAddress, size: usize)
+            -> Option<*mut u8> {
+            let offset = usize::try_from(physical_address.0.checked_sub(FAKE_MEMORY_BASE)?).ok()?;
+            if offset.checked_add(size)? > self.pages.len() * PAGE_TABLE_SIZE {
+                return None;
+            }
+            Some(UnsafeCell::raw_get(self.pages.as_ptr()).cast::<u8>().add(offset))
+        }
+        unsafe fn invalidate(&mut self, virtual_address: VirtualAddress) {
+            self.invalidated.push(virtual_address.0);
         }
     }
 
+    const RWX_ALL: RWX = RWX { read: true, write: true, execute: true };
+    const RW: RWX = RWX { read: true, write: true, execute: false };
+
     #[test]
     fn test_4kb_page_ok() {
-        /// Create a new memory unit of 4MB
-        let physical_memory = alloc::vec![0u8; 4096];
-        let cr3 = physical_memory.as_ptr() as u64;
+        let mut memory = FakeMemory::new(8);
+        let mut pml4 = unsafe { PML4::new(&mut memory).unwrap() };
         let virt_addr = VirtualAddress(0x0123_8000);
-        let pml4 = unsafe { PML4::from_addr(PhysicalAddress(cr3)) };
-        let mut allocator = Allocator;
-        let raw: Option<u64> = Some(0x1337_b00b);
 
-        let mapped_page = pml4.map(virt_addr, raw, PageSize::Page4Kb, &mut allocator);
+        let mapped_page = pml4.map_page(virt_addr, 0x1337_b000 | PAGE_PRESENT, PageSize::Page4Kb);
 
         assert!(mapped_page.is_ok());
+        assert_eq!(
+            pml4.translate(VirtualAddress(0x0123_8abc)),
+            Some((PhysicalAddress(0x1337_babc), PageSize::Page4Kb, PAGE_PRESENT)),
+        );
     }
 
     #[test]
     fn test_4kb_page_err() {
-        let physical_memory = alloc::vec![0u8; 4096];
-        let cr3 = physical_memory.as_ptr() as u64;
+        let mut memory = FakeMemory::new(8);
+        let mut pml4 = unsafe { PML4::new(&mut memory).unwrap() };
         let virt_addr = VirtualAddress(0x0123_8100);
-        let pml4 = unsafe { PML4::from_addr(PhysicalAddress(cr3)) };
-        let mut allocator = Allocator;
-        let raw: Option<u64> = Some(0x1337_b00b);
 
-        let mapped_page = pml4.map(virt_addr, raw, PageSize::Page4Kb, &mut allocator);
+        let mapped_page = pml4.map_page(virt_addr, 0x1337_b000 | PAGE_PRESENT, PageSize::Page4Kb);
 
         assert!(mapped_page.is_err());
     }
 
     #[test]
     fn test_2mb_page_ok() {
+        let mut memory = FakeMemory::new(8);
+        let mut pml4 = unsafe { PML4::new(&mut memory).unwrap() };
         let virt_addr = VirtualAddress(0x0123 << 21);
-        let physical_memory = alloc::vec![0u8; 4096];
-        let cr3 = physical_memory.as_ptr() as u64;
-        let pml4 = unsafe { PML4::from_addr(PhysicalAddress(cr3)) };
-        let mut allocator = Allocator;
-        let raw: Option<u64> = Some(0x1337_b00b);
 
-        let mapped_page = pml4.map(virt_addr, raw, PageSize::Page2Mb, &mut allocator);
+        let mapped_page = pml4.map_page(virt_addr, 0x4000_0000 | PAGE_PRESENT, PageSize::Page2Mb);
 
         assert!(mapped_page.is_ok());
+        // The PDE maps the page directly
+        assert_eq!(
+            pml4.translate(VirtualAddress((0x0123 << 21) + 0x1_2345)),
+            Some((PhysicalAddress(0x4001_2345), PageSize::Page2Mb, PAGE_PRESENT | PAGE_LARGE)),
+        );
+
+        // The walk ends at the PDE, and at the first entry which is not present
+        let walk = pml4.page_walk(virt_addr);
+        assert!(walk[..2].iter().all(|entry| entry.is_some_and(|e| e & PAGE_PRESENT != 0)));
+        assert_eq!(walk[2], Some(0x4000_0000 | PAGE_PRESENT | PAGE_LARGE));
+        assert_eq!(walk[3], None);
+        assert_eq!(pml4.page_walk(VirtualAddress(0x0124 << 21))[2..], [Some(0), None]);
+        assert_eq!(pml4.page_walk(VirtualAddress(1 << 39))[1..], [None, None, None]);
     }
 
     #[test]
     fn test_2mb_page_err() {
+        let mut memory = FakeMemory::new(8);
+        let mut pml4 = unsafe { PML4::new(&mut memory).unwrap() };
         let virt_addr = VirtualAddress(0x0123 << 20);
-        let physical_memory = alloc::vec![0u8; 4096];
-        let cr3 = physical_memory.as_ptr() as u64;
-        let pml4 = unsafe { PML4::from_addr(PhysicalAddress(cr3)) };
-        let mut allocator = Allocator;
-        let raw: Option<u64> = Some(0x1337_b00b);
 
-        let mapped_page = pml4.map(virt_addr, raw, PageSize::Page2Mb, &mut allocator);
+        let mapped_page = pml4.map_page(virt_addr, 0x4000_0000 | PAGE_PRESENT, PageSize::Page2Mb);
 
         assert!(mapped_page.is_err());
     }
 
     #[test]
     fn test_1gb_page_ok() {
-        let virt_addr = VirtualAddress(0x1234_1234_8000_0000);
-        let physical_memory = alloc::vec![0u8; 4096];
-        let cr3 = physical_memory.as_ptr() as u64;
-        let pml4 = unsafe { PML4::from_addr(PhysicalAddress(cr3)) };
-        let mut allocator = Allocator;
-        let raw: Option<u64> = Some(0x1337_b00b);
+        let mut memory = FakeMemory::new(8);
+        let mut pml4 = unsafe { PML4::new(&mut memory).unwrap() };
+        let virt_addr = VirtualAddress(0x1234_1234_8000_0000 & 0xffff_ffff_c000_0000);
 
-        let mapped_page = pml4.map(virt_addr, raw, PageSize::Page1Gb, &mut allocator).unwrap();
+        let mapped_page = pml4.map_page(virt_addr, 0x4000_0000 | PAGE_PRESENT, PageSize::Page1Gb);
 
         assert!(mapped_page.is_ok());
+        assert_eq!(
+            pml4.translate(VirtualAddress(virt_addr.0 + 0x20_1000)),
+            Some((PhysicalAddress(0x4020_1000), PageSize::Page1Gb, PAGE_PRESENT | PAGE_LARGE)),
+        );
     }
 
     #[test]
     fn test_1gb_page_err() {
+        let mut memory = FakeMemory::new(8);
+        let mut pml4 = unsafe { PML4::new(&mut memory).unwrap() };
         let virt_addr = VirtualAddress(0x0123 << 29);
-        let physical_memory = alloc::vec![0u8; 4096];
-        let cr3 = physical_memory.as_ptr() as u64;
-        let pml4 = unsafe { PML4::from_addr(PhysicalAddress(cr3)) };
-        let mut allocator = Allocator;
-        let raw: Option<u64> = Some(0x1337_b00b);
 
-        let mapped_page = pml4.map(virt_addr, raw, PageSize::Page1Gb, &mut allocator);
+        let mapped_page = pml4.map_page(virt_addr, 0x4000_0000 | PAGE_PRESENT, PageSize::Page1Gb);
 
         assert!(mapped_page.is_err());
     }
+
+    #[test]
+    fn large_page_conflict() {
+        let mut memory = FakeMemory::new(8);
+        let mut pml4 = unsafe { PML4::new(&mut memory).unwrap() };
+
+        pml4.map_page(VirtualAddress(0x20_0000), 0x20_0000 | PAGE_PRESENT, PageSize::Page2Mb)
+            .unwrap();
+        // A 4Kb page cannot be placed under the 2Mb one
+        assert!(matches!(
+            pml4.map_page(VirtualAddress(0x20_1000), 0x1000 | PAGE_PRESENT, PageSize::Page4Kb),
+            Err(MapError::LargePageConflict((VirtualAddress(0x20_1000), 2))),
+        ));
+    }
+
+    #[test]
+    fn map_range_identity() {
+        let mut memory = FakeMemory::new(8);
+        let mut pml4 = unsafe { PML4::new(&mut memory).unwrap() };
+
+        pml4.map_range_identity(PhysicalAddress(0), 4 * 1024 * 1024 * 1024, RWX_ALL).unwrap();
+
+        let page_size =
+            if x86::page_1gb_supported() { PageSize::Page1Gb } else { PageSize::Page2Mb };
+        for addr in [0, 0x1000, 0x20_0000, 0x4000_0000, 0xffff_f000] {
+            let (phys, size, _) = pml4.translate(VirtualAddress(addr)).expect("Not mapped");
+            assert_eq!(phys, PhysicalAddress(addr));
+            assert_eq!(size, page_size);
+        }
+        assert!(pml4.translate(VirtualAddress(0x1_0000_0000)).is_none());
+
+        // PML4 and PDPT, plus 4 page directories when using 2Mb pages
+        let tables = if x86::page_1gb_supported() { 2 } else { 6 };
+        assert_eq!(memory.allocated(), tables);
+    }
+
+    #[test]
+    fn map_range_unaligned() {
+        let mut memory = FakeMemory::new(16);
+        let mut pml4 = unsafe { PML4::new(&mut memory).unwrap() };
+
+        // A 4Kb page, a 2Mb page and another 4Kb page
+        pml4.map_range(
+            VirtualAddress(0x1f_f000),
+            PhysicalAddress(0x3f_f000),
+            0x20_2000,
+            RWX { read: true, write: false, execute: false },
+        ).unwrap();
+
+        let flags = PAGE_PRESENT | PAGE_NXE;
+        assert_eq!(
+            pml4.translate(VirtualAddress(0x1f_f000)),
+            Some((PhysicalAddress(0x3f_f000), PageSize::Page4Kb, flags)),
+        );
+        assert_eq!(
+            pml4.translate(VirtualAddress(0x20_0000)),
+            Some((PhysicalAddress(0x40_0000), PageSize::Page2Mb, flags | PAGE_LARGE)),
+        );
+        assert_eq!(
+            pml4.translate(VirtualAddress(0x40_0000)),
+            Some((PhysicalAddress(0x60_0000), PageSize::Page4Kb, flags)),
+        );
+        assert!(pml4.translate(VirtualAddress(0x40_1000)).is_none());
+
+        // Physical and virtual addresses with different alignments can only use 4Kb pages
+        pml4.map_range(VirtualAddress(0x8000_0000), PhysicalAddress(0x1000), 0x20_0000, RWX_ALL)
+            .unwrap();
+        assert_eq!(
+            pml4.translate(VirtualAddress(0x801f_f000)),
+            Some((PhysicalAddress(0x20_0000), PageSize::Page4Kb, PAGE_PRESENT | PAGE_WRITE)),
+        );
+
+        assert!(matches!(
+            pml4.map_range(VirtualAddress(0), PhysicalAddress(0x100), 0x1000, RWX_ALL),
+            Err(MapError::AddressUnaligned(_)),
+        ));
+    }
+
+    #[test]
+    fn unmap_page() {
+        let mut memory = FakeMemory::new(8);
+        let cr3 = {
+            let mut pml4 = unsafe { PML4::new(&mut memory).unwrap() };
+            pml4.map_range(VirtualAddress(0xb00_0000_0000), PhysicalAddress(0x5000), 0x2000, RW)
+                .unwrap();
+
+            // The page has to be unmapped from its start
+            assert!(matches!(
+                pml4.unmap_page(VirtualAddress(0xb00_0000_0010)),
+                Err(MapError::AddressUnaligned(_)),
+            ));
+            assert_eq!(
+                pml4.unmap_page(VirtualAddress(0xb00_0000_0000)).unwrap(),
+                (PhysicalAddress(0x5000), PageSize::Page4Kb),
+            );
+            assert!(pml4.translate(VirtualAddress(0xb00_0000_0000)).is_none());
+            assert!(pml4.translate(VirtualAddress(0xb00_0000_1000)).is_some());
+            assert!(matches!(
+                pml4.unmap_page(VirtualAddress(0xb00_0000_0000)),
+                Err(MapError::NotMapped(VirtualAddress(0xb00_0000_0000))),
+            ));
+            pml4.cr3()
+        };
+        // The page table still maps the second page
+        assert!(memory.freed.is_empty());
+        assert_eq!(memory.invalidated, [0xb00_0000_0000]);
+
+        // Unmapping the last page frees the PT, PD and PDPT but not the PML4
+        let mut pml4 = unsafe { PML4::from_addr(&mut memory, cr3).unwrap() };
+        assert_eq!(
+            pml4.unmap_page(VirtualAddress(0xb00_0000_1000)).unwrap(),
+            (PhysicalAddress(0x6000), PageSize::Page4Kb),
+        );
+        assert!(pml4.translate(VirtualAddress(0xb00_0000_1000)).is_none());
+        assert_eq!(memory.freed.len(), 3);
+        assert_eq!(memory.allocated(), 1);
+        assert!(!memory.freed.contains(&cr3.0));
+        assert_eq!(memory.invalidated, [0xb00_0000_0000, 0xb00_0000_1000]);
+
+        // Large pages are unmapped whole
+        let mut pml4 = unsafe { PML4::from_addr(&mut memory, cr3).unwrap() };
+        pml4.map_page(VirtualAddress(0x20_0000), 0x20_0000 | PAGE_PRESENT, PageSize::Page2Mb)
+            .unwrap();
+        assert_eq!(
+            pml4.unmap_page(VirtualAddress(0x20_0000)).unwrap(),
+            (PhysicalAddress(0x20_0000), PageSize::Page2Mb),
+        );
+        assert_eq!(memory.freed.len(), 5);
+    }
+
+    #[test]
+    fn protect() {
+        let mut memory = FakeMemory::new(8);
+        let mut pml4 = unsafe { PML4::new(&mut memory).unwrap() };
+        pml4.map_range(VirtualAddress(0x1000), PhysicalAddress(0x1000), 0x3000, RW).unwrap();
+        pml4.map_page(VirtualAddress(0x20_0000), 0x20_0000 | PAGE_PRESENT, PageSize::Page2Mb)
+            .unwrap();
+
+        // Make the middle page read-only and executable
+        let rx = RWX { read: true, write: false, execute: true };
+        pml4.protect(VirtualAddress(0x2000), 0x1000, rx).unwrap();
+        let flags = |pml4: &PML4<_>, addr| pml4.translate(VirtualAddress(addr)).unwrap().2;
+        assert_eq!(flags(&pml4, 0x1000), PAGE_PRESENT | PAGE_WRITE | PAGE_NXE);
+        assert_eq!(flags(&pml4, 0x2000), PAGE_PRESENT);
+        assert_eq!(flags(&pml4, 0x3000), PAGE_PRESENT | PAGE_WRITE | PAGE_NXE);
+
+        // A large page cannot be changed partially
+        assert!(matches!(
+            pml4.protect(VirtualAddress(0x20_0000), 0x1000, RW),
+            Err(MapError::AddressUnaligned(_)),
+        ));
+        pml4.protect(VirtualAddress(0x20_0000), 0x20_0000, RW).unwrap();
+        assert_eq!(flags(&pml4, 0x20_0000), PAGE_PRESENT | PAGE_LARGE | PAGE_WRITE | PAGE_NXE);
+
+        // Holes are reported
+        assert!(matches!(
+            pml4.protect(VirtualAddress(0x1000), 0x5000, RW),
+            Err(MapError::NotMapped(VirtualAddress(0x4000))),
+        ));
+        assert_eq!(memory.invalidated, [0x2000, 0x20_0000, 0x1000, 0x2000, 0x3000]);
+    }
+
+    // The growable set is given memory of the host, which it accesses as is
+    fn host_memory(address: u64) -> *mut u8 {
+        address as *mut u8
+    }
+
+    #[test]
+    fn test_growable_mmu_fragmentation() {
+        let memory: &'static mut [Page] = Vec::leak(
+            (0..256).map(|_| Page([0; PAGE_TABLE_SIZE])).collect(),
+        );
+        let start = memory.as_ptr() as u64;
+        let mut set = RangeSet::new();
+        set.insert(Range::new(start, start + (memory.len() * PAGE_TABLE_SIZE) as u64 - 1));
+        let mut mmu = Mmu::new(set).into_growable(host_memory);
+
+        let pages = (0..200)
+            .map(|_| mmu.allocate(PAGE_TABLE_SIZE as u64, PAGE_TABLE_SIZE as u64).unwrap() as u64)
+            .collect::<Vec<_>>();
+        // Freeing every other page leaves 100 free ranges, more than a `RangeSet` holds
+        for page in pages.iter().step_by(2) {
+            assert_eq!(mmu.deallocate(*page..=*page + PAGE_TABLE_SIZE as u64 - 1), Some(()));
+        }
+        assert!(mmu.free_ranges().len() >= 100);
+    }
+
 }
@@ -0,0 +1,421 @@
+//! Minimal ARP, IPv4 and UDP stack on top of a device sending and receiving Ethernet frames. It
+//! only talks to hosts on its subnet and to its gateway, and drops fragmented packets.
+#![no_std]
+
+pub mod wire;
+
+use wire::{
+    ArpPacket, EthernetHeader, Ipv4Header, UdpHeader, ARP_PACKET_SIZE, ARP_REPLY, ARP_REQUEST,
+    ETHERNET_HEADER_SIZE, ETHERTYPE_ARP, ETHERTYPE_IPV4, IPV4_HEADER_SIZE, IP_PROTOCOL_UDP,
+    UDP_HEADER_SIZE,
+};
+
+pub type MacAddress = [u8; 6];
+pub type Ipv4Address = [u8; 4];
+
+pub const BROADCAST_MAC: MacAddress = [0xff; 6];
+pub const BROADCAST_IP: Ipv4Address = [0xff; 4];
+
+/// Largest IPv4 packet we send or receive
+pub const MTU: usize = 1500;
+/// Largest Ethernet frame, without the frame check sequence
+pub const MAX_FRAME_SIZE: usize = ETHERNET_HEADER_SIZE + MTU;
+// Shorter frames are padded with zeros, as Ethernet requires
+const MIN_FRAME_SIZE: usize = 60;
+/// Largest UDP payload that fits in one packet
+pub const MAX_UDP_PAYLOAD: usize = MTU - IPV4_HEADER_SIZE - UDP_HEADER_SIZE;
+
+// Number of IP addresses whose Ethernet address we remember
+const ARP_CACHE_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    // The payload does not fit in a single packet
+    PayloadTooLarge(usize),
+    // The destination is not on our subnet and we have no gateway
+    NoRoute(Ipv4Address),
+    // An ARP request went out for this next hop, send again once it answered
+    ArpPending(Ipv4Address),
+    // The device could not send the frame
+    Device,
+}
+
+/// A network card sending and receiving Ethernet frames
+pub trait Device {
+    /// Returns the Ethernet address of the card
+    fn mac(&self) -> MacAddress;
+    /// Send a single frame, without the frame check sequence
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), NetError>;
+    /// Copy the next received frame in `buffer` and return its length, if there is one. Frames
+    /// longer than `buffer` are truncated.
+    fn receive(&mut self, buffer: &mut [u8]) -> Option<usize>;
+}
+
+/// IPv4 settings of an interface, usually the ones the DHCP server gave the bootloader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpConfig {
+    pub ip: Ipv4Address,
+    pub subnet_mask: Ipv4Address,
+    // Router for the hosts outside of our subnet, all zeros if there is none
+    pub gateway: Ipv4Address,
+}
+
+impl IpConfig {
+    /// Returns `true` if `ip` is on our subnet
+    pub fn is_local(&self, ip: Ipv4Address) -> bool {
+        (0..4).all(|index| (ip[index] ^ self.ip[index]) & self.subnet_mask[index] == 0)
+    }
+
+    /// Returns `true` if `ip` is the limited broadcast or the broadcast of our subnet
+    pub fn is_broadcast(&self, ip: Ipv4Address) -> bool {
+        let host_bits_set = (0..4).all(|index| ip[index] | self.subnet_mask[index] == 0xff);
+        ip == BROADCAST_IP || (self.is_local(ip) && host_bits_set)
+    }
+}
+
+/// A UDP datagram received by the interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Datagram<'a> {
+    pub source: Ipv4Address,
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub payload: &'a [u8],
+}
+
+/// Network interface answering ARP requests and exchanging UDP datagrams through `D`
+pub struct Interface<D: Device> {
+    device: D,
+    config: IpConfig,
+    mac: MacAddress,
+    // Recently resolved addresses, replaced in round robin order
+    arp_cache: [Option<(Ipv4Address, MacAddress)>; ARP_CACHE_SIZE],
+    next_arp_entry: usize,
+    // Identification of the next IPv4 packet we send
+    next_id: u16,
+}
+
+impl<D: Device> Interface<D> {
+    pub fn new(device: D, config: IpConfig) -> Self {
+        let mac = device.mac();
+        Self {
+            device,
+            config,
+            mac,
+            arp_cache: [None; ARP_CACHE_SIZE],
+            next_arp_entry: 0,
+            next_id: 1,
+        }
+    }
+
+    pub fn config(&self) -> IpConfig {
+        self.config
+    }
+
+    pub fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Returns the Ethernet address of `ip`, if we know it
+    pub fn lookup(&self, ip: Ipv4Address) -> Option<MacAddress> {
+        self.arp_cache.iter().flatten().find(|(cached, _)| *cached == ip).map(|(_, mac)| *mac)
+    }
+
+    // Remember that `ip` is at `mac`. When `add` is false, only an existing entry is updated.
+    fn learn(&mut self, ip: Ipv4Address, mac: MacAddress, add: bool) {
+        if let Some(entry) = self.arp_cache.iter_mut().flatten().find(|(cached, _)| *cached == ip) {
+            entry.1 = mac;
+        } else if add {
+            self.arp_cache[self.next_arp_entry] = Some((ip, mac));
+            self.next_arp_entry = (self.next_arp_entry + 1) % ARP_CACHE_SIZE;
+        }
+    }
+
+    /// Handle the next received frame, if there is one. ARP requests for our address are answered
+    /// and UDP datagrams sent to us are returned, every other frame is dropped.
+    pub fn poll<'a>(&mut self, buffer: &'a mut [u8]) -> Result<Option<Datagram<'a>>, NetError> {
+        let Some(len) = self.device.receive(buffer) else {
+            return Ok(None);
+        };
+        let buffer: &'a [u8] = buffer;
+        let Some((ethernet, payload)) = buffer.get(..len).and_then(EthernetHeader::parse) else {
+            return Ok(None);
+        };
+        match ethernet.ethertype {
+            ETHERTYPE_ARP => {
+                if let Some(arp) = ArpPacket::parse(payload) {
+                    self.handle_arp(&arp)?;
+                }
+                Ok(None)
+            }
+            ETHERTYPE_IPV4 => Ok(self.handle_ipv4(payload)),
+            _ => Ok(None),
+        }
+    }
+
+    fn handle_arp(&mut self, arp: &ArpPacket) -> Result<(), NetError> {
+        // As in RFC 826, the sender is only added to the cache if the packet is for us
+        let for_us = arp.target_ip == self.config.ip;
+        if arp.sender_ip != [0; 4] {
+            self.learn(arp.sender_ip, arp.sender_mac, for_us);
+        }
+        if for_us && arp.operation == ARP_REQUEST {
+            let reply = ArpPacket {
+                operation: ARP_REPLY,
+                sender_mac: self.mac,
+                sender_ip: self.config.ip,
+                target_mac: arp.sender_mac,
+                target_ip: arp.sender_ip,
+            };
+            self.send_arp(arp.sender_mac, &reply)?;
+        }
+        Ok(())
+    }
+
+    fn handle_ipv4<'a>(&self, packet: &'a [u8]) -> Option<Datagram<'a>> {
+        let (ip, payload) = Ipv4Header::parse(packet)?;
+        if ip.protocol != IP_PROTOCOL_UDP
+                || (ip.destination != self.config.ip && !self.config.is_broadcast(ip.destination)) {
+            return None;
+        }
+        let (udp, payload) = UdpHeader::parse(&ip, payload)?;
+        Some(Datagram {
+            source: ip.source,
+            source_port: udp.source_port,
+            destination_port: udp.destination_port,
+            payload,
+        })
+    }
+
+    fn send_arp(&mut self, destination: MacAddress, arp: &ArpPacket) -> Result<(), NetError> {
+        let mut frame = [0u8; MIN_FRAME_SIZE];
+        EthernetHeader { destination, source: self.mac, ethertype: ETHERTYPE_ARP }
+            .write(&mut frame);
+        arp.write(&mut frame[ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + ARP_PACKET_SIZE]);
+        self.device.transmit(&frame)
+    }
+
+    /// Returns the Ethernet address packets for `ip` are sent to. If we do not know it yet, an ARP
+    /// request goes out and `NetError::ArpPending` is returned.
+    pub fn resolve(&mut self, ip: Ipv4Address) -> Result<MacAddress, NetError> {
+        if self.config.is_broadcast(ip) {
+            return Ok(BROADCAST_MAC);
+        }
+        let next_hop = if self.config.is_local(ip) {
+            ip
+        } else if self.config.gateway != [0; 4] {
+            self.config.gateway
+        } else {
+            return Err(NetError::NoRoute(ip));
+        };
+        if let Some(mac) = self.lookup(next_hop) {
+            return Ok(mac);
+        }
+
+        let request = ArpPacket {
+            operation: ARP_REQUEST,
+            sender_mac: self.mac,
+            sender_ip: self.config.ip,
+            target_mac: [0; 6],
+            target_ip: next_hop,
+        };
+        self.send_arp(BROADCAST_MAC, &request)?;
+        Err(NetError::ArpPending(next_hop))
+    }
+
+    /// Send `payload` in a single UDP datagram
+    pub fn send_udp(
+        &mut self,
+        destination: Ipv4Address,
+        source_port: u16,
+        destination_port: u16,
+        payload: &[u8],
+    ) -> Result<(), NetError> {
+        if payload.len() > MAX_UDP_PAYLOAD {
+            return Err(NetError::PayloadTooLarge(payload.len()));
+        }
+        let destination_mac = self.resolve(destination)?;
+
+        let ip = Ipv4Header {
+            source: self.config.ip,
+            destination,
+            protocol: IP_PROTOCOL_UDP,
+            identification: self.next_id,
+        };
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let udp_offset = ETHERNET_HEADER_SIZE + IPV4_HEADER_SIZE;
+        let payload_offset = udp_offset + UDP_HEADER_SIZE;
+        let len = core::cmp::max(payload_offset + payload.len(), MIN_FRAME_SIZE);
+        EthernetHeader { destination: destination_mac, source: self.mac, ethertype: ETHERTYPE_IPV4 }
+            .write(&mut frame);
+        ip.write(&mut frame[ETHERNET_HEADER_SIZE..], UDP_HEADER_SIZE + payload.len());
+        frame[payload_offset..payload_offset + payload.len()].copy_from_slice(payload);
+        UdpHeader { source_port, destination_port }
+            .write(&ip, &mut frame[udp_offset..], payload.len());
+        self.device.transmit(&frame[..len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate std;
+    use std::{collections::VecDeque, vec::Vec};
+
+    const OUR_MAC: MacAddress = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+    const GATEWAY_MAC: MacAddress = [0x52, 0x55, 10, 0, 2, 2];
+    const CONFIG: IpConfig = IpConfig {
+        ip: [10, 0, 2, 15],
+        subnet_mask: [255, 255, 255, 0],
+        gateway: [10, 0, 2, 2],
+    };
+
+    // Device handing out queued frames and recording the ones sent through it
+    #[derive(Default)]
+    struct FakeDevice {
+        received: VecDeque<Vec<u8>>,
+        sent: Vec<Vec<u8>>,
+    }
+
+    impl Device for FakeDevice {
+        fn mac(&self) -> MacAddress {
+            OUR_MAC
+        }
+
+        fn transmit(&mut self, frame: &[u8]) -> Result<(), NetError> {
+            self.sent.push(frame.to_vec());
+            Ok(())
+        }
+
+        fn receive(&mut self, buffer: &mut [u8]) -> Option<usize> {
+            let frame = self.received.pop_front()?;
+            let len = frame.len().min(buffer.len());
+            buffer[..len].copy_from_slice(&frame[..len]);
+            Some(len)
+        }
+    }
+
+    fn arp_frame(destination: MacAddress, arp: &ArpPacket) -> Vec<u8> {
+        let mut frame = std::vec![0u8; ETHERNET_HEADER_SIZE + ARP_PACKET_SIZE];
+        EthernetHeader { destination, source: arp.sender_mac, ethertype: ETHERTYPE_ARP }
+            .write(&mut frame);
+        arp.write(&mut frame[ETHERNET_HEADER_SIZE..]);
+        frame
+    }
+
+    #[test]
+    fn checksum() {
+        // Header from the example of RFC 1071, section 3
+        let bytes = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(wire::internet_checksum(&bytes), !0xddf2);
+        assert_eq!(wire::internet_checksum(&[0xff]), 0x00ff);
+    }
+
+    #[test]
+    fn subnet() {
+        assert!(CONFIG.is_local([10, 0, 2, 200]));
+        assert!(!CONFIG.is_local([10, 0, 3, 2]));
+        assert!(CONFIG.is_broadcast([10, 0, 2, 255]));
+        assert!(CONFIG.is_broadcast(BROADCAST_IP));
+        assert!(!CONFIG.is_broadcast([10, 0, 3, 255]));
+    }
+
+    #[test]
+    fn arp() {
+        let mut interface = Interface::new(FakeDevice::default(), CONFIG);
+        let mut buffer = [0u8; MAX_FRAME_SIZE];
+
+        // A request for someone else is not answered nor cached
+        let request = ArpPacket {
+            operation: ARP_REQUEST,
+            sender_mac: GATEWAY_MAC,
+            sender_ip: CONFIG.gateway,
+            target_mac: [0; 6],
+            target_ip: [10, 0, 2, 3],
+        };
+        interface.device_mut().received.push_back(arp_frame(BROADCAST_MAC, &request));
+        assert_eq!(interface.poll(&mut buffer), Ok(None));
+        assert!(interface.device_mut().sent.is_empty());
+        assert_eq!(interface.lookup(CONFIG.gateway), None);
+
+        // A request for us is answered and the sender is cached
+        let request = ArpPacket { target_ip: CONFIG.ip, ..request };
+        interface.device_mut().received.push_back(arp_frame(BROADCAST_MAC, &request));
+        assert_eq!(interface.poll(&mut buffer), Ok(None));
+        assert_eq!(interface.lookup(CONFIG.gateway), Some(GATEWAY_MAC));
+        let reply = interface.device_mut().sent.pop().expect("No ARP reply");
+        assert_eq!(reply.len(), MIN_FRAME_SIZE);
+        let (ethernet, payload) = EthernetHeader::parse(&reply).unwrap();
+        assert_eq!(ethernet.destination, GATEWAY_MAC);
+        assert_eq!(ArpPacket::parse(payload), Some(ArpPacket {
+            operation: ARP_REPLY,
+            sender_mac: OUR_MAC,
+            sender_ip: CONFIG.ip,
+            target_mac: GATEWAY_MAC,
+            target_ip: CONFIG.gateway,
+        }));
+    }
+
+    #[test]
+    fn udp() {
+        let mut interface = Interface::new(FakeDevice::default(), CONFIG);
+        let mut buffer = [0u8; MAX_FRAME_SIZE];
+
+        // Hosts outside of the subnet go through the gateway, which has to be resolved first
+        let server = [192, 168, 1, 10];
+        assert_eq!(interface.send_udp(server, 1234, 7, b"hello"),
+            Err(NetError::ArpPending(CONFIG.gateway)));
+        let request = interface.device_mut().sent.pop().expect("No ARP request");
+        let (ethernet, payload) = EthernetHeader::parse(&request).unwrap();
+        assert_eq!(ethernet.destination, BROADCAST_MAC);
+        let request = ArpPacket::parse(payload).unwrap();
+        assert_eq!((request.operation, request.target_ip), (ARP_REQUEST, CONFIG.gateway));
+
+        let reply = ArpPacket {
+            operation: ARP_REPLY,
+            sender_mac: GATEWAY_MAC,
+            sender_ip: CONFIG.gateway,
+            target_mac: OUR_MAC,
+            target_ip: CONFIG.ip,
+        };
+        interface.device_mut().received.push_back(arp_frame(OUR_MAC, &reply));
+        assert_eq!(interface.poll(&mut buffer), Ok(None));
+        assert_eq!(interface.send_udp(server, 1234, 7, b"hello"), Ok(()));
+        assert_eq!(interface.send_udp(server, 1234, 7, &[0; MAX_UDP_PAYLOAD + 1]),
+            Err(NetError::PayloadTooLarge(MAX_UDP_PAYLOAD + 1)));
+
+        // The datagram we sent is valid, make it come back to us as if the server echoed it
+        let mut frame = interface.device_mut().sent.pop().expect("No datagram sent");
+        let (ethernet, packet) = EthernetHeader::parse(&frame).unwrap();
+        assert_eq!(ethernet.destination, GATEWAY_MAC);
+        let (ip, payload) = Ipv4Header::parse(packet).expect("Invalid IPv4 header");
+        assert_eq!((ip.source, ip.destination), (CONFIG.ip, server));
+        let (udp, payload) = UdpHeader::parse(&ip, payload).expect("Invalid UDP header");
+        assert_eq!((udp.source_port, udp.destination_port, payload), (1234, 7, &b"hello"[..]));
+
+        let echo_ip = Ipv4Header { source: server, destination: CONFIG.ip, ..ip };
+        let udp_offset = ETHERNET_HEADER_SIZE + IPV4_HEADER_SIZE;
+        echo_ip.write(&mut frame[ETHERNET_HEADER_SIZE..], UDP_HEADER_SIZE + 5);
+        UdpHeader { source_port: 7, destination_port: 1234 }
+            .write(&echo_ip, &mut frame[udp_offset..], 5);
+        interface.device_mut().received.push_back(frame.clone());
+        assert_eq!(interface.poll(&mut buffer), Ok(Some(Datagram {
+            source: server,
+            source_port: 7,
+            destination_port: 1234,
+            payload: b"hello",
+        })));
+
+        // Corrupted datagrams are dropped
+        frame[udp_offset + UDP_HEADER_SIZE] ^= 1;
+        interface.device_mut().received.push_back(frame);
+        assert_eq!(interface.poll(&mut buffer), Ok(None));
+        assert_eq!(interface.poll(&mut buffer), Ok(None));
+    }
+}
@@ -0,0 +1,227 @@
+//! Layout of the Ethernet, ARP, IPv4 and UDP headers. Parsing returns `None` for anything we do not
+//! handle, as such frames are dropped anyway.
+use crate::{Ipv4Address, MacAddress};
+
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+pub const ARP_PACKET_SIZE: usize = 28;
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+// ARP hardware type of Ethernet
+const ARP_HARDWARE_ETHERNET: u16 = 1;
+
+pub const IPV4_HEADER_SIZE: usize = 20;
+pub const IP_PROTOCOL_UDP: u8 = 17;
+// Time to live of the packets we send
+const DEFAULT_TTL: u8 = 64;
+// Flags and fragment offset of an IPv4 header. We only set "don't fragment" and drop fragments.
+const IPV4_DONT_FRAGMENT: u16 = 1 << 14;
+const IPV4_MORE_FRAGMENTS: u16 = 1 << 13;
+const IPV4_FRAGMENT_OFFSET: u16 = 0x1fff;
+
+pub const UDP_HEADER_SIZE: usize = 8;
+
+// Add `bytes` as big endian 16-bit words to the one's complement `sum`
+fn checksum_add(mut sum: u32, bytes: &[u8]) -> u32 {
+    let mut words = bytes.chunks_exact(2);
+    for word in &mut words {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+// Fold the carries of `sum` back in and complement it
+fn checksum_finish(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Returns the Internet checksum (RFC 1071) of `bytes`. Checking bytes which include their
+/// checksum gives 0.
+pub fn internet_checksum(bytes: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, bytes))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EthernetHeader {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ethertype: u16,
+}
+
+impl EthernetHeader {
+    /// Parse the header at the start of `frame`, returning it along with the payload
+    pub fn parse(frame: &[u8]) -> Option<(Self, &[u8])> {
+        let (header, payload) = frame.split_at_checked(ETHERNET_HEADER_SIZE)?;
+        let header = Self {
+            destination: header[0..6].try_into().ok()?,
+            source: header[6..12].try_into().ok()?,
+            ethertype: u16::from_be_bytes([header[12], header[13]]),
+        };
+        Some((header, payload))
+    }
+
+    pub fn write(&self, buffer: &mut [u8]) {
+        buffer[0..6].copy_from_slice(&self.destination);
+        buffer[6..12].copy_from_slice(&self.source);
+        buffer[12..14].copy_from_slice(&self.ethertype.to_be_bytes());
+    }
+}
+
+/// ARP packet resolving IPv4 addresses to Ethernet addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub operation: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Address,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Address,
+}
+
+impl ArpPacket {
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        let packet = packet.get(..ARP_PACKET_SIZE)?;
+        let hardware = u16::from_be_bytes([packet[0], packet[1]]);
+        let protocol = u16::from_be_bytes([packet[2], packet[3]]);
+        if hardware != ARP_HARDWARE_ETHERNET || protocol != ETHERTYPE_IPV4
+                || packet[4] != 6 || packet[5] != 4 {
+            return None;
+        }
+        Some(Self {
+            operation: u16::from_be_bytes([packet[6], packet[7]]),
+            sender_mac: packet[8..14].try_into().ok()?,
+            sender_ip: packet[14..18].try_into().ok()?,
+            target_mac: packet[18..24].try_into().ok()?,
+            target_ip: packet[24..28].try_into().ok()?,
+        })
+    }
+
+    pub fn write(&self, buffer: &mut [u8]) {
+        buffer[0..2].copy_from_slice(&ARP_HARDWARE_ETHERNET.to_be_bytes());
+        buffer[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        buffer[4] = 6;
+        buffer[5] = 4;
+        buffer[6..8].copy_from_slice(&self.operation.to_be_bytes());
+        buffer[8..14].copy_from_slice(&self.sender_mac);
+        buffer[14..18].copy_from_slice(&self.sender_ip);
+        buffer[18..24].copy_from_slice(&self.target_mac);
+        buffer[24..28].copy_from_slice(&self.target_ip);
+    }
+}
+
+/// The fields of an IPv4 header we care about. Options are skipped when parsing and never sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Header {
+    pub source: Ipv4Address,
+    pub destination: Ipv4Address,
+    pub protocol: u8,
+    pub identification: u16,
+}
+
+impl Ipv4Header {
+    /// Parse the header at the start of `packet`, returning it along with the payload. Fragments
+    /// and packets with an invalid checksum are dropped.
+    pub fn parse(packet: &[u8]) -> Option<(Self, &[u8])> {
+        if packet.len() < IPV4_HEADER_SIZE || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = usize::from(packet[0] & 0xf) * 4;
+        let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+        // Frames may be padded past the end of the packet
+        if header_len < IPV4_HEADER_SIZE || total_len < header_len || total_len > packet.len()
+                || internet_checksum(&packet[..header_len]) != 0 {
+            return None;
+        }
+        let flags = u16::from_be_bytes([packet[6], packet[7]]);
+        if flags & (IPV4_MORE_FRAGMENTS | IPV4_FRAGMENT_OFFSET) != 0 {
+            return None;
+        }
+        let header = Self {
+            source: packet[12..16].try_into().ok()?,
+            destination: packet[16..20].try_into().ok()?,
+            protocol: packet[9],
+            identification: u16::from_be_bytes([packet[4], packet[5]]),
+        };
+        Some((header, &packet[header_len..total_len]))
+    }
+
+    /// Write the header of a packet with `payload_len` bytes after it
+    pub fn write(&self, buffer: &mut [u8], payload_len: usize) {
+        let total_len = (IPV4_HEADER_SIZE + payload_len) as u16;
+        let header = &mut buffer[..IPV4_HEADER_SIZE];
+        header[0] = 0x45;
+        header[1] = 0;
+        header[2..4].copy_from_slice(&total_len.to_be_bytes());
+        header[4..6].copy_from_slice(&self.identification.to_be_bytes());
+        header[6..8].copy_from_slice(&IPV4_DONT_FRAGMENT.to_be_bytes());
+        header[8] = DEFAULT_TTL;
+        header[9] = self.protocol;
+        header[10..12].fill(0);
+        header[12..16].copy_from_slice(&self.source);
+        header[16..20].copy_from_slice(&self.destination);
+        let checksum = internet_checksum(header);
+        header[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    // Sum of the pseudo header covered by the UDP checksum
+    fn pseudo_header_sum(&self, len: usize) -> u32 {
+        let sum = checksum_add(0, &self.source);
+        let sum = checksum_add(sum, &self.destination);
+        sum + u32::from(self.protocol) + len as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpHeader {
+    pub source_port: u16,
+    pub destination_port: u16,
+}
+
+impl UdpHeader {
+    /// Parse the header at the start of `datagram`, carried by the packet with header `ip`,
+    /// returning it along with the payload
+    pub fn parse<'a>(ip: &Ipv4Header, datagram: &'a [u8]) -> Option<(Self, &'a [u8])> {
+        if datagram.len() < UDP_HEADER_SIZE {
+            return None;
+        }
+        let len = usize::from(u16::from_be_bytes([datagram[4], datagram[5]]));
+        if len < UDP_HEADER_SIZE || len > datagram.len() {
+            return None;
+        }
+        let datagram = &datagram[..len];
+        // A zero checksum means the sender did not compute one
+        let checksum = u16::from_be_bytes([datagram[6], datagram[7]]);
+        let sum = checksum_add(ip.pseudo_header_sum(len), datagram);
+        if checksum != 0 && checksum_finish(sum) != 0 {
+            return None;
+        }
+        let header = Self {
+            source_port: u16::from_be_bytes([datagram[0], datagram[1]]),
+            destination_port: u16::from_be_bytes([datagram[2], datagram[3]]),
+        };
+        Some((header, &datagram[UDP_HEADER_SIZE..]))
+    }
+
+    /// Write the header in front of the payload, which is already in `buffer` after it
+    pub fn write(&self, ip: &Ipv4Header, buffer: &mut [u8], payload_len: usize) {
+        let len = UDP_HEADER_SIZE + payload_len;
+        let datagram = &mut buffer[..len];
+        datagram[0..2].copy_from_slice(&self.source_port.to_be_bytes());
+        datagram[2..4].copy_from_slice(&self.destination_port.to_be_bytes());
+        datagram[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+        datagram[6..8].fill(0);
+        // A computed checksum of 0 is sent as all ones, as 0 means there is no checksum
+        let checksum = match checksum_finish(checksum_add(ip.pseudo_header_sum(len), datagram)) {
+            0 => 0xffff,
+            checksum => checksum,
+        };
+        datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
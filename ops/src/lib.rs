@@ -1,8 +0,0 @@
-#![no_std]
-mod range;
-
-pub use range::RangeSet;
-
-#[cfg(test)]
-mod tests {
-}
@@ -0,0 +1,484 @@
+//! Parser for the bootloader configuration file. Each line holds a key and its value, separated by
+//! whitespace, similar to PXELINUX:
+//!
+//! ```text
+//! # Comments and empty lines are ignored
+//! kernel pizza.kernel sha256=<64 hex digits>
+//! cmdline console=serial cores=4
+//! module ramdisk.img crc32=<8 hex digits>
+//! initrd tests.img
+//! serial com1 115200
+//! mtftp 224.0.1.2 1758 1759
+//! ```
+//!
+//! `module` and `initrd` can be repeated, every other key can appear at most once. Files can be
+//! followed by the digest they are checked against once downloaded. `mtftp` downloads the kernel
+//! and modules through multicast TFTP, given the multicast address and optionally the client and
+//! server ports.
+#![no_std]
+
+pub use checksum::Digest;
+
+/// Name of the configuration file shared by all the machines
+pub const DEFAULT_CONFIG: &[u8] = b"pizza.cfg";
+/// Directory holding the configuration files specific to a machine, named after its MAC or IP
+pub const CONFIG_DIR: &[u8] = b"pizza.cfg.d/";
+/// Maximum length of a configuration file name, which is the one named after the MAC address
+pub const MAX_CONFIG_PATH_LEN: usize = CONFIG_DIR.len() + "01-xx-xx-xx-xx-xx-xx".len();
+
+/// Baud rate the serial ports use when the configuration does not pick one
+pub const DEFAULT_BAUD: u32 = 115200;
+/// UDP ports used by MTFTP when the configuration does not pick them
+pub const DEFAULT_MTFTP_CLIENT_PORT: u16 = 1758;
+pub const DEFAULT_MTFTP_SERVER_PORT: u16 = 1759;
+/// Maximum number of modules the bootloader can load with the kernel
+pub const MAX_MODULES: usize = 16;
+/// Maximum length of a module name, including the null terminator
+pub const MAX_MODULE_NAME_LEN: usize = 64;
+// I/O ports of COM1 to COM4
+const COM_PORTS: [u16; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+
+/// Errors found while parsing a configuration. Lines are counted from 1.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    // Configurations are plain ASCII text
+    NotAscii,
+    UnknownKey(usize),
+    MissingValue(usize),
+    DuplicateKey(usize),
+    InvalidSerial(usize),
+    InvalidDigest(usize),
+    InvalidMtftp(usize),
+    // More than `MAX_MODULES` modules, reported on the first one too many
+    TooManyModules(usize),
+    // A module name which does not fit in `MAX_MODULE_NAME_LEN` or holds a null byte
+    InvalidModuleName(usize),
+}
+
+/// A file to download, with its expected digest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileEntry<'data> {
+    pub name: &'data [u8],
+    pub digest: Option<Digest>,
+}
+
+impl<'data> FileEntry<'data> {
+    // Parse `<name> [digest]`
+    fn parse(value: &'data [u8]) -> Option<Self> {
+        let mut fields = fields(value);
+        let name = fields.next()?;
+        let digest = match fields.next() {
+            Some(digest) => Some(Digest::parse(digest)?),
+            None => None,
+        };
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(Self { name, digest })
+    }
+}
+
+/// Serial port the bootloader and kernel should use, instead of all the ports the BIOS found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialConfig {
+    // I/O port of the serial port
+    pub port: u16,
+    pub baud: u32,
+}
+
+impl SerialConfig {
+    // Parse `<port> [baud]`, where the port is one of `com1` to `com4` or a hexadecimal I/O port
+    fn parse(value: &[u8]) -> Option<Self> {
+        let mut fields = fields(value);
+        let port = match fields.next()? {
+            [name @ .., index @ b'1'..=b'4'] if name.eq_ignore_ascii_case(b"com") => {
+                COM_PORTS[usize::from(index - b'1')]
+            }
+            [b'0', b'x', digits @ ..] => parse_number(digits, 16)?.try_into().ok()?,
+            _ => return None,
+        };
+        let baud = match fields.next() {
+            Some(baud) => parse_number(baud, 10)?,
+            None => DEFAULT_BAUD,
+        };
+        // The UART can only divide its 115200 base rate by an integer
+        if baud == 0 || !DEFAULT_BAUD.is_multiple_of(baud) || fields.next().is_some() {
+            return None;
+        }
+        Some(Self { port, baud })
+    }
+
+    /// Returns the divisor of the UART base rate giving `baud`
+    pub fn divisor(&self) -> u16 {
+        (DEFAULT_BAUD / self.baud) as u16
+    }
+}
+
+/// Multicast TFTP session the files are downloaded through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MtftpConfig {
+    // Multicast IP address the server sends the file to
+    pub ip: [u8; 4],
+    pub client_port: u16,
+    pub server_port: u16,
+}
+
+impl MtftpConfig {
+    // Parse `<ip> [client port [server port]]`
+    fn parse(value: &[u8]) -> Option<Self> {
+        let mut fields = fields(value);
+        let mut ip = [0u8; 4];
+        let mut octets = fields.next()?.split(|&byte| byte == b'.');
+        for octet in &mut ip {
+            *octet = parse_number(octets.next()?, 10)?.try_into().ok()?;
+        }
+        // Multicast addresses are in 224.0.0.0/4
+        if octets.next().is_some() || ip[0] & 0xf0 != 0xe0 {
+            return None;
+        }
+        let client_port = match fields.next() {
+            Some(port) => parse_number(port, 10)?.try_into().ok()?,
+            None => DEFAULT_MTFTP_CLIENT_PORT,
+        };
+        let server_port = match fields.next() {
+            Some(port) => parse_number(port, 10)?.try_into().ok()?,
+            None => DEFAULT_MTFTP_SERVER_PORT,
+        };
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(Self { ip, client_port, server_port })
+    }
+}
+
+/// A parsed configuration file
+#[derive(Debug, Clone, Copy)]
+pub struct Config<'data> {
+    // Text of the configuration, used to iterate over the modules
+    text: &'data [u8],
+    kernel: Option<FileEntry<'data>>,
+    cmdline: Option<&'data [u8]>,
+    serial: Option<SerialConfig>,
+    mtftp: Option<MtftpConfig>,
+}
+
+impl<'data> Config<'data> {
+    /// Parse and validate the configuration in `text`
+    pub fn parse(text: &'data [u8]) -> Result<Self, ConfigError> {
+        if !text.is_ascii() {
+            return Err(ConfigError::NotAscii);
+        }
+        let mut config = Self { text, kernel: None, cmdline: None, serial: None, mtftp: None };
+        let mut modules = 0;
+
+        for (line, key, value) in entries(text) {
+            if value.is_empty() {
+                return Err(ConfigError::MissingValue(line));
+            }
+            match key {
+                b"kernel" | b"cmdline" | b"serial" | b"mtftp" => {
+                    let duplicate = match key {
+                        b"kernel" => {
+                            let kernel = FileEntry::parse(value)
+                                .ok_or(ConfigError::InvalidDigest(line))?;
+                            config.kernel.replace(kernel).is_some()
+                        }
+                        b"cmdline" => config.cmdline.replace(value).is_some(),
+                        b"serial" => {
+                            let serial = SerialConfig::parse(value)
+                                .ok_or(ConfigError::InvalidSerial(line))?;
+                            config.serial.replace(serial).is_some()
+                        }
+                        _ => {
+                            let mtftp = MtftpConfig::parse(value)
+                                .ok_or(ConfigError::InvalidMtftp(line))?;
+                            config.mtftp.replace(mtftp).is_some()
+                        }
+                    };
+                    if duplicate {
+                        return Err(ConfigError::DuplicateKey(line));
+                    }
+                }
+                b"module" | b"initrd" => {
+                    let module = FileEntry::parse(value)
+                        .ok_or(ConfigError::InvalidDigest(line))?;
+                    // Modules are recorded in the boot state, which has a fixed room for them
+                    if module.name.len() >= MAX_MODULE_NAME_LEN || module.name.contains(&0) {
+                        return Err(ConfigError::InvalidModuleName(line));
+                    }
+                    modules += 1;
+                    if modules > MAX_MODULES {
+                        return Err(ConfigError::TooManyModules(line));
+                    }
+                }
+                _ => return Err(ConfigError::UnknownKey(line)),
+            }
+        }
+        Ok(config)
+    }
+
+    /// The kernel file to download
+    pub fn kernel(&self) -> Option<FileEntry<'data>> {
+        self.kernel
+    }
+
+    pub fn cmdline(&self) -> Option<&'data [u8]> {
+        self.cmdline
+    }
+
+    pub fn serial(&self) -> Option<SerialConfig> {
+        self.serial
+    }
+
+    pub fn mtftp(&self) -> Option<MtftpConfig> {
+        self.mtftp
+    }
+
+    /// Iterate over the modules to load with the kernel, in the order they are listed
+    pub fn modules(&self) -> impl Iterator<Item = FileEntry<'data>> {
+        entries(self.text)
+            .filter(|(_, key, _)| matches!(*key, b"module" | b"initrd"))
+            // All the entries were validated by `parse`
+            .filter_map(|(_, _, value)| FileEntry::parse(value))
+    }
+}
+
+// Iterate over the line number, key and value of each line that is not empty or a comment. Keys
+// are not case sensitive, so they are returned in lower case if they are known.
+fn entries(text: &[u8]) -> impl Iterator<Item = (usize, &[u8], &[u8])> {
+    text.split(|&byte| byte == b'\n').enumerate().filter_map(|(index, line)| {
+        let line = line.trim_ascii();
+        if line.is_empty() || line[0] == b'#' {
+            return None;
+        }
+        let split = line.iter().position(u8::is_ascii_whitespace).unwrap_or(line.len());
+        let (key, value) = line.split_at(split);
+        let key = [&b"kernel"[..], b"cmdline", b"serial", b"mtftp", b"module", b"initrd"]
+            .into_iter()
+            .find(|known| known.eq_ignore_ascii_case(key))
+            .unwrap_or(key);
+        Some((index + 1, key, value.trim_ascii()))
+    })
+}
+
+// Iterate over the fields of a value, separated by whitespace
+fn fields(value: &[u8]) -> impl Iterator<Item = &[u8]> {
+    value.split(u8::is_ascii_whitespace).filter(|field| !field.is_empty())
+}
+
+// Parse the unsigned number `digits` in `radix`, which can be 10 or 16. Signs are not accepted.
+fn parse_number(digits: &[u8], radix: u32) -> Option<u32> {
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0u32, |value, &digit| {
+        let digit = char::from(digit).to_digit(radix)?;
+        value.checked_mul(radix)?.checked_add(digit)
+    })
+}
+
+/// Name of a configuration file, as sent to the TFTP server
+#[derive(Debug, Clone, Copy)]
+pub struct ConfigPath {
+    bytes: [u8; MAX_CONFIG_PATH_LEN],
+    len: usize,
+}
+
+impl ConfigPath {
+    fn new(prefix: &[u8]) -> Self {
+        let mut path = Self { bytes: [0; MAX_CONFIG_PATH_LEN], len: 0 };
+        path.push(prefix);
+        path
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.bytes[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn push_hex(&mut self, byte: u8) {
+        const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+        self.push(&[DIGITS[usize::from(byte >> 4)], DIGITS[usize::from(byte & 0xf)]]);
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Returns the configuration files to try, from the most specific to the least, the same way
+/// PXELINUX does: the one named after the MAC address (`01-` for Ethernet, followed by the
+/// address in lower case hex), the ones named after the IP address in upper case hex, removing
+/// one digit at a time, and finally `DEFAULT_CONFIG`. Unknown addresses are all zeros and skipped.
+pub fn config_paths(mac: [u8; 6], ip: [u8; 4]) -> impl Iterator<Item = ConfigPath> {
+    let by_mac = (mac != [0; 6]).then(|| {
+        let mut path = ConfigPath::new(CONFIG_DIR);
+        path.push(b"01");
+        for byte in mac {
+            path.push(b"-");
+            path.push_hex(byte);
+        }
+        path.bytes[CONFIG_DIR.len()..path.len].make_ascii_lowercase();
+        path
+    });
+
+    let mut ip_path = ConfigPath::new(CONFIG_DIR);
+    for byte in ip {
+        ip_path.push_hex(byte);
+    }
+    let ip_digits = if ip == [0; 4] { 0 } else { ip.len() * 2 };
+    let by_ip = (1..=ip_digits).rev().map(move |digits| {
+        let mut path = ip_path;
+        path.len = CONFIG_DIR.len() + digits;
+        path
+    });
+
+    by_mac.into_iter().chain(by_ip).chain(core::iter::once(ConfigPath::new(DEFAULT_CONFIG)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    #[test]
+    fn parse() {
+        let text = b"\
+            # Machine in the lab\n\
+            \n\
+            KERNEL kernels/pizza.kernel crc32=cbf43926\n\
+            cmdline   console=serial cores=4  \n\
+            module ramdisk.img\n\
+            serial COM2 9600\n\
+            mtftp 224.0.1.2 2000\n\
+            \tinitrd tests.img\n";
+        let config = Config::parse(text).expect("Failed to parse config");
+
+        assert_eq!(config.kernel(), Some(FileEntry {
+            name: b"kernels/pizza.kernel",
+            digest: Some(Digest::Crc32(0xcbf4_3926)),
+        }));
+        assert_eq!(config.cmdline(), Some(&b"console=serial cores=4"[..]));
+        assert_eq!(config.serial(), Some(SerialConfig { port: 0x2f8, baud: 9600 }));
+        assert_eq!(config.serial().map(|serial| serial.divisor()), Some(12));
+        assert_eq!(config.mtftp(), Some(MtftpConfig {
+            ip: [224, 0, 1, 2],
+            client_port: 2000,
+            server_port: DEFAULT_MTFTP_SERVER_PORT,
+        }));
+        let modules = config.modules().map(|module| module.name).collect::<Vec<_>>();
+        assert_eq!(modules, [&b"ramdisk.img"[..], b"tests.img"]);
+        assert!(config.modules().all(|module| module.digest.is_none()));
+
+        let config = Config::parse(b"").expect("Failed to parse config");
+        assert_eq!(config.kernel(), None);
+        assert_eq!(config.mtftp(), None);
+        assert_eq!(config.modules().count(), 0);
+    }
+
+    #[test]
+    fn serial() {
+        let serial = |value: &str| SerialConfig::parse(value.as_bytes());
+        assert_eq!(serial("com1"), Some(SerialConfig { port: 0x3f8, baud: 115200 }));
+        assert_eq!(serial("0x2e8 57600"), Some(SerialConfig { port: 0x2e8, baud: 57600 }));
+        assert_eq!(serial("com5"), None);
+        assert_eq!(serial("1016"), None);
+        assert_eq!(serial("com1 0"), None);
+        assert_eq!(serial("com1 +9600"), None);
+        assert_eq!(serial("com1 7"), None);
+        assert_eq!(serial("com1 9600 8n1"), None);
+    }
+
+    #[test]
+    fn mtftp() {
+        let mtftp = |value: &str| MtftpConfig::parse(value.as_bytes());
+        assert_eq!(mtftp("239.255.0.1 1 2"), Some(MtftpConfig {
+            ip: [239, 255, 0, 1],
+            client_port: 1,
+            server_port: 2,
+        }));
+        assert_eq!(mtftp("224.0.1.2").map(|mtftp| mtftp.client_port), Some(1758));
+        // Not a multicast address
+        assert_eq!(mtftp("192.168.0.1"), None);
+        assert_eq!(mtftp("224.0.1"), None);
+        assert_eq!(mtftp("224.0.1.2.3"), None);
+        assert_eq!(mtftp("224.0.1.256"), None);
+        assert_eq!(mtftp("224.0.1.2 65536"), None);
+        assert_eq!(mtftp("224.0.1.2 1 2 3"), None);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Config::parse(b"kernel a\n\xff").err(), Some(ConfigError::NotAscii));
+        assert_eq!(Config::parse(b"kernel a\nappend b").err(), Some(ConfigError::UnknownKey(2)));
+        assert_eq!(Config::parse(b"\nkernel\n").err(), Some(ConfigError::MissingValue(2)));
+        assert_eq!(Config::parse(b"module  \n").err(), Some(ConfigError::MissingValue(1)));
+        assert_eq!(
+            Config::parse(b"kernel a\nKernel b").err(),
+            Some(ConfigError::DuplicateKey(2)),
+        );
+        assert_eq!(Config::parse(b"serial com9").err(), Some(ConfigError::InvalidSerial(1)));
+        assert_eq!(Config::parse(b"mtftp 10.0.0.1").err(), Some(ConfigError::InvalidMtftp(1)));
+        assert_eq!(
+            Config::parse(b"kernel a md5=cbf43926").err(),
+            Some(ConfigError::InvalidDigest(1)),
+        );
+        assert_eq!(
+            Config::parse(b"module a\nmodule b crc32=cbf43926 c").err(),
+            Some(ConfigError::InvalidDigest(2)),
+        );
+    }
+
+    #[test]
+    fn module_limits() {
+        let name = [b'a'; MAX_MODULE_NAME_LEN - 1];
+        let mut text = Vec::new();
+        for _ in 0..MAX_MODULES {
+            text.extend_from_slice(b"initrd ");
+            text.extend_from_slice(&name);
+            text.push(b'\n');
+        }
+        assert_eq!(Config::parse(&text).map(|config| config.modules().count()), Ok(MAX_MODULES));
+
+        text.extend_from_slice(b"module b\n");
+        assert_eq!(
+            Config::parse(&text).err(),
+            Some(ConfigError::TooManyModules(MAX_MODULES + 1)),
+        );
+
+        let mut text = b"module ".to_vec();
+        text.extend_from_slice(&[b'a'; MAX_MODULE_NAME_LEN]);
+        assert_eq!(Config::parse(&text).err(), Some(ConfigError::InvalidModuleName(1)));
+        assert_eq!(
+            Config::parse(b"kernel a\ninitrd b\0c").err(),
+            Some(ConfigError::InvalidModuleName(2)),
+        );
+    }
+
+    #[test]
+    fn paths() {
+        let paths = config_paths([0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd], [192, 168, 2, 91])
+            .collect::<Vec<_>>();
+        let paths = paths.iter().map(|path| path.as_bytes()).collect::<Vec<_>>();
+        assert_eq!(paths, [
+            &b"pizza.cfg.d/01-88-99-aa-bb-cc-dd"[..],
+            b"pizza.cfg.d/C0A8025B",
+            b"pizza.cfg.d/C0A8025",
+            b"pizza.cfg.d/C0A802",
+            b"pizza.cfg.d/C0A80",
+            b"pizza.cfg.d/C0A8",
+            b"pizza.cfg.d/C0A",
+            b"pizza.cfg.d/C0",
+            b"pizza.cfg.d/C",
+            b"pizza.cfg",
+        ]);
+        assert_eq!(paths[0].len(), MAX_CONFIG_PATH_LEN);
+
+        // Without an address, only the default is left
+        let paths = config_paths([0; 6], [0; 4]).collect::<Vec<_>>();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].as_bytes(), DEFAULT_CONFIG);
+    }
+}
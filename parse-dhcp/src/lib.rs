@@ -0,0 +1,274 @@
+//! Parser for the options area of BOOTP/DHCP packets (RFC 2132). The area starts with a magic
+//! cookie, followed by options encoded as a one byte code, a one byte length and the data. The pad
+//! and end options are a single byte.
+#![no_std]
+
+use read_me::{Reader, ReaderError};
+
+/// Magic cookie at the start of the options area, identifying it as DHCP options
+pub const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// Single byte option used to align the following options
+pub const OPTION_PAD: u8 = 0;
+/// Subnet mask of the client, 4 bytes
+pub const OPTION_SUBNET_MASK: u8 = 1;
+/// Routers on the subnet of the client, in order of preference, 4 bytes each
+pub const OPTION_ROUTER: u8 = 3;
+/// DNS servers, in order of preference, 4 bytes each
+pub const OPTION_DNS_SERVER: u8 = 6;
+/// Name of the client, which may or may not include the domain name
+pub const OPTION_HOSTNAME: u8 = 12;
+/// Vendor specific information, itself encoded as options
+pub const OPTION_VENDOR_SPECIFIC: u8 = 43;
+/// Type of the DHCP message, 1 byte
+pub const OPTION_MESSAGE_TYPE: u8 = 53;
+/// TFTP server name, used when the `sname` field of the packet holds options
+pub const OPTION_TFTP_SERVER_NAME: u8 = 66;
+/// Boot file name, used when the `file` field of the packet holds options
+pub const OPTION_BOOTFILE_NAME: u8 = 67;
+/// Site specific option with the name of the kernel file to download
+pub const OPTION_PIZZA_KERNEL: u8 = 224;
+/// Site specific option with the kernel command line
+pub const OPTION_PIZZA_CMDLINE: u8 = 225;
+/// Single byte option marking the end of the valid options
+pub const OPTION_END: u8 = 255;
+
+/// Codes the DHCP standard leaves for site specific options
+pub const SITE_SPECIFIC_OPTIONS: core::ops::RangeInclusive<u8> = 224..=254;
+
+#[derive(Debug)]
+pub enum DhcpError {
+    // The options area does not start with `MAGIC_COOKIE`
+    InvalidMagicCookie([u8; 4]),
+    ReaderError(ReaderError),
+}
+
+impl From<ReaderError> for DhcpError {
+    fn from(err: ReaderError) -> Self {
+        Self::ReaderError(err)
+    }
+}
+
+/// A single option with its data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DhcpOption<'data> {
+    pub code: u8,
+    pub data: &'data [u8],
+}
+
+/// Options area of a BOOTP/DHCP packet
+#[derive(Debug, Clone, Copy)]
+pub struct Options<'data> {
+    // Encoded options, after the magic cookie
+    bytes: &'data [u8],
+}
+
+impl<'data> Options<'data> {
+    /// Parse the options area of a packet, also known as the vendor area in BOOTP
+    pub fn parse(bytes: &'data [u8]) -> Result<Self, DhcpError> {
+        let mut reader = Reader::from(bytes);
+        let cookie = reader.read::<[u8; 4]>()?;
+        if cookie != MAGIC_COOKIE {
+            return Err(DhcpError::InvalidMagicCookie(cookie));
+        }
+        Ok(Self { bytes: &bytes[reader.offset()..] })
+    }
+
+    /// Iterate over the options, until the end option or the first malformed one
+    pub fn iter(&self) -> OptionsIterator<'data> {
+        OptionsIterator::from(self.bytes)
+    }
+
+    /// Returns the data of the first option with `code`
+    pub fn get(&self, code: u8) -> Option<&'data [u8]> {
+        self.iter().find(|option| option.code == code).map(|option| option.data)
+    }
+
+    pub fn subnet_mask(&self) -> Option<[u8; 4]> {
+        self.get(OPTION_SUBNET_MASK).and_then(ip)
+    }
+
+    /// Returns the preferred router
+    pub fn router(&self) -> Option<[u8; 4]> {
+        self.get(OPTION_ROUTER).and_then(ip)
+    }
+
+    /// Iterate over the DNS servers, in order of preference
+    pub fn dns_servers(&self) -> impl Iterator<Item = [u8; 4]> + 'data {
+        self.get(OPTION_DNS_SERVER).unwrap_or(&[]).chunks_exact(4).filter_map(ip)
+    }
+
+    pub fn hostname(&self) -> Option<&'data [u8]> {
+        self.get(OPTION_HOSTNAME).map(trim_null)
+    }
+
+    pub fn message_type(&self) -> Option<u8> {
+        self.get(OPTION_MESSAGE_TYPE)?.first().copied()
+    }
+
+    pub fn tftp_server_name(&self) -> Option<&'data [u8]> {
+        self.get(OPTION_TFTP_SERVER_NAME).map(trim_null)
+    }
+
+    pub fn bootfile_name(&self) -> Option<&'data [u8]> {
+        self.get(OPTION_BOOTFILE_NAME).map(trim_null)
+    }
+
+    /// Iterate over the vendor specific options, which are encoded like the top level ones
+    pub fn vendor_specific(&self) -> Option<OptionsIterator<'data>> {
+        self.get(OPTION_VENDOR_SPECIFIC).map(OptionsIterator::from)
+    }
+
+    /// Returns the data of the site specific option with `code`, which has to be in the range of
+    /// `SITE_SPECIFIC_OPTIONS`
+    pub fn site_specific(&self, code: u8) -> Option<&'data [u8]> {
+        if !SITE_SPECIFIC_OPTIONS.contains(&code) {
+            return None;
+        }
+        self.get(code)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OptionsIterator<'data> {
+    bytes: &'data [u8],
+    // Offset of the next option in `bytes`
+    offset: usize,
+}
+
+impl<'data> OptionsIterator<'data> {
+    pub fn from(bytes: &'data [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+}
+
+impl<'data> Iterator for OptionsIterator<'data> {
+    type Item = DhcpOption<'data>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let code = *self.bytes.get(self.offset)?;
+            match code {
+                OPTION_PAD => self.offset += 1,
+                OPTION_END => {
+                    // Nothing after the end option is valid
+                    self.offset = self.bytes.len();
+                    return None;
+                }
+                _ => {
+                    let len = usize::from(*self.bytes.get(self.offset + 1)?);
+                    let start = self.offset + 2;
+                    let Some(data) = self.bytes.get(start..start + len) else {
+                        // A truncated option ends the area
+                        self.offset = self.bytes.len();
+                        return None;
+                    };
+                    self.offset = start + len;
+                    return Some(DhcpOption { code, data });
+                }
+            }
+        }
+    }
+}
+
+// Returns the IP address at the start of `data`
+fn ip(data: &[u8]) -> Option<[u8; 4]> {
+    data.get(..4)?.try_into().ok()
+}
+
+// Some servers include the null terminator in string options, which is not part of the string
+fn trim_null(data: &[u8]) -> &[u8] {
+    let len = data.iter().position(|byte| *byte == 0).unwrap_or(data.len());
+    &data[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    // Build an options area out of `options`, followed by the end option and some garbage
+    fn area(options: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut bytes = MAGIC_COOKIE.to_vec();
+        for (code, data) in options {
+            bytes.push(*code);
+            bytes.push(data.len() as u8);
+            bytes.extend_from_slice(data);
+            // Pad between the options
+            bytes.push(OPTION_PAD);
+        }
+        bytes.extend_from_slice(&[OPTION_END, OPTION_HOSTNAME, 2, b'n', b'o']);
+        bytes
+    }
+
+    #[test]
+    fn standard_options() {
+        let bytes = area(&[
+            (OPTION_MESSAGE_TYPE, &[5]),
+            (OPTION_SUBNET_MASK, &[255, 255, 255, 0]),
+            (OPTION_ROUTER, &[10, 0, 2, 2, 10, 0, 2, 1]),
+            (OPTION_DNS_SERVER, &[10, 0, 2, 3, 8, 8, 8, 8]),
+            (OPTION_HOSTNAME, b"pizza\0"),
+            (OPTION_TFTP_SERVER_NAME, b"10.0.2.2"),
+            (OPTION_BOOTFILE_NAME, b"pizza.boot"),
+        ]);
+        let options = Options::parse(&bytes).expect("Failed to parse options");
+
+        assert_eq!(options.message_type(), Some(5));
+        assert_eq!(options.subnet_mask(), Some([255, 255, 255, 0]));
+        assert_eq!(options.router(), Some([10, 0, 2, 2]));
+        assert_eq!(options.dns_servers().collect::<Vec<_>>(), [[10, 0, 2, 3], [8, 8, 8, 8]]);
+        assert_eq!(options.hostname(), Some(&b"pizza"[..]));
+        assert_eq!(options.tftp_server_name(), Some(&b"10.0.2.2"[..]));
+        assert_eq!(options.bootfile_name(), Some(&b"pizza.boot"[..]));
+        // Options after the end option are ignored
+        assert_eq!(options.iter().count(), 7);
+        assert!(options.vendor_specific().is_none());
+    }
+
+    #[test]
+    fn vendor_and_site_specific() {
+        let bytes = area(&[
+            (OPTION_VENDOR_SPECIFIC, &[6, 1, 8, OPTION_PAD, 10, 2, 0, 1, OPTION_END]),
+            (OPTION_PIZZA_KERNEL, b"kernels/pizza.kernel"),
+            (OPTION_PIZZA_CMDLINE, b"console=serial cores=4"),
+        ]);
+        let options = Options::parse(&bytes).expect("Failed to parse options");
+
+        let vendor = options.vendor_specific().expect("No vendor options").collect::<Vec<_>>();
+        assert_eq!(vendor, [
+            DhcpOption { code: 6, data: &[8] },
+            DhcpOption { code: 10, data: &[0, 1] },
+        ]);
+        assert_eq!(options.site_specific(OPTION_PIZZA_KERNEL), Some(&b"kernels/pizza.kernel"[..]));
+        assert_eq!(
+            options.site_specific(OPTION_PIZZA_CMDLINE),
+            Some(&b"console=serial cores=4"[..]),
+        );
+        assert!(options.site_specific(230).is_none());
+        assert!(options.site_specific(OPTION_VENDOR_SPECIFIC).is_none());
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(matches!(
+            Options::parse(&[1, 2, 3, 4, OPTION_END]),
+            Err(DhcpError::InvalidMagicCookie([1, 2, 3, 4])),
+        ));
+        assert!(Options::parse(&MAGIC_COOKIE[..3]).is_err());
+
+        // The data of the last option goes past the end of the area
+        let mut bytes = MAGIC_COOKIE.to_vec();
+        bytes.extend_from_slice(&[OPTION_SUBNET_MASK, 4, 255, 255, 255, 0, OPTION_ROUTER, 4, 10]);
+        let options = Options::parse(&bytes).expect("Failed to parse options");
+        assert_eq!(options.subnet_mask(), Some([255, 255, 255, 0]));
+        assert!(options.router().is_none());
+
+        // An option too short for its type
+        let bytes = area(&[(OPTION_SUBNET_MASK, &[255, 255])]);
+        let options = Options::parse(&bytes).expect("Failed to parse options");
+        assert!(options.subnet_mask().is_none());
+    }
+}
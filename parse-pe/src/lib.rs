@@ -2,7 +2,10 @@
 
 mod pe;
 
-pub use pe::Pe;
+pub use pe::{
+    DataDirectory, Pe, PeError, Relocation, RelocationType, SectionHeader,
+    IMAGE_DIRECTORY_ENTRY_BASERELOC, IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_READ, IMAGE_SCN_MEM_WRITE,
+};
 
 #[cfg(test)]
 mod tests {
@@ -29,4 +32,249 @@ mod tests {
             Some(())
         });
     }
+
+    use std::vec::Vec;
+
+    const TEST_IMAGE_BASE: u64 = 0x1337_0000_0000;
+    // Offset in the file of the optional header, right after the `PE\0\0` magic and COFF header
+    const OPT_HEADER_OFFSET: usize = 0x58;
+    // All the headers fit in the first 0x200 bytes of the file
+    const FILE_ALIGNMENT: usize = 0x200;
+
+    // Section of the synthetic PEs built below
+    struct TestSection {
+        name: &'static [u8; 8],
+        rva: u32,
+        // Initialized data, stored in the file
+        data: Vec<u8>,
+        // Size of the section in memory
+        virtual_size: u32,
+        // `IMAGE_SCN_*` flags
+        characteristics: u32,
+    }
+
+    // Build a minimal PE32+ with `sections`. `reloc` is the index of the section holding the base
+    // relocation table.
+    fn build_pe(sections: &[TestSection], reloc: Option<usize>, characteristics: u16) -> Vec<u8> {
+        let mut pe = std::vec![0u8; FILE_ALIGNMENT];
+        pe[..2].copy_from_slice(b"MZ");
+        pe[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        pe[0x40..0x44].copy_from_slice(b"PE\0\0");
+
+        // COFF header
+        let mut coff = Vec::new();
+        coff.extend_from_slice(&0x8664u16.to_le_bytes());
+        coff.extend_from_slice(&(sections.len() as u16).to_le_bytes());
+        coff.extend_from_slice(&[0; 12]);
+        coff.extend_from_slice(&(112u16 + 16 * 8).to_le_bytes());
+        coff.extend_from_slice(&characteristics.to_le_bytes());
+        pe[0x44..OPT_HEADER_OFFSET].copy_from_slice(&coff);
+
+        // Optional header, without the data directories
+        let mut opt = Vec::new();
+        opt.extend_from_slice(&0x20bu16.to_le_bytes());
+        opt.extend_from_slice(&[0; 14]);
+        // Entry point and base of code
+        opt.extend_from_slice(&0x1000u32.to_le_bytes());
+        opt.extend_from_slice(&0x1000u32.to_le_bytes());
+        opt.extend_from_slice(&TEST_IMAGE_BASE.to_le_bytes());
+        opt.extend_from_slice(&0x1000u32.to_le_bytes());
+        opt.extend_from_slice(&(FILE_ALIGNMENT as u32).to_le_bytes());
+        opt.extend_from_slice(&[0; 16]);
+        let size_of_image = sections.iter().map(|s| s.rva + s.virtual_size).max().unwrap_or(0);
+        opt.extend_from_slice(&size_of_image.to_le_bytes());
+        opt.extend_from_slice(&(FILE_ALIGNMENT as u32).to_le_bytes());
+        opt.extend_from_slice(&[0; 4 + 2 + 2 + 32 + 4]);
+        opt.extend_from_slice(&16u32.to_le_bytes());
+        assert_eq!(opt.len(), 112);
+
+        // Data directories
+        for idx in 0..16 {
+            match reloc.map(|reloc| &sections[reloc]) {
+                Some(section) if idx == IMAGE_DIRECTORY_ENTRY_BASERELOC => {
+                    opt.extend_from_slice(&section.rva.to_le_bytes());
+                    opt.extend_from_slice(&(section.data.len() as u32).to_le_bytes());
+                }
+                _ => opt.extend_from_slice(&[0; 8]),
+            }
+        }
+
+        // Section headers, followed by their data
+        let mut headers = Vec::new();
+        let mut data = Vec::new();
+        for section in sections {
+            let pointer = FILE_ALIGNMENT + data.len();
+            headers.extend_from_slice(section.name);
+            headers.extend_from_slice(&section.virtual_size.to_le_bytes());
+            headers.extend_from_slice(&section.rva.to_le_bytes());
+            headers.extend_from_slice(&(section.data.len() as u32).to_le_bytes());
+            headers.extend_from_slice(&(pointer as u32).to_le_bytes());
+            headers.extend_from_slice(&[0; 12]);
+            headers.extend_from_slice(&section.characteristics.to_le_bytes());
+
+            data.extend_from_slice(&section.data);
+            data.resize(data.len().next_multiple_of(FILE_ALIGNMENT), 0);
+        }
+        opt.extend_from_slice(&headers);
+
+        pe[OPT_HEADER_OFFSET..OPT_HEADER_OFFSET + opt.len()].copy_from_slice(&opt);
+        pe.extend_from_slice(&data);
+        pe
+    }
+
+    // A relocation block for `page_rva`, with the raw `entries`
+    fn reloc_block(page_rva: u32, entries: &[u16]) -> Vec<u8> {
+        let mut block = Vec::new();
+        block.extend_from_slice(&page_rva.to_le_bytes());
+        block.extend_from_slice(&(8 + 2 * entries.len() as u32).to_le_bytes());
+        entries.iter().for_each(|entry| block.extend_from_slice(&entry.to_le_bytes()));
+        block
+    }
+
+    // An image with a `.text` section holding a 64-bit pointer at 0x10 and a 32-bit one at 0x20,
+    // both to the start of `.data`, and the relocations for them
+    fn relocatable_pe(characteristics: u16) -> Vec<u8> {
+        let mut text = std::vec![0x90u8; 0x40];
+        text[0x10..0x18].copy_from_slice(&(TEST_IMAGE_BASE + 0x2000).to_le_bytes());
+        text[0x20..0x24].copy_from_slice(&0x2000u32.to_le_bytes());
+
+        let mut reloc = reloc_block(0x1000, &[(10 << 12) | 0x10, (3 << 12) | 0x20, 0]);
+        reloc.extend_from_slice(&reloc_block(0x2000, &[(10 << 12) | 0x8]));
+
+        let mut data = std::vec![0u8; 0x10];
+        data[0x8..0x10].copy_from_slice(&(TEST_IMAGE_BASE + 0x1000).to_le_bytes());
+
+        build_pe(&[
+            TestSection {
+                name: b".text\0\0\0", rva: 0x1000, data: text, virtual_size: 0x40,
+                characteristics: IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_EXECUTE,
+            },
+            TestSection {
+                name: b".data\0\0\0", rva: 0x2000, data, virtual_size: 0x10,
+                characteristics: IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE,
+            },
+            TestSection {
+                name: b".reloc\0\0", rva: 0x3000, data: reloc, virtual_size: 0x18,
+                characteristics: IMAGE_SCN_MEM_READ,
+            },
+        ], Some(2), characteristics)
+    }
+
+    #[test]
+    fn image_bounds() {
+        let bytes = relocatable_pe(0);
+        let pe = Pe::parse(&bytes).expect("Failed to parse PE");
+
+        assert_eq!(pe.image_base(), TEST_IMAGE_BASE);
+        assert_eq!(pe.entry_point(), TEST_IMAGE_BASE + 0x1000);
+        assert_eq!(pe.image_bounds(), Some((TEST_IMAGE_BASE + 0x1000, TEST_IMAGE_BASE + 0x3018)));
+    }
+
+    #[test]
+    fn section_permissions() {
+        let bytes = relocatable_pe(0);
+        let pe = Pe::parse(&bytes).expect("Failed to parse PE");
+
+        let permissions = pe.section_headers()
+            .map(|sh| (sh.is_readable(), sh.is_writable(), sh.is_executable()))
+            .collect::<Vec<_>>();
+        assert_eq!(permissions, [(true, false, true), (true, true, false), (true, false, false)]);
+    }
+
+    #[test]
+    fn load_bss() {
+        // `.bss` only has a few initialized bytes and is mostly backed by nothing in the file
+        let bytes = build_pe(&[
+            TestSection {
+                name: b".text\0\0\0", rva: 0x1000, data: std::vec![0x90; 0x10], virtual_size: 0x10,
+                characteristics: IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_EXECUTE,
+            },
+            TestSection {
+                name: b".bss\0\0\0\0", rva: 0x2000, data: std::vec![0x41; 0x8],
+                virtual_size: 0x1_0000, characteristics: IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE,
+            },
+        ], None, 0);
+        let pe = Pe::parse(&bytes).expect("Failed to parse PE");
+        let (start, end) = pe.image_bounds().unwrap();
+        assert_eq!(end - start, 0x1_1000);
+
+        // Start from memory full of garbage
+        let mut image = std::vec![0xccu8; (end - start) as usize];
+        pe.load_into(&mut image).expect("Failed to load PE");
+        assert!(image[..0x10].iter().all(|b| *b == 0x90));
+        assert!(image[0x1000..0x1008].iter().all(|b| *b == 0x41));
+        assert!(image[0x1008..].iter().all(|b| *b == 0));
+
+        // The image has to have room for the whole `.bss`
+        let mut image = std::vec![0u8; 0x1008];
+        assert!(pe.load_into(&mut image).is_none());
+    }
+
+    #[test]
+    fn relocations() {
+        let bytes = relocatable_pe(0);
+        let pe = Pe::parse(&bytes).expect("Failed to parse PE");
+
+        let dir = pe.data_directory(IMAGE_DIRECTORY_ENTRY_BASERELOC).expect("No .reloc");
+        assert_eq!(dir.rva(), 0x3000);
+        assert_eq!(dir.size(), 0x18);
+        assert!(pe.data_directory(0).is_none());
+
+        let relocs = pe.relocations().expect("Failed to parse .reloc").collect::<Vec<_>>();
+        assert_eq!(relocs, [
+            Relocation { rva: 0x1010, typ: RelocationType::Dir64 },
+            Relocation { rva: 0x1020, typ: RelocationType::HighLow },
+            Relocation { rva: 0x2008, typ: RelocationType::Dir64 },
+        ]);
+    }
+
+    #[test]
+    fn apply_relocations() {
+        let bytes = relocatable_pe(0);
+        let pe = Pe::parse(&bytes).expect("Failed to parse PE");
+        let (start, end) = pe.image_bounds().unwrap();
+        let mut image = std::vec![0u8; (end - start) as usize];
+        pe.load_into(&mut image).expect("Failed to load PE");
+
+        // Same base, nothing changes
+        let loaded = image.clone();
+        pe.apply_relocations(TEST_IMAGE_BASE, &mut image).expect("Failed to relocate");
+        assert_eq!(image, loaded);
+
+        // Move the image down, such that the 32-bit pointer still fits
+        let new_base = TEST_IMAGE_BASE - 0x1000;
+        pe.apply_relocations(new_base, &mut image).expect("Failed to relocate");
+        let read_u64 = |image: &[u8], offset: usize| {
+            Reader::from(&image[offset..]).read::<u64>().unwrap()
+        };
+        assert_eq!(read_u64(&image, 0x10), new_base + 0x2000);
+        assert_eq!(read_u64(&image, 0x1008), new_base + 0x1000);
+        assert_eq!(&image[0x20..0x24], &0x1000u32.to_le_bytes());
+        // Everything else is untouched
+        assert_eq!(&image[..0x10], &loaded[..0x10]);
+    }
+
+    #[test]
+    fn apply_relocations_errors() {
+        let bytes = relocatable_pe(0);
+        let pe = Pe::parse(&bytes).expect("Failed to parse PE");
+        let (start, end) = pe.image_bounds().unwrap();
+        let mut image = std::vec![0u8; (end - start) as usize];
+        pe.load_into(&mut image).expect("Failed to load PE");
+
+        // Moving to the higher half does not fit the 32-bit pointer
+        assert!(matches!(
+            pe.apply_relocations(0xffff_ffff_8000_0000, &mut image),
+            Err(PeError::RelocationOverflow(0x1020)),
+        ));
+
+        // Images without relocations can only be loaded at their base
+        let bytes = relocatable_pe(0x0001);
+        let pe = Pe::parse(&bytes).expect("Failed to parse PE");
+        assert!(pe.apply_relocations(TEST_IMAGE_BASE, &mut image).is_ok());
+        assert!(matches!(
+            pe.apply_relocations(TEST_IMAGE_BASE + 0x10_0000, &mut image),
+            Err(PeError::RelocationsStripped),
+        ));
+    }
 }
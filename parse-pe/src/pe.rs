@@ -1,10 +1,14 @@
 mod coff;
 mod opt;
+mod reloc;
 mod sh;
 
-use coff::CoffHeader;
-use opt::{OptionalHeader, OptionalHeaderType, DataDirectory};
+use coff::{CoffHeader, IMAGE_FILE_RELOCS_STRIPPED};
+use opt::{OptionalHeader, OptionalHeaderType, MAX_DATA_DIRECTORIES};
 use sh::SectionHeadersIterator;
+pub use sh::{SectionHeader, IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_READ, IMAGE_SCN_MEM_WRITE};
+pub use opt::DataDirectory;
+pub use reloc::{Relocation, RelocationType, RelocationsIterator, IMAGE_DIRECTORY_ENTRY_BASERELOC};
 use read_me::{Reader, ReaderError};
 
 pub const MZ_MAGIC: &[u8; 2] = b"MZ";
@@ -17,6 +21,8 @@ pub struct Pe<'data> {
     bytes: &'data [u8],
     coff_header: CoffHeader,
     opt_header: OptionalHeader,
+    // Only the first `number_of_rva_and_sizes` are filled in, the rest are empty
+    data_directories: [DataDirectory; MAX_DATA_DIRECTORIES],
     section_headers_offset: usize,
 }
 
@@ -57,8 +63,13 @@ impl<'data> Pe<'data> {
             return Err(PeError::UnsupportedOptionalMagic(opt_magic));
         };
 
-        for _ in 0..opt_header.number_of_rva_and_sizes() {
-            let _data_dir = reader.read::<DataDirectory>();
+        let mut data_directories = [DataDirectory::default(); MAX_DATA_DIRECTORIES];
+        for idx in 0..usize::try_from(opt_header.number_of_rva_and_sizes())? {
+            let data_dir = reader.read::<DataDirectory>()?;
+            // Directories past the ones defined by the specification are skipped
+            if let Some(entry) = data_directories.get_mut(idx) {
+                *entry = data_dir;
+            }
         }
 
         // Save the offset of the section headers
@@ -68,6 +79,7 @@ impl<'data> Pe<'data> {
             bytes,
             coff_header,
             opt_header,
+            data_directories,
             section_headers_offset: offset,
         })
     }
@@ -77,6 +89,86 @@ impl<'data> Pe<'data> {
         self.opt_header.image_base().saturating_add(u64::from(self.opt_header.addr_entry_point()))
     }
 
+    /// Returns the preferred address of the image, chosen by the linker
+    pub fn image_base(&self) -> u64 {
+        self.opt_header.image_base()
+    }
+
+    /// Returns the data directory at `index`, if it is present
+    pub fn data_directory(&self, index: usize) -> Option<DataDirectory> {
+        self.data_directories.get(index).copied().filter(|dir| dir.size() != 0)
+    }
+
+    // Returns the `size` bytes from the file found at `rva`
+    fn rva_bytes(&self, rva: u32, size: u32) -> Option<&'data [u8]> {
+        let section = self.section_headers().find(|section| {
+            let start = section.virtual_address();
+            rva >= start && rva - start < section.size_of_raw_data()
+        })?;
+        let offset = section.pointer_to_raw_data()
+            .checked_add(rva - section.virtual_address())?;
+        let start = usize::try_from(offset).ok()?;
+        self.bytes.get(start..start.checked_add(usize::try_from(size).ok()?)?)
+    }
+
+    /// Returns an iterator over the fixups of the base relocation table. The iterator is empty if
+    /// the image does not have the table.
+    pub fn relocations(&self) -> Result<RelocationsIterator<'data>, PeError> {
+        let bytes = match self.data_directory(IMAGE_DIRECTORY_ENTRY_BASERELOC) {
+            Some(dir) => self.rva_bytes(dir.rva(), dir.size()).ok_or(PeError::RelocationTable)?,
+            None => &[],
+        };
+        Ok(RelocationsIterator::from(bytes))
+    }
+
+    /// Patch `image`, loaded through `load_into`, such that it can run at `new_base` instead of
+    /// the preferred image base. All the addresses of the image move by the same amount, such
+    /// that the entry point becomes `new_base` plus its RVA.
+    pub fn apply_relocations(&self, new_base: u64, image: &mut [u8]) -> Result<(), PeError> {
+        let image_base = self.image_base();
+        let delta = new_base.wrapping_sub(image_base);
+        if delta == 0 {
+            return Ok(());
+        }
+
+        if self.coff_header.characteristics() & IMAGE_FILE_RELOCS_STRIPPED != 0 {
+            return Err(PeError::RelocationsStripped);
+        }
+
+        // The image starts with the lowest section and not with the headers
+        let (image_start, _) = self.image_bounds().ok_or(PeError::ImageBounds)?;
+        let first_rva = image_start.saturating_sub(image_base);
+
+        for reloc in self.relocations()? {
+            let offset = u64::from(reloc.rva).checked_sub(first_rva)
+                .and_then(|offset| usize::try_from(offset).ok())
+                .ok_or(PeError::RelocationOutOfBounds(reloc.rva))?;
+
+            match reloc.typ {
+                RelocationType::Dir64 => {
+                    let field = image.get_mut(offset..offset.saturating_add(8))
+                        .ok_or(PeError::RelocationOutOfBounds(reloc.rva))?;
+                    let value = u64::from_le_bytes(field.try_into()?).wrapping_add(delta);
+                    field.copy_from_slice(&value.to_le_bytes());
+                }
+                RelocationType::HighLow => {
+                    let field = image.get_mut(offset..offset.saturating_add(4))
+                        .ok_or(PeError::RelocationOutOfBounds(reloc.rva))?;
+                    let value = u64::from(u32::from_le_bytes(field.try_into()?)).wrapping_add(delta);
+                    // The patched address has to fit in 32 bits
+                    let value = u32::try_from(value)
+                        .map_err(|_| PeError::RelocationOverflow(reloc.rva))?;
+                    field.copy_from_slice(&value.to_le_bytes());
+                }
+                RelocationType::Unsupported(typ) => {
+                    return Err(PeError::UnsupportedRelocation(typ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Returns an iterator over the section headers of this PE
     pub fn section_headers(&self) -> SectionHeadersIterator {
         // Offset of the sections
@@ -109,26 +201,33 @@ impl<'data> Pe<'data> {
 
     /// Computes and returns the address bounds of the image: Image start and image end
     pub fn image_bounds(&self) -> Option<(u64, u64)> {
-        let mut image_start = None;
-        let mut image_end = None;
+        let mut image_start: Option<u64> = None;
+        let mut image_end: Option<u64> = None;
         self.access_sections(|base, size, _bytes| {
             let end = base.saturating_add(u64::from(size));
 
-            if image_start.is_none() {
-                image_start = Some(base);
-            }
-
-            if image_end.is_none() {
-                image_end = Some(end);
-            }
-
-            // Lowest start and end we can get
-            image_start.map(|start| core::cmp::min(start, base));
-            image_end.map(|e| core::cmp::min(e, end));
+            // Lowest start and highest end we can get
+            image_start = Some(image_start.map_or(base, |start| core::cmp::min(start, base)));
+            image_end = Some(image_end.map_or(end, |e| core::cmp::max(e, end)));
             Some(())
         })?;
         Some((image_start?, image_end?))
     }
+
+    /// Copy all the sections into `image`, which represents the memory the image is loaded at,
+    /// starting with its lowest section. Each section is placed at its offset from the image start
+    /// and the part of it which is not backed by the file, like `.bss`, is zeroed.
+    pub fn load_into(&self, image: &mut [u8]) -> Option<()> {
+        let (image_start, _) = self.image_bounds()?;
+        self.access_sections(|base, size, bytes| {
+            let offset = usize::try_from(base.checked_sub(image_start)?).ok()?;
+            let section = image.get_mut(offset..offset.checked_add(usize::try_from(size).ok()?)?)?;
+            let (initialized, uninitialized) = section.split_at_mut(bytes.len());
+            initialized.copy_from_slice(bytes);
+            uninitialized.fill(0);
+            Some(())
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -139,6 +238,18 @@ pub enum PeError {
     UnsupportedOptionalMagic(u16),
     Bad([u8; 8]),
     TryFromIntError(core::num::TryFromIntError),
+    TryFromSliceError(core::array::TryFromSliceError),
+    // The image has no sections
+    ImageBounds,
+    // The base relocation table points outside of the sections
+    RelocationTable,
+    // The image can only be loaded at its preferred base
+    RelocationsStripped,
+    // The fixup at this RVA is outside of the image
+    RelocationOutOfBounds(u32),
+    // The fixup at this RVA does not fit its field anymore
+    RelocationOverflow(u32),
+    UnsupportedRelocation(u8),
 }
 
 impl From<ReaderError> for PeError {
@@ -152,3 +263,9 @@ impl From<core::num::TryFromIntError> for PeError {
         Self::TryFromIntError(err)
     }
 }
+
+impl From<core::array::TryFromSliceError> for PeError {
+    fn from(err: core::array::TryFromSliceError) -> Self {
+        Self::TryFromSliceError(err)
+    }
+}
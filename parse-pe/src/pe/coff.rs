@@ -25,6 +25,10 @@ impl CoffHeader {
     pub fn number_of_sections(&self) -> u16 {
         self.number_of_sections
     }
+
+    pub fn characteristics(&self) -> u16 {
+        self.characteristics
+    }
 }
 
 #[derive(Debug)]
@@ -56,6 +60,9 @@ pub enum Error {
     UnsupportedMachine,
 }
 
+/// The image does not contain base relocations and must be loaded at its preferred base address
+pub const IMAGE_FILE_RELOCS_STRIPPED: u16 = 0x0001;
+
 /// x86
 pub const IMAGE_FILE_MACHINE_I386: u16 = 0x14c;
 /// x64
@@ -75,12 +75,28 @@ impl OptionalHeader {
     }
 }
 
+/// Maximum number of data directories a PE can have
+pub const MAX_DATA_DIRECTORIES: usize = 16;
+
+#[derive(Debug, Default, Clone, Copy)]
 #[derive(ReadMe)]
 pub struct DataDirectory {
     rva: u32,
     size: u32,
 }
 
+impl DataDirectory {
+    /// Returns the RVA of the table
+    pub fn rva(&self) -> u32 {
+        self.rva
+    }
+
+    /// Returns the size of the table in bytes
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+}
+
 pub trait PeArch: Clone + Copy {
     type Bases: Primitive;
 
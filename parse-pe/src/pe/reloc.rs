@@ -0,0 +1,104 @@
+//! Module that parses the base relocation table (`.reloc`) of a PE. The table is made out of
+//! blocks, one for each 4Kb page that contains fixups, each followed by 16-bit entries.
+use parseme::ReadMe;
+use read_me::{Primitive, Reader, ReaderError};
+
+/// Index of the base relocation table in the data directories
+pub const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+
+// Relocation types we care about
+// Skipped, used to pad a block to a 32-bit boundary
+const IMAGE_REL_BASED_ABSOLUTE: u8 = 0;
+// The fixup applies the delta to the 32-bit field at the offset
+const IMAGE_REL_BASED_HIGHLOW: u8 = 3;
+// The fixup applies the delta to the 64-bit field at the offset
+const IMAGE_REL_BASED_DIR64: u8 = 10;
+
+#[derive(Debug)]
+#[derive(ReadMe)]
+struct BlockHeader {
+    // The image base plus this RVA is added to each offset in the block to get the fixup address
+    page_rva: u32,
+    // Size of the block in bytes, including this header
+    block_size: u32,
+}
+
+/// Kind of the field a relocation has to patch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationType {
+    HighLow,
+    Dir64,
+    // Any other type, which we do not know how to apply
+    Unsupported(u8),
+}
+
+/// A single fixup from the base relocation table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation {
+    // RVA of the field to be patched
+    pub rva: u32,
+    pub typ: RelocationType,
+}
+
+#[derive(Debug)]
+pub struct RelocationsIterator<'data> {
+    // Bytes of the base relocation table
+    bytes: &'data [u8],
+    // Offset of the next entry
+    offset: usize,
+    // Page RVA of the current block
+    page_rva: u32,
+    // Offset where the current block ends
+    block_end: usize,
+}
+
+impl<'data> RelocationsIterator<'data> {
+    pub fn from(bytes: &'data [u8]) -> Self {
+        Self { bytes, offset: 0, page_rva: 0, block_end: 0 }
+    }
+}
+
+impl<'data> Iterator for RelocationsIterator<'data> {
+    type Item = Relocation;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Move to the next block if we consumed the current one
+            if self.offset >= self.block_end {
+                let mut reader = Reader::from(self.bytes);
+                reader.seek(self.block_end).ok()?;
+                let header = reader.read::<BlockHeader>().ok()?;
+                // A block has at least its header, otherwise we would never move forward
+                let block_size = usize::try_from(header.block_size).ok()?;
+                if block_size < header.size_on_disk() {
+                    return None;
+                }
+                self.page_rva = header.page_rva;
+                self.offset = reader.offset();
+                self.block_end = core::cmp::min(
+                    self.block_end.saturating_add(block_size),
+                    self.bytes.len(),
+                );
+                continue;
+            }
+
+            let mut reader = Reader::from(self.bytes);
+            reader.seek(self.offset).ok()?;
+            let entry = reader.read::<u16>().ok()?;
+            self.offset = reader.offset();
+
+            // The type is in the high 4 bits and the offset in the page in the low 12 bits
+            let typ = match (entry >> 12) as u8 {
+                IMAGE_REL_BASED_ABSOLUTE => continue,
+                IMAGE_REL_BASED_HIGHLOW => RelocationType::HighLow,
+                IMAGE_REL_BASED_DIR64 => RelocationType::Dir64,
+                typ => RelocationType::Unsupported(typ),
+            };
+
+            return Some(Relocation {
+                rva: self.page_rva.saturating_add(u32::from(entry & 0xfff)),
+                typ,
+            });
+        }
+    }
+}
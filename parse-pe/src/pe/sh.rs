@@ -2,6 +2,13 @@
 use parseme::ReadMe;
 use read_me::{Reader, ReaderError};
 
+/// The section can be executed as code
+pub const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+/// The section can be read
+pub const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+/// The section can be written to
+pub const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+
 #[derive(Debug)]
 #[derive(ReadMe)]
 pub struct SectionHeader {
@@ -40,6 +47,18 @@ impl SectionHeader {
     pub fn size_of_raw_data(&self) -> u32 {
         self.size_of_raw_data
     }
+    pub fn characteristics(&self) -> u32 {
+        self.characteristics
+    }
+    pub fn is_executable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_EXECUTE != 0
+    }
+    pub fn is_readable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_READ != 0
+    }
+    pub fn is_writable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_WRITE != 0
+    }
 }
 
 #[derive(Debug)]
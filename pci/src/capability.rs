@@ -0,0 +1,157 @@
+//! Decoding of the capabilities the kernel cares about: MSI, MSI-X and PCI Express
+use crate::{ConfigSpace, Function};
+
+/// Power management capability
+pub const CAP_POWER_MANAGEMENT: u8 = 0x01;
+/// Message signaled interrupts capability
+pub const CAP_MSI: u8 = 0x05;
+/// Vendor specific capability, like the ones describing the virtio structures
+pub const CAP_VENDOR_SPECIFIC: u8 = 0x09;
+/// PCI Express capability
+pub const CAP_PCIE: u8 = 0x10;
+/// Extended message signaled interrupts capability
+pub const CAP_MSIX: u8 = 0x11;
+
+// Bits of the MSI message control register
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_64BIT: u16 = 1 << 7;
+const MSI_PER_VECTOR_MASKING: u16 = 1 << 8;
+// Bits of the MSI-X message control register
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_TABLE_SIZE: u16 = 0x7ff;
+
+/// Returns the name of the capability with `id`
+pub fn capability_name(id: u8) -> &'static str {
+    match id {
+        CAP_POWER_MANAGEMENT => "PM",
+        CAP_MSI => "MSI",
+        CAP_VENDOR_SPECIFIC => "Vendor",
+        CAP_PCIE => "PCIe",
+        CAP_MSIX => "MSI-X",
+        _ => "Unknown",
+    }
+}
+
+/// MSI capability
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msi {
+    // Offset of the capability in the configuration space
+    pub offset: u16,
+    control: u16,
+}
+
+impl Msi {
+    pub fn parse<C: ConfigSpace>(function: &Function<C>, offset: u16) -> Self {
+        Self { offset, control: function.read_u16(offset + 2) }
+    }
+
+    /// Returns the number of vectors the function asks for
+    pub fn vectors(&self) -> u8 {
+        1 << ((self.control >> 1) & 7)
+    }
+
+    /// Returns `true` if the message address can be above 4GiB
+    pub fn is_64bit(&self) -> bool {
+        self.control & MSI_64BIT != 0
+    }
+
+    pub fn per_vector_masking(&self) -> bool {
+        self.control & MSI_PER_VECTOR_MASKING != 0
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.control & MSI_ENABLE != 0
+    }
+}
+
+/// MSI-X capability. The vector table and the pending bit array live in memory BARs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiX {
+    // Offset of the capability in the configuration space
+    pub offset: u16,
+    // Number of entries of the vector table
+    pub table_size: u16,
+    pub table_bar: u8,
+    // Offset of the vector table in its BAR
+    pub table_offset: u32,
+    pub pba_bar: u8,
+    // Offset of the pending bit array in its BAR
+    pub pba_offset: u32,
+    pub enabled: bool,
+}
+
+impl MsiX {
+    pub fn parse<C: ConfigSpace>(function: &Function<C>, offset: u16) -> Self {
+        let control = function.read_u16(offset + 2);
+        let table = function.read_u32(offset + 4);
+        let pba = function.read_u32(offset + 8);
+        // The low 3 bits of both registers select the BAR
+        Self {
+            offset,
+            table_size: (control & MSIX_TABLE_SIZE) + 1,
+            table_bar: (table & 7) as u8,
+            table_offset: table & !7,
+            pba_bar: (pba & 7) as u8,
+            pba_offset: pba & !7,
+            enabled: control & MSIX_ENABLE != 0,
+        }
+    }
+}
+
+/// Role of a PCI Express function in the hierarchy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortType {
+    Endpoint,
+    LegacyEndpoint,
+    RootPort,
+    UpstreamSwitchPort,
+    DownstreamSwitchPort,
+    PcieToPciBridge,
+    PciToPcieBridge,
+    IntegratedEndpoint,
+    RootComplexEventCollector,
+    Unknown(u8),
+}
+
+impl From<u8> for PortType {
+    fn from(value: u8) -> Self {
+        match value {
+            0x0 => Self::Endpoint,
+            0x1 => Self::LegacyEndpoint,
+            0x4 => Self::RootPort,
+            0x5 => Self::UpstreamSwitchPort,
+            0x6 => Self::DownstreamSwitchPort,
+            0x7 => Self::PcieToPciBridge,
+            0x8 => Self::PciToPcieBridge,
+            0x9 => Self::IntegratedEndpoint,
+            0xa => Self::RootComplexEventCollector,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+/// PCI Express capability
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pcie {
+    // Offset of the capability in the configuration space
+    pub offset: u16,
+    pub port_type: PortType,
+    // Negotiated link speed, 1 for 2.5GT/s, 2 for 5GT/s and so on. Integrated endpoints do not
+    // have a link and report 0.
+    pub link_speed: u8,
+    // Negotiated number of lanes
+    pub link_width: u8,
+}
+
+impl Pcie {
+    pub fn parse<C: ConfigSpace>(function: &Function<C>, offset: u16) -> Self {
+        let capabilities = function.read_u16(offset + 2);
+        let link_status = function.read_u16(offset + 0x12);
+        Self {
+            offset,
+            port_type: PortType::from(((capabilities >> 4) & 0xf) as u8),
+            link_speed: (link_status & 0xf) as u8,
+            link_width: ((link_status >> 4) & 0x3f) as u8,
+        }
+    }
+}
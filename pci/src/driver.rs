@@ -0,0 +1,91 @@
+//! Registry of the drivers, matched to the functions by vendor and device ID
+use crate::{functions, ConfigSpace, Function};
+
+/// Most drivers the registry holds
+pub const MAX_DRIVERS: usize = 16;
+
+/// Vendor and device ID of a function a driver handles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceId {
+    pub vendor: u16,
+    pub device: u16,
+}
+
+impl DeviceId {
+    pub const fn new(vendor: u16, device: u16) -> Self {
+        Self { vendor, device }
+    }
+}
+
+/// A driver for the functions with one of the `ids`
+pub struct Driver<C: ConfigSpace> {
+    pub name: &'static str,
+    pub ids: &'static [DeviceId],
+    // Initialise the driver for the function, returning `true` if it took it
+    pub probe: fn(&Function<C>) -> bool,
+}
+
+impl<C: ConfigSpace> Driver<C> {
+    /// Returns `true` if the driver handles `function`
+    pub fn matches(&self, function: &Function<C>) -> bool {
+        let id = DeviceId::new(function.vendor_id(), function.device_id());
+        self.ids.contains(&id)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DriverError {
+    // The registry already holds `MAX_DRIVERS`
+    RegistryFull,
+    // A driver with the same name is already registered
+    AlreadyRegistered(&'static str),
+}
+
+/// Drivers known to the kernel
+pub struct Drivers<C: ConfigSpace> {
+    drivers: [Option<Driver<C>>; MAX_DRIVERS],
+}
+
+impl<C: ConfigSpace> Drivers<C> {
+    pub const fn new() -> Self {
+        Self { drivers: [const { None }; MAX_DRIVERS] }
+    }
+
+    pub fn register(&mut self, driver: Driver<C>) -> Result<(), DriverError> {
+        if self.iter().any(|registered| registered.name == driver.name) {
+            return Err(DriverError::AlreadyRegistered(driver.name));
+        }
+        let slot = self.drivers.iter_mut().find(|slot| slot.is_none())
+            .ok_or(DriverError::RegistryFull)?;
+        *slot = Some(driver);
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Driver<C>> {
+        self.drivers.iter().flatten()
+    }
+
+    /// Returns the first driver, in registration order, handling `function`
+    pub fn find(&self, function: &Function<C>) -> Option<&Driver<C>> {
+        self.iter().find(|driver| driver.matches(function))
+    }
+
+    /// Offer every function to the drivers handling it, until one takes it. Calls `bound` for each
+    /// function a driver took.
+    pub fn probe_all(&self, config: &C, mut bound: impl FnMut(&Function<C>, &Driver<C>)) {
+        for function in functions(config) {
+            let driver = self.iter()
+                .filter(|driver| driver.matches(&function))
+                .find(|driver| (driver.probe)(&function));
+            if let Some(driver) = driver {
+                bound(&function, driver);
+            }
+        }
+    }
+}
+
+impl<C: ConfigSpace> Default for Drivers<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
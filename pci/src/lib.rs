@@ -0,0 +1,801 @@
+//! Access to the configuration space of PCI devices, used to find devices and the resources they
+//! decode, and to hand them to their drivers
+#![no_std]
+
+mod capability;
+mod class;
+mod driver;
+
+pub use capability::{
+    capability_name, Msi, MsiX, Pcie, PortType, CAP_MSI, CAP_MSIX, CAP_PCIE,
+    CAP_POWER_MANAGEMENT, CAP_VENDOR_SPECIFIC,
+};
+pub use class::class_name;
+pub use driver::{DeviceId, Driver, DriverError, Drivers, MAX_DRIVERS};
+
+use core::fmt;
+
+/// Vendor ID read from functions that do not exist
+pub const INVALID_VENDOR: u16 = 0xffff;
+
+// Offsets of the registers in the common part of the configuration space header
+pub const REG_VENDOR_ID: u16 = 0x00;
+pub const REG_DEVICE_ID: u16 = 0x02;
+pub const REG_COMMAND: u16 = 0x04;
+pub const REG_STATUS: u16 = 0x06;
+pub const REG_REVISION: u16 = 0x08;
+pub const REG_CLASS: u16 = 0x09;
+pub const REG_HEADER_TYPE: u16 = 0x0e;
+pub const REG_BAR0: u16 = 0x10;
+pub const REG_CAPABILITIES: u16 = 0x34;
+pub const REG_INTERRUPT_LINE: u16 = 0x3c;
+// Bus numbers of a type 1 (bridge) header
+pub const REG_PRIMARY_BUS: u16 = 0x18;
+pub const REG_SECONDARY_BUS: u16 = 0x19;
+pub const REG_SUBORDINATE_BUS: u16 = 0x1a;
+
+/// Header type of endpoints
+pub const HEADER_TYPE_ENDPOINT: u8 = 0;
+/// Header type of PCI-to-PCI bridges
+pub const HEADER_TYPE_BRIDGE: u8 = 1;
+
+/// Command register bit enabling the decoding of I/O BARs
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+/// Command register bit enabling the decoding of memory BARs
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+/// Command register bit allowing the function to initiate DMA
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+// Status register bit set when the function has a capabilities list
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+// Set in the header type of the first function of multi-function devices
+const HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+
+/// Number of BARs in a type 0 (endpoint) header
+pub const ENDPOINT_BARS: u8 = 6;
+/// Number of BARs in a type 1 (bridge) header
+pub const BRIDGE_BARS: u8 = 2;
+
+// Legacy configuration mechanism: the address of the register goes in `CONFIG_ADDRESS` and its
+// value is accessed through `CONFIG_DATA`
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// Location of a function on the PCI buses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    // 0 to 31
+    pub device: u8,
+    // 0 to 7
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self { bus, device, function }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A way to access the configuration space of the functions. Registers are accessed as aligned
+/// dwords, the narrower accessors are built on top of them.
+pub trait ConfigSpace {
+    /// Read the dword at `offset`, which is aligned to 4 bytes
+    fn read_u32(&self, address: PciAddress, offset: u16) -> u32;
+    /// Write the dword at `offset`, which is aligned to 4 bytes
+    fn write_u32(&self, address: PciAddress, offset: u16, value: u32);
+
+    fn read_u16(&self, address: PciAddress, offset: u16) -> u16 {
+        (self.read_u32(address, offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+
+    fn read_u8(&self, address: PciAddress, offset: u16) -> u8 {
+        (self.read_u32(address, offset & !3) >> ((offset & 3) * 8)) as u8
+    }
+
+    /// Write the word at `offset`, preserving the other half of its dword
+    fn write_u16(&self, address: PciAddress, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        let dword = self.read_u32(address, offset & !3) & !(0xffff << shift);
+        self.write_u32(address, offset & !3, dword | (u32::from(value) << shift));
+    }
+}
+
+/// Configuration space accessed through the legacy `0xcf8`/`0xcfc` I/O ports. It only reaches
+/// the first 256 bytes of each function.
+pub struct LegacyConfig;
+
+impl LegacyConfig {
+    // Select the register at `offset` of the function at `address`
+    fn select(address: PciAddress, offset: u16) {
+        let value = (1 << 31)
+            | (u32::from(address.bus) << 16)
+            | (u32::from(address.device & 0x1f) << 11)
+            | (u32::from(address.function & 7) << 8)
+            | u32::from(offset & 0xfc);
+        cpu::x86::out_u32(CONFIG_ADDRESS, value);
+    }
+}
+
+impl ConfigSpace for LegacyConfig {
+    fn read_u32(&self, address: PciAddress, offset: u16) -> u32 {
+        if offset >= 256 {
+            return u32::MAX;
+        }
+        Self::select(address, offset);
+        cpu::x86::in_u32(CONFIG_DATA)
+    }
+
+    fn write_u32(&self, address: PciAddress, offset: u16, value: u32) {
+        if offset >= 256 {
+            return;
+        }
+        Self::select(address, offset);
+        cpu::x86::out_u32(CONFIG_DATA, value);
+    }
+}
+
+/// Configuration space accessed through the Enhanced Configuration Access Mechanism of PCI
+/// Express, where the 4KiB of each function are mapped in memory
+pub struct EcamConfig {
+    // Virtual address of the configuration space of `start_bus`
+    base: u64,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+impl EcamConfig {
+    /// Access the configuration space of the `start_bus..=end_bus` buses through the ECAM region
+    /// mapped at `base`
+    ///
+    /// # Safety
+    ///
+    /// The ECAM region of the buses has to be mapped at `base` as uncached memory, for as long as
+    /// this lives
+    pub unsafe fn new(base: u64, start_bus: u8, end_bus: u8) -> Self {
+        Self { base, start_bus, end_bus }
+    }
+
+    // Returns a pointer to the register at `offset` of the function at `address`, if the ECAM
+    // region covers it
+    fn register(&self, address: PciAddress, offset: u16) -> Option<*mut u32> {
+        if !(self.start_bus..=self.end_bus).contains(&address.bus) || offset >= 4096 {
+            return None;
+        }
+        let offset = (u64::from(address.bus - self.start_bus) << 20)
+            | (u64::from(address.device & 0x1f) << 15)
+            | (u64::from(address.function & 7) << 12)
+            | u64::from(offset & !3);
+        Some((self.base + offset) as *mut u32)
+    }
+}
+
+impl ConfigSpace for EcamConfig {
+    fn read_u32(&self, address: PciAddress, offset: u16) -> u32 {
+        self.register(address, offset)
+            .map_or(u32::MAX, |register| unsafe { register.read_volatile() })
+    }
+
+    fn write_u32(&self, address: PciAddress, offset: u16, value: u32) {
+        if let Some(register) = self.register(address, offset) {
+            unsafe { register.write_volatile(value) };
+        }
+    }
+}
+
+/// The configuration mechanism picked at boot: ECAM when the firmware describes it, the legacy
+/// I/O ports otherwise
+pub enum PciConfig {
+    Legacy(LegacyConfig),
+    Ecam(EcamConfig),
+}
+
+impl ConfigSpace for PciConfig {
+    fn read_u32(&self, address: PciAddress, offset: u16) -> u32 {
+        match self {
+            Self::Legacy(config) => config.read_u32(address, offset),
+            Self::Ecam(config) => config.read_u32(address, offset),
+        }
+    }
+
+    fn write_u32(&self, address: PciAddress, offset: u16, value: u32) {
+        match self {
+            Self::Legacy(config) => config.write_u32(address, offset, value),
+            Self::Ecam(config) => config.write_u32(address, offset, value),
+        }
+    }
+}
+
+/// Resource decoded by a Base Address Register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Io { port: u16 },
+    Memory { address: u64, prefetchable: bool, is_64: bool },
+}
+
+/// A function present on the bus
+pub struct Function<'config, C: ConfigSpace> {
+    config: &'config C,
+    pub address: PciAddress,
+}
+
+// Derives would require `C` to be `Copy`, but only the reference is copied
+impl<C: ConfigSpace> Clone for Function<'_, C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<C: ConfigSpace> Copy for Function<'_, C> {}
+
+impl<'config, C: ConfigSpace> Function<'config, C> {
+    /// Returns the function at `address`, if there is one
+    pub fn new(config: &'config C, address: PciAddress) -> Option<Self> {
+        let function = Self { config, address };
+        (function.vendor_id() != INVALID_VENDOR).then_some(function)
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        self.config.read_u8(self.address, offset)
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        self.config.read_u16(self.address, offset)
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        self.config.read_u32(self.address, offset)
+    }
+
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        self.config.write_u16(self.address, offset, value)
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        self.config.write_u32(self.address, offset, value)
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read_u16(REG_VENDOR_ID)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.read_u16(REG_DEVICE_ID)
+    }
+
+    /// Returns the class, subclass and programming interface
+    pub fn class(&self) -> (u8, u8, u8) {
+        let class = self.read_u32(REG_REVISION);
+        ((class >> 24) as u8, (class >> 16) as u8, (class >> 8) as u8)
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.read_u8(REG_REVISION)
+    }
+
+    /// Returns the layout of the header, without the multi-function bit
+    pub fn header_type(&self) -> u8 {
+        self.read_u8(REG_HEADER_TYPE) & !HEADER_MULTI_FUNCTION
+    }
+
+    /// Returns `true` if this is the first function of a device with more than one function
+    pub fn is_multi_function(&self) -> bool {
+        self.read_u8(REG_HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0
+    }
+
+    /// Returns the primary, secondary and subordinate bus numbers of a bridge
+    pub fn bridge_buses(&self) -> Option<(u8, u8, u8)> {
+        if self.header_type() != HEADER_TYPE_BRIDGE {
+            return None;
+        }
+        Some((
+            self.read_u8(REG_PRIMARY_BUS),
+            self.read_u8(REG_SECONDARY_BUS),
+            self.read_u8(REG_SUBORDINATE_BUS),
+        ))
+    }
+
+    /// Returns the number of BARs in the header
+    pub fn bar_count(&self) -> u8 {
+        match self.header_type() {
+            HEADER_TYPE_ENDPOINT => ENDPOINT_BARS,
+            HEADER_TYPE_BRIDGE => BRIDGE_BARS,
+            _ => 0,
+        }
+    }
+
+    /// Set the `COMMAND_*` bits in `flags`, keeping the ones already set
+    pub fn enable(&self, flags: u16) {
+        let command = self.read_u16(REG_COMMAND);
+        self.write_u16(REG_COMMAND, command | flags);
+    }
+
+    /// Decode the BAR with `index`. The upper half of a 64-bit BAR is part of the BAR below it, so
+    /// it returns `None`, like unimplemented BARs.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        let bars = self.bar_count();
+        if index >= bars || self.is_upper_bar(index) {
+            return None;
+        }
+        let offset = REG_BAR0 + u16::from(index) * 4;
+        let low = self.read_u32(offset);
+        if low & 1 != 0 {
+            let port = (low & !3) as u16;
+            return (port != 0).then_some(Bar::Io { port });
+        }
+
+        let is_64 = (low >> 1) & 3 == 2;
+        let high = if is_64 && index + 1 < bars { self.read_u32(offset + 4) } else { 0 };
+        let address = (u64::from(high) << 32) | u64::from(low & !0xf);
+        (address != 0).then_some(Bar::Memory { address, prefetchable: low & 8 != 0, is_64 })
+    }
+
+    // Returns `true` if the BAR with `index` holds the upper half of the 64-bit BAR below it
+    fn is_upper_bar(&self, index: u8) -> bool {
+        // Walk from the first BAR, as the upper half of a 64-bit BAR can look like anything
+        let mut bar = 0;
+        while bar < index {
+            let low = self.read_u32(REG_BAR0 + u16::from(bar) * 4);
+            let is_64 = low & 1 == 0 && (low >> 1) & 3 == 2;
+            if is_64 && bar + 1 == index {
+                return true;
+            }
+            bar += if is_64 { 2 } else { 1 };
+        }
+        false
+    }
+
+    /// Returns the size of the region decoded by the BAR with `index`, found by writing all ones
+    /// to it and reading back which bits stuck. Decoding is disabled meanwhile, so this must not
+    /// race with a driver using the function.
+    pub fn bar_size(&self, index: u8) -> Option<u64> {
+        let bar = self.bar(index)?;
+        let offset = REG_BAR0 + u16::from(index) * 4;
+
+        let command = self.read_u16(REG_COMMAND);
+        self.write_u16(REG_COMMAND, command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+        let probe = |offset: u16| {
+            let original = self.read_u32(offset);
+            self.write_u32(offset, u32::MAX);
+            let mask = self.read_u32(offset);
+            self.write_u32(offset, original);
+            mask
+        };
+        let low = probe(offset);
+        let high = match bar {
+            Bar::Memory { is_64: true, .. } => probe(offset + 4),
+            // The upper bits of 32-bit BARs never stick
+            _ => u32::MAX,
+        };
+        self.write_u16(REG_COMMAND, command);
+
+        let mask = match bar {
+            // Devices may only decode 16 bits of I/O ports, leaving the upper bits as 0
+            Bar::Io { .. } if low & 0xffff_0000 == 0 => 0xffff_ffff_ffff_0000 | u64::from(low & !3),
+            Bar::Io { .. } => 0xffff_ffff_0000_0000 | u64::from(low & !3),
+            Bar::Memory { .. } => (u64::from(high) << 32) | u64::from(low & !0xf),
+        };
+        (mask != u64::MAX).then(|| (!mask).wrapping_add(1)).filter(|size| *size != 0)
+    }
+
+    /// Returns the offset of the first capability with `id`
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities().find(|(cap_id, _)| *cap_id == id).map(|(_, offset)| offset)
+    }
+
+    pub fn msi(&self) -> Option<Msi> {
+        self.find_capability(CAP_MSI).map(|offset| Msi::parse(self, offset))
+    }
+
+    pub fn msix(&self) -> Option<MsiX> {
+        self.find_capability(CAP_MSIX).map(|offset| MsiX::parse(self, offset))
+    }
+
+    pub fn pcie(&self) -> Option<Pcie> {
+        self.find_capability(CAP_PCIE).map(|offset| Pcie::parse(self, offset))
+    }
+
+    /// Iterate over the capabilities list, as the ID and offset of each capability
+    pub fn capabilities(&self) -> Capabilities<'config, C> {
+        let next = if self.read_u16(REG_STATUS) & STATUS_CAPABILITIES != 0 {
+            self.read_u8(REG_CAPABILITIES) & !3
+        } else {
+            0
+        };
+        Capabilities { function: *self, next, remaining: MAX_CAPABILITIES }
+    }
+}
+
+// Upper bound on the length of a capabilities list, such that a looping list ends
+const MAX_CAPABILITIES: usize = 48;
+
+/// Iterator over the capabilities of a function
+pub struct Capabilities<'config, C: ConfigSpace> {
+    function: Function<'config, C>,
+    // Offset of the next capability, 0 at the end of the list
+    next: u8,
+    remaining: usize,
+}
+
+impl<C: ConfigSpace> Iterator for Capabilities<'_, C> {
+    // Capability ID and its offset in the configuration space
+    type Item = (u8, u16);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The first 64 bytes are the header, capabilities are always after it
+        if self.next < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let offset = u16::from(self.next);
+        let id = self.function.read_u8(offset);
+        self.next = self.function.read_u8(offset + 1) & !3;
+        Some((id, offset))
+    }
+}
+
+/// Iterator over the functions reachable from the host bridges, following PCI-to-PCI bridges to
+/// the buses behind them
+pub struct Functions<'config, C: ConfigSpace> {
+    config: &'config C,
+    // Buses we found and have not scanned yet, one bit each
+    pending: [u64; 4],
+    // Bus being scanned, past 255 once we are done
+    bus: u16,
+    // Device and function number of the next address to probe on `bus`
+    slot: u16,
+    // The device being scanned has more than one function
+    multi_function: bool,
+}
+
+impl<C: ConfigSpace> Functions<'_, C> {
+    fn set_pending(&mut self, bus: u8) {
+        self.pending[usize::from(bus / 64)] |= 1 << (bus % 64);
+    }
+
+    fn is_pending(&self, bus: u8) -> bool {
+        self.pending[usize::from(bus / 64)] & (1 << (bus % 64)) != 0
+    }
+}
+
+impl<'config, C: ConfigSpace> Iterator for Functions<'config, C> {
+    type Item = Function<'config, C>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Ok(bus) = u8::try_from(self.bus) {
+            if !self.is_pending(bus) || self.slot >= 256 {
+                self.bus += 1;
+                self.slot = 0;
+                continue;
+            }
+
+            let (device, function) = ((self.slot / 8) as u8, (self.slot % 8) as u8);
+            let found = Function::new(self.config, PciAddress::new(bus, device, function));
+            if function == 0 {
+                self.multi_function = found.is_some_and(|found| found.is_multi_function());
+            }
+            // The other functions only exist in multi-function devices
+            self.slot += if function == 0 && !self.multi_function { 8 } else { 1 };
+
+            if let Some(found) = found {
+                // Firmware numbers the buses depth first, so the ones behind a bridge come after
+                // the bus of the bridge
+                if let Some((_, secondary, _)) = found.bridge_buses() {
+                    self.set_pending(secondary);
+                }
+                return Some(found);
+            }
+        }
+        None
+    }
+}
+
+/// Iterate over every function on the buses reachable from the host bridges
+pub fn functions<C: ConfigSpace>(config: &C) -> Functions<'_, C> {
+    let mut functions =
+        Functions { config, pending: [0; 4], bus: 0, slot: 0, multi_function: false };
+    functions.set_pending(0);
+    // With more than one host bridge, function N of the first one is the root of bus N
+    let host = Function::new(config, PciAddress::new(0, 0, 0));
+    if host.is_some_and(|host| host.is_multi_function()) {
+        for function in 1..8 {
+            if Function::new(config, PciAddress::new(0, 0, function)).is_some() {
+                functions.set_pending(function);
+            }
+        }
+    }
+    functions
+}
+
+/// Returns the first function with `vendor_id` and one of the `device_ids`
+pub fn find<'config, C: ConfigSpace>(
+    config: &'config C,
+    vendor_id: u16,
+    device_ids: &[u16],
+) -> Option<Function<'config, C>> {
+    functions(config).find(|function| {
+        function.vendor_id() == vendor_id && device_ids.contains(&function.device_id())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate std;
+    use std::{cell::RefCell, collections::BTreeMap, vec::Vec};
+
+    // Configuration space of a few made up functions
+    #[derive(Default)]
+    struct FakeConfig {
+        functions: RefCell<BTreeMap<PciAddress, [u8; 256]>>,
+        // Bits of a register the function lets software change, all of them when not listed
+        writable: RefCell<BTreeMap<(PciAddress, u16), u32>>,
+    }
+
+    impl FakeConfig {
+        fn add(&self, address: PciAddress, vendor_id: u16, device_id: u16, header_type: u8) {
+            let mut space = [0u8; 256];
+            space[0..2].copy_from_slice(&vendor_id.to_le_bytes());
+            space[2..4].copy_from_slice(&device_id.to_le_bytes());
+            space[REG_HEADER_TYPE as usize] = header_type;
+            self.functions.borrow_mut().insert(address, space);
+        }
+
+        fn set_u32(&self, address: PciAddress, offset: u16, value: u32) {
+            let offset = usize::from(offset);
+            if let Some(space) = self.functions.borrow_mut().get_mut(&address) {
+                space[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        // Make the BAR at `index` decode `size` bytes, with its type in the read-only `flags`
+        fn set_bar(&self, address: PciAddress, index: u8, value: u32, size: u32) {
+            let offset = REG_BAR0 + u16::from(index) * 4;
+            self.set_u32(address, offset, value);
+            let flags = if value & 1 != 0 { 3 } else { 0xf };
+            self.writable.borrow_mut().insert((address, offset), !(size - 1) & !flags);
+        }
+    }
+
+    impl ConfigSpace for FakeConfig {
+        fn read_u32(&self, address: PciAddress, offset: u16) -> u32 {
+            let offset = usize::from(offset);
+            self.functions.borrow().get(&address).map_or(u32::MAX, |space| {
+                u32::from_le_bytes(space[offset..offset + 4].try_into().unwrap())
+            })
+        }
+
+        fn write_u32(&self, address: PciAddress, offset: u16, value: u32) {
+            let writable = self.writable.borrow().get(&(address, offset)).copied();
+            let value = match writable {
+                Some(mask) => (self.read_u32(address, offset) & !mask) | (value & mask),
+                None => value,
+            };
+            self.set_u32(address, offset, value);
+        }
+    }
+
+    // Add a PCI-to-PCI bridge forwarding to the `secondary..=subordinate` buses
+    fn add_bridge(config: &FakeConfig, address: PciAddress, secondary: u8, subordinate: u8) {
+        config.add(address, 0x1b36, 0x0001, HEADER_TYPE_BRIDGE);
+        let buses = u32::from_le_bytes([address.bus, secondary, subordinate, 0]);
+        config.set_u32(address, REG_PRIMARY_BUS, buses);
+    }
+
+    #[test]
+    fn enumerate() {
+        let config = FakeConfig::default();
+        config.add(PciAddress::new(0, 0, 0), 0x8086, 0x1237, 0);
+        // Multi-function device
+        config.add(PciAddress::new(0, 1, 0), 0x8086, 0x7000, HEADER_MULTI_FUNCTION);
+        config.add(PciAddress::new(0, 1, 3), 0x8086, 0x7113, 0);
+        // Functions other than 0 of single function devices are not probed
+        config.add(PciAddress::new(0, 2, 0), 0x1af4, 0x1000, 0);
+        config.add(PciAddress::new(0, 2, 1), 0x1af4, 0x1041, 0);
+        // Buses are only scanned when a bridge leads to them
+        add_bridge(&config, PciAddress::new(0, 3, 0), 3, 4);
+        add_bridge(&config, PciAddress::new(3, 0, 0), 4, 4);
+        config.add(PciAddress::new(4, 31, 0), 0x1af4, 0x1041, 0);
+        config.add(PciAddress::new(7, 0, 0), 0x1af4, 0x1041, 0);
+
+        let found = functions(&config).map(|function| function.address).collect::<Vec<_>>();
+        assert_eq!(found, [
+            PciAddress::new(0, 0, 0),
+            PciAddress::new(0, 1, 0),
+            PciAddress::new(0, 1, 3),
+            PciAddress::new(0, 2, 0),
+            PciAddress::new(0, 3, 0),
+            PciAddress::new(3, 0, 0),
+            PciAddress::new(4, 31, 0),
+        ]);
+
+        let bridge = Function::new(&config, PciAddress::new(0, 3, 0)).unwrap();
+        assert_eq!(bridge.bridge_buses(), Some((0, 3, 4)));
+        assert_eq!(bridge.bar_count(), BRIDGE_BARS);
+        let virtio = find(&config, 0x1af4, &[0x1041, 0x1000]).expect("Device not found");
+        assert_eq!(virtio.address, PciAddress::new(0, 2, 0));
+        assert_eq!(virtio.bridge_buses(), None);
+        assert!(find(&config, 0x1af4, &[0x1042]).is_none());
+        assert_eq!(std::format!("{}", PciAddress::new(3, 31, 7)), "03:1f.7");
+    }
+
+    #[test]
+    fn multiple_host_bridges() {
+        let config = FakeConfig::default();
+        config.add(PciAddress::new(0, 0, 0), 0x8086, 0x29c0, HEADER_MULTI_FUNCTION);
+        config.add(PciAddress::new(0, 0, 2), 0x8086, 0x29c0, 0);
+        config.add(PciAddress::new(2, 5, 0), 0x1af4, 0x1041, 0);
+
+        let found = functions(&config).map(|function| function.address).collect::<Vec<_>>();
+        assert_eq!(found, [
+            PciAddress::new(0, 0, 0),
+            PciAddress::new(0, 0, 2),
+            PciAddress::new(2, 5, 0),
+        ]);
+    }
+
+    #[test]
+    fn bars() {
+        let config = FakeConfig::default();
+        let address = PciAddress::new(0, 3, 0);
+        config.add(address, 0x1af4, 0x1000, 0);
+        config.set_bar(address, 0, 0xc041, 0x40);
+        config.set_bar(address, 1, 0xfebf_1000, 0x1000);
+        // 64-bit prefetchable BAR, above 4GiB
+        config.set_bar(address, 4, 0x0000_000c, 0x4000);
+        config.set_u32(address, REG_BAR0 + 20, 0x0000_0080);
+        config.set_u32(address, REG_COMMAND, u32::from(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+        let function = Function::new(&config, address).expect("Function not found");
+        assert_eq!(function.bar(0), Some(Bar::Io { port: 0xc040 }));
+        assert_eq!(function.bar(1), Some(Bar::Memory {
+            address: 0xfebf_1000,
+            prefetchable: false,
+            is_64: false,
+        }));
+        assert_eq!(function.bar(2), None);
+        assert_eq!(function.bar(4), Some(Bar::Memory {
+            address: 0x80_0000_0000,
+            prefetchable: true,
+            is_64: true,
+        }));
+        // The upper half of the 64-bit BAR is not a BAR of its own
+        assert_eq!(function.bar(5), None);
+        assert_eq!(function.bar(6), None);
+
+        assert_eq!(function.bar_size(0), Some(0x40));
+        assert_eq!(function.bar_size(1), Some(0x1000));
+        assert_eq!(function.bar_size(2), None);
+        assert_eq!(function.bar_size(4), Some(0x4000));
+        // Sizing leaves the BARs and the command register as they were
+        assert_eq!(function.bar(0), Some(Bar::Io { port: 0xc040 }));
+        assert_eq!(function.bar(4), Some(Bar::Memory {
+            address: 0x80_0000_0000,
+            prefetchable: true,
+            is_64: true,
+        }));
+        assert_eq!(function.read_u16(REG_COMMAND), COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE);
+    }
+
+    #[test]
+    fn capabilities() {
+        let config = FakeConfig::default();
+        let address = PciAddress::new(0, 3, 0);
+        config.add(address, 0x1af4, 0x1041, 0);
+        // MSI-X at 0x98, PCIe at 0x70, MSI at 0x50 and a vendor capability at 0x40
+        config.set_u32(address, REG_COMMAND, u32::from(STATUS_CAPABILITIES) << 16);
+        config.set_u32(address, REG_CAPABILITIES, 0x98);
+        config.set_u32(address, 0x98, 0x8002_7011);
+        config.set_u32(address, 0x9c, 0x0000_3001);
+        config.set_u32(address, 0xa0, 0x0000_3801);
+        config.set_u32(address, 0x70, 0x0002_5010);
+        config.set_u32(address, 0x80, 0x0041_0000);
+        config.set_u32(address, 0x50, 0x0186_4005);
+        config.set_u32(address, 0x40, 0x0000_0009);
+
+        let function = Function::new(&config, address).expect("Function not found");
+        let capabilities = function.capabilities().collect::<Vec<_>>();
+        assert_eq!(capabilities, [(0x11, 0x98), (0x10, 0x70), (0x05, 0x50), (0x09, 0x40)]);
+        assert_eq!(function.find_capability(CAP_VENDOR_SPECIFIC), Some(0x40));
+        assert_eq!(function.find_capability(CAP_POWER_MANAGEMENT), None);
+
+        assert_eq!(function.msix(), Some(MsiX {
+            offset: 0x98,
+            table_size: 3,
+            table_bar: 1,
+            table_offset: 0x3000,
+            pba_bar: 1,
+            pba_offset: 0x3800,
+            enabled: true,
+        }));
+        let pcie = function.pcie().expect("No PCIe capability");
+        assert_eq!((pcie.port_type, pcie.link_speed, pcie.link_width), (PortType::Endpoint, 1, 4));
+        let msi = function.msi().expect("No MSI capability");
+        assert_eq!((msi.vectors(), msi.is_64bit(), msi.enabled()), (8, true, false));
+        assert!(msi.per_vector_masking());
+
+        // A list pointing back to itself ends
+        config.set_u32(address, 0x40, 0x0000_9809);
+        assert_eq!(function.capabilities().count(), MAX_CAPABILITIES);
+
+        function.enable(COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER);
+        assert_eq!(function.read_u16(REG_COMMAND), COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER);
+        assert_eq!(function.read_u16(REG_STATUS), STATUS_CAPABILITIES);
+    }
+
+    #[test]
+    fn ecam() {
+        // Two buses of ECAM region, starting at bus 1
+        let mut region = std::vec![u32::MAX; 2 << 18];
+        let config = unsafe { EcamConfig::new(region.as_mut_ptr() as u64, 1, 2) };
+        let address = PciAddress::new(2, 3, 1);
+        let base = ((1 << 20) | (3 << 15) | (1 << 12)) / 4;
+        region[base] = 0x1041_1af4;
+        let config = &config;
+
+        assert_eq!(config.read_u16(address, REG_DEVICE_ID), 0x1041);
+        config.write_u32(address, 0x100, 0x1234_5678);
+        assert_eq!(config.read_u32(address, 0x100), 0x1234_5678);
+        assert_eq!(config.read_u8(address, 0x103), 0x12);
+        // Buses outside of the region and offsets past 4KiB read as all ones
+        assert_eq!(config.read_u32(PciAddress::new(0, 3, 1), 0), u32::MAX);
+        assert_eq!(config.read_u32(PciAddress::new(3, 3, 1), 0), u32::MAX);
+        assert_eq!(config.read_u32(address, 4096), u32::MAX);
+        assert_eq!(region[base + 0x40], 0x1234_5678);
+    }
+
+    #[test]
+    fn drivers() {
+        const VIRTIO_NET: &[DeviceId] = &[DeviceId::new(0x1af4, 0x1000)];
+        let config = FakeConfig::default();
+        config.add(PciAddress::new(0, 0, 0), 0x8086, 0x1237, 0);
+        config.add(PciAddress::new(0, 2, 0), 0x1af4, 0x1000, 0);
+        config.add(PciAddress::new(0, 3, 0), 0x1af4, 0x1000, 0);
+
+        // The first driver only takes the device in slot 3, the second one takes the rest
+        let mut drivers = Drivers::new();
+        drivers.register(Driver {
+            name: "picky",
+            ids: VIRTIO_NET,
+            probe: |function| function.address.device == 3,
+        }).unwrap();
+        drivers.register(Driver { name: "virtio-net", ids: VIRTIO_NET, probe: |_| true }).unwrap();
+        assert_eq!(
+            drivers.register(Driver { name: "picky", ids: &[], probe: |_| true }).err(),
+            Some(DriverError::AlreadyRegistered("picky")),
+        );
+
+        let mut bound = Vec::new();
+        drivers.probe_all(&config, |function, driver| bound.push((function.address, driver.name)));
+        assert_eq!(bound, [
+            (PciAddress::new(0, 2, 0), "virtio-net"),
+            (PciAddress::new(0, 3, 0), "picky"),
+        ]);
+
+        let host = Function::new(&config, PciAddress::new(0, 0, 0)).unwrap();
+        assert!(drivers.find(&host).is_none());
+        for index in 2..MAX_DRIVERS {
+            let name = std::format!("driver{}", index).leak();
+            drivers.register(Driver { name, ids: &[], probe: |_| false }).unwrap();
+        }
+        assert_eq!(
+            drivers.register(Driver { name: "extra", ids: &[], probe: |_| false }).err(),
+            Some(DriverError::RegistryFull),
+        );
+    }
+
+    #[test]
+    fn class_names() {
+        assert_eq!(class_name(0x02, 0x00), "Ethernet controller");
+        assert_eq!(class_name(0x02, 0x80), "Network controller");
+        assert_eq!(class_name(0x06, 0x04), "PCI bridge");
+        assert_eq!(class_name(0x42, 0x00), "Unknown class");
+        assert_eq!(capability_name(CAP_MSIX), "MSI-X");
+    }
+}
@@ -10,6 +10,10 @@ const PXE_MAX_SIZE: u64 = 32 * 1024;
 
 const BOOTLOADER_BASE: u64 = 0x7e00;
 
+// The stage 0 downloads the flattened bootloader below the EBDA and the PXE stack, which leave at
+// least this much of the conventional memory free
+const BOOTLOADER_MAX_END: u64 = 0x8_0000;
+
 fn main() {
     // Call nasm to build the bootloader to be executed by the BIOS
     let nasm_build = Command::new("nasm")
@@ -56,16 +60,19 @@ fn main() {
 
 
     // Dump all the sections into the flat bootloader
-    bootloader_pe.access_sections(|base, _size, bytes| {
+    bootloader_pe.access_sections(|base, size, bytes| {
         // Compute the offset into the file
         let flat_offset = u64::try_from(base.saturating_sub(image_start))
             .expect("Cannot convert to u64");
         // Seek to that offset
         flat_file.seek(SeekFrom::Start(flat_offset)).expect("Failed to seek");
         // Write the contents of the section into the flat bootloader file
-        flat_file.write(bytes).ok()?;
+        flat_file.write_all(bytes).ok()?;
+        // The rest of the section, like `.bss`, is not in the PE and has to be zeroed
+        let uninitialized = usize::try_from(size).ok()?.saturating_sub(bytes.len());
+        flat_file.write_all(&vec![0u8; uninitialized]).ok()?;
         Some(())
-    });
+    }).expect("Failed to flatten the bootloader");
 
     // Get the entry point
     let entry_point = bootloader_pe.entry_point();
@@ -84,7 +91,8 @@ fn main() {
         std::process::exit(1);
     }
 
-    // Check the size of the bootloader
+    // Check the size of the bootloader. Only the stage 0 is loaded by the PXE ROM, which then
+    // downloads the flattened bootloader from the TFTP server.
     let size = std::fs::metadata("../bootloader/build/pizza.boot")
         .expect("Failed to query bootfile metadata").len();
 
@@ -92,6 +100,13 @@ fn main() {
 
     println!("PXE Remote.0 size: {}", size);
 
+    let flat_size = std::fs::metadata("../bootloader/build/pizza.flat")
+        .expect("Failed to query the flattened bootloader metadata").len();
+
+    assert!(BOOTLOADER_BASE + flat_size <= BOOTLOADER_MAX_END);
+
+    println!("Flattened bootloader size: {}", flat_size);
+
     // Build the kernel
     let build_kernel = Command::new("cargo")
         .current_dir("../kernel")
@@ -107,4 +122,8 @@ fn main() {
         "../kernel/target/x86_64-pc-windows-msvc/release/kernel.exe",
         "../bootloader/build/pizza.kernel",
     ).unwrap();
+
+    // The DHCP server only names pizza.boot, the stage 0 then asks the same TFTP server for
+    // pizza.flat by name, and the bootloader for pizza.kernel unless a pizza.cfg says otherwise
+    println!("Deploy to the TFTP root: pizza.boot (the DHCP boot file), pizza.flat, pizza.kernel");
 }
@@ -0,0 +1,331 @@
+//! Module defining `GrowableRangeSet`, a set of ranges which is not limited to a fixed number of
+//! entries. Once paging is up, it moves its ranges into memory it allocates from itself.
+use crate::{sum, Range, RangeSet, Ranges};
+use core::mem::{align_of, size_of};
+
+/// Number of ranges the set can hold before it has memory of its own, the same as `RangeSet`
+const INLINE_CAPACITY: usize = 32;
+
+/// Returns a pointer through which we can access the memory at `address`, which is an address of
+/// the memory tracked by the set.
+pub type Translate = fn(address: u64) -> *mut u8;
+
+/// Memory taken from the set itself to hold its entries
+struct Storage {
+    /// Range taken out of the set
+    taken: Range,
+    /// Pointer to the first entry, through which we access the storage
+    ranges: *mut Range,
+    /// How many entries fit in the storage
+    capacity: usize,
+}
+
+/// A set of non-overlapping inclusive `u64` ranges, with the same operations as `RangeSet`.
+///
+/// It starts out with room for as many ranges as a `RangeSet`. After `enable_growth` is called,
+/// whenever it runs out of room it allocates a bigger storage from the ranges it holds, moves the
+/// ranges there and gives the memory of the old storage back to itself.
+pub struct GrowableRangeSet {
+    /// Storage used until the set grows for the first time
+    inline: [Range; INLINE_CAPACITY],
+    /// Storage allocated from the set, once it outgrew the inline one
+    storage: Option<Storage>,
+    /// Number of in use entries
+    in_use: u32,
+    /// Used to access the memory of a new storage. The set cannot grow until we have it.
+    translate: Option<Translate>,
+}
+
+impl Default for GrowableRangeSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GrowableRangeSet {
+    pub const fn new() -> Self {
+        Self {
+            inline: [Range { start: 0, end: 0 }; INLINE_CAPACITY],
+            storage: None,
+            in_use: 0,
+            translate: None,
+        }
+    }
+
+    /// Create a set holding the same ranges as the fixed size `set`, usually the one handed over
+    /// by the bootloader.
+    pub fn from_fixed(set: &RangeSet) -> Self {
+        let mut growable = Self::new();
+        growable.inline[..set.len()].copy_from_slice(set.entries());
+        growable.in_use = set.len() as u32;
+        growable
+    }
+
+    /// Returns a fixed size set holding the same ranges as this one, if they fit in it
+    pub fn to_fixed(&self) -> Option<RangeSet> {
+        let mut set = RangeSet::new();
+        for &range in self.entries() {
+            set.insert(range)?;
+        }
+        Some(set)
+    }
+
+    /// Allow the set to grow into memory it allocates from itself, which it accesses through
+    /// `translate`. This should be called once paging is up and the memory in the set is mapped.
+    pub fn enable_growth(&mut self, translate: Translate) {
+        self.translate = Some(translate);
+    }
+
+    /// Get all the entries in the set as a slice, sorted by their start
+    pub fn entries(&self) -> &[Range] {
+        match &self.storage {
+            Some(storage) => unsafe {
+                core::slice::from_raw_parts(storage.ranges, self.len())
+            },
+            None => &self.inline[..self.len()],
+        }
+    }
+
+    /// Iterate over the entries of the set, in address order
+    pub fn iter(&self) -> core::slice::Iter<'_, Range> {
+        self.entries().iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.in_use as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.in_use == 0
+    }
+
+    /// Returns how many ranges the set can hold before it has to grow again
+    pub fn capacity(&self) -> usize {
+        self.storage.as_ref().map_or(INLINE_CAPACITY, |storage| storage.capacity)
+    }
+
+    /// Returns the range the set took from itself to hold its entries, if it grew
+    pub fn storage(&self) -> Option<Range> {
+        self.storage.as_ref().map(|storage| storage.taken)
+    }
+
+    /// Insert a new range into the set. See `RangeSet::insert`.
+    pub fn insert(&mut self, range: Range) -> Option<()> {
+        self.reserve();
+        self.view().insert(range)
+    }
+
+    /// Remove `range` from the set. See `RangeSet::remove`.
+    pub fn remove(&mut self, range: Range) -> Option<()> {
+        self.reserve();
+        self.view().remove(range)
+    }
+
+    /// Remove `range` from the set, only if all of it is in the set
+    pub fn consume(&mut self, range: Range) -> Option<()> {
+        self.reserve();
+        self.view().consume(range)
+    }
+
+    /// Subtracts a `RangeSet` from `self`. See `RangeSet::subtract`.
+    pub fn subtract(&mut self, rs: &RangeSet) -> Option<()> {
+        for &range in rs.entries() {
+            self.remove(range)?;
+        }
+        Some(())
+    }
+
+    /// Only keep the parts of the set which are inside `range`
+    pub fn intersect(&mut self, range: Range) {
+        self.view().intersect(range)
+    }
+
+    /// Compute the size of the range covered by this set. Returns `None` if it does not fit in a
+    /// `u64`.
+    pub fn sum(&self) -> Option<u64> {
+        sum(self.entries())
+    }
+
+    /// Allocate `size` bytes of memory with `align` requirement for alignment. See
+    /// `RangeSet::allocate_in`.
+    pub fn allocate(&mut self, size: u64, align: u64) -> Option<usize> {
+        self.allocate_in(size, align, Range::new(0, u64::MAX))
+    }
+
+    /// Allocate `size` bytes of memory with `align` requirement for alignment, which ends at or
+    /// below `limit`. See `RangeSet::allocate_in`.
+    pub fn allocate_limit(&mut self, size: u64, align: u64, limit: u64) -> Option<usize> {
+        self.allocate_in(size, align, Range::new(0, limit))
+    }
+
+    /// Allocate `size` bytes of memory with `align` requirement for alignment, entirely inside
+    /// `range`. See `RangeSet::allocate_in`.
+    pub fn allocate_in(&mut self, size: u64, align: u64, range: Range) -> Option<usize> {
+        self.reserve();
+        self.view().allocate_in(size, align, range)
+    }
+
+    /// Allocate the `size` bytes at `address`, which all have to be in the set
+    pub fn allocate_at(&mut self, address: u64, size: u64) -> Option<usize> {
+        self.reserve();
+        self.view().allocate_at(address, size)
+    }
+
+    /// Returns a mutable view over the current storage of the set
+    fn view(&mut self) -> Ranges<'_> {
+        let ranges = match &self.storage {
+            Some(storage) => unsafe {
+                core::slice::from_raw_parts_mut(storage.ranges, storage.capacity)
+            },
+            None => &mut self.inline[..],
+        };
+        Ranges { ranges, in_use: &mut self.in_use }
+    }
+
+    /// Make room for the operation that follows, which adds at most one entry to the set. Growing
+    /// also takes a range from the set, so we grow while there is still room for that as well. If
+    /// we cannot grow, the operation fails by itself in case it needs the room.
+    fn reserve(&mut self) {
+        if self.len() + 2 <= self.capacity() {
+            return;
+        }
+        if let Some(translate) = self.translate {
+            self.grow(translate);
+        }
+    }
+
+    /// Move the entries to a storage twice as big, allocated from the set itself
+    fn grow(&mut self, translate: Translate) -> Option<()> {
+        let capacity = self.capacity().checked_mul(2)?;
+        // The number of entries has to fit in `in_use`
+        u32::try_from(capacity).ok()?;
+        let bytes = u64::try_from(capacity.checked_mul(size_of::<Range>())?).ok()?;
+
+        let start = self.view()
+            .allocate_in(bytes, align_of::<Range>() as u64, Range::new(0, u64::MAX))? as u64;
+        let taken = Range::new(start, start + bytes - 1);
+        let ranges = translate(start) as *mut Range;
+
+        // The new memory holds garbage, so we initialise all of it before using it as a slice
+        for idx in 0..capacity {
+            let range = self.entries().get(idx).copied().unwrap_or(Range::new(0, 0));
+            unsafe { ranges.add(idx).write(range) };
+        }
+
+        let old = self.storage.replace(Storage { taken, ranges, capacity });
+
+        // The storage we moved out of goes back in the set
+        if let Some(old) = old {
+            self.view().insert(old.taken)?;
+        }
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{check_canonical, points, Rng};
+
+    extern crate std;
+    use std::collections::BTreeSet;
+    use std::vec::Vec;
+
+    /// Size of the memory the set can grow into in the model test
+    const ARENA_SIZE: usize = 16 * 1024;
+    /// Address at which the arena is tracked by the set
+    const ARENA_BASE: u64 = 0x10000;
+
+    #[repr(C, align(4096))]
+    struct Arena([u8; ARENA_SIZE]);
+
+    /// Memory backing the storage of the set in the model test, the only test growing a set
+    static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+    fn translate(address: u64) -> *mut u8 {
+        let offset = usize::try_from(address - ARENA_BASE).unwrap();
+        assert!(offset < ARENA_SIZE, "Set grew outside of the arena");
+        unsafe { core::ptr::addr_of_mut!(ARENA).cast::<u8>().add(offset) }
+    }
+
+    #[test]
+    fn no_growth_before_paging() {
+        let mut set = GrowableRangeSet::new();
+        for idx in 0..INLINE_CAPACITY as u64 {
+            set.insert(Range::new(idx * 10, idx * 10 + 5)).expect("Could not insert range");
+        }
+        assert!(set.insert(Range::new(1000, 1005)).is_none());
+        assert_eq!(set.len(), INLINE_CAPACITY);
+        assert!(set.storage().is_none());
+    }
+
+    #[test]
+    fn fixed_handoff() {
+        let mut fixed = RangeSet::new();
+        fixed.insert(Range::new(0, 10)).expect("Could not insert range");
+        fixed.insert(Range::new(15, 20)).expect("Could not insert range");
+
+        let mut set = GrowableRangeSet::from_fixed(&fixed);
+        assert_eq!(set.entries(), fixed.entries());
+
+        set.remove(Range::new(5, 16)).expect("Could not remove range");
+        let fixed = set.to_fixed().expect("Could not convert set");
+        assert_eq!(fixed.entries(), &[Range::new(0, 4), Range::new(17, 20)]);
+    }
+
+    // Compare the set against a model of the points it holds, while it grows
+    #[test]
+    fn growable_model() {
+        // Ranges of the operations we do are picked from [0, UNIVERSE). Even the first storage
+        // the set grows into does not fit in it, such that it always comes from the arena.
+        const UNIVERSE: u64 = 1024;
+
+        let mut rng = Rng::new(0xdead_beef_1234_5678);
+        let mut set = GrowableRangeSet::new();
+        let arena = Range::new(ARENA_BASE, ARENA_BASE + ARENA_SIZE as u64 - 1);
+        set.insert(arena).expect("Could not insert range");
+        set.enable_growth(translate);
+
+        // Points in [0, UNIVERSE) which are in the set
+        let mut model = BTreeSet::new();
+
+        for _ in 0..5000 {
+            let range = rng.range(UNIVERSE, 16);
+            let points = points(&[range]);
+            match rng.below(3) {
+                0 => {
+                    set.insert(range).expect("Could not insert range");
+                    model = &model | &points;
+                }
+                1 => {
+                    set.remove(range).expect("Could not remove range");
+                    model = &model - &points;
+                }
+                _ => {
+                    let expected = points.is_subset(&model);
+                    assert_eq!(set.consume(range).is_some(), expected);
+                    if expected {
+                        model = &model - &points;
+                    }
+                }
+            }
+
+            check_canonical(set.entries());
+            let (user, rest): (Vec<Range>, Vec<Range>) = set.iter()
+                .partition(|range| range.end < UNIVERSE);
+            assert_eq!(self::points(&user), model);
+            assert!(set.len() < set.capacity());
+
+            // Whatever the set did not take for its storage is still in it
+            let storage = set.storage().unwrap_or(Range::new(ARENA_BASE, ARENA_BASE - 1));
+            assert!(!storage.is_valid() || arena.contains(&storage));
+            assert!(rest.iter().all(|range| arena.contains(range) && !range.overlaps(&storage)));
+            let storage_size = storage.end.wrapping_sub(storage.start).wrapping_add(1);
+            assert_eq!(set.sum(), Some(model.len() as u64 + ARENA_SIZE as u64 - storage_size));
+        }
+
+        // The set had to outgrow the inline storage more than once
+        assert!(set.capacity() > 2 * INLINE_CAPACITY);
+    }
+}
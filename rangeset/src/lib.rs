@@ -1,14 +1,22 @@
-//! Library which provides a `RangeSet` which contains non-overlapping sets of
-//! `u64` inclusive ranges. The `RangeSet` can be used to insert or remove
-//! ranges of `u64`s and thus is very useful for physical memory management.
+//! Library which provides a `RangeSet` which contains non-overlapping sets of `u64` inclusive
+//! ranges. The `RangeSet` can be used to insert or remove ranges of `u64`s and thus is very useful
+//! for physical memory management.
+//!
+//! The entries of a set are kept sorted by their start and they never overlap or touch, such that
+//! iterating the set walks the ranges in address order.
 
 #![no_std]
 
+mod growable;
+
+pub use growable::{GrowableRangeSet, Translate};
+
 use core::cmp;
+use core::ops::RangeInclusive;
 
-/// An inclusive range. We do not use `RangeInclusive` as it does not implement
-/// `Copy`
-#[derive(Clone, Copy, Debug)]
+/// An inclusive range. We do not use `RangeInclusive` as it does not implement `Copy` and its
+/// layout is not fixed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(C)]
 pub struct Range {
     pub start: u64,
@@ -16,9 +24,38 @@ pub struct Range {
 }
 
 impl Range {
-    pub fn new(start: u64, end: u64) -> Self {
+    pub const fn new(start: u64, end: u64) -> Self {
         Self { start, end }
     }
+
+    /// Returns `false` if the range is descending, which we do not support
+    pub fn is_valid(&self) -> bool {
+        self.start <= self.end
+    }
+
+    /// Returns `true` if the entirety of `other` is contained inside this range
+    pub fn contains(&self, other: &Range) -> bool {
+        self.is_valid() && other.is_valid() && self.start <= other.start && other.end <= self.end
+    }
+
+    /// Returns `true` if the two ranges have any point in common
+    pub fn overlaps(&self, other: &Range) -> bool {
+        self.is_valid() && other.is_valid() && self.start <= other.end && other.start <= self.end
+    }
+
+    /// Returns `true` if the two ranges overlap or if one of them starts right after the other
+    /// ends, in which case they can be merged into a single range.
+    fn overlaps_or_touches(&self, other: &Range) -> bool {
+        self.is_valid() && other.is_valid()
+            && self.start <= other.end.saturating_add(1)
+            && other.start <= self.end.saturating_add(1)
+    }
+}
+
+impl From<RangeInclusive<u64>> for Range {
+    fn from(range: RangeInclusive<u64>) -> Self {
+        Self::new(*range.start(), *range.end())
+    }
 }
 
 /// A set of non-overlapping inclusive `u64` ranges
@@ -30,12 +67,17 @@ pub struct RangeSet {
 
     /// Number of in use entries in `ranges`
     ///
-    /// This is not a usize to make the structure fixed size so we can pass it
-    /// directly from protected mode to long mode. Since `ranges` is fixed u32
-    /// is plenty large for this use.
+    /// This is not a usize to make the structure fixed size so we can pass it directly from
+    /// protected mode to long mode. Since `ranges` is fixed u32 is plenty large for this use.
     in_use: u32,
 }
 
+impl Default for RangeSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RangeSet {
     /// Create a new empty RangeSet
     pub const fn new() -> RangeSet {
@@ -45,232 +87,778 @@ impl RangeSet {
         }
     }
 
-    /// Get all the entries in the RangeSet as a slice
+    /// Returns a mutable view over the entries of this set
+    fn view(&mut self) -> Ranges<'_> {
+        Ranges { ranges: &mut self.ranges, in_use: &mut self.in_use }
+    }
+
+    /// Get all the entries in the RangeSet as a slice, sorted by their start
     pub fn entries(&self) -> &[Range] {
         &self.ranges[..self.in_use as usize]
     }
 
-    /// Delete the Range contained in the RangeSet at `idx`
-    fn delete(&mut self, idx: usize) {
-        assert!(idx < self.in_use as usize, "Index out of bounds");
+    /// Iterate over the entries of the RangeSet, in address order
+    pub fn iter(&self) -> core::slice::Iter<'_, Range> {
+        self.entries().iter()
+    }
 
-        // Copy the deleted range to the end of the list
-        for ii in idx..self.in_use as usize - 1 {
-            self.ranges.swap(ii, ii+1);
-        }
+    pub fn len(&self) -> usize {
+        self.in_use as usize
+    }
 
-        // Decrement the number of valid ranges
-        self.in_use -= 1;
+    pub fn is_empty(&self) -> bool {
+        self.in_use == 0
     }
 
     /// Insert a new range into this RangeSet.
     ///
-    /// If the range overlaps with an existing range, then the ranges will
-    /// be merged. If the range has no overlap with an existing range then
-    /// it will simply be added to the set.
-    pub fn insert(&mut self, mut range: Range) {
-        assert!(range.start <= range.end, "Invalid range shape");
-
-        // Outside loop forever until we run out of merges with existing
-        // ranges.
-        'try_merges: loop {
-            for ii in 0..self.in_use as usize {
-                let ent = self.ranges[ii];
-
-                // Check for overlap with an existing range.
-                // Note that we do a saturated add of one to each range.
-                // This is done so that two ranges that are 'touching' but
-                // not overlapping will be combined.
-                if !overlaps(range.start, range.end.saturating_add(1),
-                        ent.start, ent.end.saturating_add(1)) {
-                    continue;
-                }
+    /// If the range overlaps or touches existing ranges, then the ranges will be merged. If the
+    /// range has no overlap with an existing range then it will simply be added to the set.
+    /// Returns `None` if the range is descending or if there is no room left for a new entry.
+    pub fn insert(&mut self, range: Range) -> Option<()> {
+        self.view().insert(range)
+    }
 
-                // There was overlap with an existing range. Make this range
-                // a combination of the existing ranges.
-                range.start = cmp::min(range.start, ent.start);
-                range.end   = cmp::max(range.end,   ent.end);
+    /// Remove `range` from the RangeSet
+    ///
+    /// Any range in the RangeSet which overlaps with `range` will be trimmed such that there is no
+    /// more overlap. If this results in a range in the set becoming empty, the range will be
+    /// removed entirely from the set. Parts of `range` which are not in the set are ignored.
+    /// Returns `None`, without changing the set, if the range is descending or if an entry has to
+    /// be split in two and there is no room left for it.
+    pub fn remove(&mut self, range: Range) -> Option<()> {
+        self.view().remove(range)
+    }
 
-                // Delete the old range, as the new one is now all inclusive
-                self.delete(ii);
+    /// Remove `range` from the RangeSet, only if all of it is in the set
+    pub fn consume(&mut self, range: Range) -> Option<()> {
+        self.view().consume(range)
+    }
 
-                // Start over looking for merges
-                continue 'try_merges;
-            }
+    /// Subtracts a `RangeSet` from `self`. Returns `None` if we ran out of room while doing so,
+    /// in which case only part of `rs` was subtracted.
+    pub fn subtract(&mut self, rs: &RangeSet) -> Option<()> {
+        self.view().subtract(rs.entries())
+    }
 
-            break;
-        }
+    /// Only keep the parts of the RangeSet which are inside `range`
+    pub fn intersect(&mut self, range: Range) {
+        self.view().intersect(range)
+    }
 
-        assert!((self.in_use as usize) < self.ranges.len(),
-            "Too many entries in RangeSet on insert");
+    /// Compute the size of the range covered by this rangeset. Returns `None` if it does not fit
+    /// in a `u64`.
+    pub fn sum(&self) -> Option<u64> {
+        sum(self.entries())
+    }
 
-        // Add the new range to the end
-        self.ranges[self.in_use as usize] = range;
-        self.in_use += 1;
+    /// Allocate `size` bytes of memory with `align` requirement for alignment. See `allocate_in`.
+    pub fn allocate(&mut self, size: u64, align: u64) -> Option<usize> {
+        self.view().allocate_in(size, align, Range::new(0, u64::MAX))
     }
 
-    /// Remove `range` from the RangeSet
+    /// Allocate `size` bytes of memory with `align` requirement for alignment, which ends at or
+    /// below `limit`. See `allocate_in`.
+    pub fn allocate_limit(&mut self, size: u64, align: u64, limit: u64) -> Option<usize> {
+        self.view().allocate_in(size, align, Range::new(0, limit))
+    }
+
+    /// Allocate `size` bytes of memory with `align` requirement for alignment, entirely inside
+    /// `range`. Returns the address of the allocation, which is removed from the set.
     ///
-    /// Any range in the RangeSet which overlaps with `range` will be trimmed
-    /// such that there is no more overlap. If this results in a range in
-    /// the set becoming empty, the range will be removed entirely from the
-    /// set.
-    pub fn remove(&mut self, range: Range) {
-        assert!(range.start <= range.end, "Invalid range shape");
-        
-        'try_subtractions: loop {
-            for ii in 0..self.in_use as usize {
-                let ent = self.ranges[ii];
-
-                // If there is no overlap, there is nothing to do with this
-                // range.
-                if !overlaps(range.start, range.end, ent.start, ent.end) {
-                    continue;
-                }
+    /// We pick the smallest entry the allocation fits in, which is the one at the lowest address
+    /// if there are more of the same size, and allocate from its lowest aligned address. Only the
+    /// allocated bytes are removed, the ones skipped for the alignment remain in the set.
+    /// Allocation fails if:
+    /// - `size` is zero or `align` is not a power of 2
+    /// - There is no entry in which the allocation fits, inside `range`
+    /// - The allocation does not fit into the pointer size of the target. For example trying to
+    ///   allocate 0xff_ffff_ffff in 32-bit mode.
+    /// - Aligning the allocation splits an entry and there is no room left for it
+    pub fn allocate_in(&mut self, size: u64, align: u64, range: Range) -> Option<usize> {
+        self.view().allocate_in(size, align, range)
+    }
 
-                // If this entry is entirely contained by the range to remove,
-                // then we can just delete it.
-                if contains(ent.start, ent.end, range.start, range.end) {
-                    self.delete(ii);
-                    continue 'try_subtractions;
-                }
+    /// Allocate the `size` bytes at `address`, which all have to be in the set
+    pub fn allocate_at(&mut self, address: u64, size: u64) -> Option<usize> {
+        self.view().allocate_at(address, size)
+    }
+}
 
-                // At this point we know there is overlap, but only partial.
-                // This means we need to adjust the size of the current range
-                // and potentially insert a new entry if the entry is split
-                // in two.
-
-                if range.start <= ent.start {
-                    // If the overlap is on the low end of the range, adjust
-                    // the start of the range to the end of the range we want
-                    // to remove.
-                    self.ranges[ii].start = range.end.saturating_add(1);
-                } else if range.end >= ent.end {
-                    // If the overlap is on the high end of the range, adjust
-                    // the end of the range to the start of the range we want
-                    // to remove.
-                    self.ranges[ii].end = range.start.saturating_sub(1);
-                } else {
-                    // If the range to remove fits inside of the range then
-                    // we need to split it into two ranges.
-                    self.ranges[ii].start = range.end.saturating_add(1);
-
-                    assert!((self.in_use as usize) < self.ranges.len(),
-                        "Too many entries in RangeSet on split");
-
-                    self.ranges[self.in_use as usize] = Range {
-                        start: ent.start,
-                        end:   range.start.saturating_sub(1),
-                    };
-                    self.in_use += 1;
-                    continue 'try_subtractions;
-                }
-            }
+/// Operations shared by `RangeSet` and `GrowableRangeSet`, such that their users, like the
+/// physical memory manager, can work with either of them
+pub trait RangeSetOps {
+    /// Get all the entries in the set as a slice, sorted by their start
+    fn entries(&self) -> &[Range];
+    /// Insert a new range into the set, see `RangeSet::insert`
+    fn insert(&mut self, range: Range) -> Option<()>;
+    /// Allocate `size` bytes aligned to `align` inside `range`, see `RangeSet::allocate_in`
+    fn allocate_in(&mut self, size: u64, align: u64, range: Range) -> Option<usize>;
+    /// Allocate the `size` bytes at `address`, see `RangeSet::allocate_at`
+    fn allocate_at(&mut self, address: u64, size: u64) -> Option<usize>;
+
+    /// Allocate `size` bytes aligned to `align` anywhere in the set
+    fn allocate(&mut self, size: u64, align: u64) -> Option<usize> {
+        self.allocate_in(size, align, Range::new(0, u64::MAX))
+    }
+}
+
+impl RangeSetOps for RangeSet {
+    fn entries(&self) -> &[Range] {
+        RangeSet::entries(self)
+    }
+
+    fn insert(&mut self, range: Range) -> Option<()> {
+        RangeSet::insert(self, range)
+    }
+
+    fn allocate_in(&mut self, size: u64, align: u64, range: Range) -> Option<usize> {
+        RangeSet::allocate_in(self, size, align, range)
+    }
+
+    fn allocate_at(&mut self, address: u64, size: u64) -> Option<usize> {
+        RangeSet::allocate_at(self, address, size)
+    }
+}
+
+impl RangeSetOps for GrowableRangeSet {
+    fn entries(&self) -> &[Range] {
+        GrowableRangeSet::entries(self)
+    }
+
+    fn insert(&mut self, range: Range) -> Option<()> {
+        GrowableRangeSet::insert(self, range)
+    }
+
+    fn allocate_in(&mut self, size: u64, align: u64, range: Range) -> Option<usize> {
+        GrowableRangeSet::allocate_in(self, size, align, range)
+    }
+
+    fn allocate_at(&mut self, address: u64, size: u64) -> Option<usize> {
+        GrowableRangeSet::allocate_at(self, address, size)
+    }
+}
+
+impl<'a> IntoIterator for &'a RangeSet {
+    type Item = &'a Range;
+    type IntoIter = core::slice::Iter<'a, Range>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
 
-            break;
+/// Compute the size covered by `entries`, if it fits in a `u64`
+fn sum(entries: &[Range]) -> Option<u64> {
+    entries.iter().try_fold(0u64, |acc, x| acc.checked_add((x.end - x.start).checked_add(1)?))
+}
+
+/// Mutable view over the storage of a set of ranges. All the operations are implemented on it,
+/// such that they are shared between the sets with different backing storage.
+pub(crate) struct Ranges<'a> {
+    /// Storage for the entries, its length is the capacity of the set
+    pub(crate) ranges: &'a mut [Range],
+    /// Number of in use entries in `ranges`
+    pub(crate) in_use: &'a mut u32,
+}
+
+impl Ranges<'_> {
+    fn len(&self) -> usize {
+        *self.in_use as usize
+    }
+
+    fn entries(&self) -> &[Range] {
+        &self.ranges[..self.len()]
+    }
+
+    /// Insert `range` at `idx`, moving the entries after it one position to the right
+    fn insert_at(&mut self, idx: usize, range: Range) -> Option<()> {
+        let len = self.len();
+        if len == self.ranges.len() {
+            return None;
         }
+        self.ranges.copy_within(idx..len, idx + 1);
+        self.ranges[idx] = range;
+        *self.in_use += 1;
+        Some(())
+    }
+
+    /// Delete the entries at `idxs`, moving the entries after them to the left
+    fn delete(&mut self, idxs: core::ops::Range<usize>) {
+        let len = self.len();
+        self.ranges.copy_within(idxs.end..len, idxs.start);
+        *self.in_use -= idxs.len() as u32;
     }
 
-    /// Subtracts a `RangeSet` from `self`
-    pub fn subtract(&mut self, rs: &RangeSet) {
-        for &ent in rs.entries() {
-            self.remove(ent);
+    pub(crate) fn insert(&mut self, range: Range) -> Option<()> {
+        if !range.is_valid() {
+            return None;
         }
+
+        // The entries are sorted and do not touch, so the ones we have to merge with are next to
+        // each other
+        let first = self.entries().iter().position(|ent| ent.overlaps_or_touches(&range));
+        let first = match first {
+            Some(first) => first,
+            None => {
+                // Nothing to merge with, so the range goes in front of the first entry after it
+                let idx = self.entries().iter().position(|ent| ent.start > range.end)
+                    .unwrap_or(self.len());
+                return self.insert_at(idx, range);
+            }
+        };
+        let count = self.entries()[first..].iter()
+            .take_while(|ent| ent.overlaps_or_touches(&range))
+            .count();
+
+        // The first entry becomes the combination of all of them, and the others go away
+        let last = self.ranges[first + count - 1];
+        self.ranges[first] = Range::new(
+            cmp::min(range.start, self.ranges[first].start),
+            cmp::max(range.end, last.end),
+        );
+        self.delete(first + 1..first + count);
+        Some(())
     }
 
-    /// Compute the size of the range covered by this rangeset
-    pub fn sum(&self) -> Option<u64> {
-        self.entries().iter().try_fold(0u64, |acc, x| {
-            Some(acc + (x.end - x.start).checked_add(1)?)
-        })
+    pub(crate) fn remove(&mut self, range: Range) -> Option<()> {
+        if !range.is_valid() {
+            return None;
+        }
+
+        let mut idx = 0;
+        while idx < self.len() {
+            let ent = self.ranges[idx];
+
+            // If there is no overlap, there is nothing to do with this range.
+            if !ent.overlaps(&range) {
+                idx += 1;
+                continue;
+            }
+
+            // If this entry is entirely contained by the range to remove, then we can just
+            // delete it.
+            if range.contains(&ent) {
+                self.delete(idx..idx + 1);
+                continue;
+            }
+
+            if range.start > ent.start && range.end < ent.end {
+                // If the range to remove fits inside of the entry then we need to split it into
+                // two ranges. No other entry can overlap the range in this case.
+                self.insert_at(idx + 1, Range::new(range.end + 1, ent.end))?;
+                self.ranges[idx].end = range.start - 1;
+                return Some(());
+            } else if range.start <= ent.start {
+                // The overlap is on the low end of the entry
+                self.ranges[idx].start = range.end + 1;
+            } else {
+                // The overlap is on the high end of the entry
+                self.ranges[idx].end = range.start - 1;
+            }
+            idx += 1;
+        }
+
+        Some(())
     }
 
-    /// Allocate `size` bytes of memory with `align` requirement for alignment
-    pub fn allocate(&mut self, size: u64, align: u64) -> Option<usize> {
-        // Don't allow allocations of zero size
-        if size == 0 {
+    pub(crate) fn consume(&mut self, range: Range) -> Option<()> {
+        if !self.entries().iter().any(|ent| ent.contains(&range)) {
             return None;
         }
+        self.remove(range)
+    }
 
-        // Validate alignment is non-zero and a power of 2
-        if align.count_ones() != 1 {
+    pub(crate) fn subtract(&mut self, entries: &[Range]) -> Option<()> {
+        for &ent in entries {
+            self.remove(ent)?;
+        }
+        Some(())
+    }
+
+    pub(crate) fn intersect(&mut self, range: Range) {
+        let mut idx = 0;
+        while idx < self.len() {
+            let ent = self.ranges[idx];
+            if !ent.overlaps(&range) {
+                self.delete(idx..idx + 1);
+                continue;
+            }
+            self.ranges[idx] = Range::new(
+                cmp::max(ent.start, range.start),
+                cmp::min(ent.end, range.end),
+            );
+            idx += 1;
+        }
+    }
+
+    pub(crate) fn allocate_in(&mut self, size: u64, align: u64, range: Range) -> Option<usize> {
+        // Don't allow allocations of zero size
+        if size == 0 || !align.is_power_of_two() {
             return None;
         }
 
-        // Generate a mask for the specified alignment
-        let alignmask = align - 1;
+        // The allocation has to be addressable in the current processor state
+        let range = Range::new(range.start, cmp::min(range.end, usize::MAX as u64));
 
-        // Go through each memory range in the `RangeSet`
-        let mut allocation = None;
+        // Size of the entry the best allocation so far comes from, and the allocation itself
+        let mut best: Option<(u64, Range)> = None;
         for ent in self.entries() {
-            // Determine number of bytes required for front padding to satisfy
-            // alignment requirements.
-            let align_fix = (align - (ent.start & alignmask)) & alignmask;
-            
-            // Compute base and end of allocation as an inclusive range
-            // [base, end]
-            let base = ent.start;
-            let end  = base.checked_add(size - 1)?.checked_add(align_fix)?;
-
-            // Validate that this allocation is addressable in the current
-            // processor state.
-            if base > core::usize::MAX as u64 ||
-                    end > core::usize::MAX as u64 {
+            // Align the start of the allocation inside the entry and the range
+            let start = match cmp::max(ent.start, range.start).checked_add(align - 1) {
+                Some(start) => start & !(align - 1),
+                None => continue,
+            };
+            let end = match start.checked_add(size - 1) {
+                Some(end) => end,
+                None => continue,
+            };
+
+            // Check that this entry has enough room to satisfy the allocation
+            if end > ent.end || end > range.end {
                 continue;
             }
 
-            // Check that this entry has enough room to satisfy allocation
-            if end > ent.end {
-                continue;
+            // The entries are sorted, so on ties we keep the one at the lowest address
+            let ent_size = ent.end - ent.start;
+            if best.is_none_or(|(best_size, _)| ent_size < best_size) {
+                best = Some((ent_size, Range::new(start, end)));
             }
+        }
 
-            // Compute the "best" allocation size to date
-            let prev_size = allocation.map(|(base, end, _)| end - base);
+        let (_, allocation) = best?;
+        self.remove(allocation)?;
+        usize::try_from(allocation.start).ok()
+    }
 
-            if allocation.is_none() || prev_size.unwrap() > end - base {
-                // Update the allocation to the new best size
-                allocation = Some((base, end, (base + align_fix) as usize));
+    pub(crate) fn allocate_at(&mut self, address: u64, size: u64) -> Option<usize> {
+        let address_usize = usize::try_from(address).ok()?;
+        let end = address.checked_add(size.checked_sub(1)?)?;
+        usize::try_from(end).ok()?;
+        self.consume(Range::new(address, end))?;
+        Some(address_usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate std;
+    use std::collections::BTreeSet;
+    use std::vec::Vec;
+
+    /// Minimal xorshift PRNG, such that the model tests are reproducible
+    pub struct Rng(u64);
+
+    impl Rng {
+        pub fn new(seed: u64) -> Self {
+            Self(seed)
+        }
+
+        pub fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        pub fn below(&mut self, max: u64) -> u64 {
+            self.next() % max
+        }
+
+        /// Returns a range of at most `max_len` points in [0, universe)
+        pub fn range(&mut self, universe: u64, max_len: u64) -> Range {
+            let start = self.below(universe);
+            let end = cmp::min(start + self.below(max_len), universe - 1);
+            Range::new(start, end)
+        }
+    }
+
+    /// Returns all the points in `entries`, which is the model we check the sets against
+    pub fn points(entries: &[Range]) -> BTreeSet<u64> {
+        entries.iter().flat_map(|range| range.start..=range.end).collect()
+    }
+
+    /// Checks the entries are sorted, valid and that they do not overlap or touch
+    pub fn check_canonical(entries: &[Range]) {
+        assert!(entries.iter().all(|range| range.is_valid()));
+        assert!(entries.windows(2).all(|pair| {
+            pair[0].end < pair[1].start && pair[0].end + 1 < pair[1].start
+        }));
+    }
+
+    /// Returns the maximal ranges made out of the points in `model`
+    fn runs(model: &BTreeSet<u64>) -> Vec<Range> {
+        let mut runs: Vec<Range> = Vec::new();
+        for &point in model {
+            match runs.last_mut() {
+                Some(last) if last.end.checked_add(1) == Some(point) => last.end = point,
+                _ => runs.push(Range::new(point, point)),
             }
         }
+        runs
+    }
 
-        allocation.map(|(base, end, ptr)| {
-            // Remove this range from the available set
-            self.remove(Range { start: base, end: end });
+    fn set_from(model: &BTreeSet<u64>) -> RangeSet {
+        let mut set = RangeSet::new();
+        // Insert the points one by one, in an order which makes a lot of merges
+        let points = model.iter().rev();
+        for &point in points.clone().step_by(2).chain(points.skip(1).step_by(2)) {
+            set.insert(Range::new(point, point)).expect("Could not insert range");
+        }
+        assert_eq!(set.entries(), &runs(model)[..]);
+        set
+    }
 
-            // Return out the pointer!
-            ptr
+    /// Returns the allocation the model expects for `size` and `align`, inside `range`
+    fn model_allocate(
+        model: &BTreeSet<u64>,
+        size: u64,
+        align: u64,
+        range: Range,
+    ) -> Option<Range> {
+        runs(model).iter().filter_map(|run| {
+            let start = cmp::max(run.start, range.start).checked_add(align - 1)? & !(align - 1);
+            let end = start.checked_add(size - 1)?;
+            (end <= run.end && end <= range.end)
+                .then_some((run.end - run.start, Range::new(start, end)))
         })
+        .min_by_key(|(size, allocation)| (*size, allocation.start))
+        .map(|(_, allocation)| allocation)
     }
-}
 
-/// Determines of the two ranges [x1, x2] and [y1, y2] have any overlap
-fn overlaps(mut x1: u64, mut x2: u64, mut y1: u64, mut y2: u64) -> bool {
-    // Make sure x2 is always > x1
-    if x1 > x2 {
-        core::mem::swap(&mut x1, &mut x2);
+    #[test]
+    fn new() {
+        let set = RangeSet::new();
+        assert!(set.is_empty());
+        assert_eq!(set.sum(), Some(0));
     }
 
-    // Make sure y2 is always > y1
-    if y1 > y2 {
-        core::mem::swap(&mut y1, &mut y2);
+    #[test]
+    fn overlap() {
+        let range1 = Range::new(10, 20);
+        for (start, end) in [(10, 15), (10, 30), (10, 20), (5, 11), (5, 15), (5, 10), (15, 25),
+                (19, 25)] {
+            assert!(range1.overlaps(&Range::new(start, end)));
+            assert!(range1.overlaps_or_touches(&Range::new(start, end)));
+        }
     }
 
-    // Check if there is overlap
-    x1 <= y2 && y1 <= x2
-}
+    #[test]
+    fn touch() {
+        let range1 = Range::new(10, 20);
+        for (start, end) in [(5, 9), (21, 30)] {
+            assert!(!range1.overlaps(&Range::new(start, end)));
+            assert!(range1.overlaps_or_touches(&Range::new(start, end)));
+        }
+        assert!(Range::new(u64::MAX, u64::MAX).overlaps_or_touches(&Range::new(0, u64::MAX - 1)));
+        assert!(!Range::new(u64::MAX, u64::MAX).overlaps_or_touches(&Range::new(0, 0)));
+    }
+
+    #[test]
+    fn no_overlap() {
+        let range1 = Range::new(10, 20);
+        for (start, end) in [(5, 8), (22, 30), (15, 5), (15, 12), (25, 12)] {
+            assert!(!range1.overlaps_or_touches(&Range::new(start, end)));
+        }
+    }
+
+    #[test]
+    fn range_set_no_overlap() {
+        let mut set = RangeSet::new();
+        set.insert(Range::new(30, 40)).expect("Could not insert range");
+        set.insert(Range::new(0, 10)).expect("Could not insert range");
+        set.insert(Range::new(15, 20)).expect("Could not insert range");
+
+        assert_eq!(set.entries(), &[Range::new(0, 10), Range::new(15, 20), Range::new(30, 40)]);
+        assert_eq!(set.len(), 3);
+    }
+
+    #[test]
+    fn range_set_recursive_overlap() {
+        let mut set = RangeSet::new();
+        set.insert(Range::new(0, 10)).expect("Could not insert range");
+        set.insert(Range::new(15, 20)).expect("Could not insert range");
+        set.insert(Range::new(30, 40)).expect("Could not insert range");
+        set.insert(Range::new(19, 25)).expect("Could not insert range");
+        set.insert(Range::new(24, 35)).expect("Could not insert range");
+
+        assert_eq!(set.entries(), &[Range::new(0, 10), Range::new(15, 40)]);
+    }
+
+    #[test]
+    fn range_set_recursive_touching_overlap() {
+        let mut set = RangeSet::new();
+        set.insert(Range::new(0, 10)).expect("Could not insert range");
+        set.insert(Range::new(15, 20)).expect("Could not insert range");
+        set.insert(Range::new(30, 40)).expect("Could not insert range");
+        set.insert(Range::new(21, 29)).expect("Could not insert range");
+        set.insert(Range::new(11, 14)).expect("Could not insert range");
+
+        assert_eq!(set.entries(), &[Range::new(0, 40)]);
+    }
+
+    #[test]
+    fn range_set_basic_consume() {
+        let mut set = RangeSet::new();
+        set.insert(Range::new(0, 10)).expect("Could not insert range");
+        set.insert(Range::new(15, 20)).expect("Could not insert range");
+        set.insert(Range::new(30, 40)).expect("Could not insert range");
+        set.insert(Range::new(50, 100)).expect("Could not insert range");
+
+        set.consume(Range::new(0, 5)).expect("Could not consume");
+        set.consume(Range::new(6, 10)).expect("Could not consume");
+        set.consume(Range::new(15, 20)).expect("Could not consume");
+        set.consume(Range::new(33, 39)).expect("Could not consume");
+        set.consume(Range::new(55, 100)).expect("Could not consume");
+
+        assert!(set.consume(Range::new(49, 50)).is_none());
+
+        assert_eq!(set.entries(), &[Range::new(30, 32), Range::new(40, 40), Range::new(50, 54)]);
+    }
+
+    #[test]
+    fn range_set_sum() {
+        let mut set = RangeSet::new();
+        set.insert(Range::new(0, 10)).expect("Could not insert range");
+        set.insert(Range::new(15, 20)).expect("Could not insert range");
+        set.insert(Range::new(30, 40)).expect("Could not insert range");
+        assert_eq!(set.sum(), Some(28));
 
-/// Returns true if the entirity of [x1, x2] is contained inside [y1, y2], else
-/// returns false.
-fn contains(mut x1: u64, mut x2: u64, mut y1: u64, mut y2: u64) -> bool {
-    // Make sure x2 is always > x1
-    if x1 > x2 {
-        core::mem::swap(&mut x1, &mut x2);
+        set.insert(Range::new(0, u64::MAX)).expect("Could not insert range");
+        assert_eq!(set.sum(), None);
     }
 
-    // Make sure y2 is always > y1
-    if y1 > y2 {
-        core::mem::swap(&mut y1, &mut y2);
+    #[test]
+    fn range_set_remove_fragments() {
+        let mut set = RangeSet::new();
+        set.insert(Range::new(0, 10)).expect("Could not insert range");
+        set.insert(Range::new(20, 30)).expect("Could not insert range");
+        set.insert(Range::new(30, 40)).expect("Could not insert range");
+
+        set.remove(Range::new(5, 8)).expect("Could not remove range");
+        set.insert(Range::new(40, 60)).expect("Could not insert range");
+        set.remove(Range::new(25, 55)).expect("Could not remove range");
+        set.remove(Range::new(58, 60)).expect("Could not remove range");
+        set.remove(Range::new(20, 23)).expect("Could not remove range");
+        set.remove(Range::new(10, 10)).expect("Could not remove range");
+
+        assert_eq!(set.entries(), &[
+            Range::new(0, 4),
+            Range::new(9, 9),
+            Range::new(24, 24),
+            Range::new(56, 57),
+        ]);
     }
 
-    x1 >= y1 && x2 <= y2
+    #[test]
+    fn range_set_remove_bios() {
+        let mut set = RangeSet::new();
+        set.insert(Range::new(0, 0x77ff)).expect("Could not insert range");
+        set.insert(Range::new(0x100000, 0x3ff3cfff)).expect("Could not insert range");
+        set.insert(Range::new(0xaa34, 0x9c3ff)).expect("Could not insert range");
+
+        set.remove(Range::new(0, 0xfffff)).expect("Could not remove range");
+
+        assert_eq!(set.entries(), &[Range::new(0x100000, 0x3ff3cfff)]);
+    }
+
+    #[test]
+    fn range_set_full() {
+        let mut set = RangeSet::new();
+        for idx in 0..32 {
+            set.insert(Range::new(idx * 10 + 1, idx * 10 + 6)).expect("Could not insert range");
+        }
+        let full = set;
+
+        // Anything needing a new entry fails without touching the set
+        assert!(set.insert(Range::new(1000, 1005)).is_none());
+        assert!(set.remove(Range::new(3, 4)).is_none());
+        assert!(set.allocate(2, 4).is_none());
+        assert_eq!(set.entries(), full.entries());
+
+        // While merging and trimming still work
+        set.insert(Range::new(7, 9)).expect("Could not insert range");
+        set.remove(Range::new(1, 2)).expect("Could not remove range");
+        assert_eq!(set.allocate(2, 1), Some(11));
+    }
+
+    #[test]
+    fn allocate() {
+        let mut set = RangeSet::new();
+        set.insert(Range::new(0x1001, 0x8fff)).expect("Could not insert range");
+        set.insert(Range::new(0x10000, 0x10fff)).expect("Could not insert range");
+        set.insert(Range::new(0x20000, 0x20fff)).expect("Could not insert range");
+
+        // The smallest entry wins, the lowest one among equals
+        assert_eq!(set.allocate(0x1000, 0x1000), Some(0x10000));
+        // Only the allocated bytes are taken, not the ones skipped for alignment
+        assert_eq!(set.allocate(0x1000, 0x1000), Some(0x20000));
+        assert_eq!(set.allocate(0x1000, 0x1000), Some(0x2000));
+        assert_eq!(set.entries(), &[Range::new(0x1001, 0x1fff), Range::new(0x3000, 0x8fff)]);
+
+        // Above the limit nothing fits
+        assert_eq!(set.allocate_limit(0x1000, 0x1000, 0x3ffe), None);
+        assert_eq!(set.allocate_limit(0x1000, 0x1000, 0x3fff), Some(0x3000));
+
+        // Below the start of the range nothing is taken
+        assert_eq!(set.allocate_in(0x10, 0x10, Range::new(0x1800, 0x8fff)), Some(0x1800));
+        assert_eq!(set.allocate_at(0x1000, 0x10), None);
+        assert_eq!(set.allocate_at(0x1001, 0x10), Some(0x1001));
+        assert_eq!(set.allocate_at(0x1800, 0x10), None);
+
+        assert_eq!(set.allocate(0, 1), None);
+        assert_eq!(set.allocate(1, 3), None);
+        assert_eq!(set.allocate_at(0x5000, 0), None);
+    }
+
+    // Check every operation against the model, for all the sets and ranges of a small universe,
+    // at both ends of the address space
+    #[test]
+    fn exhaustive() {
+        const POINTS: u64 = 8;
+
+        for base in [0, u64::MAX - (POINTS - 1)] {
+            let models: Vec<BTreeSet<u64>> = (0..1u32 << POINTS).map(|bits| {
+                (0..POINTS).filter(|bit| bits & (1 << bit) != 0).map(|bit| base + bit).collect()
+            }).collect();
+            let ranges: Vec<Range> = (base..=base + (POINTS - 1))
+                .flat_map(|start| (start..=base + (POINTS - 1)).map(move |end| Range::new(start, end)))
+                .collect();
+
+            for model in &models {
+                let set = set_from(model);
+                assert_eq!(set.sum(), Some(model.len() as u64));
+
+                for &range in &ranges {
+                    let points = points(&[range]);
+
+                    let mut inserted = set;
+                    inserted.insert(range).expect("Could not insert range");
+                    check_canonical(inserted.entries());
+                    assert_eq!(self::points(inserted.entries()), model | &points);
+
+                    let mut removed = set;
+                    removed.remove(range).expect("Could not remove range");
+                    check_canonical(removed.entries());
+                    assert_eq!(self::points(removed.entries()), model - &points);
+
+                    let mut consumed = set;
+                    let contained = points.is_subset(model)
+                        && runs(model).iter().any(|run| run.contains(&range));
+                    assert_eq!(consumed.consume(range).is_some(), contained);
+                    assert_eq!(self::points(consumed.entries()),
+                        if contained { model - &points } else { model.clone() });
+
+                    let mut intersected = set;
+                    intersected.intersect(range);
+                    check_canonical(intersected.entries());
+                    assert_eq!(self::points(intersected.entries()), model & &points);
+                }
+
+                for other in &models {
+                    let mut subtracted = set;
+                    subtracted.subtract(&set_from(other)).expect("Could not subtract set");
+                    check_canonical(subtracted.entries());
+                    assert_eq!(self::points(subtracted.entries()), model - other);
+                }
+
+                for size in 1..=POINTS {
+                    for align in [1, 2, 4, 8, 16] {
+                        let inside = ranges.iter().copied().chain([Range::new(0, u64::MAX)]);
+                        for range in inside {
+                            let mut allocated = set;
+                            let expected = model_allocate(model, size, align, range);
+                            let result = allocated.allocate_in(size, align, range);
+                            assert_eq!(result.map(|start| start as u64),
+                                expected.map(|range| range.start));
+
+                            let taken = expected.map_or(BTreeSet::new(), |range| points(&[range]));
+                            check_canonical(allocated.entries());
+                            assert_eq!(self::points(allocated.entries()), model - &taken);
+                        }
+                    }
+
+                    for address in base..=base + (POINTS - 1) {
+                        let mut allocated = set;
+                        let wanted = address.checked_add(size - 1)
+                            .map_or(BTreeSet::new(), |end| points(&[Range::new(address, end)]));
+                        let expected = wanted.len() as u64 == size && wanted.is_subset(model);
+                        let result = allocated.allocate_at(address, size);
+                        assert_eq!(result, expected.then_some(address as usize));
+                        assert_eq!(self::points(allocated.entries()),
+                            if expected { model - &wanted } else { model.clone() });
+                    }
+                }
+            }
+        }
+    }
+
+    // Compare the set against a model of the points it holds, with the set running out of room
+    #[test]
+    fn model() {
+        // Ranges of the operations we do are picked from [0, UNIVERSE)
+        const UNIVERSE: u64 = 512;
+
+        let mut rng = Rng::new(0x1234_5678_9abc_def0);
+        for _ in 0..20 {
+            let mut set = RangeSet::new();
+            let mut model = BTreeSet::new();
+
+            for _ in 0..500 {
+                let range = rng.range(UNIVERSE, 24);
+                // A set without room left can refuse operations which would need another entry
+                let full = set.len() == 32;
+                let before = set;
+                let result = match rng.below(4) {
+                    0 => set.insert(range).map(|_| model.extend(range.start..=range.end)),
+                    1 => set.remove(range)
+                        .map(|_| model.retain(|point| !(range.start..=range.end).contains(point))),
+                    2 => {
+                        let size = 1 + rng.below(16);
+                        let align = 1 << rng.below(5);
+                        let inside = rng.range(UNIVERSE, UNIVERSE);
+                        let expected = model_allocate(&model, size, align, inside);
+                        match set.allocate_in(size, align, inside) {
+                            Some(start) => {
+                                let expected = expected.expect("Allocated more than the model");
+                                assert_eq!(start as u64, expected.start);
+                                model.retain(|point| !(expected.start..=expected.end)
+                                    .contains(point));
+                                Some(())
+                            }
+                            None => expected.map_or(Some(()), |_| None),
+                        }
+                    }
+                    _ => {
+                        let other = (0..rng.below(4)).fold(RangeSet::new(), |mut other, _| {
+                            other.insert(rng.range(UNIVERSE, 24));
+                            other
+                        });
+                        let expected = &model - &points(other.entries());
+                        if set.subtract(&other).is_none() {
+                            // Only part of the set was subtracted
+                            assert!(full || set.len() == 32);
+                            let left = points(set.entries());
+                            assert!(expected.is_subset(&left) && left.is_subset(&model));
+                            model = left;
+                        } else {
+                            model = expected;
+                        }
+                        Some(())
+                    }
+                };
+
+                // Failures only come from running out of room and leave the set untouched
+                if result.is_none() {
+                    assert!(full);
+                    assert_eq!(set.entries(), before.entries());
+                }
+                check_canonical(set.entries());
+                assert_eq!(points(set.entries()), model);
+                assert_eq!(set.sum(), Some(model.len() as u64));
+            }
+        }
+    }
 }
@@ -2,6 +2,10 @@
 
 use core::array::TryFromSliceError;
 
+// The `ReadMe` derive names this crate by its path, which the tests need to resolve
+#[cfg(test)]
+extern crate self as read_me;
+
 pub struct Reader<'a> {
     bytes: &'a [u8],
     idx: usize,
@@ -9,8 +13,9 @@ pub struct Reader<'a> {
 
 impl<'a> Reader<'a> {
     pub fn peek<P: Primitive>(&self) -> Result<P, ReaderError> {
-        let end = self.idx.saturating_add(core::mem::size_of::<P>());
-        P::read(self.bytes.get(self.idx..end)
+        // The in-memory size of `P` might include padding, so we hand out all the remaining bytes
+        // and let `P` consume only what it needs
+        P::read(self.bytes.get(self.idx..)
             .ok_or(ReaderError::OutOfBounds(self.idx, self.bytes.len()))?)
     }
 
@@ -18,7 +23,7 @@ impl<'a> Reader<'a> {
         // Read the value
         let value = self.peek::<P>()?;
         // If the read was successful, move the cursor
-        self.idx += core::mem::size_of::<P>();
+        self.idx += value.size_on_disk();
         // Return the value
         Ok(value)
     }
@@ -140,6 +145,9 @@ macro_rules! read_impl {
 read_impl!(u8);
 read_impl!(u8, 1);
 read_impl!(u8, 2);
+read_impl!(u8, 3);
+read_impl!(u8, 4);
+read_impl!(u8, 6);
 read_impl!(u8, 8);
 read_impl!(u8, 10);
 read_impl!(u16);
@@ -152,3 +160,48 @@ read_impl!(i8);
 read_impl!(i16);
 read_impl!(i32);
 read_impl!(i64);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parseme::ReadMe;
+
+    // 7 bytes on disk, padded to 8 in memory
+    #[derive(ReadMe, Debug, PartialEq)]
+    struct Padded {
+        kind: u8,
+        value: u32,
+        flags: u16,
+    }
+
+    #[test]
+    fn padded_structs() {
+        assert_eq!(core::mem::size_of::<Padded>(), 8);
+        let bytes = [1, 0x44, 0x33, 0x22, 0x11, 0x02, 0x01, 2, 0, 0, 0, 0, 0xff, 0xff];
+        let mut reader = Reader::from(&bytes[..]);
+
+        let first = reader.read::<Padded>().expect("Failed to read the first struct");
+        assert_eq!(first, Padded { kind: 1, value: 0x1122_3344, flags: 0x0102 });
+        assert_eq!(first.size_on_disk(), 7);
+        assert_eq!(reader.offset(), 7);
+
+        // The last struct ends with the data, even though its in-memory size would not fit
+        let second = reader.read::<Padded>().expect("Failed to read the last struct");
+        assert_eq!(second, Padded { kind: 2, value: 0, flags: 0xffff });
+        assert_eq!(reader.offset(), bytes.len());
+        assert!(matches!(reader.read::<Padded>(), Err(ReaderError::InsufficientBytes(1, 0))));
+    }
+
+    #[test]
+    fn primitives() {
+        let bytes = [1, 2, 3, 4, 5, 6];
+        let mut reader = Reader::from(&bytes[..]);
+        assert_eq!(reader.peek::<u16>().ok(), Some(0x0201));
+        assert_eq!(reader.read::<[u8; 3]>().ok(), Some([1, 2, 3]));
+        assert_eq!(reader.read::<u16>().ok(), Some(0x0504));
+        assert_eq!(reader.offset(), 5);
+        assert!(matches!(reader.read::<u16>(), Err(ReaderError::InsufficientBytes(2, 1))));
+        // A failed read does not move the cursor
+        assert_eq!(reader.read::<u8>().ok(), Some(6));
+    }
+}
@@ -29,11 +29,19 @@ impl Serial {
                 continue;
             }
             // Initialize the port
-            init_serial(port_addr);
+            init_serial(port_addr, 1);
             *port = Some(port_addr);
         }
         serial
     }
+
+    /// Initialize only the serial port at the I/O `port`, running at the UART base rate of 115200
+    /// baud divided by `divisor`
+    pub fn init_port(port: u16, divisor: u16) -> Self {
+        init_serial(port, divisor);
+        Self { ports: [Some(port), None, None, None] }
+    }
+
     // Broadcast write `bytes` to all known and initialized serial ports
     fn write_bytes(&mut self, bytes: &[u8]) {
         for value in bytes {
@@ -49,18 +57,200 @@ impl Serial {
     pub fn write_str(&mut self, text: &str) {
         self.write_bytes(text.as_bytes());
     }
+
+    /// Returns the next byte received on any of the ports, if there is one, without waiting
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        self.ports.iter().flatten().find(|port| data_ready(**port) != 0).map(|port| in_u8(*port))
+    }
+
+    /// Wait for the next byte received on any of the ports
+    pub fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read_byte() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Feed the bytes received so far to `editor`, echoing the edits, without waiting. Returns the
+    /// line once it is complete.
+    pub fn try_read_line<'a, const N: usize>(
+        &mut self,
+        editor: &'a mut LineEditor<N>,
+    ) -> Option<&'a str> {
+        while let Some(byte) = self.try_read_byte() {
+            if editor.feed(byte, &mut |bytes| self.write_bytes(bytes)) {
+                return Some(editor.line());
+            }
+        }
+        None
+    }
+
+    /// Wait for a complete line, typed with the editing `editor` provides
+    pub fn read_line<'a, const N: usize>(&mut self, editor: &'a mut LineEditor<N>) -> &'a str {
+        loop {
+            let byte = self.read_byte();
+            if editor.feed(byte, &mut |bytes| self.write_bytes(bytes)) {
+                return editor.line();
+            }
+        }
+    }
 }
 
-// Initialize a serial communication port at `port`
-fn init_serial(port: u16) {
+// Control characters the line editor handles
+const CTRL_A: u8 = 0x01;
+const CTRL_E: u8 = 0x05;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const ESCAPE: u8 = 0x1b;
+const DELETE: u8 = 0x7f;
+
+// Progress through an ANSI escape sequence, as sent by the arrow and home/end keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    None,
+    // Got the escape character
+    Escape,
+    // Got the escape character and `[`
+    Csi,
+}
+
+/// Line of up to `N` printable ASCII characters being typed on a terminal. Supports moving with
+/// the arrows, home and end (or Ctrl-A and Ctrl-E), deleting with backspace and clearing the line
+/// with Ctrl-U.
+pub struct LineEditor<const N: usize> {
+    line: [u8; N],
+    len: usize,
+    // Position of the terminal cursor in the line
+    cursor: usize,
+    escape: EscapeState,
+    // The previous line ended with a carriage return, a line feed right after it is part of it
+    after_cr: bool,
+    // The line was handed out, the next byte starts a new one
+    complete: bool,
+}
+
+impl<const N: usize> LineEditor<N> {
+    pub const fn new() -> Self {
+        Self {
+            line: [0; N],
+            len: 0,
+            cursor: 0,
+            escape: EscapeState::None,
+            after_cr: false,
+            complete: false,
+        }
+    }
+
+    /// Returns the line typed so far
+    pub fn line(&self) -> &str {
+        // Only printable ASCII characters make it in the line
+        core::str::from_utf8(&self.line[..self.len]).unwrap_or("")
+    }
+
+    /// Apply the received `byte` to the line, giving `echo` what the terminal has to show for it.
+    /// Returns `true` once the line is complete.
+    pub fn feed(&mut self, byte: u8, echo: &mut impl FnMut(&[u8])) -> bool {
+        if self.complete {
+            self.complete = false;
+            self.len = 0;
+            self.cursor = 0;
+        }
+        let after_cr = core::mem::replace(&mut self.after_cr, false);
+
+        match (self.escape, byte) {
+            (EscapeState::Escape, b'[') => self.escape = EscapeState::Csi,
+            (EscapeState::Csi, b'0'..=b'9' | b';') => {}
+            (EscapeState::Csi, _) => {
+                self.escape = EscapeState::None;
+                match byte {
+                    b'C' => self.move_to(self.cursor + 1, echo),
+                    b'D' => self.move_to(self.cursor.saturating_sub(1), echo),
+                    b'H' => self.move_to(0, echo),
+                    b'F' => self.move_to(self.len, echo),
+                    _ => {}
+                }
+            }
+            (EscapeState::Escape, _) => self.escape = EscapeState::None,
+            (EscapeState::None, b'\n') if after_cr => {}
+            (EscapeState::None, b'\r' | b'\n') => {
+                self.after_cr = byte == b'\r';
+                self.complete = true;
+                echo(b"\n");
+            }
+            (EscapeState::None, ESCAPE) => self.escape = EscapeState::Escape,
+            (EscapeState::None, BACKSPACE | DELETE) if self.cursor > 0 => {
+                self.line.copy_within(self.cursor..self.len, self.cursor - 1);
+                self.len -= 1;
+                self.cursor -= 1;
+                echo(&[BACKSPACE]);
+                self.redraw_tail(1, echo);
+            }
+            (EscapeState::None, CTRL_A) => self.move_to(0, echo),
+            (EscapeState::None, CTRL_E) => self.move_to(self.len, echo),
+            (EscapeState::None, CTRL_U) => {
+                let cleared = self.len;
+                self.move_to(0, echo);
+                self.len = 0;
+                self.redraw_tail(cleared, echo);
+            }
+            (EscapeState::None, b' '..=b'~') if self.len < N => {
+                self.line.copy_within(self.cursor..self.len, self.cursor + 1);
+                self.line[self.cursor] = byte;
+                self.len += 1;
+                self.cursor += 1;
+                echo(&[byte]);
+                self.redraw_tail(0, echo);
+            }
+            // Other control characters and bytes past the capacity are dropped
+            _ => {}
+        }
+        self.complete
+    }
+
+    // Move the cursor to `position`, clamped to the end of the line
+    fn move_to(&mut self, position: usize, echo: &mut impl FnMut(&[u8])) {
+        let position = core::cmp::min(position, self.len);
+        while self.cursor > position {
+            echo(&[BACKSPACE]);
+            self.cursor -= 1;
+        }
+        if position > self.cursor {
+            echo(&self.line[self.cursor..position]);
+            self.cursor = position;
+        }
+    }
+
+    // Rewrite the line after the cursor, blank the `erased` characters which were past its end
+    // and put the cursor back
+    fn redraw_tail(&self, erased: usize, echo: &mut impl FnMut(&[u8])) {
+        echo(&self.line[self.cursor..self.len]);
+        for _ in 0..erased {
+            echo(b" ");
+        }
+        for _ in self.cursor..self.len + erased {
+            echo(&[BACKSPACE]);
+        }
+    }
+}
+
+impl<const N: usize> Default for LineEditor<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Initialize a serial communication port at `port`, with a baud rate of 115200 / `divisor`
+fn init_serial(port: u16, divisor: u16) {
     // Disable interupts
     out_u8(port.saturating_add(1), 0x00);
     // Set the DLAB (Divisor Access Bit) in order to set the divisor
     out_u8(port.saturating_add(3), 0x80);
-    // Set divisor to 1 (lo bytes)
-    out_u8(port, 1);
-    // Set divisor to 1 (hi byte)
-    out_u8(port.saturating_add(1), 0);
+    // Set divisor (lo byte)
+    out_u8(port, divisor as u8);
+    // Set divisor (hi byte)
+    out_u8(port.saturating_add(1), (divisor >> 8) as u8);
     // Set 8 data bits, no parity and 1 stop bit (8n1). Also disable DLAB
     out_u8(port.saturating_add(3), 0b00000011);
     // Disable FIFO Buffer state (not present in all processors)
@@ -108,3 +298,56 @@ fn write(port: u16, value: u8) {
     write_data(port, value);
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate std;
+    use std::{string::String, vec::Vec};
+
+    // Feed `input` to `editor`, returning the completed lines and everything echoed
+    fn type_in<const N: usize>(editor: &mut LineEditor<N>, input: &[u8]) -> (Vec<String>, Vec<u8>) {
+        let mut lines = Vec::new();
+        let mut echoed = Vec::new();
+        for byte in input {
+            if editor.feed(*byte, &mut |bytes| echoed.extend_from_slice(bytes)) {
+                lines.push(editor.line().into());
+            }
+        }
+        (lines, echoed)
+    }
+
+    #[test]
+    fn line_editing() {
+        let mut editor = LineEditor::<32>::new();
+        let (lines, echoed) = type_in(&mut editor, b"helo\x08\x08lo\r");
+        assert_eq!(lines, ["helo"]);
+        assert_eq!(echoed, b"helo\x08 \x08\x08 \x08lo\n");
+
+        // Insert in the middle with the arrows, then jump to the end
+        let (lines, _) = type_in(&mut editor, b"rdmr 10\x1b[D\x1b[D\x1b[D\x1b[Ds\x1b[F0\r\n");
+        assert_eq!(lines, ["rdmsr 100"]);
+
+        // The line feed after a carriage return does not end an empty line, but a second one does
+        let (lines, _) = type_in(&mut editor, b"\n");
+        assert_eq!(lines, [""]);
+
+        // Clear the line, delete at the start of it and ignore other control characters
+        let (lines, _) = type_in(&mut editor, b"reboot\x15\x7fcores\x01\x7f\x07\r");
+        assert_eq!(lines, ["cores"]);
+    }
+
+    #[test]
+    fn line_capacity() {
+        let mut editor = LineEditor::<4>::new();
+        let (lines, echoed) = type_in(&mut editor, b"dump 0x1000\r");
+        assert_eq!(lines, ["dump"]);
+        assert_eq!(echoed, b"dump\n");
+
+        // Backspace in the middle of the line redraws what is after the cursor
+        let (lines, echoed) = type_in(&mut editor, b"abc\x1b[D\x08\r");
+        assert_eq!(lines, ["ac"]);
+        assert_eq!(echoed, b"abc\x08\x08c \x08\x08\n");
+    }
+}
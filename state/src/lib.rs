@@ -3,12 +3,571 @@
 //! Pacakge that contains states and contexts to be passed between the different stages of booting
 
 use mmu::Mmu;
+use parse_config::MAX_CONFIG_PATH_LEN;
+pub use parse_config::{MAX_MODULES, MAX_MODULE_NAME_LEN};
 use serial::Serial;
 use sync::lockcell::LockCell;
 
-/// Contains the bidirectional state to be passed between the bootloader and the kernel
+/// Version of the `BootState` layout. Has to be bumped every time the structure changes, such
+/// that a kernel never runs with a bootloader that fills in a different layout.
+pub const BOOT_STATE_VERSION: u32 = 8;
+/// Maximum number of E820 entries we keep
+pub const MAX_MEMORY_MAP_ENTRIES: usize = 64;
+/// Maximum length of the kernel command line, in bytes
+pub const MAX_CMDLINE_LEN: usize = 256;
+/// Maximum number of DNS servers saved in `DhcpInfo`
+pub const MAX_DNS_SERVERS: usize = 2;
+/// Size of the identity map the bootloader hands over to the kernel, which the kernel removes
+/// once it runs from the higher half
+pub const IDENTITY_MAP_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+/// Virtual address of the region holding the kernel stacks, one after the other. The stack with
+/// index 0 is the one the bootloader enters the kernel with.
+pub const STACK_REGION_BASE: u64 = 0xffff_fe00_0000_0000;
+/// Size of the unmapped memory left below each stack, such that an overflow faults instead of
+/// silently corrupting the memory below it
+pub const STACK_GUARD_SIZE: u64 = 4096;
+// Size of a kernel stack, unless picked at build time
+const DEFAULT_STACK_SIZE: u64 = 64 * 1024;
+
+/// Returns the size of each kernel stack the bootloader records in `BootState::stack_size`. It can
+/// be set at build time of the bootloader, in bytes, with the decimal or `0x` prefixed hexadecimal
+/// `PIZZA_STACK_SIZE` environment variable, an invalid value fails the build. The size is rounded
+/// up to whole pages.
+pub const fn stack_size() -> u32 {
+    let size = match option_env!("PIZZA_STACK_SIZE") {
+        Some(size) => match parse_size(size) {
+            Some(size) if size != 0 && size <= u32::MAX as u64 - 0xfff => size,
+            _ => panic!("PIZZA_STACK_SIZE is not a size between 1 byte and 4 GiB"),
+        },
+        None => DEFAULT_STACK_SIZE,
+    };
+    ((size + 0xfff) & !0xfff) as u32
+}
+
+// Parse a decimal or `0x` prefixed hexadecimal size
+const fn parse_size(size: &str) -> Option<u64> {
+    let (digits, radix) = match size.as_bytes() {
+        [b'0', b'x', ..] => (size.split_at(2).1, 16),
+        _ => (size, 10),
+    };
+    match u64::from_str_radix(digits, radix) {
+        Ok(size) => Some(size),
+        Err(_) => None,
+    }
+}
+
+/// Contains the bidirectional state to be passed between the bootloader and the kernel. The
+/// bootloader is 32-bit and the kernel is 64-bit, so all the fields before `mmu` only use fixed
+/// width types, such that both of them agree on their offsets.
 #[repr(C)]
 pub struct BootState {
+    // Set to `BOOT_STATE_VERSION` by the bootloader that created this structure
+    pub version: u32,
+    // Size of each kernel stack, as the bootloader was built with. The kernel uses this one for the
+    // stacks it allocates, such that the guards are where the bootloader left the first one.
+    pub stack_size: u32,
+    // Raw memory map, as reported by the BIOS
+    pub memory_map: LockCell<MemoryMap>,
+    // Physical memory the kernel image was loaded at
+    pub kernel_physical: LockCell<Region>,
+    // Virtual memory the kernel image is mapped at
+    pub kernel_virtual: LockCell<Region>,
+    // Physical memory occupied by the bootloader image, including this structure
+    pub bootloader: LockCell<Region>,
+    // Command line passed to the kernel
+    pub cmdline: LockCell<CommandLine>,
+    // Network configuration the machine was booted with
+    pub dhcp: LockCell<DhcpInfo>,
+    // Values taken from the configuration file the bootloader downloaded
+    pub config: LockCell<BootConfig>,
+    // Files loaded in physical memory with the kernel
+    pub modules: LockCell<ModuleList>,
+    // Virtual memory through which the kernel accesses the physical memory
+    pub physical_window: LockCell<Region>,
     pub mmu: LockCell<Option<Mmu>>,
     pub serial: LockCell<Option<Serial>>,
 }
+
+impl BootState {
+    /// Create an empty boot state, for the current `BOOT_STATE_VERSION`
+    pub const fn new() -> Self {
+        Self {
+            version: BOOT_STATE_VERSION,
+            stack_size: stack_size(),
+            memory_map: LockCell::new(MemoryMap::new()),
+            kernel_physical: LockCell::new(Region::empty()),
+            kernel_virtual: LockCell::new(Region::empty()),
+            bootloader: LockCell::new(Region::empty()),
+            cmdline: LockCell::new(CommandLine::new()),
+            dhcp: LockCell::new(DhcpInfo::empty()),
+            config: LockCell::new(BootConfig::empty()),
+            modules: LockCell::new(ModuleList::new()),
+            physical_window: LockCell::new(Region::empty()),
+            mmu: LockCell::new(None),
+            serial: LockCell::new(None),
+        }
+    }
+
+    /// Returns `true` if this structure was created with the same layout we were compiled with
+    pub fn is_compatible(&self) -> bool {
+        self.version == BOOT_STATE_VERSION
+            && self.stack_size != 0
+            && self.stack_size.is_multiple_of(4096)
+    }
+
+    /// Returns the virtual memory of the kernel stack with `index`, without its guard
+    pub fn stack(&self, index: u64) -> Region {
+        let size = u64::from(self.stack_size);
+        let slot = size + STACK_GUARD_SIZE;
+        Region {
+            base: STACK_REGION_BASE.saturating_add(index.saturating_mul(slot)) + STACK_GUARD_SIZE,
+            size,
+        }
+    }
+
+    /// Returns `true` if `address` is in the guard of one of the kernel stacks
+    pub fn is_stack_guard(&self, address: u64) -> bool {
+        let slot = u64::from(self.stack_size) + STACK_GUARD_SIZE;
+        address.checked_sub(STACK_REGION_BASE)
+            .is_some_and(|offset| offset % slot < STACK_GUARD_SIZE)
+    }
+}
+
+impl Default for BootState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A contiguous memory region
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Region {
+    pub base: u64,
+    pub size: u64,
+}
+
+impl Region {
+    pub const fn empty() -> Self {
+        Self { base: 0, size: 0 }
+    }
+
+    /// Returns the address right after the end of the region
+    pub fn end(&self) -> u64 {
+        self.base.saturating_add(self.size)
+    }
+}
+
+/// Type of the memory described by an E820 entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    // RAM the operating system is free to use
+    Usable,
+    // In use or reserved by the system
+    Reserved,
+    // Holds ACPI tables, can be reused once they are no longer needed
+    AcpiReclaimable,
+    // ACPI Non-Volatile-Sleeping memory, has to be preserved
+    AcpiNvs,
+    // Memory in which errors have been detected
+    Unusable,
+    Unknown(u32),
+}
+
+impl From<u32> for MemoryType {
+    fn from(value: u32) -> Self {
+        match value {
+            1 => Self::Usable,
+            2 => Self::Reserved,
+            3 => Self::AcpiReclaimable,
+            4 => Self::AcpiNvs,
+            5 => Self::Unusable,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+/// Address range descriptor returned by the E820 BIOS call
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct E820Entry {
+    pub base: u64,
+    pub length: u64,
+    // Raw type of the range, see `MemoryType`
+    pub typ: u32,
+    // Extended attributes from ACPI 3.0, or 1 if the BIOS did not return them
+    pub attributes: u32,
+}
+
+impl E820Entry {
+    pub const fn empty() -> Self {
+        Self { base: 0, length: 0, typ: 0, attributes: 0 }
+    }
+
+    pub fn memory_type(&self) -> MemoryType {
+        MemoryType::from(self.typ)
+    }
+}
+
+/// The E820 entries, in the order the BIOS reported them
+#[derive(Debug)]
+#[repr(C)]
+pub struct MemoryMap {
+    entries: [E820Entry; MAX_MEMORY_MAP_ENTRIES],
+    len: u32,
+}
+
+impl MemoryMap {
+    pub const fn new() -> Self {
+        Self { entries: [E820Entry::empty(); MAX_MEMORY_MAP_ENTRIES], len: 0 }
+    }
+
+    /// Append `entry` to the map. Returns `None` if the map is full.
+    pub fn push(&mut self, entry: E820Entry) -> Option<()> {
+        let slot = self.entries.get_mut(usize::try_from(self.len).ok()?)?;
+        *slot = entry;
+        self.len += 1;
+        Some(())
+    }
+
+    pub fn entries(&self) -> &[E820Entry] {
+        let len = core::cmp::min(self.len as usize, MAX_MEMORY_MAP_ENTRIES);
+        &self.entries[..len]
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A file the bootloader loaded in physical memory for the kernel. The memory it occupies is not
+/// part of the free memory handed over in the `Mmu`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Module {
+    // Null terminated name of the file on the TFTP server
+    name: [u8; MAX_MODULE_NAME_LEN],
+    // Physical address of the contents, aligned to a page
+    pub base: u64,
+    // Size of the file in bytes
+    pub length: u64,
+}
+
+impl Module {
+    const fn empty() -> Self {
+        Self { name: [0; MAX_MODULE_NAME_LEN], base: 0, length: 0 }
+    }
+
+    /// Create a module for the file `name`. Returns `None` if the name does not fit or contains a
+    /// null byte.
+    pub fn new(name: &[u8], base: u64, length: u64) -> Option<Self> {
+        if name.len() >= MAX_MODULE_NAME_LEN || name.contains(&0) {
+            return None;
+        }
+        let mut module = Self { base, length, ..Self::empty() };
+        module.name[..name.len()].copy_from_slice(name);
+        Some(module)
+    }
+
+    pub fn name(&self) -> &[u8] {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(MAX_MODULE_NAME_LEN);
+        &self.name[..len]
+    }
+
+    /// Returns the physical memory holding the module
+    pub fn region(&self) -> Region {
+        Region { base: self.base, size: self.length }
+    }
+}
+
+/// The modules, in the order the bootloader loaded them
+#[derive(Debug)]
+#[repr(C)]
+pub struct ModuleList {
+    modules: [Module; MAX_MODULES],
+    len: u32,
+}
+
+impl ModuleList {
+    pub const fn new() -> Self {
+        Self { modules: [Module::empty(); MAX_MODULES], len: 0 }
+    }
+
+    /// Append `module` to the list. Returns `None` if the list is full.
+    pub fn push(&mut self, module: Module) -> Option<()> {
+        let slot = self.modules.get_mut(usize::try_from(self.len).ok()?)?;
+        *slot = module;
+        self.len += 1;
+        Some(())
+    }
+
+    pub fn entries(&self) -> &[Module] {
+        let len = core::cmp::min(self.len as usize, MAX_MODULES);
+        &self.modules[..len]
+    }
+
+    /// Returns the first module loaded from the file `name`
+    pub fn find(&self, name: &[u8]) -> Option<&Module> {
+        self.entries().iter().find(|module| module.name() == name)
+    }
+}
+
+impl Default for ModuleList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Kernel command line, stored inline
+#[derive(Debug)]
+#[repr(C)]
+pub struct CommandLine {
+    bytes: [u8; MAX_CMDLINE_LEN],
+    len: u32,
+}
+
+impl CommandLine {
+    pub const fn new() -> Self {
+        Self { bytes: [0; MAX_CMDLINE_LEN], len: 0 }
+    }
+
+    /// Replace the command line with `bytes`. Returns `None` if it does not fit.
+    pub fn set(&mut self, bytes: &[u8]) -> Option<()> {
+        self.bytes.get_mut(..bytes.len())?.copy_from_slice(bytes);
+        self.len = u32::try_from(bytes.len()).ok()?;
+        Some(())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        let len = core::cmp::min(self.len as usize, MAX_CMDLINE_LEN);
+        &self.bytes[..len]
+    }
+
+    /// Returns the command line as a string, if it is valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        core::str::from_utf8(self.as_bytes()).ok()
+    }
+
+    /// Returns `true` if `flag` is one of the whitespace separated words of the command line
+    pub fn has_flag(&self, flag: &[u8]) -> bool {
+        let mut words = self.as_bytes().split(u8::is_ascii_whitespace);
+        !flag.is_empty() && words.any(|word| word == flag)
+    }
+}
+
+impl Default for CommandLine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Network configuration from the DHCP acknowledgement the PXE firmware received. IP addresses
+/// are in network order.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct DhcpInfo {
+    // Address the client already had, if any
+    pub client_ip: [u8; 4],
+    // Address assigned to us by the DHCP server
+    pub your_ip: [u8; 4],
+    // TFTP server we were booted from
+    pub server_ip: [u8; 4],
+    // Relay agent address
+    pub gateway_ip: [u8; 4],
+    pub mac: [u8; 6],
+    pub _reserved: [u8; 2],
+    // Null terminated server host name
+    pub server_name: [u8; 64],
+    // Null terminated boot file name
+    pub boot_file: [u8; 128],
+    // The following fields come from the DHCP options and are zero if the server did not send them
+    pub subnet_mask: [u8; 4],
+    // Preferred router on our subnet
+    pub router: [u8; 4],
+    // DNS servers, in order of preference
+    pub dns: [[u8; 4]; MAX_DNS_SERVERS],
+    // Null terminated host name
+    pub hostname: [u8; 64],
+}
+
+impl DhcpInfo {
+    pub const fn empty() -> Self {
+        Self {
+            client_ip: [0; 4],
+            your_ip: [0; 4],
+            server_ip: [0; 4],
+            gateway_ip: [0; 4],
+            mac: [0; 6],
+            _reserved: [0; 2],
+            server_name: [0; 64],
+            boot_file: [0; 128],
+            subnet_mask: [0; 4],
+            router: [0; 4],
+            dns: [[0; 4]; MAX_DNS_SERVERS],
+            hostname: [0; 64],
+        }
+    }
+
+    /// Returns `true` if the bootloader filled in the structure
+    pub fn is_valid(&self) -> bool {
+        self.your_ip != [0; 4]
+    }
+}
+
+impl Default for DhcpInfo {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+/// Configuration the bootloader booted with. Strings are null terminated.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct BootConfig {
+    // Name of the configuration file, empty if none was found
+    pub config_file: [u8; MAX_CONFIG_PATH_LEN + 1],
+    // Name of the kernel file that was downloaded
+    pub kernel_file: [u8; 128],
+    // I/O port of the serial console, 0 if all the ports the BIOS found are used
+    pub serial_port: u16,
+    pub _reserved: u16,
+    pub serial_baud: u32,
+}
+
+impl BootConfig {
+    pub const fn empty() -> Self {
+        Self {
+            config_file: [0; MAX_CONFIG_PATH_LEN + 1],
+            kernel_file: [0; 128],
+            serial_port: 0,
+            _reserved: 0,
+            serial_baud: 0,
+        }
+    }
+
+    /// Returns `true` if the bootloader booted with a configuration file
+    pub fn is_valid(&self) -> bool {
+        self.config_file[0] != 0
+    }
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_map_push() {
+        let mut map = MemoryMap::new();
+        assert!(map.entries().is_empty());
+
+        let entry = E820Entry { base: 0x10_0000, length: 0x7ee_0000, typ: 1, attributes: 1 };
+        for _ in 0..MAX_MEMORY_MAP_ENTRIES {
+            assert!(map.push(entry).is_some());
+        }
+        // The map is full
+        assert!(map.push(entry).is_none());
+        assert_eq!(map.entries().len(), MAX_MEMORY_MAP_ENTRIES);
+        assert_eq!(map.entries()[0].memory_type(), MemoryType::Usable);
+    }
+
+    #[test]
+    fn modules() {
+        let mut modules = ModuleList::new();
+        assert!(modules.find(b"ramdisk.img").is_none());
+
+        let ramdisk = Module::new(b"ramdisk.img", 0x20_0000, 0x1234).expect("Valid module");
+        assert_eq!(ramdisk.name(), b"ramdisk.img");
+        assert_eq!(ramdisk.region().end(), 0x20_1234);
+        let full_name = [b'a'; MAX_MODULE_NAME_LEN - 1];
+        let full = Module::new(&full_name, 0x30_0000, 0).expect("Valid module");
+        assert_eq!(full.name(), full_name);
+        assert!(Module::new(&[b'a'; MAX_MODULE_NAME_LEN], 0, 0).is_none());
+        assert!(Module::new(b"bad\0name", 0, 0).is_none());
+
+        modules.push(ramdisk).expect("Failed to push module");
+        for _ in 1..MAX_MODULES {
+            modules.push(full).expect("Failed to push module");
+        }
+        // The list is full
+        assert!(modules.push(full).is_none());
+        assert_eq!(modules.entries().len(), MAX_MODULES);
+        assert_eq!(modules.find(b"ramdisk.img"), Some(&ramdisk));
+        assert_eq!(modules.find(&full_name), Some(&full));
+    }
+
+    #[test]
+    fn memory_types() {
+        assert_eq!(MemoryType::from(2), MemoryType::Reserved);
+        assert_eq!(MemoryType::from(3), MemoryType::AcpiReclaimable);
+        assert_eq!(MemoryType::from(4), MemoryType::AcpiNvs);
+        assert_eq!(MemoryType::from(0x1337), MemoryType::Unknown(0x1337));
+    }
+
+    #[test]
+    fn cmdline() {
+        let mut cmdline = CommandLine::new();
+        assert_eq!(cmdline.as_str(), Some(""));
+        assert!(cmdline.set(b"console=serial cores=4").is_some());
+        assert_eq!(cmdline.as_str(), Some("console=serial cores=4"));
+        assert!(cmdline.has_flag(b"cores=4"));
+        assert!(!cmdline.has_flag(b"cores"));
+        assert!(!cmdline.has_flag(b""));
+        assert!(cmdline.set(&[b'a'; MAX_CMDLINE_LEN + 1]).is_none());
+        // A failed update leaves the previous command line
+        assert_eq!(cmdline.as_str(), Some("console=serial cores=4"));
+    }
+
+    #[test]
+    fn stacks() {
+        let state = BootState::new();
+        let size = u64::from(stack_size());
+        assert_eq!(u64::from(state.stack_size), size);
+        assert!(size != 0 && size.is_multiple_of(4096));
+        assert!(state.is_compatible());
+
+        // Each stack has a guard below it, and the next guard right after it
+        let first = state.stack(0);
+        let second = state.stack(1);
+        assert_eq!(first.base, STACK_REGION_BASE + STACK_GUARD_SIZE);
+        assert_eq!(first.size, size);
+        assert_eq!(second.base, first.end() + STACK_GUARD_SIZE);
+
+        assert!(state.is_stack_guard(STACK_REGION_BASE));
+        assert!(state.is_stack_guard(first.base - 8));
+        assert!(!state.is_stack_guard(first.base));
+        assert!(!state.is_stack_guard(first.end() - 8));
+        assert!(state.is_stack_guard(first.end()));
+        assert!(state.is_stack_guard(second.base - 1));
+        assert!(!state.is_stack_guard(STACK_REGION_BASE - 1));
+
+        assert_eq!(parse_size("0x4000"), Some(0x4000));
+        assert_eq!(parse_size("16384"), Some(0x4000));
+        assert_eq!(parse_size("16K"), None);
+        assert_eq!(parse_size("0x"), None);
+
+        // A kernel cannot work with stacks which are not whole pages
+        let mut odd = BootState::new();
+        odd.stack_size = 0x1800;
+        assert!(!odd.is_compatible());
+    }
+
+    #[test]
+    fn fixed_layout() {
+        // These have to be the same for the 32-bit bootloader and the 64-bit kernel
+        assert_eq!(core::mem::size_of::<E820Entry>(), 24);
+        assert_eq!(core::mem::size_of::<Region>(), 16);
+        assert_eq!(core::mem::size_of::<DhcpInfo>(), 296);
+        assert_eq!(core::mem::size_of::<BootConfig>(), 172);
+        assert_eq!(core::mem::size_of::<Module>(), 80);
+        assert_eq!(core::mem::offset_of!(BootState, stack_size), 4);
+        assert_eq!(core::mem::offset_of!(BootState, memory_map), 8);
+    }
+}